@@ -0,0 +1,190 @@
+// HTTP API for bridge admins, served under /api/v1/admin when `web.adminToken` is set
+use std::convert::TryFrom;
+
+use anyhow::Result;
+use log::*;
+use matrix_sdk::ruma::UserId;
+use serde::Deserialize;
+use warp::{Filter, Rejection, Reply};
+
+use crate::{
+  appservice_api::{decode_path_segment, with_context},
+  bot, payloads, room_reference,
+  webhook::RequestContext,
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TransferRequest {
+  user_id: String,
+}
+
+pub fn filter(
+  context: RequestContext,
+) -> impl Filter<Extract = (Box<dyn Reply>,), Error = Rejection> + Clone {
+  let transfer = warp::path!("api" / "v1" / "admin" / "hooks" / String / "transfer")
+    .and(warp::filters::method::post())
+    .and(warp::header::optional::<String>("authorization"))
+    .and(warp::body::json())
+    .and(with_context(context.clone()))
+    .and_then(transfer);
+
+  // The room can be an id, an alias, a matrix.to link or a matrix: URI, percent-encoded
+  let room_hooks = warp::path!("api" / "v1" / "admin" / "rooms" / String / "hooks")
+    .and(warp::filters::method::get())
+    .and(warp::header::optional::<String>("authorization"))
+    .and(with_context(context))
+    .and_then(room_hooks);
+
+  transfer.or(room_hooks).unify()
+}
+
+// Unlike the homeserver's token, the admin token is only accepted as a bearer token, so it
+// doesn't end up in access logs
+fn unauthorized(
+  context: &RequestContext,
+  authorization: &Option<String>,
+) -> Option<Box<dyn Reply>> {
+  let token = authorization
+    .as_deref()
+    .and_then(|header| header.strip_prefix("Bearer "));
+  match (&context.config.web.admin_token, token) {
+    (Some(admin_token), Some(token)) if payloads::is_secret(admin_token, token.as_bytes()) => None,
+    (None, _) => Some(error_reply(
+      http::StatusCode::NOT_FOUND,
+      "M_UNRECOGNIZED",
+      "The admin API is disabled",
+    )),
+    _ => Some(error_reply(
+      http::StatusCode::UNAUTHORIZED,
+      "M_UNKNOWN_TOKEN",
+      "Unknown admin token",
+    )),
+  }
+}
+
+fn error_reply(status: http::StatusCode, errcode: &str, error: &str) -> Box<dyn Reply> {
+  Box::new(warp::reply::with_status(
+    warp::reply::json(&serde_json::json!({ "errcode": errcode, "error": error })),
+    status,
+  ))
+}
+
+fn result_reply(action: &str, result: Result<Box<dyn Reply>>) -> Box<dyn Reply> {
+  match result {
+    Ok(reply) => reply,
+    Err(e) => {
+      error!(
+        "Error handling admin request to {}: {}",
+        action,
+        e.to_string()
+      );
+      error_reply(
+        http::StatusCode::INTERNAL_SERVER_ERROR,
+        "M_UNKNOWN",
+        &e.to_string(),
+      )
+    }
+  }
+}
+
+async fn transfer(
+  hook_id: String,
+  authorization: Option<String>,
+  body: TransferRequest,
+  context: RequestContext,
+) -> Result<Box<dyn Reply>, Rejection> {
+  if let Some(reply) = unauthorized(&context, &authorization) {
+    return Ok(reply);
+  }
+  Ok(result_reply(
+    "transfer a webhook",
+    handle_transfer(&hook_id, body, &context).await,
+  ))
+}
+
+// Offers the hook to the new owner, the same way !webhook transfer does
+async fn handle_transfer(
+  hook_id: &str,
+  body: TransferRequest,
+  context: &RequestContext,
+) -> Result<Box<dyn Reply>> {
+  let hook = match context.store.get_webhook_by_id(hook_id).await? {
+    Some(hook) => hook,
+    None => {
+      return Ok(error_reply(
+        http::StatusCode::NOT_FOUND,
+        "M_NOT_FOUND",
+        "No webhook with that id",
+      ))
+    }
+  };
+  let new_owner = match UserId::try_from(body.user_id.as_str()) {
+    Ok(new_owner) => new_owner,
+    Err(_) => {
+      return Ok(error_reply(
+        http::StatusCode::BAD_REQUEST,
+        "M_INVALID_PARAM",
+        "userId isn't a valid user id",
+      ))
+    }
+  };
+
+  let client = context
+    .appservice
+    .virtual_user_client(&context.config.webhook_bot.localpart)
+    .await?;
+  bot::offer_transfer(&context.store, &client, &hook, &new_owner).await?;
+
+  Ok(Box::new(warp::reply::json(&serde_json::json!({}))))
+}
+
+async fn room_hooks(
+  room: String,
+  authorization: Option<String>,
+  context: RequestContext,
+) -> Result<Box<dyn Reply>, Rejection> {
+  if let Some(reply) = unauthorized(&context, &authorization) {
+    return Ok(reply);
+  }
+  let room = decode_path_segment(&room);
+  Ok(result_reply(
+    "list a room's webhooks",
+    handle_room_hooks(&room, &context).await,
+  ))
+}
+
+async fn handle_room_hooks(room: &str, context: &RequestContext) -> Result<Box<dyn Reply>> {
+  let client = context
+    .appservice
+    .virtual_user_client(&context.config.webhook_bot.localpart)
+    .await?;
+  let room_id = match room_reference::resolve(&client, room).await? {
+    Some(room_id) => room_id,
+    None => {
+      return Ok(error_reply(
+        http::StatusCode::NOT_FOUND,
+        "M_NOT_FOUND",
+        "No room matches that id, alias or link",
+      ))
+    }
+  };
+
+  let hooks: Vec<_> = context
+    .store
+    .get_webhooks_for_room(room_id.as_str())
+    .await?
+    .into_iter()
+    .map(|hook| {
+      serde_json::json!({
+        "id": hook.id,
+        "label": hook.label,
+        "userId": hook.user_id,
+      })
+    })
+    .collect();
+  Ok(Box::new(warp::reply::json(&serde_json::json!({
+    "roomId": room_id,
+    "hooks": hooks,
+  }))))
+}
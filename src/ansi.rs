@@ -0,0 +1,227 @@
+// Terminal output, as CI jobs and scripts post it. The escape sequences that color it, and other
+// control characters, would garble how clients show the text, so they're stripped from everything
+// hooks post. Requests with `"format": "ansi"` keep the colors instead, as HTML.
+use crate::bot::escape_html;
+
+const ESC: char = '\x1b';
+
+// xterm's colors for the 16 basic codes, normal then bright
+const COLORS: [&str; 16] = [
+  "#000000", "#cd0000", "#00cd00", "#cdcd00", "#0000ee", "#cd00cd", "#00cdcd", "#e5e5e5",
+  "#7f7f7f", "#ff0000", "#00ff00", "#ffff00", "#5c5cff", "#ff00ff", "#00ffff", "#ffffff",
+];
+
+/// The text without escape sequences, or control characters other than newlines and tabs
+pub fn strip(text: &str) -> String {
+  pieces(text)
+    .into_iter()
+    .filter_map(|piece| match piece {
+      Piece::Text(text) => Some(text),
+      Piece::Style(_) => None,
+    })
+    .collect()
+}
+
+/// The text as HTML, in the colors and styles its escape sequences give it
+pub fn to_html(text: &str) -> String {
+  let mut html = String::new();
+  let mut style = Style::default();
+  for piece in pieces(text) {
+    match piece {
+      Piece::Style(params) => style.apply(params),
+      Piece::Text(text) => {
+        let (open, close) = style.tags();
+        html.push_str(&format!("{}{}{}", open, escape_html(text), close));
+      }
+    }
+  }
+  html
+}
+
+#[derive(Debug, PartialEq)]
+enum Piece<'a> {
+  Text(&'a str),
+  /// The parameters of an SGR sequence, like `1;31` for bold red
+  Style(&'a str),
+}
+
+fn pieces(text: &str) -> Vec<Piece<'_>> {
+  let mut pieces = Vec::new();
+  let mut start = 0;
+  let mut i = 0;
+  while let Some(c) = text[i..].chars().next() {
+    if c == '\n' || c == '\t' || !c.is_control() {
+      i += c.len_utf8();
+      continue;
+    }
+    if start < i {
+      pieces.push(Piece::Text(&text[start..i]));
+    }
+    let end = if c == ESC {
+      escape_end(text, i)
+    } else {
+      i + c.len_utf8()
+    };
+    if text[i..end].starts_with("\x1b[") && text[..end].ends_with('m') {
+      pieces.push(Piece::Style(&text[i + 2..end - 1]));
+    }
+    i = end;
+    start = end;
+  }
+  if start < text.len() {
+    pieces.push(Piece::Text(&text[start..]));
+  }
+  pieces
+}
+
+// Where the escape sequence starting at i ends. One that isn't finished runs to the end of the text.
+fn escape_end(text: &str, i: usize) -> usize {
+  let rest = &text[i + 1..];
+  if let Some(csi) = rest.strip_prefix('[') {
+    // Parameters, then the letter that says what they're for
+    match csi.find(|c: char| ('\x40'..='\x7e').contains(&c)) {
+      Some(end) => i + 2 + end + 1,
+      None => text.len(),
+    }
+  } else if let Some(osc) = rest.strip_prefix(']') {
+    // Like a window title or link, ended by a bell or ESC \
+    match osc.find(|c: char| c == '\x07' || c == ESC) {
+      Some(end) if osc[end..].starts_with("\x1b\\") => i + 2 + end + 2,
+      Some(end) => i + 2 + end + 1,
+      None => text.len(),
+    }
+  } else {
+    // Like ESC ( B, which picks a character set: any of space to /, then the letter
+    let intermediates = rest
+      .find(|c: char| !(' '..='/').contains(&c))
+      .unwrap_or_else(|| rest.len());
+    match rest[intermediates..].chars().next() {
+      Some(c) if ('\x30'..='\x7e').contains(&c) => i + 1 + intermediates + 1,
+      _ => i + 1 + intermediates,
+    }
+  }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Style {
+  bold: bool,
+  italic: bool,
+  underline: bool,
+  color: Option<String>,
+  background: Option<String>,
+}
+
+impl Style {
+  // Codes that aren't about color or these styles, like blinking, are ignored
+  fn apply(&mut self, params: &str) {
+    let mut codes = params
+      .split(';')
+      .map(|code| code.parse::<u8>().unwrap_or_default());
+    while let Some(code) = codes.next() {
+      match code {
+        0 => *self = Style::default(),
+        1 => self.bold = true,
+        3 => self.italic = true,
+        4 => self.underline = true,
+        22 => self.bold = false,
+        23 => self.italic = false,
+        24 => self.underline = false,
+        30..=37 => self.color = Some(color(code - 30)),
+        38 => self.color = extended_color(&mut codes),
+        39 => self.color = None,
+        40..=47 => self.background = Some(color(code - 40)),
+        48 => self.background = extended_color(&mut codes),
+        49 => self.background = None,
+        90..=97 => self.color = Some(color(code - 90 + 8)),
+        100..=107 => self.background = Some(color(code - 100 + 8)),
+        _ => {}
+      }
+    }
+  }
+
+  // The tags that open and close text in the style
+  fn tags(&self) -> (String, String) {
+    let mut open = String::new();
+    let mut close = String::new();
+    for (on, tag) in [(self.bold, "b"), (self.italic, "i"), (self.underline, "u")] {
+      if on {
+        open.push_str(&format!("<{}>", tag));
+        close.insert_str(0, &format!("</{}>", tag));
+      }
+    }
+    if self.color.is_some() || self.background.is_some() {
+      open.push_str("<span");
+      if let Some(color) = &self.color {
+        open.push_str(&format!(" data-mx-color=\"{}\"", color));
+      }
+      if let Some(background) = &self.background {
+        open.push_str(&format!(" data-mx-bg-color=\"{}\"", background));
+      }
+      open.push('>');
+      close.insert_str(0, "</span>");
+    }
+    (open, close)
+  }
+}
+
+// A color from 256, given as 5;n, or in RGB, given as 2;r;g;b
+fn extended_color(codes: &mut impl Iterator<Item = u8>) -> Option<String> {
+  match codes.next()? {
+    5 => Some(color(codes.next()?)),
+    2 => Some(format!(
+      "#{:02x}{:02x}{:02x}",
+      codes.next()?,
+      codes.next()?,
+      codes.next()?
+    )),
+    _ => None,
+  }
+}
+
+// One of xterm's 256 colors: the basic 16, then a 6x6x6 cube, then grays
+fn color(n: u8) -> String {
+  match n {
+    0..=15 => COLORS[n as usize].to_string(),
+    16..=231 => {
+      let level = |v: u8| if v == 0 { 0 } else { 55 + 40 * v };
+      let n = n - 16;
+      format!(
+        "#{:02x}{:02x}{:02x}",
+        level(n / 36),
+        level(n / 6 % 6),
+        level(n % 6)
+      )
+    }
+    _ => {
+      let gray = 8 + 10 * (n - 232);
+      format!("#{:02x}{:02x}{:02x}", gray, gray, gray)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_strip() {
+    assert_eq!(
+      strip("\x1b[1;32mPASS\x1b[0m\tsrc/main.rs\r\n\x1b]0;title\x07\x1b[2Kdone\0\x08\x1b(B"),
+      "PASS\tsrc/main.rs\ndone"
+    );
+    assert_eq!(strip("cut off \x1b[3"), "cut off ");
+  }
+
+  #[test]
+  fn test_to_html() {
+    assert_eq!(
+      to_html(
+        "\x1b[1;31merror\x1b[22m: <bad>\x1b[0m ok \x1b[38;5;208morange\x1b[48;2;0;0;255mblue"
+      ),
+      "<b><span data-mx-color=\"#cd0000\">error</span></b>\
+      <span data-mx-color=\"#cd0000\">: &lt;bad&gt;</span> ok \
+      <span data-mx-color=\"#ff8700\">orange</span>\
+      <span data-mx-color=\"#ff8700\" data-mx-bg-color=\"#0000ff\">blue</span>"
+    );
+  }
+}
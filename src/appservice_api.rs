@@ -0,0 +1,492 @@
+// Endpoints the homeserver calls on the appservice that matrix-sdk-appservice only stubs out.
+// These are served ahead of the SDK's own filter, so they take precedence for matching paths.
+use std::{collections::HashMap, convert::TryFrom};
+
+use anyhow::Result;
+use log::*;
+use matrix_sdk::ruma::{
+  api::{
+    appservice::event::push_events,
+    client::r0::room::create_room::{Request as CreateRoomRequest, RoomPreset},
+    IncomingRequest,
+  },
+  RoomAliasId, UserId,
+};
+use warp::{hyper::body::Bytes, path::FullPath, Filter, Rejection, Reply};
+
+use crate::{
+  bot, bridge_info,
+  config::Config,
+  ephemeral, outgoing, space,
+  webhook::{self, RequestContext},
+};
+
+pub const PROTOCOL: &str = "webhook";
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+pub fn filter(
+  context: RequestContext,
+) -> impl Filter<Extract = (Box<dyn Reply>,), Error = Rejection> + Clone {
+  let transactions = warp::path!("_matrix" / "app" / "v1" / "transactions" / String)
+    .or(warp::path!("transactions" / String))
+    .unify()
+    .and(warp::filters::method::put())
+    .and(authenticated(context.registration.hs_token.clone()))
+    .and(warp::path::full())
+    .and(warp::body::bytes())
+    .and(with_context(context.clone()))
+    .and_then(transaction);
+
+  let users = warp::path!("_matrix" / "app" / "v1" / "users" / String)
+    .or(warp::path!("users" / String))
+    .unify()
+    .and(warp::filters::method::get())
+    .and(authenticated(context.registration.hs_token.clone()))
+    .and(with_context(context.clone()))
+    .and_then(user_query);
+
+  let rooms = warp::path!("_matrix" / "app" / "v1" / "rooms" / String)
+    .or(warp::path!("rooms" / String))
+    .unify()
+    .and(warp::filters::method::get())
+    .and(authenticated(context.registration.hs_token.clone()))
+    .and(with_context(context))
+    .and_then(room_query);
+
+  let thirdparty = warp::path("_matrix")
+    .and(warp::path("app"))
+    .and(warp::path("v1").or(warp::path("unstable")).unify())
+    .and(warp::path("thirdparty"))
+    .and(warp::filters::method::get())
+    .and(authenticated(context.registration.hs_token.clone()));
+
+  let protocol = thirdparty
+    .clone()
+    .and(warp::path!("protocol" / String))
+    .and(with_context(context.clone()))
+    .and_then(protocol_query);
+
+  let location = thirdparty
+    .clone()
+    .and(warp::path!("location" / String))
+    .and(warp::query::<HashMap<String, String>>())
+    .and(with_context(context.clone()))
+    .and_then(location_query);
+
+  let location_reverse = thirdparty
+    .clone()
+    .and(warp::path!("location"))
+    .and(warp::query::<HashMap<String, String>>())
+    .and(with_context(context.clone()))
+    .and_then(location_reverse_query);
+
+  let user = thirdparty
+    .clone()
+    .and(warp::path!("user" / String))
+    .and(warp::query::<HashMap<String, String>>())
+    .and(with_context(context.clone()))
+    .and_then(user_lookup_query);
+
+  let user_reverse = thirdparty
+    .and(warp::path!("user"))
+    .and(warp::query::<HashMap<String, String>>())
+    .and(with_context(context))
+    .and_then(user_reverse_query);
+
+  transactions
+    .or(users)
+    .unify()
+    .or(rooms)
+    .unify()
+    .or(protocol)
+    .unify()
+    .or(location)
+    .unify()
+    .or(location_reverse)
+    .unify()
+    .or(user)
+    .unify()
+    .or(user_reverse)
+    .unify()
+}
+
+pub fn with_context(
+  context: RequestContext,
+) -> impl Filter<Extract = (RequestContext,), Error = std::convert::Infallible> + Clone {
+  warp::any().map(move || context.clone())
+}
+
+// The homeserver authenticates with the hs_token, either as a query parameter or (in newer
+// versions of the spec) as a bearer token
+fn authenticated(hs_token: String) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+  warp::query::<HashMap<String, String>>()
+    .and(warp::header::optional::<String>("authorization"))
+    .and_then(
+      move |query: HashMap<String, String>, authorization: Option<String>| {
+        let hs_token = hs_token.clone();
+        async move {
+          let token = query.get("access_token").cloned().or_else(|| {
+            authorization.and_then(|header| header.strip_prefix("Bearer ").map(str::to_string))
+          });
+          if token.as_deref() == Some(hs_token.as_str()) {
+            Ok(())
+          } else {
+            Err(warp::reject::custom(Unauthorized))
+          }
+        }
+      },
+    )
+    .untuple_one()
+}
+
+async fn transaction(
+  txn_id: String,
+  path: FullPath,
+  body: Bytes,
+  context: RequestContext,
+) -> Result<Box<dyn Reply>, Rejection> {
+  Ok(match handle_transaction(path, body, &context).await {
+    Ok(()) => Box::new(warp::reply::json(&serde_json::json!({}))),
+    Err(e) => {
+      error!("Error handling transaction {}: {}", &txn_id, e.to_string());
+      internal_error()
+    }
+  })
+}
+
+// Handles the ephemeral events ourselves, then passes the transaction on to the bot's client so
+// that the SDK can update its state and call the registered event handlers
+async fn handle_transaction(path: FullPath, body: Bytes, context: &RequestContext) -> Result<()> {
+  let transaction: ephemeral::Transaction = serde_json::from_slice(&body)?;
+  for event in transaction.ephemeral_events() {
+    ephemeral::handle(&event);
+  }
+  outgoing::forward_events(&transaction.events, context).await?;
+
+  let request = http::Request::builder()
+    .method(http::Method::PUT)
+    .uri(path.as_str())
+    .body(body)?;
+  let incoming = push_events::v1::IncomingRequest::try_from_http_request(request)?;
+  let client = context
+    .appservice
+    .virtual_user_client(&context.config.webhook_bot.localpart)
+    .await?;
+  client.receive_transaction(incoming).await?;
+
+  Ok(())
+}
+
+async fn user_query(user_id: String, context: RequestContext) -> Result<Box<dyn Reply>, Rejection> {
+  let user_id = decode_path_segment(&user_id);
+  let result = provision_puppet(&user_id, &context).await;
+  Ok(query_reply(&user_id, result))
+}
+
+async fn room_query(alias: String, context: RequestContext) -> Result<Box<dyn Reply>, Rejection> {
+  let alias = decode_path_segment(&alias);
+  let result = provision_alias_room(&alias, &context).await;
+  Ok(query_reply(&alias, result))
+}
+
+pub fn decode_path_segment(segment: &str) -> String {
+  percent_encoding::percent_decode_str(segment)
+    .decode_utf8_lossy()
+    .to_string()
+}
+
+// Query endpoints answer with an empty object if the entity exists (or now exists), and 404 if not
+fn query_reply(subject: &str, result: Result<bool>) -> Box<dyn Reply> {
+  match result {
+    Ok(true) => Box::new(warp::reply::json(&serde_json::json!({}))),
+    Ok(false) => not_found(),
+    Err(e) => {
+      error!(
+        "Error responding to homeserver query for {}: {}",
+        subject,
+        e.to_string()
+      );
+      internal_error()
+    }
+  }
+}
+
+fn not_found() -> Box<dyn Reply> {
+  Box::new(warp::reply::with_status(
+    warp::reply::json(&serde_json::json!({"errcode": "M_NOT_FOUND"})),
+    http::status::StatusCode::NOT_FOUND,
+  ))
+}
+
+fn internal_error() -> Box<dyn Reply> {
+  Box::new(warp::reply::with_status(
+    warp::reply::json(&serde_json::json!({})),
+    http::status::StatusCode::INTERNAL_SERVER_ERROR,
+  ))
+}
+
+async fn protocol_query(
+  protocol: String,
+  context: RequestContext,
+) -> Result<Box<dyn Reply>, Rejection> {
+  if protocol != PROTOCOL {
+    return Ok(not_found());
+  }
+  Ok(Box::new(warp::reply::json(&protocol_description(
+    &context.config,
+  ))))
+}
+
+// Describes the webhook protocol for clients' and integration managers' protocol directories.
+// Both users and locations (rooms) are looked up by hook label.
+fn protocol_description(config: &Config) -> serde_json::Value {
+  serde_json::json!({
+    "user_fields": ["label"],
+    "location_fields": ["label"],
+    "icon": &config.webhook_bot.appearance.avatar_url,
+    "field_types": {
+      "label": {
+        "regexp": "[A-Za-z0-9_.-]+",
+        "placeholder": "ci"
+      }
+    },
+    "instances": [{
+      "desc": &config.webhook_bot.appearance.display_name,
+      "icon": &config.webhook_bot.appearance.avatar_url,
+      "fields": {},
+      "network_id": PROTOCOL
+    }]
+  })
+}
+
+async fn location_query(
+  protocol: String,
+  query: HashMap<String, String>,
+  context: RequestContext,
+) -> Result<Box<dyn Reply>, Rejection> {
+  let label = match query.get("label") {
+    Some(label) if protocol == PROTOCOL && bot::is_valid_label(label) => label,
+    _ => return Ok(Box::new(warp::reply::json(&serde_json::json!([])))),
+  };
+
+  // The alias doesn't need to exist yet, the homeserver will query us for it when it's joined
+  Ok(Box::new(warp::reply::json(&serde_json::json!([
+    location_entry(&context.config, label)
+  ]))))
+}
+
+async fn location_reverse_query(
+  query: HashMap<String, String>,
+  context: RequestContext,
+) -> Result<Box<dyn Reply>, Rejection> {
+  let alias = query
+    .get("alias")
+    .and_then(|alias| RoomAliasId::try_from(alias.as_str()).ok());
+  let entries = match alias {
+    Some(alias) => match alias_label(&context.config, &alias) {
+      Some(label) => vec![location_entry(&context.config, label)],
+      None => vec![],
+    },
+    None => vec![],
+  };
+  Ok(Box::new(warp::reply::json(&entries)))
+}
+
+fn location_entry(config: &Config, label: &str) -> serde_json::Value {
+  serde_json::json!({
+    "alias": format!("#{}_{}:{}", config.webhook_bot.localpart, label, config.homeserver.domain),
+    "protocol": PROTOCOL,
+    "fields": { "label": label }
+  })
+}
+
+async fn user_lookup_query(
+  protocol: String,
+  query: HashMap<String, String>,
+  context: RequestContext,
+) -> Result<Box<dyn Reply>, Rejection> {
+  let label = match query.get("label") {
+    Some(label) if protocol == PROTOCOL => label,
+    _ => return Ok(Box::new(warp::reply::json(&serde_json::json!([])))),
+  };
+
+  let hooks = match context.store.get_webhooks_by_label(label).await {
+    Ok(hooks) => hooks,
+    Err(e) => {
+      error!(
+        "Error looking up hooks labelled {}: {}",
+        label,
+        e.to_string()
+      );
+      return Ok(internal_error());
+    }
+  };
+  let entries: Vec<_> = hooks
+    .iter()
+    .map(|hook| user_entry(&context.config, &hook.id, label))
+    .collect();
+  Ok(Box::new(warp::reply::json(&entries)))
+}
+
+async fn user_reverse_query(
+  query: HashMap<String, String>,
+  context: RequestContext,
+) -> Result<Box<dyn Reply>, Rejection> {
+  let user_id = match query
+    .get("userid")
+    .and_then(|user_id| UserId::try_from(user_id.as_str()).ok())
+  {
+    Some(user_id) => user_id,
+    None => return Ok(Box::new(warp::reply::json(&serde_json::json!([])))),
+  };
+
+  let hook = match context.store.get_puppet(user_id.localpart()).await {
+    Ok(Some(puppet)) => context.store.get_webhook_by_id(&puppet.hook_id).await,
+    Ok(None) => Ok(None),
+    Err(e) => Err(e),
+  };
+  let entries = match hook {
+    Ok(Some(hook)) => match &hook.label {
+      Some(label) => vec![user_entry(&context.config, &hook.id, label)],
+      None => vec![],
+    },
+    Ok(None) => vec![],
+    Err(e) => {
+      error!("Error looking up puppet {}: {}", user_id, e.to_string());
+      return Ok(internal_error());
+    }
+  };
+  Ok(Box::new(warp::reply::json(&entries)))
+}
+
+fn user_entry(config: &Config, hook_id: &str, label: &str) -> serde_json::Value {
+  serde_json::json!({
+    "userid": format!(
+      "@{}:{}",
+      webhook::puppet_localpart(config, hook_id),
+      config.homeserver.domain
+    ),
+    "protocol": PROTOCOL,
+    "fields": { "label": label }
+  })
+}
+
+// The label part of an alias in our namespace, e.g. "ci" for #_webhook_ci:server
+fn alias_label<'a>(config: &Config, alias: &'a RoomAliasId) -> Option<&'a str> {
+  if alias.server_name().as_str() != config.homeserver.domain {
+    return None;
+  }
+  let prefix = format!("{}_", config.webhook_bot.localpart);
+  match alias.alias().strip_prefix(&prefix) {
+    Some(label) if !label.is_empty() => Some(label),
+    _ => None,
+  }
+}
+
+// Registers the puppet for a hook that hasn't posted yet, so that the homeserver can resolve it.
+// Returns false if the user doesn't belong to any hook.
+async fn provision_puppet(user_id: &str, context: &RequestContext) -> Result<bool> {
+  let user_id = match UserId::try_from(user_id) {
+    Ok(id) => id,
+    Err(_) => return Ok(false),
+  };
+  if user_id.server_name().as_str() != context.config.homeserver.domain {
+    return Ok(false);
+  }
+
+  let localpart = user_id.localpart();
+  if context.store.get_puppet(localpart).await?.is_some() {
+    debug!("Puppet {} is already registered", user_id);
+    return Ok(true);
+  }
+
+  // Puppets are only recorded once registered, so check whether any hook would post as this user
+  let hook = context
+    .store
+    .get_all_webhooks()
+    .await?
+    .into_iter()
+    .find(|hook| webhook::hook_puppet_localpart(&context.config, hook) == localpart);
+  let hook = match hook {
+    Some(hook) => hook,
+    None => return Ok(false),
+  };
+
+  info!(
+    "Provisioning puppet {} for a hook in {}",
+    user_id, hook.room_id
+  );
+  // Looks like the hook will until its first message
+  let appearance = &context.config.webhook_bot.appearance;
+  bot::register_bot(
+    &context.config,
+    localpart,
+    hook
+      .display_name
+      .as_deref()
+      .unwrap_or(&appearance.display_name),
+    &Some(
+      hook
+        .avatar_url
+        .clone()
+        .unwrap_or_else(|| appearance.avatar_url.clone()),
+    ),
+    context.appservice.clone(),
+  )
+  .await?;
+  context
+    .store
+    .mark_puppet_registered(localpart, &hook.id)
+    .await?;
+
+  Ok(true)
+}
+
+// Creates the room for an alias in our namespace (e.g. #_webhook_ci:server) along with a hook
+// for it, labelled with the rest of the alias. Returns false for aliases we don't manage.
+async fn provision_alias_room(alias: &str, context: &RequestContext) -> Result<bool> {
+  let alias = match RoomAliasId::try_from(alias) {
+    Ok(alias) => alias,
+    Err(_) => return Ok(false),
+  };
+  let label = match alias_label(&context.config, &alias) {
+    Some(label) => label,
+    None => return Ok(false),
+  };
+
+  info!("Creating room for alias {}", alias);
+  let client = context
+    .appservice
+    .virtual_user_client(&context.config.webhook_bot.localpart)
+    .await?;
+  let mut request = CreateRoomRequest::new();
+  request.room_alias_name = Some(alias.alias());
+  request.preset = Some(RoomPreset::PublicChat);
+  let room_id = client.create_room(request).await?.room_id;
+
+  // Nobody asked for this hook directly, so the bot owns it. The room is brand new, and the bot
+  // would soon reach any per-user limit, so the limits don't apply.
+  let bot_user_id = bot::bot_user_id(&context.config)?;
+  let hook = context
+    .store
+    .create_webhook(
+      room_id.as_str(),
+      bot_user_id.as_str(),
+      Some(label),
+      None,
+      &Default::default(),
+    )
+    .await?;
+  space::add_bridged_room(&client, &room_id).await;
+  bridge_info::publish(&context.config, &client, &hook).await;
+  info!(
+    "Created room {} for alias {} with hook labelled {}",
+    room_id, alias, label
+  );
+
+  Ok(true)
+}
@@ -1,34 +1,53 @@
-use crate::{config, store::Store};
+use crate::{
+  bridge_info, config,
+  confirmation::{self, Action},
+  digest, outgoing, oversize, payloads,
+  power_levels::PowerLevels,
+  room_reference, space, status,
+  store::{self, OutgoingFilters, OutgoingWebhook, Store},
+  webhook,
+};
 use anyhow::{anyhow, Context};
 use matrix_sdk::{
   media::MediaFormat,
   ruma::{
-    api::client::r0::room::create_room::RoomPreset,
-    events::{room::message::MessageType, AnyMessageEventContent, SyncMessageEvent},
+    api::client::r0::{
+      membership::leave_room, room::create_room::RoomPreset, state::send_state_event,
+    },
+    events::{
+      room::message::MessageType, AnyMessageEventContent, AnyStateEventContent, EventType,
+      SyncMessageEvent,
+    },
+    serde::Raw,
     RoomId, ServerName,
   },
 };
-use std::{convert::TryFrom, sync::Arc};
+use std::{
+  collections::{BTreeMap, HashMap},
+  convert::TryFrom,
+  sync::Arc,
+};
 
 use matrix_sdk::ruma::api::client::r0::room::create_room::Request as CreateRoomRequest;
 
 use matrix_sdk_appservice::{
   matrix_sdk::{
-    room::Room,
+    room::{Joined, Room},
     ruma::{
       events::{
+        reaction::ReactionEventContent,
         room::member::{MemberEventContent, MembershipState},
         room::message::MessageEventContent,
         SyncStateEvent,
       },
       UserId,
     },
-    Client,
+    Client, SyncSettings,
   },
   AppService, Result,
 };
 
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use lazy_static::lazy_static;
 use log::*;
 
@@ -37,17 +56,20 @@ use log::*;
 lazy_static! {
   static ref USER_AVATAR_CACHE: Arc<DashMap<String, String>> = Arc::new(DashMap::new());
   static ref USER_DISPLAY_NAME_CACHE: Arc<DashMap<String, String>> = Arc::new(DashMap::new());
+  // Users known to exist through logging in, for which registering failed or is disabled
+  static ref LOGGED_IN_USERS: DashSet<String> = DashSet::new();
 }
 
 pub async fn handle_room_member(
   config: Arc<config::Config>,
+  store: Arc<Store>,
   appservice: AppService,
   room: Room,
   event: SyncStateEvent<MemberEventContent>,
 ) -> Result<()> {
   let room_id = room.room_id().to_string();
   let event_copy = event.clone();
-  let result = handle_room_member_inner(config, appservice, room, event).await;
+  let result = handle_room_member_inner(config, store, appservice, room, event).await;
   if let Err(err) = result {
     error!(
       "Error handling membership event for room {}, {:?}: {}",
@@ -79,14 +101,92 @@ pub async fn handle_room_message(
   Ok(())
 }
 
+pub async fn handle_reaction(
+  config: Arc<config::Config>,
+  store: Arc<Store>,
+  appservice: AppService,
+  room: Room,
+  event: SyncMessageEvent<ReactionEventContent>,
+) -> Result<()> {
+  let room_id = room.room_id().to_string();
+  let result = handle_reaction_inner(config, store, appservice, room, event).await;
+  if let Err(err) = result {
+    error!(
+      "Error handling reaction for room {}: {}",
+      room_id,
+      err.to_string()
+    );
+  }
+
+  Ok(())
+}
+
+/// For the bot's encrypted client, which sees the unencrypted rooms' messages too. Those already
+/// reach handle_room_message through the appservice.
+pub async fn handle_encrypted_room_message(
+  config: Arc<config::Config>,
+  store: Arc<Store>,
+  appservice: AppService,
+  room: Room,
+  event: SyncMessageEvent<MessageEventContent>,
+) -> Result<()> {
+  if !room.is_encrypted() {
+    return Ok(());
+  }
+  handle_room_message(config, store, appservice, room, event).await
+}
+
+/// Like handle_encrypted_room_message, for reactions
+pub async fn handle_encrypted_reaction(
+  config: Arc<config::Config>,
+  store: Arc<Store>,
+  appservice: AppService,
+  room: Room,
+  event: SyncMessageEvent<ReactionEventContent>,
+) -> Result<()> {
+  if !room.is_encrypted() {
+    return Ok(());
+  }
+  handle_reaction(config, store, appservice, room, event).await
+}
+
+pub fn bot_user_id(config: &config::Config) -> anyhow::Result<UserId> {
+  let homeserver = <&ServerName>::try_from(config.homeserver.domain.as_str())?;
+  Ok(UserId::parse_with_server_name(
+    config.webhook_bot.localpart.as_str(),
+    homeserver,
+  )?)
+}
+
 pub async fn register_bot(
+  config: &config::Config,
   localpart: &str,
   display_name: &str,
   avatar_url: &Option<String>,
   appservice: AppService,
 ) -> anyhow::Result<Client> {
-  info!("Registering the webhook bot with the homeserver");
-  appservice.register_virtual_user(localpart).await?;
+  if !LOGGED_IN_USERS.contains(localpart) {
+    info!("Registering the webhook bot with the homeserver");
+    let registered = if config.homeserver.register_users() {
+      match appservice.register_virtual_user(localpart).await {
+        Ok(()) => true,
+        Err(e) => {
+          warn!(
+            "Failed to register {}, logging in instead: {}",
+            localpart,
+            e.to_string()
+          );
+          false
+        }
+      }
+    } else {
+      false
+    };
+    if !registered {
+      appservice_login(config, &appservice, localpart).await?;
+      LOGGED_IN_USERS.insert(localpart.to_string());
+    }
+  }
   let client = appservice.virtual_user_client(localpart).await?;
 
   let cached_display_name = USER_DISPLAY_NAME_CACHE.get(localpart);
@@ -107,7 +207,7 @@ pub async fn register_bot(
     let cached_avatar_url = USER_AVATAR_CACHE.get(localpart);
     if cached_avatar_url.is_none() || cached_avatar_url.unwrap().value() != avatar_url {
       info!("Need to download avatar for {}", localpart);
-      match download_avatar(avatar_url).await {
+      match download_image(avatar_url).await {
         Ok((avatar_mime, avatar_bytes)) => {
           let mut slice = avatar_bytes.as_slice();
           let old_avatar_bytes = client.avatar(MediaFormat::File).await?;
@@ -147,170 +247,3052 @@ async fn handle_room_message_inner(
     _ => return Ok(()),
   };
 
-  if !text_msg.body.starts_with("!webhook") {
+  // Answers to a question from ask_confirmation
+  if is_yes(&text_msg.body) {
+    return match confirmation::take(room.room_id().as_str(), event.sender.as_str(), None) {
+      Some(action) => {
+        let client = appservice
+          .virtual_user_client(&config.webhook_bot.localpart)
+          .await?;
+        run_confirmed(&store, &client, &room, &event.sender, action).await
+      }
+      None => Ok(()),
+    };
+  }
+
+  let args: Vec<&str> = text_msg.body.split_whitespace().collect();
+  if args.first() != Some(&"!webhook") {
     return Ok(());
   }
 
   info!(
-    "Received !webhook message in room {}. Creating webhook",
+    "Received !webhook message in room {}",
     room.room_id().to_string()
   );
 
-  // Register webhook for room
+  let default_reply = ReplyTo {
+    in_room: config.webhook_bot.reply_in_room,
+    notify: config.webhook_bot.notify,
+  };
+  let (args, reply) = match take_reply_flag(args, default_reply) {
+    Some(parsed) => parsed,
+    None => return send_notice(&room, "--reply must be room, dm or silent").await,
+  };
+
   let client = appservice
     .virtual_user_client(&config.webhook_bot.localpart)
     .await?;
 
-  let admin_room_id = get_or_create_admin_room(&client, &event.sender)
-    .await
-    .context("Failed to get or create admin room")?;
-  let admin_room = match client.get_joined_room(&admin_room_id) {
-    Some(room) => room,
-    None => return Err(anyhow!("Failed to get the room that we should be inside")),
-  };
+  // A hook lets anyone holding its URL post to the room, or read it for outgoing ones, so making
+  // one takes more than membership. Plain hooks are checked in create_webhook, since they can be
+  // made for other rooms.
+  if let (["connect", ..] | ["out", "add", ..] | ["claim", _], Room::Joined(joined)) =
+    (&args[1..], &room)
+  {
+    if let Some((required, actual)) = missing_create_level(&config, joined, &event.sender).await? {
+      return send_notice(&room, &create_level_message(required, actual)).await;
+    }
+  }
 
-  let hook = store
-    .create_webhook(room.room_id().as_str(), event.sender.as_str())
-    .await?;
+  // Destructive commands on someone else's hook are only for the bridge's admins. The commands
+  // themselves then act on whatever hook they're given.
+  if let ["delete" | "regenerate" | "pause" | "resume", id_or_label]
+  | ["transfer" | "secret" | "events" | "level" | "thread", id_or_label, ..]
+  | ["state" | "custom" | "atroom" | "template" | "templates", id_or_label, ..]
+  | ["oversize" | "digest" | "preview", id_or_label, ..] = &args[1..]
+  {
+    if let Some(hook) = find_webhook(&store, &room, id_or_label).await? {
+      if hook.user_id != event.sender.as_str()
+        && !config.webhook_bot.is_admin(event.sender.as_str())
+      {
+        return send_notice(
+          &room,
+          "Only the webhook's creator or a bridge admin can do that",
+        )
+        .await;
+      }
+    }
+  }
 
-  let hook_url = format!(
-    "{}api/v1/matrix/hook/{}",
-    &config.web.hook_url_base, &hook.id
-  );
+  // Taking apart a connection or outgoing hook someone else made, or taking over its label, is for
+  // the room's moderators and the bridge's admins, like purging
+  if let Room::Joined(joined) = &room {
+    let owners = match guarded_target(&args[1..]) {
+      Some(GuardedTarget::Connection(label)) => connection_owners(&store, &room, label).await?,
+      Some(GuardedTarget::Outgoing(id)) => outgoing_owners(&store, &room, id).await?,
+      None => vec![],
+    };
+    if !owns_all(&owners, &event.sender)
+      && !config.webhook_bot.is_admin(event.sender.as_str())
+      && !is_room_moderator(joined, &event.sender).await?
+    {
+      return send_notice(
+        &room,
+        "Only the webhook's creator or a bridge admin can do that",
+      )
+      .await;
+    }
+  }
 
-  admin_room
-    .send(
-      AnyMessageEventContent::RoomMessage(MessageEventContent::notice_html(
-        format!(
-          r#"
-Here's your webhook url: {url}
-To send a message, POST the following JSON to that URL:
-{{
-  "text": "Hello world!",
-  "format": "plain",
-  "displayName": "My Cool Webhook",
-  "avatarUrl": "{avatar_url}"
-}}
-"#,
-          url = &hook_url,
-          avatar_url = &config.webhook_bot.appearance.avatar_url
-        ),
-        format!(
-          r#"Here's your webhook url: <a href="{url}">{url}</a><br>
-To send a message, POST the following JSON to that URL:
-<pre><code>{{
-  "text": "Hello world!",
-  "format": "plain",
-  "displayName": "My Cool Webhook",
-  "avatarUrl": "{avatar_url}"
-}}</code></pre>
-"#,
-          url = &hook_url,
-          avatar_url = &config.webhook_bot.appearance.avatar_url
-        ),
-      )),
-      None,
-    )
-    .await
-    .context("Failed to send admin room message")?;
+  // Purging is per room, so it's for the room's moderators as well as the bridge's admins
+  if let (["purge"], Room::Joined(joined)) = (&args[1..], &room) {
+    if !config.webhook_bot.is_admin(event.sender.as_str())
+      && !is_room_moderator(joined, &event.sender).await?
+    {
+      return send_notice(
+        &room,
+        "Only the room's moderators or a bridge admin can purge its webhooks",
+      )
+      .await;
+    }
+  }
 
-  if let Room::Joined(room) = room {
-    room
-      .send(
-        AnyMessageEventContent::RoomMessage(MessageEventContent::notice_plain(
-          "I've sent you a private message with your hook information",
-        )),
+  match &args[1..] {
+    [] => create_webhook(&config, &store, &client, &room, &event.sender, reply, &[]).await,
+    ["create", args @ ..] => {
+      create_webhook(&config, &store, &client, &room, &event.sender, reply, args).await
+    }
+    ["out", "add", url, flags @ ..] => {
+      add_outgoing_webhook(&store, &client, &room, &event.sender, url, flags).await
+    }
+    ["out", "list"] => list_outgoing_webhooks(&store, &client, &room, &event.sender).await,
+    ["out", "remove", id] => {
+      remove_outgoing_webhook(&store, &client, &room, &event.sender, id).await
+    }
+    ["connect", label, url, flags @ ..] => {
+      create_connection(
+        &config,
+        &store,
+        &client,
+        &room,
+        &event.sender,
+        reply,
+        label,
+        url,
+        flags,
+      )
+      .await
+    }
+    ["disconnect", label] => remove_connection(&store, &room, label).await,
+    ["claim", id] => claim_webhook(&config, &store, &client, &room, &event.sender, id).await,
+    ["rejoin", id] => rejoin_webhook(&store, &client, &room, &event.sender, id).await,
+    ["transfer", id, new_owner] => transfer_webhook(&store, &client, &room, id, new_owner).await,
+    ["set-name", id_or_label, name @ ..] => {
+      let name = Some(name.join(" ")).filter(|name| !name.is_empty());
+      set_webhook_name(&store, &room, &event.sender, id_or_label, name).await
+    }
+    ["set-avatar", id_or_label] => {
+      set_webhook_avatar(&store, &room, &event.sender, id_or_label, None).await
+    }
+    ["set-avatar", id_or_label, url] => {
+      set_webhook_avatar(&store, &room, &event.sender, id_or_label, Some(url)).await
+    }
+    ["rooms", "add", id, target] => {
+      add_webhook_room(&store, &client, &room, &event.sender, id, target).await
+    }
+    ["rooms", "remove", id, target] => {
+      remove_webhook_room(&store, &client, &room, &event.sender, id, target).await
+    }
+    ["list"] => list_webhooks(&config, &store, &client, &room, &event.sender, false).await,
+    ["list", "mine"] => list_webhooks(&config, &store, &client, &room, &event.sender, true).await,
+    ["status"] => {
+      let (plain, html) = status::report(&store, &client).await;
+      send_html_notice(&room, plain, html).await
+    }
+    ["stats"] => webhook_stats(&config, &store, &client, &room, &event.sender).await,
+    ["info", id_or_label] => {
+      webhook_info(&config, &store, &client, &room, &event.sender, id_or_label).await
+    }
+    ["purge"] => {
+      ask_confirmation(
+        &room,
+        &event.sender,
+        Action::Purge,
+        "Delete every webhook in this room?",
+      )
+      .await
+    }
+    ["delete", id_or_label] => match find_webhook(&store, &room, id_or_label).await? {
+      // The question is public, so it doesn't name the hook by its id
+      Some(hook) => {
+        let question = match &hook.label {
+          Some(label) => format!("Delete webhook {}?", label),
+          None => "Delete that webhook?".to_string(),
+        };
+        ask_confirmation(
+          &room,
+          &event.sender,
+          Action::Delete { hook_id: hook.id },
+          &question,
+        )
+        .await
+      }
+      None => send_notice(&room, "There's no webhook with that id or label here").await,
+    },
+    ["pause", id_or_label] => {
+      set_webhook_enabled(&store, &room, &event.sender, id_or_label, false).await
+    }
+    ["resume", id_or_label] => {
+      set_webhook_enabled(&store, &room, &event.sender, id_or_label, true).await
+    }
+    ["secret", id_or_label] => {
+      set_webhook_secret(&store, &client, &room, &event.sender, id_or_label, None).await
+    }
+    ["secret", id_or_label, secret] => {
+      set_webhook_secret(
+        &store,
+        &client,
+        &room,
+        &event.sender,
+        id_or_label,
+        Some(*secret),
+      )
+      .await
+    }
+    ["events", id_or_label] => {
+      set_webhook_events(&store, &room, &event.sender, id_or_label, None).await
+    }
+    ["events", id_or_label, events] => {
+      set_webhook_events(&store, &room, &event.sender, id_or_label, Some(*events)).await
+    }
+    ["level", id_or_label] => {
+      set_webhook_min_level(&store, &room, &event.sender, id_or_label, None).await
+    }
+    ["level", id_or_label, level] => {
+      set_webhook_min_level(&store, &room, &event.sender, id_or_label, Some(*level)).await
+    }
+    ["thread", id_or_label] => {
+      set_webhook_thread(&store, &room, &event.sender, id_or_label, None).await
+    }
+    ["thread", id_or_label, mode] => {
+      set_webhook_thread(&store, &room, &event.sender, id_or_label, Some(*mode)).await
+    }
+    ["state", id_or_label] => {
+      toggle_setting(
+        &store,
+        &room,
+        &event.sender,
+        id_or_label,
         None,
+        &ROOM_STATE_TOGGLE,
       )
       .await
-      .context("Failed to send private message notification")?;
+    }
+    ["state", id_or_label, allowed] => {
+      toggle_setting(
+        &store,
+        &room,
+        &event.sender,
+        id_or_label,
+        Some(*allowed),
+        &ROOM_STATE_TOGGLE,
+      )
+      .await
+    }
+    ["custom", id_or_label] => {
+      toggle_setting(
+        &store,
+        &room,
+        &event.sender,
+        id_or_label,
+        None,
+        &CUSTOM_EVENTS_TOGGLE,
+      )
+      .await
+    }
+    ["custom", id_or_label, allowed] => {
+      toggle_setting(
+        &store,
+        &room,
+        &event.sender,
+        id_or_label,
+        Some(*allowed),
+        &CUSTOM_EVENTS_TOGGLE,
+      )
+      .await
+    }
+    ["atroom", id_or_label] => {
+      toggle_setting(
+        &store,
+        &room,
+        &event.sender,
+        id_or_label,
+        None,
+        &ROOM_MENTION_TOGGLE,
+      )
+      .await
+    }
+    ["atroom", id_or_label, allowed] => {
+      toggle_setting(
+        &store,
+        &room,
+        &event.sender,
+        id_or_label,
+        Some(*allowed),
+        &ROOM_MENTION_TOGGLE,
+      )
+      .await
+    }
+    ["preview", id_or_label] => {
+      toggle_setting(
+        &store,
+        &room,
+        &event.sender,
+        id_or_label,
+        None,
+        &PREVIEW_TOGGLE,
+      )
+      .await
+    }
+    ["preview", id_or_label, enabled] => {
+      toggle_setting(
+        &store,
+        &room,
+        &event.sender,
+        id_or_label,
+        Some(*enabled),
+        &PREVIEW_TOGGLE,
+      )
+      .await
+    }
+    ["digest", id_or_label] => {
+      set_webhook_digest(&store, &room, &event.sender, id_or_label, None).await
+    }
+    ["digest", id_or_label, ..] => {
+      let policy = command_rest(&text_msg.body, 3);
+      set_webhook_digest(&store, &room, &event.sender, id_or_label, Some(policy)).await
+    }
+    ["oversize", id_or_label] => {
+      set_webhook_oversize(&store, &room, &event.sender, id_or_label, None).await
+    }
+    ["oversize", id_or_label, handling] => {
+      set_webhook_oversize(&store, &room, &event.sender, id_or_label, Some(*handling)).await
+    }
+    ["template", id_or_label] => {
+      set_webhook_template(&store, &room, &event.sender, id_or_label, None).await
+    }
+    ["template", id_or_label, ..] => {
+      let template = command_rest(&text_msg.body, 3);
+      set_webhook_template(&store, &room, &event.sender, id_or_label, Some(template)).await
+    }
+    ["templates", id_or_label] => {
+      set_webhook_named_template(&store, &room, &event.sender, id_or_label, None, None).await
+    }
+    ["templates", id_or_label, name] => {
+      set_webhook_named_template(&store, &room, &event.sender, id_or_label, Some(*name), None).await
+    }
+    ["templates", id_or_label, name, ..] => {
+      let template = command_rest(&text_msg.body, 4);
+      set_webhook_named_template(
+        &store,
+        &room,
+        &event.sender,
+        id_or_label,
+        Some(*name),
+        Some(template),
+      )
+      .await
+    }
+    ["regenerate", id_or_label] => {
+      regenerate_webhook(&config, &store, &client, &room, &event.sender, id_or_label).await
+    }
+    ["accept", id] => accept_transfer(&config, &store, &client, &room, &event.sender, id).await,
+    ["help"] => {
+      let (plain, html) = help_message(None);
+      send_html_notice(&room, plain, html).await
+    }
+    _ => {
+      let (plain, html) = help_message(Some("Unknown command."));
+      send_html_notice(&room, plain, html).await
+    }
   }
-  Ok(())
 }
 
-async fn handle_room_member_inner(
-  config: Arc<config::Config>,
-  appservice: AppService,
-  room: Room,
-  event: SyncStateEvent<MemberEventContent>,
-) -> anyhow::Result<()> {
-  if event.content.membership != MembershipState::Invite {
-    return Ok(());
-  }
-  let target_user_id = match UserId::try_from(event.state_key) {
-    Ok(id) => id,
-    Err(_) => return Ok(()),
-  };
-  let homeserver = <&ServerName>::try_from(config.homeserver.domain.as_str())?;
-  let bot_user_id =
-    UserId::parse_with_server_name(config.webhook_bot.localpart.as_str(), homeserver)?;
-  if target_user_id != bot_user_id {
-    debug!("Ignoring invite that is not for the webhook bot");
-    return Ok(());
-  }
-  info!(
-    "Received invite to room {}. Joining",
-    room.room_id().to_string()
-  );
-
-  let client = appservice
-    .virtual_user_client(&config.webhook_bot.localpart)
-    .await?;
-  client.join_room_by_id(room.room_id()).await?;
-
-  Ok(())
+/// Where the details of a new hook go
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ReplyTo {
+  /// In the room the command was sent in, unless it's public
+  in_room: bool,
+  /// Whether to say in the room that a private message was sent
+  notify: bool,
 }
 
-async fn download_avatar(url: &str) -> anyhow::Result<(mime::Mime, Vec<u8>)> {
-  info!("Downloading avatar at {}", url);
-  let response = reqwest::get(url)
-    .await
-    .context("Failed to fetch avatar from provided url")?;
-
-  let response = response.error_for_status()?;
-  let mime_raw = match response.headers().get(reqwest::header::CONTENT_TYPE) {
-    Some(mime) => mime,
-    None => return Err(anyhow!("Server did not return a Content-Type header")),
+// Takes a `--reply room|dm|silent` flag out of the command, wherever it is. None if its value is
+// unknown.
+fn take_reply_flag(mut args: Vec<&str>, default: ReplyTo) -> Option<(Vec<&str>, ReplyTo)> {
+  let position = match args.iter().position(|arg| *arg == "--reply") {
+    Some(position) => position,
+    None => return Some((args, default)),
   };
-
-  let mime: mime::Mime = mime_raw
-    .to_str()
-    .context("Failed to convert Content-Type to a string")?
-    .parse()
-    .context("Could not parse Content-Type into a mime type")?;
-
-  let body = response.bytes().await?;
-  if body.is_empty() {
-    return Err(anyhow!("Avatar request returned empty"));
-  }
-
-  Ok((mime, body.to_vec()))
+  let reply = match args.get(position + 1) {
+    Some(&"room") => ReplyTo {
+      in_room: true,
+      notify: default.notify,
+    },
+    Some(&"dm") => ReplyTo {
+      in_room: false,
+      notify: true,
+    },
+    Some(&"silent") => ReplyTo {
+      in_room: false,
+      notify: false,
+    },
+    _ => return None,
+  };
+  args.drain(position..position + 2);
+  Some((args, reply))
 }
 
-async fn get_or_create_admin_room(
-  client: &Client,
-  counterparty: &UserId,
-) -> anyhow::Result<RoomId> {
-  for room in client.joined_rooms() {
-    if room.is_public() {
-      trace!("Skipping {} because it is public", room.room_id());
-      continue;
-    }
-
-    let members = match room.joined_members_no_sync().await {
-      Ok(members) => members,
-      Err(_) => {
-        trace!(
-          "Skipping {} because I could not get the members",
-          room.room_id()
-        );
-        continue;
-      }
-    };
+struct Command {
+  usage: &'static str,
+  description: &'static str,
+}
 
-    if members.len() > 2 {
-      trace!(
-        "Skipping {} because it has {} members",
+// Every command the bot understands, for !webhook help. Keep in step with the match in
+// handle_room_message_inner.
+const COMMANDS: &[Command] = &[
+  Command {
+    usage: "create [room] [label] [--as name] [--reply room|dm|silent]",
+    description: "Create a webhook for this room, or another one you're in. The URL is sent to you privately, unless --reply says otherwise. --as picks the webhook user's name",
+  },
+  Command {
+    usage: "list [mine]",
+    description: "Privately list this room's webhooks, or only the ones you created",
+  },
+  Command {
+    usage: "stats",
+    description: "Privately show how many messages this room's webhooks have posted, and how many requests failed",
+  },
+  Command {
+    usage: "status",
+    description: "Show the bridge's version, uptime, and whether it can reach its database and homeserver",
+  },
+  Command {
+    usage: "info <id or label>",
+    description: "Privately show a webhook's URL, owner, rooms and when it was created and last used",
+  },
+  Command {
+    usage: "delete <id or label>",
+    description: "Delete a webhook you created, or any webhook if you're a bridge admin, once you confirm",
+  },
+  Command {
+    usage: "purge",
+    description: "Delete every webhook in this room, once you confirm. For the room's moderators and bridge admins",
+  },
+  Command {
+    usage: "pause <id or label>",
+    description: "Turn away a webhook's requests until it's resumed. For its creator and bridge admins",
+  },
+  Command {
+    usage: "resume <id or label>",
+    description: "Let a paused webhook post again",
+  },
+  Command {
+    usage: "regenerate <id or label>",
+    description: "Give a webhook you created, or any webhook if you're a bridge admin, a new URL for when the old one has leaked",
+  },
+  Command {
+    usage: "secret <id or label> [secret|off]",
+    description: "Turn away requests from services like GitHub that aren't signed with a secret. Unless you give one, a random one is sent to the webhook's creator. off stops checking",
+  },
+  Command {
+    usage: "events <id or label> [type,type|all]",
+    description: "Show or pick which of a service's events a webhook posts, like push,merge_request for GitLab. all posts every one",
+  },
+  Command {
+    usage: "level <id or label> [debug|info|warning|error|fatal|all]",
+    description: "Show or pick the least severe Sentry alerts a webhook posts. all posts every one",
+  },
+  Command {
+    usage: "thread <id or label> [on|off|new]",
+    description: "Show or pick whether a webhook posts in a thread rather than the main timeline. new starts a fresh thread with the next message",
+  },
+  Command {
+    usage: "state <id or label> [on|off]",
+    description: "Show or pick whether a webhook may change the room's name and topic, like for who's on call",
+  },
+  Command {
+    usage: "custom <id or label> [on|off]",
+    description: "Show or pick whether a webhook may send events of its own types, for bots and widgets in the room",
+  },
+  Command {
+    usage: "atroom <id or label> [on|off]",
+    description: "Show or pick whether a webhook may ping the whole room with @room, for critical alerts",
+  },
+  Command {
+    usage: "templates <id or label> [name] [template|off]",
+    description: "List a webhook's named templates, or show or set one, which requests use by giving its name as template and its variables as vars. off removes it",
+  },
+  Command {
+    usage: "preview <id or label> [on|off]",
+    description: "Show or pick whether a webhook's messages get a preview of the first link in them, with the page's title, description and image",
+  },
+  Command {
+    usage: "digest <id or label> [seconds [messages]|off]",
+    description: "Show or set how long a webhook holds its messages, to post them together as one, and how many it holds at most. off posts them as they come",
+  },
+  Command {
+    usage: "oversize <id or label> [split|truncate]",
+    description: "Show or pick whether a webhook's messages too big to send are split into several, or cut short with the whole text attached as a file",
+  },
+  Command {
+    usage: "template <id or label> [template|off]",
+    description: "Show or set the Tera template a webhook renders any JSON sent to its URL with /custom appended through. off clears it",
+  },
+  Command {
+    usage: "out add <url> [--types a,b] [--senders @*:server] [--keywords a,b]",
+    description: "Forward messages in this room to a URL, optionally filtered",
+  },
+  Command {
+    usage: "out list",
+    description: "Privately list this room's outgoing webhooks",
+  },
+  Command {
+    usage: "out remove <id>",
+    description: "Stop forwarding messages to an outgoing webhook",
+  },
+  Command {
+    usage: "connect <label> <url>",
+    description: "Create an incoming and an outgoing webhook sharing a label",
+  },
+  Command {
+    usage: "disconnect <label>",
+    description: "Remove both halves of a connection",
+  },
+  Command {
+    usage: "set-name <id or label> [name]",
+    description: "Set the name a webhook posts with when its requests don't give one. Leave the name out to clear it",
+  },
+  Command {
+    usage: "set-avatar <id or label> [url]",
+    description: "Set the avatar a webhook posts with when its requests don't give one. Leave the URL out to clear it",
+  },
+  Command {
+    usage: "rooms add|remove <id or label> <room>",
+    description: "Allow or forbid a webhook to post to another room, picked with \"room\" in its requests",
+  },
+  Command {
+    usage: "claim <id>",
+    description: "Move a webhook you created, or any webhook if you're a bridge admin, to this room, keeping its URL",
+  },
+  Command {
+    usage: "rejoin <id or label>",
+    description: "Bring back a webhook that was paused after being kicked",
+  },
+  Command {
+    usage: "transfer <id or label> <@user:server>",
+    description: "Offer a webhook you created, or any webhook if you're a bridge admin, to someone else",
+  },
+  Command {
+    usage: "accept <id>",
+    description: "Take a webhook someone offered you",
+  },
+  Command {
+    usage: "help",
+    description: "Show this message",
+  },
+];
+
+const PAYLOAD_FORMAT_URL: &str = "https://github.com/turt2live/matrix-appservice-webhooks#usage";
+
+// The plain and html versions of the list of commands, after an optional first line
+fn help_message(intro: Option<&str>) -> (String, String) {
+  let intro_plain = intro
+    .map(|intro| format!("{}\n", intro))
+    .unwrap_or_default();
+  let intro_html = intro
+    .map(|intro| format!("<p>{}</p>", escape_html(intro)))
+    .unwrap_or_default();
+
+  let plain = COMMANDS
+    .iter()
+    .map(|command| format!("!webhook {}: {}", command.usage, command.description))
+    .collect::<Vec<_>>()
+    .join("\n");
+  let html = COMMANDS
+    .iter()
+    .map(|command| {
+      format!(
+        "<li><code>!webhook {}</code>: {}</li>",
+        escape_html(command.usage),
+        escape_html(command.description)
+      )
+    })
+    .collect::<String>();
+
+  (
+    format!(
+      "{}Commands:\n{}\nWebhooks accept the same JSON as the nodejs bridge: {}",
+      intro_plain, plain, PAYLOAD_FORMAT_URL
+    ),
+    format!(
+      "{}<p>Commands:</p><ul>{}</ul><p>Webhooks accept the same JSON as the <a href=\"{url}\">nodejs bridge</a>.</p>",
+      intro_html,
+      html,
+      url = PAYLOAD_FORMAT_URL
+    ),
+  )
+}
+
+async fn create_webhook(
+  config: &config::Config,
+  store: &Store,
+  client: &Client,
+  room: &Room,
+  sender: &UserId,
+  reply: ReplyTo,
+  args: &[&str],
+) -> anyhow::Result<()> {
+  // Hooks for other rooms can be made from anywhere, like a private chat with the bot
+  let (target, args) = match args {
+    [reference, args @ ..] if room_reference::parse(reference).is_some() => {
+      match other_target_room(config, client, room, sender, reference).await? {
+        Some(target) => (target, args),
+        None => return Ok(()),
+      }
+    }
+    args => match room {
+      Room::Joined(joined) => {
+        if let Some((required, actual)) = missing_create_level(config, joined, sender).await? {
+          return send_notice(room, &create_level_message(required, actual)).await;
+        }
+        (joined.clone(), args)
+      }
+      _ => return Ok(()),
+    },
+  };
+
+  let (label, flags) = match args {
+    [label, flags @ ..] if !label.starts_with("--") => (Some(*label), flags),
+    flags => (None, flags),
+  };
+  let mut flags = match parse_flags(flags) {
+    Some(flags) => flags,
+    None => return send_notice(room, "Options must be given as --name value").await,
+  };
+  let puppet_name = flags.remove("as");
+  if let Some(unknown) = flags.keys().next() {
+    return send_notice(room, &format!("Unknown option --{}", unknown)).await;
+  }
+
+  let puppet_localpart = match puppet_name {
+    Some(name) if !is_valid_puppet_name(name) => {
+      return send_notice(
+        room,
+        "Names may only contain lowercase letters, numbers, '.', '_', '=' and '-', and can't start with '_'",
+      )
+      .await
+    }
+    Some(name) => {
+      let localpart = format!("{}_{}", &config.webhook_bot.localpart, name);
+      if store.is_puppet_localpart_taken(&localpart).await? {
+        return send_notice(room, "That name is already taken").await;
+      }
+      Some(localpart)
+    }
+    None => None,
+  };
+
+  if let Some(label) = label {
+    if !is_valid_label(label) {
+      return send_notice(room, INVALID_LABEL_MESSAGE).await;
+    }
+    if store
+      .is_label_in_use(target.room_id().as_str(), label)
+      .await?
+    {
+      return send_notice(room, "That room already has a hook with that label").await;
+    }
+  }
+
+  info!("Creating webhook for room {}", target.room_id());
+  let hook = match store
+    .create_webhook(
+      target.room_id().as_str(),
+      sender.as_str(),
+      label,
+      puppet_localpart.as_deref(),
+      &config.limits,
+    )
+    .await
+  {
+    Ok(hook) => hook,
+    Err(e) => return limit_notice(room, e).await,
+  };
+
+  space::add_bridged_room(client, target.room_id()).await;
+  bridge_info::publish(config, client, &hook).await;
+
+  let (plain, html) = hook_instructions(config, &hook.id);
+  send_hook_details(
+    client,
+    room,
+    sender,
+    reply,
+    plain,
+    html,
+    "I've sent you a private message with your hook information",
+  )
+  .await
+}
+
+// The room a hook is being made for from another room, joining it if need be. The sender has to be
+// in it, with enough power to create hooks there. Otherwise they're told why and this returns
+// None, leaving the room again if it was only joined for this.
+async fn other_target_room(
+  config: &config::Config,
+  client: &Client,
+  room: &Room,
+  sender: &UserId,
+  reference: &str,
+) -> anyhow::Result<Option<Joined>> {
+  let room_id = match room_reference::resolve(client, reference).await? {
+    Some(room_id) => room_id,
+    None => {
+      send_notice(room, "I couldn't find that room").await?;
+      return Ok(None);
+    }
+  };
+
+  let joined_now = client.get_joined_room(&room_id).is_none();
+  if joined_now {
+    if let Err(e) = client.join_room_by_id(&room_id).await {
+      warn!("Failed to join {}: {}", room_id, e.to_string());
+      send_notice(
+        room,
+        "I couldn't join that room. If it's invite-only, invite me first",
+      )
+      .await?;
+      return Ok(None);
+    }
+    client.sync_once(SyncSettings::default()).await?;
+  }
+  let target = client
+    .get_joined_room(&room_id)
+    .ok_or_else(|| anyhow!("Couldn't get joined room {}", room_id))?;
+
+  let is_member = target
+    .joined_members_no_sync()
+    .await?
+    .iter()
+    .any(|member| member.user_id() == sender);
+  let refusal = if !is_member {
+    Some("You need to be in that room to create webhooks for it".to_string())
+  } else {
+    missing_create_level(config, &target, sender)
+      .await?
+      .map(|(required, actual)| create_level_message(required, actual))
+  };
+
+  match refusal {
+    Some(refusal) => {
+      if joined_now {
+        if let Err(e) = target.leave().await {
+          warn!("Failed to leave {}: {}", room_id, e.to_string());
+        }
+      }
+      send_notice(room, &refusal).await?;
+      Ok(None)
+    }
+    None => Ok(Some(target)),
+  }
+}
+
+pub fn hook_url(config: &config::Config, hook_id: &str) -> String {
+  format!(
+    "{}api/v1/matrix/hook/{}",
+    &config.web.hook_url_base, hook_id
+  )
+}
+
+// Tells the room when a hook wasn't created because of the limits in the config. Other errors
+// are passed on.
+async fn limit_notice(room: &Room, error: anyhow::Error) -> anyhow::Result<()> {
+  match error.downcast_ref::<store::LimitExceeded>() {
+    Some(limit) => send_notice(room, &limit.to_string()).await,
+    None => Err(error),
+  }
+}
+
+// The plain and html versions of the message explaining how to use a new hook
+fn hook_instructions(config: &config::Config, hook_id: &str) -> (String, String) {
+  let hook_url = hook_url(config, hook_id);
+  (
+    format!(
+      r#"
+Here's your webhook url: {url}
+To send a message, POST the following JSON to that URL:
+{{
+  "text": "Hello world!",
+  "format": "plain",
+  "displayName": "My Cool Webhook",
+  "avatarUrl": "{avatar_url}"
+}}
+"#,
+      url = &hook_url,
+      avatar_url = &config.webhook_bot.appearance.avatar_url
+    ),
+    format!(
+      r#"Here's your webhook url: <a href="{url}">{url}</a><br>
+To send a message, POST the following JSON to that URL:
+<pre><code>{{
+  "text": "Hello world!",
+  "format": "plain",
+  "displayName": "My Cool Webhook",
+  "avatarUrl": "{avatar_url}"
+}}</code></pre>
+"#,
+      url = &hook_url,
+      avatar_url = &config.webhook_bot.appearance.avatar_url
+    ),
+  )
+}
+
+async fn add_outgoing_webhook(
+  store: &Store,
+  client: &Client,
+  room: &Room,
+  sender: &UserId,
+  url: &str,
+  flags: &[&str],
+) -> anyhow::Result<()> {
+  if !is_http_url(url) {
+    return send_notice(room, "That doesn't look like an http(s) URL").await;
+  }
+  let filters = match outgoing_filters(flags) {
+    Ok(filters) => filters,
+    Err(message) => return send_notice(room, &message).await,
+  };
+
+  info!("Adding outgoing webhook for room {}", room.room_id());
+  let hook = store
+    .create_outgoing_webhook(room.room_id().as_str(), sender.as_str(), url, filters)
+    .await?;
+
+  let (plain, html) = outgoing_details(room, &hook);
+  send_admin_notice(client, sender, plain, html).await?;
+
+  send_notice(
+    room,
+    "Messages in this room will now be forwarded to an outgoing webhook",
+  )
+  .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn create_connection(
+  config: &config::Config,
+  store: &Store,
+  client: &Client,
+  room: &Room,
+  sender: &UserId,
+  reply: ReplyTo,
+  label: &str,
+  url: &str,
+  flags: &[&str],
+) -> anyhow::Result<()> {
+  if !is_valid_label(label) {
+    return send_notice(room, INVALID_LABEL_MESSAGE).await;
+  }
+  if !is_http_url(url) {
+    return send_notice(room, "That doesn't look like an http(s) URL").await;
+  }
+  let filters = match outgoing_filters(flags) {
+    Ok(filters) => filters,
+    Err(message) => return send_notice(room, &message).await,
+  };
+  if store
+    .is_label_in_use(room.room_id().as_str(), label)
+    .await?
+  {
+    return send_notice(room, "This room already has a hook with that label").await;
+  }
+
+  info!("Creating connection {} for room {}", label, room.room_id());
+  let (incoming, outgoing) = match store
+    .create_connection(
+      room.room_id().as_str(),
+      sender.as_str(),
+      label,
+      url,
+      filters,
+      &config.limits,
+    )
+    .await
+  {
+    Ok(created) => created,
+    Err(e) => return limit_notice(room, e).await,
+  };
+
+  space::add_bridged_room(client, room.room_id()).await;
+  bridge_info::publish(config, client, &incoming).await;
+
+  let (incoming_plain, incoming_html) = hook_instructions(config, &incoming.id);
+  let (outgoing_plain, outgoing_html) = outgoing_details(room, &outgoing);
+  send_hook_details(
+    client,
+    room,
+    sender,
+    reply,
+    format!(
+      "Connection {}:\n{}\n{}",
+      label, incoming_plain, outgoing_plain
+    ),
+    format!(
+      "Connection <code>{}</code>:<br>{}<br>{}",
+      escape_html(label),
+      incoming_html,
+      outgoing_html
+    ),
+    &format!(
+      "Connected {}. Messages in this room will be forwarded to it, and I've sent you a private message with the details",
+      label
+    ),
+  )
+  .await
+}
+
+async fn remove_connection(store: &Store, room: &Room, label: &str) -> anyhow::Result<()> {
+  if !store
+    .delete_connection(room.room_id().as_str(), label)
+    .await?
+  {
+    return send_notice(room, "This room has no connection with that label").await;
+  }
+
+  info!("Removed connection {} from {}", label, room.room_id());
+  send_notice(room, &format!("Disconnected {}", label)).await
+}
+
+#[derive(Debug, PartialEq)]
+enum GuardedTarget<'a> {
+  Connection(&'a str),
+  Outgoing(&'a str),
+}
+
+// The label or id of the hooks a command removes or replaces, if it does
+fn guarded_target<'a>(args: &[&'a str]) -> Option<GuardedTarget<'a>> {
+  match args {
+    ["connect", label, ..] | ["disconnect", label] => Some(GuardedTarget::Connection(*label)),
+    ["out", "remove", id] => Some(GuardedTarget::Outgoing(*id)),
+    _ => None,
+  }
+}
+
+// The creators of both halves of the connection with the label in the room
+async fn connection_owners(store: &Store, room: &Room, label: &str) -> anyhow::Result<Vec<String>> {
+  let room_id = room.room_id().as_str();
+  let incoming = store
+    .get_webhooks_for_room(room_id)
+    .await?
+    .into_iter()
+    .filter(|hook| hook.label.as_deref() == Some(label))
+    .map(|hook| hook.user_id);
+  let outgoing = store
+    .get_outgoing_webhooks_for_room(room_id)
+    .await?
+    .into_iter()
+    .filter(|hook| hook.label.as_deref() == Some(label))
+    .map(|hook| hook.user_id);
+  Ok(incoming.chain(outgoing).collect())
+}
+
+// The creator of the outgoing hook with the id in the room, if there is one
+async fn outgoing_owners(store: &Store, room: &Room, id: &str) -> anyhow::Result<Vec<String>> {
+  Ok(
+    store
+      .get_outgoing_webhooks_for_room(room.room_id().as_str())
+      .await?
+      .into_iter()
+      .filter(|hook| hook.id == id)
+      .map(|hook| hook.user_id)
+      .collect(),
+  )
+}
+
+// Whether the user made every one of the hooks, which they do when there are none
+fn owns_all(owners: &[String], user_id: &UserId) -> bool {
+  owners.iter().all(|owner| owner == user_id.as_str())
+}
+
+fn is_http_url(url: &str) -> bool {
+  match reqwest::Url::parse(url) {
+    Ok(parsed) => parsed.scheme() == "http" || parsed.scheme() == "https",
+    Err(_) => false,
+  }
+}
+
+// Reads the filters for an outgoing hook from the command's flags, or explains what's wrong
+fn outgoing_filters(flags: &[&str]) -> Result<OutgoingFilters, String> {
+  let mut flags = match parse_flags(flags) {
+    Some(flags) => flags,
+    None => return Err("Options must be given as --name value".to_string()),
+  };
+  let filters = OutgoingFilters {
+    event_types: flags.remove("types").map(str::to_string),
+    senders: flags.remove("senders").map(str::to_string),
+    keywords: flags.remove("keywords").map(str::to_string),
+  };
+  match flags.keys().next() {
+    Some(unknown) => Err(format!("Unknown option --{}", unknown)),
+    None => Ok(filters),
+  }
+}
+
+// The plain and html versions of the message describing a new outgoing hook
+fn outgoing_details(room: &Room, hook: &OutgoingWebhook) -> (String, String) {
+  (
+    format!(
+      "Messages in {} will now be POSTed to {} (outgoing hook {})\n\
+      Requests are signed with the secret {} in the {} header",
+      room.room_id(),
+      &hook.url,
+      &hook.id,
+      hook.secret.as_deref().unwrap_or_default(),
+      outgoing::SIGNATURE_HEADER
+    ),
+    format!(
+      "Messages in {} will now be POSTed to <code>{}</code> (outgoing hook <code>{}</code>)<br>\
+      Requests are signed with the secret <code>{}</code> in the <code>{}</code> header",
+      room.room_id(),
+      escape_html(&hook.url),
+      &hook.id,
+      hook.secret.as_deref().unwrap_or_default(),
+      outgoing::SIGNATURE_HEADER
+    ),
+  )
+}
+
+// Other people's hooks are listed without their ids, which are the secret in their URLs
+async fn list_webhooks(
+  config: &config::Config,
+  store: &Store,
+  client: &Client,
+  room: &Room,
+  sender: &UserId,
+  only_senders: bool,
+) -> anyhow::Result<()> {
+  let hooks: Vec<_> = store
+    .get_webhooks_for_room(room.room_id().as_str())
+    .await?
+    .into_iter()
+    .filter(|hook| !only_senders || hook.user_id == sender.as_str())
+    .collect();
+  if hooks.is_empty() {
+    return send_notice(room, "This room has no webhooks").await;
+  }
+  let sees_all = sees_all_ids(config, client, room, sender).await?;
+
+  let plain = hooks
+    .iter()
+    .map(|hook| {
+      format!(
+        "{}{}, created by {}",
+        listed_id(hook, sender, sees_all).unwrap_or(HIDDEN_ID),
+        describe_label(&hook.label),
+        hook.user_id
+      )
+    })
+    .collect::<Vec<_>>()
+    .join("\n");
+  let html = hooks
+    .iter()
+    .map(|hook| {
+      format!(
+        "<li>{}{}, created by {}</li>",
+        listed_id(hook, sender, sees_all).map_or_else(
+          || HIDDEN_ID.to_string(),
+          |id| format!("<code>{}</code>", id)
+        ),
+        escape_html(&describe_label(&hook.label)),
+        escape_html(&hook.user_id)
+      )
+    })
+    .collect::<Vec<_>>()
+    .join("");
+  send_admin_notice(
+    client,
+    sender,
+    format!("Webhooks for {}:\n{}", room.room_id(), plain),
+    format!("Webhooks for {}:<ul>{}</ul>", room.room_id(), html),
+  )
+  .await?;
+
+  send_notice(room, "I've sent you a private message with the list").await
+}
+
+const HIDDEN_ID: &str = "hidden id";
+
+// Whether listings show the user every hook's id, as they do the room's admins and the bridge's
+async fn sees_all_ids(
+  config: &config::Config,
+  client: &Client,
+  room: &Room,
+  user_id: &UserId,
+) -> anyhow::Result<bool> {
+  Ok(
+    config.webhook_bot.is_admin(user_id.as_str())
+      || is_room_admin(client, room.room_id().as_str(), user_id).await?,
+  )
+}
+
+// The hook's id if the user may see it in a listing
+fn listed_id<'a>(hook: &'a store::Webhook, user_id: &UserId, sees_all: bool) -> Option<&'a str> {
+  if sees_all || hook.user_id == user_id.as_str() {
+    Some(&hook.id)
+  } else {
+    None
+  }
+}
+
+// Only for the hook's creator and the room's admins, since it includes the URL
+async fn webhook_info(
+  config: &config::Config,
+  store: &Store,
+  client: &Client,
+  room: &Room,
+  sender: &UserId,
+  id_or_label: &str,
+) -> anyhow::Result<()> {
+  let hook = match find_webhook(store, room, id_or_label).await? {
+    Some(hook) => hook,
+    None => return send_notice(room, "There's no webhook with that id or label here").await,
+  };
+  if hook.user_id != sender.as_str() && !is_room_admin(client, &hook.room_id, sender).await? {
+    return send_notice(
+      room,
+      "Only the webhook's creator or an admin of its room can see its details",
+    )
+    .await;
+  }
+
+  let stats = store.get_webhook_stats(&hook.id).await?;
+  let extra_rooms = store.get_webhook_rooms(&hook.id).await?;
+  let paused = !hook.enabled
+    || store
+      .get_setting(&webhook::kicked_setting(&hook.id))
+      .await?
+      .is_some();
+  let describe_time = |timestamp: Option<i64>, missing: &str| {
+    timestamp.map_or_else(|| missing.to_string(), format_timestamp)
+  };
+  let details = [
+    ("URL", hook_url(config, &hook.id)),
+    ("Room", hook.room_id.clone()),
+    ("Owner", hook.user_id.clone()),
+    ("Label", hook.label.clone().unwrap_or_else(|| "none".into())),
+    (
+      "Posts as",
+      format!(
+        "@{}:{}",
+        webhook::hook_puppet_localpart(config, &hook),
+        &config.homeserver.domain
+      ),
+    ),
+    (
+      "Other rooms",
+      if extra_rooms.is_empty() {
+        "none".into()
+      } else {
+        extra_rooms.join(", ")
+      },
+    ),
+    ("Created", describe_time(stats.created_at, "unknown")),
+    ("Last used", describe_time(stats.last_used_at, "never")),
+    ("Deliveries", stats.deliveries.to_string()),
+    ("Failures", stats.failures.to_string()),
+    (
+      "Status",
+      if paused { "paused" } else { "active" }.to_string(),
+    ),
+  ];
+
+  let plain = details
+    .iter()
+    .map(|(name, value)| format!("{}: {}", name, value))
+    .collect::<Vec<_>>()
+    .join("\n");
+  let html = details
+    .iter()
+    .map(|(name, value)| format!("<li><b>{}</b>: {}</li>", name, escape_html(value)))
+    .collect::<Vec<_>>()
+    .join("");
+  send_admin_notice(
+    client,
+    sender,
+    format!("Webhook {}:\n{}", &hook.id, plain),
+    format!("Webhook <code>{}</code>:<ul>{}</ul>", &hook.id, html),
+  )
+  .await?;
+
+  send_notice(room, "I've sent you a private message with the details").await
+}
+
+// As a table in the admin room, with other people's hooks by label like list_webhooks
+async fn webhook_stats(
+  config: &config::Config,
+  store: &Store,
+  client: &Client,
+  room: &Room,
+  sender: &UserId,
+) -> anyhow::Result<()> {
+  let hooks = store.get_webhooks_for_room(room.room_id().as_str()).await?;
+  if hooks.is_empty() {
+    return send_notice(room, "This room has no webhooks").await;
+  }
+  let sees_all = sees_all_ids(config, client, room, sender).await?;
+
+  let mut plain = vec![];
+  let mut rows = vec![];
+  for hook in &hooks {
+    let stats = store.get_webhook_stats(&hook.id).await?;
+    let last_delivery = stats
+      .last_used_at
+      .map_or_else(|| "never".to_string(), format_timestamp);
+    let id = listed_id(hook, sender, sees_all);
+    plain.push(format!(
+      "{}{}: {} delivered, {} failed, last delivery {}",
+      id.unwrap_or(HIDDEN_ID),
+      describe_label(&hook.label),
+      stats.deliveries,
+      stats.failures,
+      last_delivery
+    ));
+    rows.push(format!(
+      "<tr><td>{}{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+      id.map_or_else(
+        || HIDDEN_ID.to_string(),
+        |id| format!("<code>{}</code>", id)
+      ),
+      escape_html(&describe_label(&hook.label)),
+      stats.deliveries,
+      stats.failures,
+      last_delivery
+    ));
+  }
+  send_admin_notice(
+    client,
+    sender,
+    format!("Webhook stats for {}:\n{}", room.room_id(), plain.join("\n")),
+    format!(
+      "Webhook stats for {}:<table><tr><th>Webhook</th><th>Deliveries</th><th>Failures</th><th>Last delivery</th></tr>{}</table>",
+      room.room_id(),
+      rows.join("")
+    ),
+  )
+  .await?;
+
+  send_notice(room, "I've sent you a private message with the stats").await
+}
+
+// Unix timestamps as UTC dates and times, like 2021-10-31 14:05:09 UTC
+fn format_timestamp(timestamp: i64) -> String {
+  let days = timestamp.div_euclid(86400);
+  let seconds = timestamp.rem_euclid(86400);
+
+  // Days since the epoch to a civil date, from Howard Hinnant's date algorithms
+  let z = days + 719468;
+  let era = z.div_euclid(146097);
+  let day_of_era = z.rem_euclid(146097);
+  let year_of_era =
+    (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let shifted_month = (5 * day_of_year + 2) / 153;
+  let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+  let month = if shifted_month < 10 {
+    shifted_month + 3
+  } else {
+    shifted_month - 9
+  };
+  let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+  format!(
+    "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+    year,
+    month,
+    day,
+    seconds / 3600,
+    seconds % 3600 / 60,
+    seconds % 60
+  )
+}
+
+// The dispatcher has already checked that the sender may delete the hook
+async fn delete_webhook(
+  store: &Store,
+  client: &Client,
+  room: &Room,
+  sender: &UserId,
+  id_or_label: &str,
+) -> anyhow::Result<()> {
+  let hook = match find_webhook(store, room, id_or_label).await? {
+    Some(hook) => hook,
+    None => return send_notice(room, "There's no webhook with that id or label here").await,
+  };
+
+  store.delete_webhook(&hook.id).await?;
+  info!("Webhook {} was deleted by {}", &hook.id, sender);
+  let message = format!(
+    "Deleted webhook {}{} in {}",
+    &hook.id,
+    describe_label(&hook.label),
+    &hook.room_id
+  );
+  send_admin_notice(client, sender, message.clone(), escape_html(&message)).await?;
+  send_notice(room, "Deleted the webhook").await
+}
+
+// The dispatcher has already checked that the sender may purge the room
+async fn purge_webhooks(
+  store: &Store,
+  client: &Client,
+  room: &Room,
+  sender: &UserId,
+) -> anyhow::Result<()> {
+  let count = store
+    .delete_webhooks_for_room(room.room_id().as_str())
+    .await?;
+  if count == 0 {
+    return send_notice(room, "This room has no webhooks").await;
+  }
+
+  info!(
+    "{} webhooks in {} were purged by {}",
+    count,
+    room.room_id(),
+    sender
+  );
+  let message = format!("Deleted all {} webhooks in {}", count, room.room_id());
+  send_admin_notice(client, sender, message.clone(), escape_html(&message)).await?;
+  send_notice(
+    room,
+    &format!(
+      "Deleted {} webhook{}",
+      count,
+      if count == 1 { "" } else { "s" }
+    ),
+  )
+  .await
+}
+
+// The dispatcher has already checked that the sender may pause the hook
+async fn set_webhook_enabled(
+  store: &Store,
+  room: &Room,
+  sender: &UserId,
+  id_or_label: &str,
+  enabled: bool,
+) -> anyhow::Result<()> {
+  let hook = match find_webhook(store, room, id_or_label).await? {
+    Some(hook) => hook,
+    None => return send_notice(room, "There's no webhook with that id or label here").await,
+  };
+  if hook.enabled == enabled {
+    return send_notice(
+      room,
+      if enabled {
+        "That webhook isn't paused"
+      } else {
+        "That webhook is already paused"
+      },
+    )
+    .await;
+  }
+
+  if !store.set_webhook_enabled(&hook.id, enabled).await? {
+    return send_notice(room, "That webhook was deleted in the meantime").await;
+  }
+  if enabled {
+    info!("Webhook {} was resumed by {}", &hook.id, sender);
+    send_notice(room, "The webhook is posting again").await
+  } else {
+    info!("Webhook {} was paused by {}", &hook.id, sender);
+    send_notice(
+      room,
+      "The webhook is paused. Its requests will be turned away until it's resumed",
+    )
+    .await
+  }
+}
+
+// A made up secret only goes to the owner, like the URL. One they give themselves is used as is,
+// for services like Stripe that make their own.
+async fn set_webhook_secret(
+  store: &Store,
+  client: &Client,
+  room: &Room,
+  sender: &UserId,
+  id_or_label: &str,
+  secret: Option<&str>,
+) -> anyhow::Result<()> {
+  let hook = match find_webhook(store, room, id_or_label).await? {
+    Some(hook) => hook,
+    None => return send_notice(room, "There's no webhook with that id or label here").await,
+  };
+  let setting = payloads::secret_setting(&hook.id);
+
+  match secret {
+    Some("off") => {
+      store.delete_setting(&setting).await?;
+      info!("Webhook {}'s secret was removed by {}", &hook.id, sender);
+      send_notice(
+        room,
+        "The webhook's requests are no longer checked against a secret",
+      )
+      .await
+    }
+    Some(secret) => {
+      store.set_setting(&setting, secret).await?;
+      info!("Webhook {}'s secret was set by {}", &hook.id, sender);
+      send_notice(
+        room,
+        "Signed requests to the webhook are now checked against that secret",
+      )
+      .await
+    }
+    None => {
+      let secret = randid::randid_str(32);
+      store.set_setting(&setting, &secret).await?;
+      info!("Webhook {}'s secret was made up for {}", &hook.id, sender);
+
+      let owner = UserId::try_from(hook.user_id.as_str())?;
+      send_admin_notice(
+        client,
+        &owner,
+        format!(
+          "The secret for webhook {} is now {}. Give it to the service sending to the webhook, and requests that aren't signed with it will be turned away.",
+          &hook.id, &secret
+        ),
+        format!(
+          "The secret for webhook <code>{}</code> is now <code>{}</code>. Give it to the service sending to the webhook, and requests that aren't signed with it will be turned away.",
+          &hook.id, &secret
+        ),
+      )
+      .await?;
+      if owner == *sender {
+        send_notice(
+          room,
+          "I've sent you a private message with the webhook's secret",
+        )
+        .await
+      } else {
+        send_notice(
+          room,
+          &format!("I've sent the webhook's secret to {}", owner),
+        )
+        .await
+      }
+    }
+  }
+}
+
+// Event types are whatever the service calls them, so they aren't checked against a list
+async fn set_webhook_events(
+  store: &Store,
+  room: &Room,
+  sender: &UserId,
+  id_or_label: &str,
+  events: Option<&str>,
+) -> anyhow::Result<()> {
+  let hook = match find_webhook(store, room, id_or_label).await? {
+    Some(hook) => hook,
+    None => return send_notice(room, "There's no webhook with that id or label here").await,
+  };
+  let setting = payloads::events_setting(&hook.id);
+
+  match events {
+    None => match store.get_setting(&setting).await? {
+      Some(events) => {
+        send_notice(
+          room,
+          &format!("The webhook only posts these events: {}", events),
+        )
+        .await
+      }
+      None => send_notice(room, "The webhook posts every event").await,
+    },
+    Some("all") => {
+      store.delete_setting(&setting).await?;
+      info!(
+        "Webhook {} was set to post every event by {}",
+        &hook.id, sender
+      );
+      send_notice(room, "The webhook now posts every event").await
+    }
+    Some(events) => {
+      let events = events
+        .split(',')
+        .filter(|event| !event.is_empty())
+        .collect::<Vec<_>>()
+        .join(",");
+      store.set_setting(&setting, &events).await?;
+      info!(
+        "Webhook {} was set to post {} by {}",
+        &hook.id, &events, sender
+      );
+      send_notice(
+        room,
+        &format!("The webhook now only posts these events: {}", events),
+      )
+      .await
+    }
+  }
+}
+
+async fn set_webhook_thread(
+  store: &Store,
+  room: &Room,
+  sender: &UserId,
+  id_or_label: &str,
+  mode: Option<&str>,
+) -> anyhow::Result<()> {
+  let hook = match find_webhook(store, room, id_or_label).await? {
+    Some(hook) => hook,
+    None => return send_notice(room, "There's no webhook with that id or label here").await,
+  };
+  let setting = webhook::auto_thread_setting(&hook.id);
+  let root_setting = webhook::thread_root_setting(&hook.id);
+
+  match mode {
+    None => match store.get_setting(&setting).await? {
+      Some(_) => send_notice(room, "The webhook posts its messages in a thread").await,
+      None => send_notice(room, "The webhook posts to the main timeline").await,
+    },
+    Some("on") => {
+      store.set_setting(&setting, "true").await?;
+      info!(
+        "Webhook {} was set to post in a thread by {}",
+        &hook.id, sender
+      );
+      send_notice(
+        room,
+        "The webhook's next message starts a thread, and the ones after go in it",
+      )
+      .await
+    }
+    Some("off") => {
+      store.delete_setting(&setting).await?;
+      store.delete_setting(&root_setting).await?;
+      info!(
+        "Webhook {} was set to post to the timeline by {}",
+        &hook.id, sender
+      );
+      send_notice(room, "The webhook now posts to the main timeline").await
+    }
+    Some("new") => {
+      if store.get_setting(&setting).await?.is_none() {
+        return send_notice(room, "The webhook doesn't post in a thread").await;
+      }
+      store.delete_setting(&root_setting).await?;
+      send_notice(room, "The webhook's next message starts a new thread").await
+    }
+    Some(_) => send_notice(room, "That should be on, off or new").await,
+  }
+}
+
+// A hook's setting that a command turns on and off, and what's said about it
+struct Toggle {
+  setting: fn(&str) -> String,
+  is_on: &'static str,
+  is_off: &'static str,
+  turned_on: &'static str,
+  turned_off: &'static str,
+  // Logged as "Webhook <id> <logged> by <user>"
+  logged_on: &'static str,
+  logged_off: &'static str,
+}
+
+const ROOM_STATE_TOGGLE: Toggle = Toggle {
+  setting: webhook::room_state_setting,
+  is_on: "The webhook may change the room's name and topic",
+  is_off: "The webhook may not change the room's name or topic",
+  turned_on: "The webhook may now change the room's name and topic, if its power level allows",
+  turned_off: "The webhook may no longer change the room's name or topic",
+  logged_on: "was allowed to change room state",
+  logged_off: "was stopped changing room state",
+};
+
+const CUSTOM_EVENTS_TOGGLE: Toggle = Toggle {
+  setting: webhook::custom_events_setting,
+  is_on: "The webhook may send custom events",
+  is_off: "The webhook may not send custom events",
+  turned_on: "The webhook may now send custom events",
+  turned_off: "The webhook may no longer send custom events",
+  logged_on: "was allowed to send custom events",
+  logged_off: "was stopped sending custom events",
+};
+
+const ROOM_MENTION_TOGGLE: Toggle = Toggle {
+  setting: webhook::room_mention_setting,
+  is_on: "The webhook may ping the whole room",
+  is_off: "The webhook may not ping the whole room",
+  turned_on: "The webhook may now ping the whole room, when a message asks to with mentionRoom",
+  turned_off: "The webhook may no longer ping the whole room",
+  logged_on: "was allowed to ping the room",
+  logged_off: "was stopped pinging the room",
+};
+
+const PREVIEW_TOGGLE: Toggle = Toggle {
+  setting: webhook::preview_setting,
+  is_on: "The webhook's messages get link previews",
+  is_off: "The webhook's messages don't get link previews",
+  turned_on: "The webhook's messages now get a preview of the first link in them",
+  turned_off: "The webhook's messages no longer get link previews",
+  logged_on: "had its link previews turned on",
+  logged_off: "had its link previews turned off",
+};
+
+// Says whether the setting is on without a value, and otherwise turns it on or off
+async fn toggle_setting(
+  store: &Store,
+  room: &Room,
+  sender: &UserId,
+  id_or_label: &str,
+  value: Option<&str>,
+  toggle: &Toggle,
+) -> anyhow::Result<()> {
+  let hook = match find_webhook(store, room, id_or_label).await? {
+    Some(hook) => hook,
+    None => return send_notice(room, "There's no webhook with that id or label here").await,
+  };
+  let setting = (toggle.setting)(&hook.id);
+
+  match value {
+    None => match store.get_setting(&setting).await? {
+      Some(_) => send_notice(room, toggle.is_on).await,
+      None => send_notice(room, toggle.is_off).await,
+    },
+    Some("on") => {
+      store.set_setting(&setting, "true").await?;
+      info!("Webhook {} {} by {}", &hook.id, toggle.logged_on, sender);
+      send_notice(room, toggle.turned_on).await
+    }
+    Some("off") => {
+      store.delete_setting(&setting).await?;
+      info!("Webhook {} {} by {}", &hook.id, toggle.logged_off, sender);
+      send_notice(room, toggle.turned_off).await
+    }
+    Some(_) => send_notice(room, "That should be on or off").await,
+  }
+}
+
+async fn set_webhook_digest(
+  store: &Store,
+  room: &Room,
+  sender: &UserId,
+  id_or_label: &str,
+  policy: Option<&str>,
+) -> anyhow::Result<()> {
+  let hook = match find_webhook(store, room, id_or_label).await? {
+    Some(hook) => hook,
+    None => return send_notice(room, "There's no webhook with that id or label here").await,
+  };
+  let setting = webhook::digest_setting(&hook.id);
+  let describe = |policy: &digest::Policy| match policy.items {
+    Some(items) => format!(
+      "every {} seconds, or once it has {} messages",
+      policy.seconds, items
+    ),
+    None => format!("every {} seconds", policy.seconds),
+  };
+
+  match policy {
+    None => match store
+      .get_setting(&setting)
+      .await?
+      .and_then(|policy| digest::Policy::parse(&policy))
+    {
+      Some(policy) => {
+        send_notice(
+          room,
+          &format!(
+            "The webhook posts its messages together {}",
+            describe(&policy)
+          ),
+        )
+        .await
+      }
+      None => send_notice(room, "The webhook posts its messages as they come").await,
+    },
+    Some("off") => {
+      store.delete_setting(&setting).await?;
+      info!("Webhook {}'s digest was turned off by {}", &hook.id, sender);
+      send_notice(
+        room,
+        "The webhook now posts its messages as they come, after any it was holding",
+      )
+      .await
+    }
+    Some(value) => match digest::Policy::parse(value) {
+      Some(policy) => {
+        store.set_setting(&setting, value).await?;
+        info!("Webhook {}'s digest was set by {}", &hook.id, sender);
+        send_notice(
+          room,
+          &format!(
+            "The webhook now posts its messages together {}",
+            describe(&policy)
+          ),
+        )
+        .await
+      }
+      None => {
+        send_notice(
+          room,
+          "That should be a number of seconds, then optionally a number of messages, or off",
+        )
+        .await
+      }
+    },
+  }
+}
+
+async fn set_webhook_oversize(
+  store: &Store,
+  room: &Room,
+  sender: &UserId,
+  id_or_label: &str,
+  handling: Option<&str>,
+) -> anyhow::Result<()> {
+  let hook = match find_webhook(store, room, id_or_label).await? {
+    Some(hook) => hook,
+    None => return send_notice(room, "There's no webhook with that id or label here").await,
+  };
+  let setting = webhook::oversize_setting(&hook.id);
+
+  match handling {
+    None => match store.get_setting(&setting).await? {
+      Some(_) => {
+        send_notice(
+          room,
+          "The webhook's messages too big to send are cut short, with the whole text attached",
+        )
+        .await
+      }
+      None => {
+        send_notice(
+          room,
+          "The webhook's messages too big to send are split into several",
+        )
+        .await
+      }
+    },
+    Some("split") => {
+      store.delete_setting(&setting).await?;
+      info!(
+        "Webhook {} was set to split big messages by {}",
+        &hook.id, sender
+      );
+      send_notice(
+        room,
+        "The webhook's messages too big to send are now split into several",
+      )
+      .await
+    }
+    Some(oversize::TRUNCATE) => {
+      store.set_setting(&setting, oversize::TRUNCATE).await?;
+      info!(
+        "Webhook {} was set to truncate big messages by {}",
+        &hook.id, sender
+      );
+      send_notice(
+        room,
+        "The webhook's messages too big to send are now cut short, with the whole text attached",
+      )
+      .await
+    }
+    Some(_) => send_notice(room, "That should be split or truncate").await,
+  }
+}
+
+async fn set_webhook_template(
+  store: &Store,
+  room: &Room,
+  sender: &UserId,
+  id_or_label: &str,
+  template: Option<&str>,
+) -> anyhow::Result<()> {
+  let hook = match find_webhook(store, room, id_or_label).await? {
+    Some(hook) => hook,
+    None => return send_notice(room, "There's no webhook with that id or label here").await,
+  };
+  let setting = payloads::template_setting(&hook.id);
+
+  match template {
+    None => match store.get_setting(&setting).await? {
+      Some(template) => {
+        send_html_notice(
+          room,
+          format!("The webhook's template is:\n{}", template),
+          format!(
+            "The webhook's template is:<pre><code>{}</code></pre>",
+            escape_html(&template)
+          ),
+        )
+        .await
+      }
+      None => send_notice(room, "The webhook has no template of its own").await,
+    },
+    Some("off") => {
+      store.delete_setting(&setting).await?;
+      info!("Webhook {}'s template was cleared by {}", &hook.id, sender);
+      send_notice(room, "Cleared the webhook's template").await
+    }
+    Some(template) => {
+      if let Some(reason) = payloads::custom::check(template) {
+        return send_notice(room, &format!("That template isn't valid: {}", reason)).await;
+      }
+      store.set_setting(&setting, template).await?;
+      info!("Webhook {}'s template was set by {}", &hook.id, sender);
+      send_notice(
+        room,
+        "JSON sent to the webhook's URL with /custom appended is now rendered through the template",
+      )
+      .await
+    }
+  }
+}
+
+async fn set_webhook_named_template(
+  store: &Store,
+  room: &Room,
+  sender: &UserId,
+  id_or_label: &str,
+  name: Option<&str>,
+  template: Option<&str>,
+) -> anyhow::Result<()> {
+  let hook = match find_webhook(store, room, id_or_label).await? {
+    Some(hook) => hook,
+    None => return send_notice(room, "There's no webhook with that id or label here").await,
+  };
+  let mut templates = payloads::named_templates(store, &hook.id).await?;
+
+  let name = match name {
+    Some(name) => name,
+    None if templates.is_empty() => {
+      return send_notice(room, "The webhook has no named templates").await
+    }
+    None => {
+      let names = templates.keys().cloned().collect::<Vec<_>>().join(", ");
+      return send_notice(room, &format!("The webhook's templates are {}", names)).await;
+    }
+  };
+  match template {
+    None => match templates.get(name) {
+      Some(template) => {
+        send_html_notice(
+          room,
+          format!("The webhook's {} template is:\n{}", name, template),
+          format!(
+            "The webhook's {} template is:<pre><code>{}</code></pre>",
+            escape_html(name),
+            escape_html(template)
+          ),
+        )
+        .await
+      }
+      None => send_notice(room, "The webhook has no template by that name").await,
+    },
+    Some("off") => {
+      if templates.remove(name).is_none() {
+        return send_notice(room, "The webhook has no template by that name").await;
+      }
+      save_named_templates(store, &hook.id, &templates).await?;
+      info!(
+        "Webhook {}'s {} template was removed by {}",
+        &hook.id, name, sender
+      );
+      send_notice(room, &format!("Removed the webhook's {} template", name)).await
+    }
+    Some(template) => {
+      if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+      {
+        return send_notice(
+          room,
+          "A template's name can only have letters, numbers, _ and -",
+        )
+        .await;
+      }
+      if let Some(reason) = payloads::custom::check(template) {
+        return send_notice(room, &format!("That template isn't valid: {}", reason)).await;
+      }
+      templates.insert(name.to_string(), template.to_string());
+      save_named_templates(store, &hook.id, &templates).await?;
+      info!(
+        "Webhook {}'s {} template was set by {}",
+        &hook.id, name, sender
+      );
+      send_notice(
+        room,
+        &format!(
+          "Requests with \"template\": \"{}\" are now rendered through the template, with their vars",
+          name
+        ),
+      )
+      .await
+    }
+  }
+}
+
+async fn save_named_templates(
+  store: &Store,
+  hook_id: &str,
+  templates: &BTreeMap<String, String>,
+) -> anyhow::Result<()> {
+  let setting = payloads::named_templates_setting(hook_id);
+  if templates.is_empty() {
+    store.delete_setting(&setting).await
+  } else {
+    store
+      .set_setting(&setting, &serde_json::to_string(templates)?)
+      .await
+  }
+}
+
+// What follows the command's first words, keeping its spacing and newlines, for arguments like
+// templates that need them
+fn command_rest(body: &str, words: usize) -> &str {
+  let mut rest = body.trim_start();
+  for _ in 0..words {
+    let end = rest.find(char::is_whitespace).unwrap_or_else(|| rest.len());
+    rest = rest[end..].trim_start();
+  }
+  rest.trim_end()
+}
+
+async fn set_webhook_min_level(
+  store: &Store,
+  room: &Room,
+  sender: &UserId,
+  id_or_label: &str,
+  level: Option<&str>,
+) -> anyhow::Result<()> {
+  let hook = match find_webhook(store, room, id_or_label).await? {
+    Some(hook) => hook,
+    None => return send_notice(room, "There's no webhook with that id or label here").await,
+  };
+  let setting = payloads::min_level_setting(&hook.id);
+
+  match level {
+    None => match store.get_setting(&setting).await? {
+      Some(level) => {
+        send_notice(
+          room,
+          &format!("The webhook posts alerts at level {} and above", level),
+        )
+        .await
+      }
+      None => send_notice(room, "The webhook posts alerts at every level").await,
+    },
+    Some("all") => {
+      store.delete_setting(&setting).await?;
+      info!(
+        "Webhook {} was set to post every level by {}",
+        &hook.id, sender
+      );
+      send_notice(room, "The webhook now posts alerts at every level").await
+    }
+    Some(level) if payloads::sentry::LEVELS.contains(&level) => {
+      store.set_setting(&setting, level).await?;
+      info!(
+        "Webhook {} was set to post {} and above by {}",
+        &hook.id, level, sender
+      );
+      send_notice(
+        room,
+        &format!("The webhook now posts alerts at level {} and above", level),
+      )
+      .await
+    }
+    Some(_) => {
+      send_notice(
+        room,
+        &format!(
+          "The level must be one of {}, or all",
+          payloads::sentry::LEVELS.join(", ")
+        ),
+      )
+      .await
+    }
+  }
+}
+
+// The old URL stops working straight away. The new one only goes to the owner, like on creation,
+// even when a bridge admin regenerates it.
+async fn regenerate_webhook(
+  config: &config::Config,
+  store: &Store,
+  client: &Client,
+  room: &Room,
+  sender: &UserId,
+  id_or_label: &str,
+) -> anyhow::Result<()> {
+  let hook = match find_webhook(store, room, id_or_label).await? {
+    Some(hook) => hook,
+    None => return send_notice(room, "There's no webhook with that id or label here").await,
+  };
+
+  // Keep posting as the same user, rather than inviting a new one named after the new id
+  let puppet_localpart = match config.webhook_bot.puppet_mode {
+    config::PuppetMode::Hook => Some(webhook::hook_puppet_localpart(config, &hook)),
+    config::PuppetMode::DisplayName => None,
+  };
+  let hook = match store
+    .regenerate_webhook_id(&hook.id, puppet_localpart.as_deref())
+    .await?
+  {
+    Some(hook) => hook,
+    None => return send_notice(room, "That webhook was deleted in the meantime").await,
+  };
+  info!("Webhook in {} was regenerated by {}", &hook.room_id, sender);
+
+  let owner = UserId::try_from(hook.user_id.as_str())?;
+  let (plain, html) = hook_instructions(config, &hook.id);
+  send_admin_notice(
+    client,
+    &owner,
+    format!("The old URL no longer works.\n{}", plain),
+    format!("<p>The old URL no longer works.</p>{}", html),
+  )
+  .await?;
+  if owner == *sender {
+    send_notice(
+      room,
+      "I've sent you a private message with the webhook's new URL",
+    )
+    .await
+  } else {
+    send_notice(
+      room,
+      &format!("I've sent the webhook's new URL to {}", owner),
+    )
+    .await
+  }
+}
+
+// Labels are only unique within a room, so they only find hooks in the room the command is sent in
+async fn find_webhook(
+  store: &Store,
+  room: &Room,
+  id_or_label: &str,
+) -> anyhow::Result<Option<store::Webhook>> {
+  if let Some(hook) = store.get_webhook_by_id(id_or_label).await? {
+    return Ok(Some(hook));
+  }
+  Ok(
+    store
+      .get_webhooks_for_room(room.room_id().as_str())
+      .await?
+      .into_iter()
+      .find(|hook| hook.label.as_deref() == Some(id_or_label)),
+  )
+}
+
+/// The level needed to create hooks in the room and the user's own, if theirs is too low
+pub async fn missing_create_level(
+  config: &config::Config,
+  room: &Joined,
+  user_id: &UserId,
+) -> anyhow::Result<Option<(i64, i64)>> {
+  let power_levels = PowerLevels::of_room(room).await?;
+  let required = config
+    .webhook_bot
+    .create_power_level
+    .unwrap_or_else(|| power_levels.state_level());
+  let actual = power_levels.user_level(user_id.as_str());
+  Ok(if actual < required {
+    Some((required, actual))
+  } else {
+    None
+  })
+}
+
+fn create_level_message(required: i64, actual: i64) -> String {
+  format!(
+    "Sorry, creating webhooks in that room needs power level {}, and you have {}",
+    required, actual
+  )
+}
+
+// Moderators being those who can change the room's state, 50 unless the room says otherwise
+pub async fn is_room_moderator(room: &Joined, user_id: &UserId) -> anyhow::Result<bool> {
+  let power_levels = PowerLevels::of_room(room).await?;
+  Ok(power_levels.user_level(user_id.as_str()) >= power_levels.state_level())
+}
+
+async fn is_room_admin(client: &Client, room_id: &str, user_id: &UserId) -> anyhow::Result<bool> {
+  let room = match client.get_joined_room(&RoomId::try_from(room_id)?) {
+    Some(room) => room,
+    None => return Ok(false),
+  };
+  let power_levels = PowerLevels::of_room(&room).await?;
+  Ok(power_levels.user_level(user_id.as_str()) >= ROOM_ADMIN_LEVEL)
+}
+
+async fn list_outgoing_webhooks(
+  store: &Store,
+  client: &Client,
+  room: &Room,
+  sender: &UserId,
+) -> anyhow::Result<()> {
+  let hooks = store
+    .get_outgoing_webhooks_for_room(room.room_id().as_str())
+    .await?;
+  if hooks.is_empty() {
+    return send_notice(room, "This room has no outgoing webhooks").await;
+  }
+
+  let plain = hooks
+    .iter()
+    .map(|hook| {
+      format!(
+        "{}{}: {}{}",
+        hook.id,
+        describe_label(&hook.label),
+        hook.url,
+        describe_filters(hook)
+      )
+    })
+    .collect::<Vec<_>>()
+    .join("\n");
+  let html = hooks
+    .iter()
+    .map(|hook| {
+      format!(
+        "<li><code>{}</code>{}: {}{}</li>",
+        hook.id,
+        escape_html(&describe_label(&hook.label)),
+        escape_html(&hook.url),
+        escape_html(&describe_filters(hook))
+      )
+    })
+    .collect::<Vec<_>>()
+    .join("");
+  send_admin_notice(
+    client,
+    sender,
+    format!("Outgoing webhooks for {}:\n{}", room.room_id(), plain),
+    format!("Outgoing webhooks for {}:<ul>{}</ul>", room.room_id(), html),
+  )
+  .await
+}
+
+async fn remove_outgoing_webhook(
+  store: &Store,
+  client: &Client,
+  room: &Room,
+  sender: &UserId,
+  id: &str,
+) -> anyhow::Result<()> {
+  let removed = store
+    .delete_outgoing_webhook(id, room.room_id().as_str())
+    .await?;
+  if !removed {
+    return send_notice(room, "This room has no outgoing webhook with that id").await;
+  }
+
+  info!("Removed outgoing webhook {} from {}", id, room.room_id());
+  send_admin_notice(
+    client,
+    sender,
+    format!("Removed outgoing webhook {}", id),
+    format!("Removed outgoing webhook <code>{}</code>", escape_html(id)),
+  )
+  .await
+}
+
+const ROOM_ADMIN_LEVEL: i64 = 100;
+
+pub const INVALID_LABEL_MESSAGE: &str =
+  "Labels may only contain letters, numbers, '_', '.' and '-'";
+
+pub fn is_valid_label(label: &str) -> bool {
+  !label.is_empty()
+    && label
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-')
+}
+
+// Chosen names are appended to the bot's localpart with a single underscore, while the hashed
+// default localparts use two, so they can never collide
+fn is_valid_puppet_name(name: &str) -> bool {
+  !name.is_empty()
+    && !name.starts_with('_')
+    && name.chars().all(|c| {
+      c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '_' || c == '=' || c == '-'
+    })
+}
+
+fn describe_label(label: &Option<String>) -> String {
+  match label {
+    Some(label) => format!(" ({})", label),
+    None => String::new(),
+  }
+}
+
+fn describe_filters(hook: &OutgoingWebhook) -> String {
+  let filters: Vec<String> = [
+    ("types", &hook.event_types),
+    ("senders", &hook.senders),
+    ("keywords", &hook.keywords),
+  ]
+  .iter()
+  .filter_map(|(name, value)| value.as_ref().map(|value| format!("{}: {}", name, value)))
+  .collect();
+  if filters.is_empty() {
+    String::new()
+  } else {
+    format!(" ({})", filters.join("; "))
+  }
+}
+
+// Parses `--name value` pairs from the end of a command
+fn parse_flags<'a>(args: &[&'a str]) -> Option<HashMap<&'a str, &'a str>> {
+  let mut flags = HashMap::new();
+  for pair in args.chunks(2) {
+    match pair {
+      [name, value] => flags.insert(name.strip_prefix("--")?, *value),
+      _ => return None,
+    };
+  }
+  Some(flags)
+}
+
+pub fn escape_html(raw: &str) -> String {
+  raw
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+// Sends a new hook's details where the command asked for them. The notice tells the room they were
+// sent privately.
+async fn send_hook_details(
+  client: &Client,
+  room: &Room,
+  sender: &UserId,
+  reply: ReplyTo,
+  plain: String,
+  html: String,
+  notice: &str,
+) -> anyhow::Result<()> {
+  if reply.in_room && !room.is_public() {
+    return send_html_notice(room, plain, html).await;
+  }
+
+  send_admin_notice(client, sender, plain, html).await?;
+  if reply.notify {
+    send_notice(room, notice)
+      .await
+      .context("Failed to send private message notification")?;
+  }
+  Ok(())
+}
+
+// Sends a notice to the room the command came from, if we're in it
+async fn send_notice(room: &Room, message: &str) -> anyhow::Result<()> {
+  if let Room::Joined(room) = room {
+    room
+      .send(
+        AnyMessageEventContent::RoomMessage(MessageEventContent::notice_plain(message)),
+        None,
+      )
+      .await?;
+  }
+  Ok(())
+}
+
+async fn send_html_notice(room: &Room, plain: String, html: String) -> anyhow::Result<()> {
+  if let Room::Joined(room) = room {
+    room
+      .send(
+        AnyMessageEventContent::RoomMessage(MessageEventContent::notice_html(plain, html)),
+        None,
+      )
+      .await?;
+  }
+  Ok(())
+}
+
+/// Sends a state event the SDK has no type for
+pub async fn send_state_event_raw(
+  client: &Client,
+  room_id: &RoomId,
+  event_type: &str,
+  state_key: &str,
+  content: serde_json::Value,
+) -> anyhow::Result<()> {
+  let request = send_state_event::Request::new_raw(
+    room_id,
+    EventType::from(event_type),
+    state_key,
+    Raw::<AnyStateEventContent>::from_json(serde_json::value::to_raw_value(&content)?),
+  );
+  client.send(request, None).await?;
+  Ok(())
+}
+
+// Sends a notice to the private admin room with a user, creating it if needed
+pub async fn send_admin_notice(
+  client: &Client,
+  user: &UserId,
+  plain: String,
+  html: String,
+) -> anyhow::Result<()> {
+  let admin_room_id = get_or_create_admin_room(client, user)
+    .await
+    .context("Failed to get or create admin room")?;
+  let admin_room = match client.get_joined_room(&admin_room_id) {
+    Some(room) => room,
+    None => return Err(anyhow!("Failed to get the room that we should be inside")),
+  };
+
+  admin_room
+    .send(
+      AnyMessageEventContent::RoomMessage(MessageEventContent::notice_html(plain, html)),
+      None,
+    )
+    .await
+    .context("Failed to send admin room message")?;
+  Ok(())
+}
+
+async fn handle_reaction_inner(
+  config: Arc<config::Config>,
+  store: Arc<Store>,
+  appservice: AppService,
+  room: Room,
+  event: SyncMessageEvent<ReactionEventContent>,
+) -> anyhow::Result<()> {
+  let relation = &event.content.relates_to;
+  if !confirmation::CONFIRM_REACTIONS.contains(&relation.emoji.as_str()) {
+    return Ok(());
+  }
+  let action = match confirmation::take(
+    room.room_id().as_str(),
+    event.sender.as_str(),
+    Some(relation.event_id.as_str()),
+  ) {
+    Some(action) => action,
+    None => return Ok(()),
+  };
+
+  let client = appservice
+    .virtual_user_client(&config.webhook_bot.localpart)
+    .await?;
+  run_confirmed(&store, &client, &room, &event.sender, action).await
+}
+
+// Asks the sender to confirm the action, which runs once they do
+async fn ask_confirmation(
+  room: &Room,
+  sender: &UserId,
+  action: Action,
+  question: &str,
+) -> anyhow::Result<()> {
+  let joined = match room {
+    Room::Joined(joined) => joined,
+    _ => return Ok(()),
+  };
+  let message = format!(
+    "{} React with ✅ or reply \"yes\" within {} seconds to go ahead.",
+    question,
+    confirmation::TIMEOUT.as_secs()
+  );
+  let response = joined
+    .send(
+      AnyMessageEventContent::RoomMessage(MessageEventContent::notice_plain(message)),
+      None,
+    )
+    .await?;
+  confirmation::add(
+    room.room_id().as_str(),
+    sender.as_str(),
+    response.event_id.as_str(),
+    action,
+  );
+  Ok(())
+}
+
+async fn run_confirmed(
+  store: &Store,
+  client: &Client,
+  room: &Room,
+  sender: &UserId,
+  action: Action,
+) -> anyhow::Result<()> {
+  match action {
+    Action::Delete { hook_id } => delete_webhook(store, client, room, sender, &hook_id).await,
+    Action::Purge => purge_webhooks(store, client, room, sender).await,
+  }
+}
+
+// Whether the message just says yes, ignoring the quote of the question if it's a reply
+fn is_yes(body: &str) -> bool {
+  let answer: Vec<&str> = body.lines().filter(|line| !line.starts_with('>')).collect();
+  answer.join(" ").trim().eq_ignore_ascii_case("yes")
+}
+
+async fn handle_room_member_inner(
+  config: Arc<config::Config>,
+  store: Arc<Store>,
+  appservice: AppService,
+  room: Room,
+  event: SyncStateEvent<MemberEventContent>,
+) -> anyhow::Result<()> {
+  let target_user_id = match UserId::try_from(event.state_key.as_str()) {
+    Ok(id) => id,
+    Err(_) => return Ok(()),
+  };
+  match event.content.membership {
+    MembershipState::Invite => {
+      handle_invite(&config, &appservice, &room, &target_user_id, &event.sender).await
+    }
+    MembershipState::Leave | MembershipState::Ban if event.sender != target_user_id => {
+      handle_kick(
+        &config,
+        &store,
+        &appservice,
+        &room,
+        &target_user_id,
+        &event.sender,
+      )
+      .await
+    }
+    _ => Ok(()),
+  }
+}
+
+async fn handle_invite(
+  config: &config::Config,
+  appservice: &AppService,
+  room: &Room,
+  target_user_id: &UserId,
+  inviter: &UserId,
+) -> anyhow::Result<()> {
+  if *target_user_id != bot_user_id(config)? {
+    // Someone may have invited a webhook user the bot didn't have the power to invite itself
+    return webhook::post_pending_messages(appservice, room.room_id(), target_user_id).await;
+  }
+
+  let client = appservice
+    .virtual_user_client(&config.webhook_bot.localpart)
+    .await?;
+  if !config.webhook_bot.may_invite(inviter.as_str()) {
+    info!(
+      "Rejecting invite to room {} from {}",
+      room.room_id(),
+      inviter
+    );
+    client
+      .send(leave_room::Request::new(room.room_id()), None)
+      .await?;
+    if config.webhook_bot.report_rejected_invites {
+      let message = format!(
+        "I rejected an invite to {} from {}, who isn't in the invite allowlist",
+        room.room_id(),
+        inviter
+      );
+      for admin in &config.webhook_bot.admins {
+        let admin = UserId::try_from(admin.as_str())?;
+        send_admin_notice(&client, &admin, message.clone(), escape_html(&message)).await?;
+      }
+    }
+    return Ok(());
+  }
+
+  info!(
+    "Received invite to room {}. Joining",
+    room.room_id().to_string()
+  );
+  client.join_room_by_id(room.room_id()).await?;
+
+  Ok(())
+}
+
+// Pauses the hooks that can no longer post, until their owners bring them back with !webhook rejoin
+async fn handle_kick(
+  config: &config::Config,
+  store: &Store,
+  appservice: &AppService,
+  room: &Room,
+  target_user_id: &UserId,
+  kicker: &UserId,
+) -> anyhow::Result<()> {
+  if !webhook::is_bridge_user(config, target_user_id.as_str())
+    || webhook::is_bridge_user(config, kicker.as_str())
+  {
+    return Ok(());
+  }
+
+  let bot_user_id = bot_user_id(config)?;
+  let hooks = store.get_webhooks_for_room(room.room_id().as_str()).await?;
+  let hooks = if *target_user_id == bot_user_id {
+    hooks
+  } else {
+    // Only the hook that posts as this user
+    match store.get_puppet(target_user_id.localpart()).await? {
+      Some(puppet) => hooks
+        .into_iter()
+        .filter(|hook| hook.id == puppet.hook_id)
+        .collect(),
+      None => vec![],
+    }
+  };
+
+  let client = appservice
+    .virtual_user_client(&config.webhook_bot.localpart)
+    .await?;
+  for hook in hooks {
+    info!(
+      "{} was kicked from {}, pausing webhook {}",
+      target_user_id,
+      room.room_id(),
+      &hook.id
+    );
+    store
+      .set_setting(&webhook::kicked_setting(&hook.id), "true")
+      .await?;
+
+    let owner = UserId::try_from(hook.user_id.as_str())?;
+    if owner == bot_user_id {
+      continue;
+    }
+    send_admin_notice(
+      &client,
+      &owner,
+      format!(
+        "{} was removed from {} by {}, so your webhook {} is paused. Send !webhook rejoin {} here to bring it back.",
+        target_user_id,
+        room.room_id(),
+        kicker,
+        &hook.id,
+        &hook.id
+      ),
+      format!(
+        "{} was removed from {} by {}, so your webhook <code>{}</code> is paused. Send <code>!webhook rejoin {}</code> here to bring it back.",
+        target_user_id,
+        room.room_id(),
+        kicker,
+        &hook.id,
+        &hook.id
+      ),
+    )
+    .await?;
+  }
+
+  if *target_user_id == bot_user_id {
+    remove_webhook_users(config, appservice, room).await;
+  }
+
+  Ok(())
+}
+
+// Without the bot there's nobody to manage the room's webhook users, so they leave too. The
+// webhook handler invites them back once a hook is rejoined.
+async fn remove_webhook_users(config: &config::Config, appservice: &AppService, room: &Room) {
+  let members = match room.joined_members_no_sync().await {
+    Ok(members) => members,
+    Err(e) => {
+      warn!(
+        "Failed to get the members of {}: {}",
+        room.room_id(),
+        e.to_string()
+      );
+      return;
+    }
+  };
+  for member in members {
+    let user_id = member.user_id();
+    if !webhook::is_bridge_user(config, user_id.as_str())
+      || user_id.localpart() == config.webhook_bot.localpart
+    {
+      continue;
+    }
+    let result = match appservice.virtual_user_client(user_id.localpart()).await {
+      Ok(client) => client
+        .send(leave_room::Request::new(room.room_id()), None)
+        .await
+        .map(|_| ())
+        .map_err(anyhow::Error::from),
+      Err(e) => Err(e.into()),
+    };
+    match result {
+      Ok(()) => info!(
+        "{} left {} after the bot was removed",
+        user_id,
+        room.room_id()
+      ),
+      Err(e) => warn!(
+        "Failed to have {} leave {}: {}",
+        user_id,
+        room.room_id(),
+        e.to_string()
+      ),
+    }
+  }
+}
+
+async fn set_webhook_name(
+  store: &Store,
+  room: &Room,
+  sender: &UserId,
+  id_or_label: &str,
+  name: Option<String>,
+) -> anyhow::Result<()> {
+  let hook = match find_webhook(store, room, id_or_label).await? {
+    Some(hook) if hook.user_id == sender.as_str() => hook,
+    _ => return send_notice(room, "You don't have a webhook with that id or label").await,
+  };
+
+  store
+    .set_webhook_appearance(&hook.id, name.as_deref(), hook.avatar_url.as_deref())
+    .await?;
+  match name {
+    Some(name) => {
+      send_notice(
+        room,
+        &format!(
+          "Requests without a displayName will now show up as {}",
+          name
+        ),
+      )
+      .await
+    }
+    None => send_notice(room, "Cleared the webhook's default name").await,
+  }
+}
+
+async fn set_webhook_avatar(
+  store: &Store,
+  room: &Room,
+  sender: &UserId,
+  id_or_label: &str,
+  url: Option<&str>,
+) -> anyhow::Result<()> {
+  let hook = match find_webhook(store, room, id_or_label).await? {
+    Some(hook) if hook.user_id == sender.as_str() => hook,
+    _ => return send_notice(room, "You don't have a webhook with that id or label").await,
+  };
+  if let Some(url) = url {
+    if !is_http_url(url) {
+      return send_notice(room, "The avatar has to be an http or https URL").await;
+    }
+  }
+
+  store
+    .set_webhook_appearance(&hook.id, hook.display_name.as_deref(), url)
+    .await?;
+  match url {
+    Some(_) => {
+      send_notice(
+        room,
+        "Requests without an avatarUrl will now use that avatar",
+      )
+      .await
+    }
+    None => send_notice(room, "Cleared the webhook's default avatar").await,
+  }
+}
+
+// Lets a hook post to another room when its requests say so. Both the owner and the bot have to be
+// in that room.
+async fn add_webhook_room(
+  store: &Store,
+  client: &Client,
+  room: &Room,
+  sender: &UserId,
+  id_or_label: &str,
+  target: &str,
+) -> anyhow::Result<()> {
+  let hook = match find_webhook(store, room, id_or_label).await? {
+    Some(hook) if hook.user_id == sender.as_str() => hook,
+    _ => return send_notice(room, "You don't have a webhook with that id or label").await,
+  };
+  let target_room = match room_reference::resolve(client, target).await? {
+    Some(room_id) => client.get_joined_room(&room_id),
+    None => None,
+  };
+  let target_room = match target_room {
+    Some(target_room) => target_room,
+    None => return send_notice(room, "I'm not in that room. Invite me there first").await,
+  };
+  let members = target_room.joined_members_no_sync().await?;
+  if !members.iter().any(|member| member.user_id() == sender) {
+    return send_notice(room, "You need to be in that room yourself").await;
+  }
+
+  store
+    .add_webhook_room(&hook.id, target_room.room_id().as_str())
+    .await?;
+  info!(
+    "Webhook {} may now post to {}",
+    &hook.id,
+    target_room.room_id()
+  );
+  send_notice(
+    room,
+    &format!(
+      "Webhook {} can now post to {} by adding \"room\": \"{}\" to its requests",
+      &hook.id,
+      target_room.room_id(),
+      target_room.room_id()
+    ),
+  )
+  .await
+}
+
+async fn remove_webhook_room(
+  store: &Store,
+  client: &Client,
+  room: &Room,
+  sender: &UserId,
+  id_or_label: &str,
+  target: &str,
+) -> anyhow::Result<()> {
+  let hook = match find_webhook(store, room, id_or_label).await? {
+    Some(hook) if hook.user_id == sender.as_str() => hook,
+    _ => return send_notice(room, "You don't have a webhook with that id or label").await,
+  };
+  let removed = match room_reference::resolve(client, target).await? {
+    Some(room_id) => {
+      store
+        .remove_webhook_room(&hook.id, room_id.as_str())
+        .await?
+    }
+    None => false,
+  };
+
+  if removed {
+    send_notice(
+      room,
+      &format!("Webhook {} can no longer post to that room", &hook.id),
+    )
+    .await
+  } else {
+    send_notice(room, "That webhook wasn't allowed to post there").await
+  }
+}
+
+// The dispatcher has already checked that the sender may transfer the hook. Bridge admins can
+// hand over hooks whose creator is gone.
+async fn transfer_webhook(
+  store: &Store,
+  client: &Client,
+  room: &Room,
+  id_or_label: &str,
+  new_owner: &str,
+) -> anyhow::Result<()> {
+  let hook = match find_webhook(store, room, id_or_label).await? {
+    Some(hook) => hook,
+    None => return send_notice(room, "There's no webhook with that id or label here").await,
+  };
+  let new_owner = match UserId::try_from(new_owner) {
+    Ok(new_owner) => new_owner,
+    Err(_) => return send_notice(room, "That doesn't look like a user id").await,
+  };
+
+  offer_transfer(store, client, &hook, &new_owner).await?;
+  send_notice(
+    room,
+    &format!(
+      "I've asked {} to accept webhook {}. It keeps its current owner until they do",
+      new_owner, &hook.id
+    ),
+  )
+  .await
+}
+
+/// The setting holding who a hook is being handed over to, until they accept
+pub fn transfer_setting(hook_id: &str) -> String {
+  format!("transfer:{}", hook_id)
+}
+
+/// Asks the new owner to accept the hook. Replaces any earlier offer for it.
+pub async fn offer_transfer(
+  store: &Store,
+  client: &Client,
+  hook: &store::Webhook,
+  new_owner: &UserId,
+) -> anyhow::Result<()> {
+  store
+    .set_setting(&transfer_setting(&hook.id), new_owner.as_str())
+    .await?;
+  info!("Offered webhook {} to {}", &hook.id, new_owner);
+
+  send_admin_notice(
+    client,
+    new_owner,
+    format!(
+      "Webhook {} in {}, created by {}, is being handed over to you. Send !webhook accept {} here to take it.",
+      &hook.id, &hook.room_id, &hook.user_id, &hook.id
+    ),
+    format!(
+      "Webhook <code>{}</code> in {}, created by {}, is being handed over to you. Send <code>!webhook accept {}</code> here to take it.",
+      &hook.id, &hook.room_id, &hook.user_id, &hook.id
+    ),
+  )
+  .await
+}
+
+async fn accept_transfer(
+  config: &config::Config,
+  store: &Store,
+  client: &Client,
+  room: &Room,
+  sender: &UserId,
+  hook_id: &str,
+) -> anyhow::Result<()> {
+  let offered_to = store.get_setting(&transfer_setting(hook_id)).await?;
+  let hook = match store.get_webhook_by_id(hook_id).await? {
+    Some(hook) if offered_to.as_deref() == Some(sender.as_str()) => hook,
+    _ => return send_notice(room, "Nobody has offered you a webhook with that id").await,
+  };
+
+  store.set_webhook_owner(&hook.id, sender.as_str()).await?;
+  store.delete_setting(&transfer_setting(&hook.id)).await?;
+  info!(
+    "Webhook {} was transferred from {} to {}",
+    &hook.id, &hook.user_id, sender
+  );
+
+  // Hooks created for aliases belong to the bot, which doesn't need telling
+  let previous_owner = UserId::try_from(hook.user_id.as_str())?;
+  if previous_owner != bot_user_id(config)? {
+    let message = format!("{} accepted webhook {}", sender, &hook.id);
+    send_admin_notice(client, &previous_owner, message.clone(), message).await?;
+  }
+  send_notice(room, &format!("Webhook {} is yours now", &hook.id)).await
+}
+
+// Moves a hook from wherever it was to the room the command is sent in, for when a room is replaced
+// and whatever calls the hook can't easily be given a new URL. Only by id, since labels are per
+// room.
+async fn claim_webhook(
+  config: &config::Config,
+  store: &Store,
+  client: &Client,
+  room: &Room,
+  sender: &UserId,
+  hook_id: &str,
+) -> anyhow::Result<()> {
+  let hook = match store.get_webhook_by_id(hook_id).await? {
+    Some(hook)
+      if hook.user_id == sender.as_str() || config.webhook_bot.is_admin(sender.as_str()) =>
+    {
+      hook
+    }
+    _ => return send_notice(room, "You don't have a webhook with that id").await,
+  };
+  if hook.room_id == room.room_id().as_str() {
+    return send_notice(room, "That webhook already posts to this room").await;
+  }
+  if let Some(label) = &hook.label {
+    if store
+      .is_label_in_use(room.room_id().as_str(), label)
+      .await?
+    {
+      return send_notice(
+        room,
+        "This room already has a hook with that webhook's label",
+      )
+      .await;
+    }
+  }
+
+  if !store
+    .set_webhook_room(&hook.id, room.room_id().as_str())
+    .await?
+  {
+    return send_notice(room, "That webhook was deleted in the meantime").await;
+  }
+  // Being kicked from the old room doesn't keep it from posting here
+  store
+    .delete_setting(&webhook::kicked_setting(&hook.id))
+    .await?;
+  info!(
+    "Webhook {} was moved from {} to {} by {}",
+    &hook.id,
+    &hook.room_id,
+    room.room_id(),
+    sender
+  );
+
+  let owner = UserId::try_from(hook.user_id.as_str())?;
+  if owner != *sender && owner != bot_user_id(config)? {
+    let message = format!(
+      "{} moved webhook {} from {} to {}",
+      sender,
+      &hook.id,
+      &hook.room_id,
+      room.room_id()
+    );
+    send_admin_notice(client, &owner, message.clone(), escape_html(&message)).await?;
+  }
+  send_notice(
+    room,
+    "The webhook posts to this room now. Its URL hasn't changed",
+  )
+  .await
+}
+
+async fn rejoin_webhook(
+  store: &Store,
+  client: &Client,
+  room: &Room,
+  sender: &UserId,
+  id_or_label: &str,
+) -> anyhow::Result<()> {
+  let hook = match find_webhook(store, room, id_or_label).await? {
+    Some(hook) if hook.user_id == sender.as_str() => hook,
+    _ => return send_notice(room, "You don't have a webhook with that id or label").await,
+  };
+
+  let room_id = RoomId::try_from(hook.room_id.as_str())?;
+  if client.get_joined_room(&room_id).is_none() {
+    if let Err(e) = client.join_room_by_id(&room_id).await {
+      warn!("Failed to rejoin {}: {}", room_id, e.to_string());
+      return send_notice(
+        room,
+        &format!(
+          "I couldn't rejoin {}. Invite me back, then try again",
+          room_id
+        ),
+      )
+      .await;
+    }
+  }
+
+  store
+    .delete_setting(&webhook::kicked_setting(&hook.id))
+    .await?;
+  info!("Webhook {} was brought back by {}", &hook.id, sender);
+  send_notice(
+    room,
+    &format!(
+      "Webhook {} is back. Its user will be invited to {} again with the next message",
+      &hook.id, room_id
+    ),
+  )
+  .await
+}
+
+// Logging in with the as_token shows that the user exists, after which the as_token works for it
+// like for registered users. The login's own access token isn't needed, so it's logged out again
+// rather than leaving a device behind.
+async fn appservice_login(
+  config: &config::Config,
+  appservice: &AppService,
+  localpart: &str,
+) -> anyhow::Result<()> {
+  let response = login_with_appservice_token(config, appservice, localpart).await?;
+  if let Some(access_token) = response
+    .get("access_token")
+    .and_then(|token| token.as_str())
+  {
+    reqwest::Client::new()
+      .post(&format!("{}/logout", client_api_url(config)))
+      .bearer_auth(access_token)
+      .send()
+      .await?;
+  }
+
+  Ok(())
+}
+
+fn client_api_url(config: &config::Config) -> String {
+  format!(
+    "{}/_matrix/client/r0",
+    config.homeserver.url.trim_end_matches('/')
+  )
+}
+
+/// Logs in as one of the bridge's users, creating a new device. Returns the homeserver's response.
+pub async fn login_with_appservice_token(
+  config: &config::Config,
+  appservice: &AppService,
+  localpart: &str,
+) -> anyhow::Result<serde_json::Value> {
+  let body = serde_json::json!({
+    "type": "m.login.application_service",
+    "identifier": { "type": "m.id.user", "user": localpart },
+  });
+  let response = reqwest::Client::new()
+    .post(&format!("{}/login", client_api_url(config)))
+    .bearer_auth(&appservice.registration().as_token)
+    .header(reqwest::header::CONTENT_TYPE, "application/json")
+    .body(serde_json::to_vec(&body)?)
+    .send()
+    .await?;
+  let status = response.status();
+  let response: serde_json::Value = serde_json::from_slice(&response.bytes().await?)?;
+  if !status.is_success() {
+    return Err(anyhow!(
+      "Appservice login as {} failed: {} {}",
+      localpart,
+      status,
+      response
+    ));
+  }
+  info!("Logged in as {} with the appservice token", localpart);
+
+  Ok(response)
+}
+
+/// The image at the URL and its type, for avatars and images in other services' payloads
+pub async fn download_image(url: &str) -> anyhow::Result<(mime::Mime, Vec<u8>)> {
+  info!("Downloading image at {}", url);
+  let response = reqwest::get(url)
+    .await
+    .context("Failed to fetch image from provided url")?;
+
+  let response = response.error_for_status()?;
+  let mime_raw = match response.headers().get(reqwest::header::CONTENT_TYPE) {
+    Some(mime) => mime,
+    None => return Err(anyhow!("Server did not return a Content-Type header")),
+  };
+
+  let mime: mime::Mime = mime_raw
+    .to_str()
+    .context("Failed to convert Content-Type to a string")?
+    .parse()
+    .context("Could not parse Content-Type into a mime type")?;
+
+  let body = response.bytes().await?;
+  if body.is_empty() {
+    return Err(anyhow!("Image request returned empty"));
+  }
+
+  Ok((mime, body.to_vec()))
+}
+
+async fn get_or_create_admin_room(
+  client: &Client,
+  counterparty: &UserId,
+) -> anyhow::Result<RoomId> {
+  for room in client.joined_rooms() {
+    if room.is_public() {
+      trace!("Skipping {} because it is public", room.room_id());
+      continue;
+    }
+
+    let members = match room.joined_members_no_sync().await {
+      Ok(members) => members,
+      Err(_) => {
+        trace!(
+          "Skipping {} because I could not get the members",
+          room.room_id()
+        );
+        continue;
+      }
+    };
+
+    if members.len() > 2 {
+      trace!(
+        "Skipping {} because it has {} members",
         room.room_id(),
         members.len()
       );
@@ -338,5 +3320,161 @@ async fn get_or_create_admin_room(
   let mut request = CreateRoomRequest::new();
   request.invite = &invites;
   request.preset = Some(RoomPreset::PrivateChat);
-  Ok(client.create_room(request).await?.room_id)
+  let room_id = client.create_room(request).await?.room_id;
+  space::add_admin_room(client, &room_id, counterparty).await;
+  Ok(room_id)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_valid_label() {
+    assert!(is_valid_label("ci"));
+    assert!(is_valid_label("deploy-bot_2.0"));
+    assert!(!is_valid_label(""));
+    assert!(!is_valid_label("has space"));
+    assert!(!is_valid_label("evil:server"));
+  }
+
+  #[test]
+  fn test_valid_puppet_name() {
+    assert!(is_valid_puppet_name("ci-bot"));
+    assert!(is_valid_puppet_name("deploy.prod_2"));
+    assert!(!is_valid_puppet_name(""));
+    assert!(!is_valid_puppet_name("_sneaky"));
+    assert!(!is_valid_puppet_name("CI"));
+    assert!(!is_valid_puppet_name("ci:bot"));
+  }
+
+  #[test]
+  fn test_help_message() {
+    let (plain, html) = help_message(Some("Unknown command."));
+    assert!(plain.starts_with("Unknown command.\n"));
+    assert_eq!(COMMANDS.len(), html.matches("<li>").count());
+    assert!(html.contains("<code>!webhook out list</code>"));
+  }
+
+  #[test]
+  fn test_format_timestamp() {
+    assert_eq!("1970-01-01 00:00:00 UTC", format_timestamp(0));
+    assert_eq!("2000-02-29 12:34:56 UTC", format_timestamp(951827696));
+    assert_eq!("2021-12-31 23:59:59 UTC", format_timestamp(1640995199));
+  }
+
+  #[test]
+  fn test_take_reply_flag() {
+    let default = ReplyTo {
+      in_room: false,
+      notify: true,
+    };
+    assert_eq!(
+      Some((vec!["!webhook", "create", "ci"], default)),
+      take_reply_flag(vec!["!webhook", "create", "ci"], default)
+    );
+    assert_eq!(
+      Some((
+        vec!["!webhook", "create", "ci", "--as", "bot"],
+        ReplyTo {
+          in_room: true,
+          notify: true
+        }
+      )),
+      take_reply_flag(
+        vec!["!webhook", "create", "ci", "--reply", "room", "--as", "bot"],
+        default
+      )
+    );
+    assert_eq!(
+      Some((
+        vec!["!webhook", "create"],
+        ReplyTo {
+          in_room: false,
+          notify: false
+        }
+      )),
+      take_reply_flag(vec!["!webhook", "create", "--reply", "silent"], default)
+    );
+    assert_eq!(
+      None,
+      take_reply_flag(vec!["!webhook", "create", "--reply", "everywhere"], default)
+    );
+    assert_eq!(
+      None,
+      take_reply_flag(vec!["!webhook", "create", "--reply"], default)
+    );
+  }
+
+  #[test]
+  fn test_guarded_target() {
+    assert_eq!(
+      Some(GuardedTarget::Connection("ci")),
+      guarded_target(&["disconnect", "ci"])
+    );
+    assert_eq!(
+      Some(GuardedTarget::Connection("ci")),
+      guarded_target(&["connect", "ci", "https://example.com/"])
+    );
+    assert_eq!(
+      Some(GuardedTarget::Outgoing("abc")),
+      guarded_target(&["out", "remove", "abc"])
+    );
+    assert_eq!(None, guarded_target(&["out", "list"]));
+
+    // Someone else's disconnect is refused unless they're a moderator or admin
+    let owners = vec!["@owner:localhost".to_string()];
+    assert!(owns_all(
+      &owners,
+      &UserId::try_from("@owner:localhost").unwrap()
+    ));
+    assert!(!owns_all(
+      &owners,
+      &UserId::try_from("@other:localhost").unwrap()
+    ));
+    assert!(owns_all(
+      &[],
+      &UserId::try_from("@other:localhost").unwrap()
+    ));
+  }
+
+  #[test]
+  fn test_listed_id() {
+    let hook = store::Webhook {
+      id: "secret".into(),
+      room_id: "!room:localhost".into(),
+      user_id: "@owner:localhost".into(),
+      label: Some("ci".into()),
+      puppet_localpart: None,
+      display_name: None,
+      avatar_url: None,
+      enabled: true,
+    };
+    let owner = UserId::try_from("@owner:localhost").unwrap();
+    let other = UserId::try_from("@other:localhost").unwrap();
+    assert_eq!(Some("secret"), listed_id(&hook, &owner, false));
+    assert_eq!(None, listed_id(&hook, &other, false));
+    assert_eq!(Some("secret"), listed_id(&hook, &other, true));
+  }
+
+  #[test]
+  fn test_is_yes() {
+    assert!(is_yes("yes"));
+    assert!(is_yes(" Yes\n"));
+    assert!(is_yes(
+      "> <@_webhook:localhost> Delete that webhook?\n\nyes"
+    ));
+    assert!(!is_yes("yes please"));
+    assert!(!is_yes("> yes\n\nno"));
+  }
+
+  #[test]
+  fn test_parse_flags() {
+    let flags = parse_flags(&["--types", "m.room.message", "--keywords", "a,b"]).unwrap();
+    assert_eq!(flags.get("types"), Some(&"m.room.message"));
+    assert_eq!(flags.get("keywords"), Some(&"a,b"));
+    assert!(parse_flags(&[]).unwrap().is_empty());
+    assert_eq!(parse_flags(&["--types"]), None);
+    assert_eq!(parse_flags(&["types", "m.room.message"]), None);
+  }
 }
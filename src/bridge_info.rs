@@ -0,0 +1,100 @@
+// Bridge info state events (MSC2346), which let clients show that a room is bridged and to what.
+// Sent both as the unstable uk.half-shot.bridge and as m.bridge, since clients look for either.
+use std::convert::TryFrom;
+
+use log::*;
+use matrix_sdk::{ruma::RoomId, Client};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::{appservice_api::PROTOCOL, bot, config::Config, store::Webhook};
+
+const EVENT_TYPES: &[&str] = &["uk.half-shot.bridge", "m.bridge"];
+
+const BRIDGE_NAME: &str = "rust-matrix-appservice-webhooks";
+
+// Room state is visible to everyone in the room, and the hook id is what lets people post to it,
+// so channels are named after a hash of it unless the hook has a label
+fn channel_id(hook: &Webhook) -> String {
+  match &hook.label {
+    Some(label) => label.clone(),
+    None => {
+      let mut hasher = Sha256::new();
+      hasher.update(&hook.id);
+      hex::encode(&hasher.finalize()[0..8])
+    }
+  }
+}
+
+fn state_key(hook: &Webhook) -> String {
+  format!("{}/{}/{}", BRIDGE_NAME, PROTOCOL, channel_id(hook))
+}
+
+fn content(config: &Config, hook: &Webhook) -> serde_json::Value {
+  let bridgebot = format!(
+    "@{}:{}",
+    &config.webhook_bot.localpart, &config.homeserver.domain
+  );
+  let channel_id = channel_id(hook);
+  json!({
+    "bridgebot": bridgebot,
+    "creator": &hook.user_id,
+    "protocol": {
+      "id": PROTOCOL,
+      "displayname": "Webhooks",
+    },
+    "channel": {
+      "id": &channel_id,
+      "displayname": hook.label.as_deref().unwrap_or(&channel_id),
+    },
+  })
+}
+
+/// Describes the hook in its room. Not worth failing the hook's creation over.
+pub async fn publish(config: &Config, client: &Client, hook: &Webhook) {
+  let room_id = match RoomId::try_from(hook.room_id.as_str()) {
+    Ok(room_id) => room_id,
+    Err(_) => return,
+  };
+  let state_key = state_key(hook);
+  let content = content(config, hook);
+  for event_type in EVENT_TYPES {
+    if let Err(e) =
+      bot::send_state_event_raw(client, &room_id, event_type, &state_key, content.clone()).await
+    {
+      warn!(
+        "Failed to send {} for webhook in {}: {}",
+        event_type,
+        &hook.room_id,
+        e.to_string()
+      );
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_state_key() {
+    let mut hook = Webhook {
+      id: "secret".into(),
+      room_id: "!room:localhost".into(),
+      user_id: "@alice:localhost".into(),
+      label: None,
+      puppet_localpart: None,
+      display_name: None,
+      avatar_url: None,
+      enabled: true,
+    };
+    assert!(!state_key(&hook).contains("secret"));
+    assert_eq!(state_key(&hook), state_key(&hook));
+
+    hook.label = Some("ci".into());
+    assert_eq!(
+      "rust-matrix-appservice-webhooks/webhook/ci",
+      state_key(&hook)
+    );
+  }
+}
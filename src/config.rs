@@ -1,6 +1,6 @@
 use anyhow::{Context, Result};
 use serde::Deserialize;
-use std::fs::File;
+use std::{collections::HashMap, fs::File};
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -8,6 +8,24 @@ pub struct Config {
   pub homeserver: Homeserver,
   pub webhook_bot: Bot,
   pub web: Web,
+  #[serde(default)]
+  pub space: Option<Space>,
+  #[serde(default)]
+  pub encryption: Option<Encryption>,
+  #[serde(default)]
+  pub provisioning: Option<Provisioning>,
+  #[serde(default)]
+  pub limits: Limits,
+  #[serde(default)]
+  pub html: Html,
+  #[serde(default)]
+  pub emoji: Emoji,
+  /// Images for emoji names of the operator's own, like :deploy:, by name
+  #[serde(default)]
+  pub custom_emoji: HashMap<String, String>,
+  /// Tera templates for hooks' `/custom` requests, by hook id
+  #[serde(default)]
+  pub templates: HashMap<String, String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -15,6 +33,63 @@ pub struct Config {
 pub struct Homeserver {
   pub url: String,
   pub domain: String,
+  #[serde(default)]
+  pub software: Software,
+  #[serde(default)]
+  pub compat: Compat,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Software {
+  Synapse,
+  Dendrite,
+  Conduit,
+}
+
+impl Default for Software {
+  fn default() -> Self {
+    Self::Synapse
+  }
+}
+
+/// Overrides for what the registration file asks of the homeserver. Unset fields follow from the
+/// homeserver's software.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Compat {
+  pub exclusive_namespaces: Option<bool>,
+  pub push_ephemeral: Option<bool>,
+  pub protocols: Option<bool>,
+  pub register_users: Option<bool>,
+}
+
+impl Homeserver {
+  /// Whether only the bridge may register users and aliases in its namespaces
+  pub fn exclusive_namespaces(&self) -> bool {
+    self.compat.exclusive_namespaces.unwrap_or(true)
+  }
+
+  /// Whether to ask for read receipts and typing notifications (MSC2409)
+  pub fn push_ephemeral(&self) -> bool {
+    self
+      .compat
+      .push_ephemeral
+      .unwrap_or(self.software != Software::Conduit)
+  }
+
+  /// Whether the bridge registers its users, rather than logging in as users that already exist
+  pub fn register_users(&self) -> bool {
+    self.compat.register_users.unwrap_or(true)
+  }
+
+  /// Whether to declare the third-party protocol
+  pub fn protocols(&self) -> bool {
+    self
+      .compat
+      .protocols
+      .unwrap_or(self.software != Software::Conduit)
+  }
 }
 
 #[derive(Debug, Deserialize)]
@@ -22,6 +97,84 @@ pub struct Homeserver {
 pub struct Bot {
   pub localpart: String,
   pub appearance: Appearance,
+  #[serde(default)]
+  pub send_read_receipts: bool,
+  #[serde(default)]
+  pub puppet_mode: PuppetMode,
+  /// The power level needed to create webhooks in a room. Defaults to the room's level for
+  /// changing state.
+  #[serde(default)]
+  pub create_power_level: Option<i64>,
+  /// Users who may delete or regenerate anyone's webhooks
+  #[serde(default)]
+  pub admins: Vec<String>,
+  /// Whether new hooks' URLs are posted in the room they're made in, if it isn't public, instead
+  /// of in a private message
+  #[serde(default)]
+  pub reply_in_room: bool,
+  /// Whether to say in the room that a private message was sent
+  #[serde(default = "default_true")]
+  pub notify: bool,
+  /// Who may invite the bot to rooms, as user ids or the server names of everyone on them.
+  /// Anyone may if unset.
+  #[serde(default)]
+  pub invite_allowlist: Option<Vec<String>>,
+  /// Whether to tell the admins about invites that weren't allowed
+  #[serde(default)]
+  pub report_rejected_invites: bool,
+  /// How often to leave rooms nobody needs the bridge in anymore, or never if 0
+  #[serde(default = "default_janitor_interval")]
+  pub janitor_interval_minutes: u64,
+  /// Remind owners of hooks that haven't posted for this many days, or never if unset
+  #[serde(default)]
+  pub stale_hook_days: Option<u64>,
+}
+
+fn default_true() -> bool {
+  true
+}
+
+fn default_janitor_interval() -> u64 {
+  60
+}
+
+impl Bot {
+  pub fn is_admin(&self, user_id: &str) -> bool {
+    self.admins.iter().any(|admin| admin == user_id)
+  }
+
+  /// Admins may always invite the bot
+  pub fn may_invite(&self, user_id: &str) -> bool {
+    let allowlist = match &self.invite_allowlist {
+      Some(allowlist) => allowlist,
+      None => return true,
+    };
+    let server_name = user_id.splitn(2, ':').nth(1).unwrap_or_default();
+    self.is_admin(user_id)
+      || allowlist.iter().any(|allowed| {
+        if allowed.starts_with('@') {
+          allowed == user_id
+        } else {
+          allowed == server_name
+        }
+      })
+  }
+}
+
+/// Which virtual user posts a webhook message
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PuppetMode {
+  /// One user per hook, renamed to whatever display name the last message used
+  Hook,
+  /// One user per display name within each hook, like the nodejs version
+  DisplayName,
+}
+
+impl Default for PuppetMode {
+  fn default() -> Self {
+    Self::Hook
+  }
 }
 
 #[derive(Debug, Deserialize)]
@@ -35,9 +188,119 @@ pub struct Appearance {
 #[serde(rename_all = "camelCase")]
 pub struct Web {
   pub hook_url_base: String,
+  /// Enables the admin API for requests bearing this token
+  #[serde(default)]
+  pub admin_token: Option<String>,
+}
+
+/// A Space the bot gathers its rooms into
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Space {
+  pub name: String,
+  /// Besides the admin rooms, also add every room a hook is created in
+  #[serde(default)]
+  pub include_bridged_rooms: bool,
+}
+
+/// The nodejs bridge's provisioning API, for integration managers like Dimension
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Provisioning {
+  /// Requests give this as their `token`. Enables the API unless it's the sample config's CHANGE_ME.
+  pub secret: String,
+}
+
+/// Lets the bot and webhook users take part in encrypted rooms
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Encryption {
+  /// Where each user's crypto store is kept, in a directory named after its localpart
+  pub store_path: String,
+}
+
+/// Caps on how many hooks can be created, to keep the bridge from being abused. Unset means no cap.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Limits {
+  pub max_hooks_per_room: Option<u32>,
+  pub max_hooks_per_user: Option<u32>,
+  /// The most media a request may have the bridge download or upload, which defaults to 10MB
+  pub max_media_bytes: Option<u64>,
+}
+
+/// Where emoji names, like :heart:, come from, and whether they're replaced by default
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Emoji {
+  /// A JSON object of emoji by name, used instead of the built-in ones
+  #[serde(default)]
+  pub path: Option<String>,
+  /// Whether emoji names are replaced in requests that don't say with `emoji`
+  #[serde(default = "default_true")]
+  pub by_default: bool,
+}
+
+impl Default for Emoji {
+  fn default() -> Self {
+    Self {
+      path: None,
+      by_default: true,
+    }
+  }
+}
+
+/// Changes to the HTML tags hooks may post, which are otherwise the ones the Matrix spec recommends
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Html {
+  #[serde(default)]
+  pub extra_tags: Vec<String>,
+  #[serde(default)]
+  pub forbidden_tags: Vec<String>,
+}
+
+impl Limits {
+  pub fn media_bytes_limit(&self) -> u64 {
+    self.max_media_bytes.unwrap_or(10 * 1024 * 1024)
+  }
 }
 
 pub fn from_file(path: &str) -> Result<Config> {
   let file = File::open(path).with_context(|| format!("Failed to open config file at {}", path))?;
   serde_yaml::from_reader(file).context("Failed to parse config file")
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn bot(extra: &str) -> Bot {
+    serde_yaml::from_str(&format!(
+      r#"
+localpart: "_webhook"
+appearance:
+  displayName: "Webhook Bridge"
+  avatarUrl: "https://example.com/avatar.png"
+{}"#,
+      extra
+    ))
+    .unwrap()
+  }
+
+  #[test]
+  fn test_may_invite() {
+    assert!(bot("").may_invite("@anyone:example.com"));
+
+    let bot = bot(
+      r#"
+admins: ["@admin:elsewhere.org"]
+inviteAllowlist: ["@alice:example.com", "trusted.org"]"#,
+    );
+    assert!(bot.may_invite("@alice:example.com"));
+    assert!(bot.may_invite("@bob:trusted.org"));
+    assert!(bot.may_invite("@admin:elsewhere.org"));
+    assert!(!bot.may_invite("@bob:example.com"));
+    assert!(!bot.may_invite("@mallory:untrusted.org"));
+  }
+}
@@ -0,0 +1,95 @@
+// Destructive commands wait for whoever sent them to confirm, by reacting to the bot's question
+// or answering "yes". Pending actions are only kept in memory, since they expire quickly anyway.
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+
+pub const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Reactions that confirm an action. Clients send the check mark with or without the emoji
+/// variation selector.
+pub const CONFIRM_REACTIONS: &[&str] = &["✅", "✔️", "✔"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+  Delete { hook_id: String },
+  Purge,
+}
+
+#[derive(Debug)]
+struct Pending {
+  /// The bot's question, which the reaction has to be to
+  event_id: String,
+  action: Action,
+  expires_at: Instant,
+}
+
+lazy_static! {
+  // By room and sender, so a newer question replaces an older one
+  static ref PENDING: DashMap<(String, String), Pending> = DashMap::new();
+}
+
+pub fn add(room_id: &str, sender: &str, event_id: &str, action: Action) {
+  PENDING.retain(|_, pending| pending.expires_at > Instant::now());
+  PENDING.insert(
+    (room_id.to_string(), sender.to_string()),
+    Pending {
+      event_id: event_id.to_string(),
+      action,
+      expires_at: Instant::now() + TIMEOUT,
+    },
+  );
+}
+
+/// The action the sender was asked to confirm in the room, if it hasn't expired. If an event id
+/// is given, the question has to be that event.
+pub fn take(room_id: &str, sender: &str, event_id: Option<&str>) -> Option<Action> {
+  let key = (room_id.to_string(), sender.to_string());
+  let (_, pending) = PENDING.remove_if(&key, |_, pending| {
+    event_id.map_or(true, |event_id| pending.event_id == event_id)
+  })?;
+  if pending.expires_at > Instant::now() {
+    Some(pending.action)
+  } else {
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_take() {
+    let room_id = uuid::Uuid::new_v4().to_string();
+    let action = Action::Delete {
+      hook_id: "hook".into(),
+    };
+    add(&room_id, "@alice:localhost", "$question", action.clone());
+
+    assert_eq!(None, take(&room_id, "@bob:localhost", None));
+    assert_eq!(
+      None,
+      take(&room_id, "@alice:localhost", Some("$something_else"))
+    );
+    assert_eq!(
+      Some(action),
+      take(&room_id, "@alice:localhost", Some("$question"))
+    );
+    // Only once
+    assert_eq!(None, take(&room_id, "@alice:localhost", None));
+
+    add(&room_id, "@alice:localhost", "$first", Action::Purge);
+    add(
+      &room_id,
+      "@alice:localhost",
+      "$second",
+      Action::Delete {
+        hook_id: "hook".into(),
+      },
+    );
+    assert_eq!(None, take(&room_id, "@alice:localhost", Some("$first")));
+    assert!(take(&room_id, "@alice:localhost", None).is_some());
+  }
+}
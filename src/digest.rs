@@ -0,0 +1,220 @@
+// Hooks in digest mode, set with `!webhook digest`, hold their messages back and post them together
+// every so many seconds, or once enough have come in, so busy sources like audit logs don't flood
+// the room. Held messages are kept in the store, so none are lost over a restart.
+use std::{
+  sync::Arc,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use log::*;
+use matrix_sdk::ruma::events::room::message::{MessageEventContent, MessageType};
+use matrix_sdk_appservice::AppService;
+
+use crate::{
+  bot::escape_html,
+  config::Config,
+  store::{DigestItem, Store},
+  webhook::{self, HookError},
+  webhook_request::WebhookRequest,
+};
+
+const INTERVAL_SECONDS: u64 = 5;
+
+// Digests that fail to post are retried until they're this late, in case the homeserver was only
+// down
+const GIVE_UP_AFTER_SECONDS: i64 = 60 * 60;
+
+/// How long a hook holds messages for, and how many it holds at most
+#[derive(Debug, PartialEq)]
+pub struct Policy {
+  pub seconds: u64,
+  pub items: Option<u64>,
+}
+
+impl Policy {
+  /// The policy written as the seconds, and then the number of messages if there's a limit
+  pub fn parse(value: &str) -> Option<Self> {
+    let mut words = value.split_whitespace();
+    let seconds = words.next()?.parse().ok().filter(|seconds| *seconds > 0)?;
+    let items = match words.next() {
+      Some(items) => Some(items.parse().ok().filter(|items| *items > 1)?),
+      None => None,
+    };
+    if words.next().is_some() {
+      return None;
+    }
+    Some(Self { seconds, items })
+  }
+}
+
+/// The message's text and HTML, to hold until the digest is posted
+pub fn parts(message: &MessageEventContent) -> (String, Option<String>) {
+  match &message.msgtype {
+    MessageType::Text(content) => (
+      content.body.clone(),
+      content.formatted.as_ref().map(|html| html.body.clone()),
+    ),
+    MessageType::Notice(content) => (
+      content.body.clone(),
+      content.formatted.as_ref().map(|html| html.body.clone()),
+    ),
+    MessageType::Emote(content) => (
+      content.body.clone(),
+      content.formatted.as_ref().map(|html| html.body.clone()),
+    ),
+    _ => (String::new(), None),
+  }
+}
+
+pub fn spawn(config: Arc<Config>, store: Arc<Store>, appservice: AppService) {
+  tokio::task::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(INTERVAL_SECONDS));
+    loop {
+      interval.tick().await;
+      if let Err(e) = post_due(&config, &store, &appservice).await {
+        warn!("Failed to post digests: {}", e.to_string());
+      }
+    }
+  });
+}
+
+async fn post_due(config: &Arc<Config>, store: &Arc<Store>, appservice: &AppService) -> Result<()> {
+  let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+  for queue in store.digest_queues().await? {
+    let policy = store
+      .get_setting(&webhook::digest_setting(&queue.hook_id))
+      .await?
+      .and_then(|policy| Policy::parse(&policy));
+    // A hook taken out of digest mode posts what it was holding right away
+    let full = policy
+      .as_ref()
+      .and_then(|policy| policy.items)
+      .map_or(false, |items| queue.items as u64 >= items);
+    let late = match &policy {
+      Some(policy) if !full => now - queue.oldest - policy.seconds as i64,
+      _ => 0,
+    };
+    if late < 0 {
+      continue;
+    }
+
+    let items = store.get_digest_items(&queue.hook_id).await?;
+    let last_id = match items.last() {
+      Some(item) => item.id,
+      None => continue,
+    };
+    let result = webhook::handler_inner(
+      &queue.hook_id,
+      combine(&items).as_digest(),
+      config.clone(),
+      appservice.clone(),
+      store.clone(),
+    )
+    .await;
+    match result {
+      Ok(_) => {
+        debug!(
+          "Posted a digest of {} messages for webhook {}",
+          items.len(),
+          queue.hook_id
+        );
+      }
+      Err(e) if !e.is::<HookError>() && late < GIVE_UP_AFTER_SECONDS => {
+        warn!(
+          "Failed to post a digest for webhook {}, will retry: {}",
+          queue.hook_id,
+          e.to_string()
+        );
+        continue;
+      }
+      Err(e) => {
+        warn!(
+          "Giving up on a digest of {} messages for webhook {}: {}",
+          items.len(),
+          queue.hook_id,
+          e.to_string()
+        );
+      }
+    }
+    store.delete_digest_items(&queue.hook_id, last_id).await?;
+  }
+  Ok(())
+}
+
+// The held messages as one, listing them in the order they came in
+fn combine(items: &[DigestItem]) -> WebhookRequest {
+  let html = |item: &DigestItem| {
+    item
+      .formatted_body
+      .clone()
+      .unwrap_or_else(|| escape_html(&item.body).replace('\n', "<br>"))
+  };
+  if let [item] = items {
+    return WebhookRequest::translated(item.body.clone(), html(item));
+  }
+
+  let mut plain = format!("{} messages:", items.len());
+  let mut list = String::new();
+  for item in items {
+    plain.push_str(&format!("\n- {}", item.body.replace('\n', "\n  ")));
+    list.push_str(&format!("<li>{}</li>", html(item)));
+  }
+  WebhookRequest::translated(
+    plain,
+    format!("<p>{} messages:</p><ul>{}</ul>", items.len(), list),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse() {
+    assert_eq!(
+      Policy::parse("300"),
+      Some(Policy {
+        seconds: 300,
+        items: None
+      })
+    );
+    assert_eq!(
+      Policy::parse("60 20"),
+      Some(Policy {
+        seconds: 60,
+        items: Some(20)
+      })
+    );
+    assert_eq!(Policy::parse("0"), None);
+    assert_eq!(Policy::parse("60 1"), None);
+    assert_eq!(Policy::parse("soon"), None);
+    assert_eq!(Policy::parse("60 20 5"), None);
+  }
+
+  #[test]
+  fn test_combine() {
+    let item = |id, body: &str, formatted_body: Option<&str>| DigestItem {
+      id,
+      hook_id: "hook".to_string(),
+      body: body.to_string(),
+      formatted_body: formatted_body.map(str::to_string),
+      received_at: 0,
+    };
+    let message = combine(&[
+      item(1, "Login by alice\nfrom 10.0.0.1", None),
+      item(2, "Deleted *repo*", Some("Deleted <em>repo</em>")),
+    ])
+    .create_message();
+    let (plain, html) = parts(&message);
+    assert_eq!(
+      plain,
+      "2 messages:\n- Login by alice\n  from 10.0.0.1\n- Deleted *repo*"
+    );
+    assert_eq!(
+      html.unwrap(),
+      "<p>2 messages:</p><ul><li>Login by alice<br>from 10.0.0.1</li>\
+      <li>Deleted <em>repo</em></li></ul>"
+    );
+  }
+}
@@ -1,39 +1,183 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fs::File, sync::RwLock};
 
+use anyhow::{Context, Result};
 use lazy_static::lazy_static;
 
-// From https://raw.githubusercontent.com/omnidan/node-emoji/master/lib/emoji.json
+use crate::{bot, html_text};
+
+// From https://raw.githubusercontent.com/omnidan/node-emoji/master/lib/emoji.json, unless the config
+// has a dataset of its own
 lazy_static! {
-  static ref EMOJI: HashMap<String, String> =
-    serde_json::from_str(include_str!("emoji.json")).unwrap();
+  static ref EMOJI: RwLock<HashMap<String, String>> =
+    RwLock::new(serde_json::from_str(include_str!("emoji.json")).unwrap());
+}
+
+/// Uses the emoji in the JSON file, an object of emoji by name, instead of the built-in ones
+pub fn load(path: &str) -> Result<()> {
+  let file = File::open(path).with_context(|| format!("Failed to open emoji file at {}", path))?;
+  let emoji: HashMap<String, String> = serde_json::from_reader(file)
+    .with_context(|| format!("Failed to parse emoji file at {}", path))?;
+  *EMOJI.write().unwrap() = emoji;
+  Ok(())
 }
 
+// Slack and GitHub names that the dataset spells differently
+const ALIASES: &[(&str, &str)] = &[
+  ("thumbs_up", "+1"),
+  ("thumbs_down", "-1"),
+  ("thinking", "thinking_face"),
+  ("simple_smile", "slightly_smiling_face"),
+  ("facepalm", "face_palm"),
+  ("frowning_face", "white_frowning_face"),
+  ("white_smiling_face", "relaxed"),
+  ("party_popper", "tada"),
+  ("cross_mark", "x"),
+  ("heavy_x", "heavy_multiplication_x"),
+  ("green_circle", "large_green_circle"),
+  ("yellow_circle", "large_yellow_circle"),
+  ("rofl", "rolling_on_the_floor_laughing"),
+  ("robot", "robot_face"),
+  ("hugs", "hugging_face"),
+  ("nerd", "nerd_face"),
+  ("stop_sign", "octagonal_sign"),
+  ("spiral_calendar", "spiral_calendar_pad"),
+  ("high_voltage", "zap"),
+];
+
+// From lightest to darkest, which Slack calls skin-tone-2 to skin-tone-6 and others tone1 to tone5
+const SKIN_TONES: &[&str] = &["🏻", "🏼", "🏽", "🏾", "🏿"];
+
 pub fn replace_emoji(s: &str) -> String {
-  let mut parts: Vec<String> = s.split(':').map(|s| s.to_owned()).collect();
-  let mut out = vec![];
-
-  let num_parts = parts.len();
-  let mut skip = false;
-  for (i, part) in parts.iter_mut().enumerate() {
-    if i == 0 || i == num_parts || skip {
-      if i != 0 && !skip {
-        out.push(":");
+  let dataset = EMOJI.read().unwrap();
+  let mut out = String::new();
+  let mut rest = s;
+  while let Some(start) = rest.find(':') {
+    out.push_str(&rest[..start]);
+    let after = &rest[start + 1..];
+    let found = after
+      .find(':')
+      .and_then(|end| find_emoji(&dataset, &after[..end]).map(|emoji| (end, emoji)));
+    match found {
+      Some((end, emoji)) => {
+        rest = &after[end + 1..];
+        // Slack follows the emoji with its skin tone, like :thumbsup::skin-tone-3:
+        match slack_skin_tone(rest) {
+          Some((tone, len)) => {
+            out.push_str(&with_skin_tone(&emoji, tone));
+            rest = &rest[len..];
+          }
+          None => out.push_str(&emoji),
+        }
+      }
+      None => {
+        out.push(':');
+        rest = after;
       }
-      out.push(part);
-      skip = false;
-      continue;
     }
+  }
+  out.push_str(rest);
+  out
+}
 
-    if let Some(replace) = EMOJI.get(part) {
-      out.push(replace);
-      skip = true;
-    } else {
-      out.push(":");
-      out.push(part);
-    }
+// The emoji by its name or an alias, which can also have a skin tone like :thumbsup_tone3:
+fn find_emoji(dataset: &HashMap<String, String>, name: &str) -> Option<String> {
+  if name.is_empty() || name.contains(char::is_whitespace) {
+    return None;
+  }
+  if let Some(emoji) = lookup(dataset, name) {
+    return Some(emoji.to_string());
   }
+  let (base, tone) = name.rsplit_once("_tone")?;
+  let tone = SKIN_TONES.get(tone.parse::<usize>().ok()?.checked_sub(1)?)?;
+  Some(with_skin_tone(lookup(dataset, base)?, tone))
+}
+
+// By name or alias. Slack writes some names with hyphens where others use underscores, and the
+// other way around, so both are tried.
+fn lookup<'a>(dataset: &'a HashMap<String, String>, name: &str) -> Option<&'a str> {
+  let alias = ALIASES
+    .iter()
+    .find(|(alias, _)| *alias == name)
+    .map(|(_, name)| *name);
+  dataset
+    .get(name)
+    .or_else(|| alias.and_then(|name| dataset.get(name)))
+    .or_else(|| dataset.get(&name.replace('_', "-")))
+    .or_else(|| dataset.get(&name.replace('-', "_")))
+    .map(String::as_str)
+}
+
+// The tone and how long its name is, if the text starts with one like :skin-tone-3:
+fn slack_skin_tone(text: &str) -> Option<(&'static str, usize)> {
+  let name = text.strip_prefix(":skin-tone-")?;
+  let end = name.find(':')?;
+  let tone = SKIN_TONES.get(name[..end].parse::<usize>().ok()?.checked_sub(2)?)?;
+  Some((tone, ":skin-tone-".len() + end + 1))
+}
 
-  out.join("")
+// The modifier goes right after the first character, in place of any variation selector, so it
+// also colors sequences like man-facepalming
+fn with_skin_tone(emoji: &str, tone: &str) -> String {
+  let mut chars = emoji.chars();
+  let first = match chars.next() {
+    Some(first) => first,
+    None => return emoji.to_string(),
+  };
+  let rest = chars.as_str();
+  format!(
+    "{}{}{}",
+    first,
+    tone,
+    rest.strip_prefix('\u{fe0f}').unwrap_or(rest)
+  )
+}
+
+/// The operator's custom emoji in the text, out of those configured
+pub fn custom_emoji_names(text: &str, configured: &HashMap<String, String>) -> Vec<String> {
+  configured
+    .keys()
+    .filter(|name| text.contains(&format!(":{}:", name)))
+    .cloned()
+    .collect()
+}
+
+/// The html with the custom emoji names outside of links and code replaced by their images, whose
+/// mxc URIs are given by name
+pub fn custom_emoji_html(html: &str, emoticons: &HashMap<String, String>) -> String {
+  if emoticons.is_empty() {
+    return html.to_string();
+  }
+  html_text::map_text(html, |text| {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(':') {
+      out.push_str(&rest[..start]);
+      let after = &rest[start + 1..];
+      let found = after.find(':').and_then(|end| {
+        emoticons
+          .get(&after[..end])
+          .map(|uri| (end, &after[..end], uri))
+      });
+      match found {
+        Some((end, name, uri)) => {
+          let name = bot::escape_html(&format!(":{}:", name));
+          out.push_str(&format!(
+            "<img data-mx-emoticon src=\"{}\" alt=\"{}\" title=\"{}\" height=\"32\">",
+            bot::escape_html(uri),
+            name,
+            name
+          ));
+          rest = &after[end + 1..];
+        }
+        None => {
+          out.push(':');
+          rest = after;
+        }
+      }
+    }
+    out.push_str(rest);
+    out
+  })
 }
 
 #[cfg(test)]
@@ -49,4 +193,46 @@ mod tests {
     assert_eq!(replace_emoji(":heart:::::heart:"), "❤️:::❤️");
     assert_eq!(replace_emoji(":sdfsdfsdfs::heart:"), ":sdfsdfsdfs:❤️");
   }
+
+  #[test]
+  fn test_skin_tones_and_aliases() {
+    assert_eq!(replace_emoji(":thumbsup::skin-tone-3:"), "👍🏼");
+    assert_eq!(replace_emoji(":+1::skin-tone-6: :thumbsup_tone1:"), "👍🏿 👍🏻");
+    assert_eq!(replace_emoji(":point_up::skin-tone-2:"), "☝🏻");
+    assert_eq!(
+      replace_emoji(":man-facepalming::skin-tone-4:"),
+      "🤦🏽\u{200d}♂️"
+    );
+    assert_eq!(replace_emoji(":thumbsup::skin-tone-9:"), "👍:skin-tone-9:");
+    assert_eq!(
+      replace_emoji(":thinking: :man_shrugging: :party_popper:"),
+      "🤔 🤷\u{200d}♂️ 🎉"
+    );
+    assert_eq!(replace_emoji("at 12:30:00"), "at 12:30:00");
+  }
+
+  #[test]
+  fn test_custom_emoji() {
+    let mut configured = HashMap::new();
+    configured.insert(
+      "deploy".to_string(),
+      "https://example.com/deploy.png".to_string(),
+    );
+    configured.insert(
+      "oops".to_string(),
+      "https://example.com/oops.png".to_string(),
+    );
+    assert_eq!(
+      custom_emoji_names("Shipped :deploy: at 12:30", &configured),
+      vec!["deploy"]
+    );
+
+    let mut emoticons = HashMap::new();
+    emoticons.insert("deploy".to_string(), "mxc://example.com/abc".to_string());
+    assert_eq!(
+      custom_emoji_html("<b>:deploy:</b> at 12:30 <code>:deploy:</code>", &emoticons),
+      "<b><img data-mx-emoticon src=\"mxc://example.com/abc\" alt=\":deploy:\" title=\":deploy:\" \
+      height=\"32\"></b> at 12:30 <code>:deploy:</code>"
+    );
+  }
 }
@@ -0,0 +1,124 @@
+// End-to-end encryption, for rooms that have it enabled. Appservice users have no device and
+// never see to-device messages, so each user that needs to encrypt or decrypt logs in for real,
+// keeps its crypto state under `encryption.storePath`, and syncs like any other client.
+use std::{convert::TryFrom, path::Path};
+
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use log::*;
+use matrix_sdk::{
+  ruma::{ServerName, UserId},
+  Client, ClientConfig, Session, SyncSettings,
+};
+use matrix_sdk_appservice::AppService;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{bot, config::Config, store::Store};
+
+lazy_static! {
+  // Logged in clients by localpart, so each user only has the one device
+  static ref CLIENTS: DashMap<String, Client> = DashMap::new();
+  // Keeps concurrent requests for a new user from logging in twice
+  static ref LOGIN_LOCK: Mutex<()> = Mutex::new(());
+}
+
+// The device a user logged in with, so it's reused after a restart along with its crypto store.
+// Named like in the login response.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct StoredSession {
+  access_token: String,
+  device_id: String,
+}
+
+fn session_setting(localpart: &str) -> String {
+  format!("encryptionSession:{}", localpart)
+}
+
+/// The user's encryption-capable client, logging in the first time. Its keys are uploaded and
+/// its rooms known by the time this returns.
+pub async fn client(
+  config: &Config,
+  store: &Store,
+  appservice: &AppService,
+  localpart: &str,
+) -> Result<Client> {
+  if let Some(client) = CLIENTS.get(localpart) {
+    return Ok(client.clone());
+  }
+  let _guard = LOGIN_LOCK.lock().await;
+  if let Some(client) = CLIENTS.get(localpart) {
+    return Ok(client.clone());
+  }
+  let encryption = config
+    .encryption
+    .as_ref()
+    .ok_or_else(|| anyhow!("Encryption isn't enabled"))?;
+
+  let session = match store.get_setting(&session_setting(localpart)).await? {
+    Some(raw) => serde_json::from_str::<StoredSession>(&raw)?,
+    None => {
+      let response = bot::login_with_appservice_token(config, appservice, localpart).await?;
+      let session = serde_json::from_value::<StoredSession>(response)?;
+      store
+        .set_setting(
+          &session_setting(localpart),
+          &serde_json::to_string(&session)?,
+        )
+        .await?;
+      info!(
+        "Logged in as {} with device {} for encryption",
+        localpart, &session.device_id
+      );
+      session
+    }
+  };
+
+  let user_id = UserId::parse_with_server_name(
+    localpart,
+    <&ServerName>::try_from(config.homeserver.domain.as_str())?,
+  )?;
+  let client_config =
+    ClientConfig::new().store_path(Path::new(&encryption.store_path).join(localpart));
+  let client =
+    Client::new_with_config(reqwest::Url::parse(&config.homeserver.url)?, client_config)?;
+  client
+    .restore_login(Session {
+      access_token: session.access_token,
+      user_id,
+      device_id: session.device_id.into(),
+    })
+    .await?;
+  client.sync_once(SyncSettings::default()).await?;
+
+  CLIENTS.insert(localpart.to_string(), client.clone());
+  Ok(client)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_stored_session() -> Result<()> {
+    // As the homeserver's login response has it, besides fields that aren't needed
+    let session: StoredSession = serde_json::from_str(
+      r#"
+    {
+      "user_id": "@_webhook:localhost",
+      "access_token": "token",
+      "device_id": "DEVICE"
+    }"#,
+    )?;
+    assert_eq!(
+      StoredSession {
+        access_token: "token".into(),
+        device_id: "DEVICE".into(),
+      },
+      session
+    );
+
+    Ok(())
+  }
+}
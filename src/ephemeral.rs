@@ -0,0 +1,142 @@
+// Ephemeral events (read receipts and typing notifications) pushed by the homeserver as per
+// MSC2409. The SDK doesn't know about these, so they are picked out of each transaction before
+// the rest of it is handed over.
+use std::collections::HashMap;
+
+use log::*;
+use serde::Deserialize;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Transaction {
+  #[serde(default)]
+  pub events: Vec<serde_json::Value>,
+  #[serde(default)]
+  ephemeral: Vec<serde_json::Value>,
+  #[serde(default, rename = "de.sorunome.msc2409.ephemeral")]
+  unstable_ephemeral: Vec<serde_json::Value>,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "type")]
+pub enum EphemeralEvent {
+  #[serde(rename = "m.receipt")]
+  Receipt {
+    room_id: String,
+    // event id -> receipt type -> user id -> receipt data
+    content: HashMap<String, HashMap<String, HashMap<String, serde_json::Value>>>,
+  },
+  #[serde(rename = "m.typing")]
+  Typing {
+    room_id: String,
+    content: TypingContent,
+  },
+  #[serde(other)]
+  Other,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct TypingContent {
+  pub user_ids: Vec<String>,
+}
+
+impl Transaction {
+  /// The ephemeral events in the transaction that we understand. Others are skipped.
+  pub fn ephemeral_events(&self) -> Vec<EphemeralEvent> {
+    self
+      .ephemeral
+      .iter()
+      .chain(self.unstable_ephemeral.iter())
+      .filter_map(|raw| match serde_json::from_value(raw.clone()) {
+        Ok(EphemeralEvent::Other) => None,
+        Ok(event) => Some(event),
+        Err(e) => {
+          debug!("Skipping malformed ephemeral event: {}", e.to_string());
+          None
+        }
+      })
+      .collect()
+  }
+}
+
+impl EphemeralEvent {
+  /// (event id, user id) pairs for every read receipt in the event
+  pub fn read_receipts(&self) -> Vec<(&str, &str)> {
+    match self {
+      Self::Receipt { content, .. } => content
+        .iter()
+        .filter_map(|(event_id, receipts)| {
+          receipts.get("m.read").map(|users| {
+            users
+              .keys()
+              .map(move |user| (event_id.as_str(), user.as_str()))
+          })
+        })
+        .flatten()
+        .collect(),
+      _ => vec![],
+    }
+  }
+}
+
+pub fn handle(event: &EphemeralEvent) {
+  match event {
+    EphemeralEvent::Receipt { room_id, .. } => {
+      for (event_id, user_id) in event.read_receipts() {
+        debug!("{} read up to {} in {}", user_id, event_id, room_id);
+      }
+    }
+    EphemeralEvent::Typing { room_id, content } => {
+      debug!("Users typing in {}: {:?}", room_id, content.user_ids);
+    }
+    EphemeralEvent::Other => {}
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use anyhow::Result;
+
+  #[test]
+  fn test_parse_transaction() -> Result<()> {
+    let raw_json = r#"
+    {
+      "events": [],
+      "de.sorunome.msc2409.ephemeral": [
+        {
+          "type": "m.receipt",
+          "room_id": "!room:localhost",
+          "content": {
+            "$event": { "m.read": { "@alice:localhost": { "ts": 1 } } }
+          }
+        },
+        {
+          "type": "m.typing",
+          "room_id": "!room:localhost",
+          "content": { "user_ids": ["@bob:localhost"] }
+        },
+        { "type": "m.presence", "content": {} },
+        { "type": "m.typing" }
+      ]
+    }"#;
+
+    let transaction: Transaction = serde_json::from_str(raw_json)?;
+    let events = transaction.ephemeral_events();
+    assert_eq!(events.len(), 2);
+    assert_eq!(
+      events[0].read_receipts(),
+      vec![("$event", "@alice:localhost")]
+    );
+    assert_eq!(
+      events[1],
+      EphemeralEvent::Typing {
+        room_id: "!room:localhost".into(),
+        content: TypingContent {
+          user_ids: vec!["@bob:localhost".into()]
+        }
+      }
+    );
+
+    Ok(())
+  }
+}
@@ -0,0 +1,71 @@
+// Redacts the messages that were posted with `ttlSeconds` once their time is up. They're kept in
+// the store, so none are forgotten over a restart.
+use std::{
+  convert::TryFrom,
+  sync::Arc,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use log::*;
+use matrix_sdk::ruma::{api::client::r0::redact::redact_event, EventId, RoomId};
+use matrix_sdk_appservice::AppService;
+use uuid::Uuid;
+
+use crate::store::{ScheduledRedaction, Store};
+
+const INTERVAL_SECONDS: u64 = 5;
+
+// Failed redactions are retried until they're this late, in case the homeserver was only down
+const GIVE_UP_AFTER_SECONDS: i64 = 60 * 60;
+
+pub fn spawn(store: Arc<Store>, appservice: AppService) {
+  tokio::task::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(INTERVAL_SECONDS));
+    loop {
+      interval.tick().await;
+      if let Err(e) = redact_due(&store, &appservice).await {
+        warn!("Failed to redact expired messages: {}", e.to_string());
+      }
+    }
+  });
+}
+
+async fn redact_due(store: &Store, appservice: &AppService) -> Result<()> {
+  let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+  for redaction in store.due_redactions().await? {
+    match redact(appservice, &redaction).await {
+      Ok(()) => {
+        debug!("Redacted expired message {}", redaction.event_id);
+      }
+      Err(e) if now - redaction.redact_at < GIVE_UP_AFTER_SECONDS => {
+        warn!(
+          "Failed to redact expired message {}, will retry: {}",
+          redaction.event_id,
+          e.to_string()
+        );
+        continue;
+      }
+      Err(e) => {
+        warn!(
+          "Giving up on redacting expired message {}: {}",
+          redaction.event_id,
+          e.to_string()
+        );
+      }
+    }
+    store.delete_scheduled_redaction(redaction.id).await?;
+  }
+  Ok(())
+}
+
+async fn redact(appservice: &AppService, redaction: &ScheduledRedaction) -> Result<()> {
+  let client = appservice.virtual_user_client(&redaction.localpart).await?;
+  let room_id = RoomId::try_from(redaction.room_id.as_str())?;
+  let event_id = EventId::try_from(redaction.event_id.as_str())?;
+  let txn_id = Uuid::new_v4().to_string();
+  let mut request = redact_event::Request::new(&room_id, &event_id, &txn_id);
+  request.reason = Some("Expired");
+  client.send(request, None).await?;
+  Ok(())
+}
@@ -0,0 +1,39 @@
+// Turns parts of an HTML message's text into markup, like pills and custom emoji, leaving links
+// and code as they are
+/// The html with each run of text outside of links and code replaced
+pub fn map_text(html: &str, mut replace: impl FnMut(&str) -> String) -> String {
+  let mut out = String::new();
+  // Links and code are left alone, so they're skipped until they're closed
+  let mut skipping = 0_usize;
+  let mut rest = html;
+  while !rest.is_empty() {
+    if rest.starts_with('<') {
+      let end = rest.find('>').map_or(rest.len(), |end| end + 1);
+      let tag = rest[1..end].trim_start_matches('/').to_ascii_lowercase();
+      let name = tag
+        .split(|c: char| c.is_whitespace() || c == '>' || c == '/')
+        .next()
+        .unwrap_or_default();
+      if ["a", "code", "pre"].contains(&name) {
+        if rest[1..].starts_with('/') {
+          skipping = skipping.saturating_sub(1);
+        } else {
+          skipping += 1;
+        }
+      }
+      out.push_str(&rest[..end]);
+      rest = &rest[end..];
+      continue;
+    }
+
+    let end = rest.find('<').unwrap_or_else(|| rest.len());
+    let text = &rest[..end];
+    if skipping > 0 {
+      out.push_str(text);
+    } else {
+      out.push_str(&replace(text));
+    }
+    rest = &rest[end..];
+  }
+  out
+}
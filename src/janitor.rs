@@ -0,0 +1,134 @@
+// Periodically leaves the rooms the bridge's users have no more business in, so the homeserver
+// stops sending us their events. That's rooms everyone else has left, and rooms a webhook user's
+// hook can no longer post to.
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use log::*;
+use matrix_sdk::ruma::{
+  api::client::r0::membership::{forget_room, leave_room},
+  RoomId, UserId,
+};
+use matrix_sdk_appservice::AppService;
+
+use crate::{
+  config::Config,
+  store::{Store, Webhook},
+  webhook,
+};
+
+pub fn spawn(config: Arc<Config>, store: Arc<Store>, appservice: AppService) {
+  let minutes = config.webhook_bot.janitor_interval_minutes;
+  if minutes == 0 {
+    return;
+  }
+  tokio::task::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(minutes * 60));
+    loop {
+      interval.tick().await;
+      if let Err(e) = sweep(&config, &store, &appservice).await {
+        warn!("Failed to clean up rooms: {}", e.to_string());
+      }
+    }
+  });
+}
+
+async fn sweep(config: &Config, store: &Store, appservice: &AppService) -> Result<()> {
+  let client = appservice
+    .virtual_user_client(&config.webhook_bot.localpart)
+    .await?;
+  for room in client.joined_rooms() {
+    let members = room.joined_members_no_sync().await?;
+    let (mut bridge_users, others): (Vec<UserId>, Vec<UserId>) = members
+      .iter()
+      .map(|member| member.user_id().clone())
+      .partition(|user_id| webhook::is_bridge_user(config, user_id.as_str()));
+
+    if others.is_empty() {
+      info!("Everyone else has left {}, leaving it", room.room_id());
+      // The bot goes last, since the others are found through it
+      bridge_users.sort_by_key(|user_id| user_id.localpart() == config.webhook_bot.localpart);
+      for user_id in &bridge_users {
+        leave_and_forget(appservice, user_id, room.room_id()).await;
+      }
+      continue;
+    }
+
+    for user_id in &bridge_users {
+      if user_id.localpart() == config.webhook_bot.localpart {
+        continue;
+      }
+      // Users without a puppet record predate it, so there's no telling which hook they belong to
+      let puppet = match store.get_puppet(user_id.localpart()).await? {
+        Some(puppet) => puppet,
+        None => continue,
+      };
+      let hook = store.get_webhook_by_id(&puppet.hook_id).await?;
+      let extra_rooms = store.get_webhook_rooms(&puppet.hook_id).await?;
+      if !can_post_to(hook.as_ref(), &extra_rooms, room.room_id().as_str()) {
+        info!(
+          "{} no longer has a hook posting to {}, leaving it",
+          user_id,
+          room.room_id()
+        );
+        leave_and_forget(appservice, user_id, room.room_id()).await;
+      }
+    }
+  }
+
+  Ok(())
+}
+
+// Whether the hook, if it still exists, may post to the room
+fn can_post_to(hook: Option<&Webhook>, extra_rooms: &[String], room_id: &str) -> bool {
+  match hook {
+    Some(hook) => hook.room_id == room_id || extra_rooms.iter().any(|room| room == room_id),
+    None => false,
+  }
+}
+
+// Failures only mean another try next time
+async fn leave_and_forget(appservice: &AppService, user_id: &UserId, room_id: &RoomId) {
+  let result = async {
+    let client = appservice.virtual_user_client(user_id.localpart()).await?;
+    client.send(leave_room::Request::new(room_id), None).await?;
+    client
+      .send(forget_room::Request::new(room_id), None)
+      .await?;
+    Ok::<_, anyhow::Error>(())
+  }
+  .await;
+  if let Err(e) = result {
+    warn!(
+      "Failed to have {} leave {}: {}",
+      user_id,
+      room_id,
+      e.to_string()
+    );
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_can_post_to() {
+    let hook = Webhook {
+      id: "hook".into(),
+      room_id: "!room:localhost".into(),
+      user_id: "@alice:localhost".into(),
+      label: None,
+      puppet_localpart: None,
+      display_name: None,
+      avatar_url: None,
+      enabled: true,
+    };
+    let extra_rooms = vec!["!other:localhost".to_string()];
+
+    assert!(can_post_to(Some(&hook), &[], "!room:localhost"));
+    assert!(can_post_to(Some(&hook), &extra_rooms, "!other:localhost"));
+    assert!(!can_post_to(Some(&hook), &[], "!other:localhost"));
+    assert!(!can_post_to(None, &extra_rooms, "!room:localhost"));
+  }
+}
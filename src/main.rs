@@ -1,4 +1,4 @@
-use std::{fs::File, net::IpAddr, str::FromStr, sync::Arc};
+use std::{collections::HashMap, fs::File, net::IpAddr, str::FromStr, sync::Arc};
 
 use anyhow::{anyhow, Context, Result};
 use clap::Parser;
@@ -7,6 +7,7 @@ use matrix_sdk::{
   room::Room,
   ruma::api::appservice::{Namespace, Namespaces, Registration, RegistrationInit},
   ruma::events::{
+    reaction::ReactionEventContent,
     room::{member::MemberEventContent, message::MessageEventContent},
     SyncMessageEvent, SyncStateEvent,
   },
@@ -18,9 +19,36 @@ use tokio::sync::oneshot;
 use uuid::Uuid;
 use warp::Filter;
 
+mod admin_api;
+mod ansi;
+mod appservice_api;
 mod bot;
+mod bridge_info;
 mod config;
+mod confirmation;
+mod digest;
 mod emoji;
+mod encryption;
+mod ephemeral;
+mod expiry;
+mod html_text;
+mod janitor;
+mod math;
+mod media;
+mod mentions;
+mod outgoing;
+mod oversize;
+mod payloads;
+mod power_levels;
+mod preview;
+mod provisioning;
+mod reply;
+mod room_reference;
+mod sanitize;
+mod scheduled;
+mod space;
+mod stale_hooks;
+mod status;
 mod store;
 mod webhook;
 mod webhook_request;
@@ -86,16 +114,38 @@ impl Opts {
   }
 }
 
+// Passes requests whose body is of the type, whatever its charset
+fn content_type(
+  expected: &'static str,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+  warp::header::<String>("content-type")
+    .and_then(move |content_type: String| async move {
+      let essence = content_type.split(';').next().unwrap_or_default();
+      if essence.trim().eq_ignore_ascii_case(expected) {
+        Ok(())
+      } else {
+        Err(warp::reject())
+      }
+    })
+    .untuple_one()
+}
+
 fn generate_registration(
   config: &crate::config::Config,
   url: &str,
   localpart: &Option<String>,
-) -> Registration {
+) -> Result<serde_yaml::Value> {
+  let homeserver = &config.homeserver;
+  let exclusive = homeserver.exclusive_namespaces();
   let mut namespaces = Namespaces::new();
   namespaces
     .users
-    .push(Namespace::new(true, "@_webhook.*".into()));
-  RegistrationInit {
+    .push(Namespace::new(exclusive, "@_webhook.*".into()));
+  namespaces.aliases.push(Namespace::new(
+    exclusive,
+    format!("#{}_.*", config.webhook_bot.localpart),
+  ));
+  let registration: Registration = RegistrationInit {
     id: Uuid::new_v4().to_string(),
     url: url.to_string(),
     hs_token: Uuid::new_v4().to_string(),
@@ -107,9 +157,22 @@ fn generate_registration(
     },
     namespaces,
     rate_limited: Some(false),
-    protocols: None,
+    protocols: if homeserver.protocols() {
+      Some(vec![appservice_api::PROTOCOL.to_string()])
+    } else {
+      None
+    },
+  }
+  .into();
+
+  // Ruma doesn't know about ephemeral event delivery yet (MSC2409), so opt in by hand
+  let mut registration = serde_yaml::to_value(&registration)?;
+  if let serde_yaml::Value::Mapping(fields) = &mut registration {
+    if homeserver.push_ephemeral() {
+      fields.insert("de.sorunome.msc2409.push_ephemeral".into(), true.into());
+    }
   }
-  .into()
+  Ok(registration)
 }
 
 #[tokio::main]
@@ -123,11 +186,15 @@ async fn main() -> Result<()> {
     .validate()
     .context("Failed to validate command line option")?;
 
+  status::mark_started();
   info!("Reading config files");
   let config = Arc::new(config::from_file(&opts.config_file)?);
+  if let Some(path) = &config.emoji.path {
+    emoji::load(path)?;
+  }
   if opts.generate_registration {
     info!("Generating appservice registration file");
-    let registration = generate_registration(&*config, &opts.url.unwrap(), &opts.localpart);
+    let registration = generate_registration(&*config, &opts.url.unwrap(), &opts.localpart)?;
     let mut out_file =
       File::create(opts.registration_file).context("Failed to open registration file")?;
     serde_yaml::to_writer(&mut out_file, &registration)
@@ -139,6 +206,7 @@ async fn main() -> Result<()> {
   let homeserver_url = config.homeserver.url.as_str();
   let server_name = config.homeserver.domain.as_str();
   let registration = AppServiceRegistration::try_from_yaml_file(&opts.registration_file)?;
+  let hs_registration = Arc::new(Registration::clone(&registration));
   let appservice = AppService::new(homeserver_url, server_name, registration).await?;
 
   info!("Opening database connection");
@@ -147,6 +215,7 @@ async fn main() -> Result<()> {
     config: config.clone(),
     store: store.clone(),
     appservice: appservice.clone(),
+    registration: hs_registration.clone(),
   };
 
   // The handler needs the webhook id from the path, the config object, the appservice object
@@ -159,24 +228,101 @@ async fn main() -> Result<()> {
       move || request_context.clone()
     }))
     .and_then(webhook::handler);
+  // Files are uploaded as multipart/form-data, with the JSON message in a part of its own
+  let multipart_filter = warp::path!("api" / "v1" / "matrix" / "hook" / String)
+    .and(warp::filters::method::post())
+    .and(warp::multipart::form().max_length(config.limits.media_bytes_limit()))
+    .and(warp::any().map({
+      let request_context = request_context.clone();
+      move || request_context.clone()
+    }))
+    .and_then(webhook::multipart_handler);
+  // A text/plain body is the message's text, as is
+  let text_filter = warp::path!("api" / "v1" / "matrix" / "hook" / String)
+    .and(warp::filters::method::post())
+    .and(content_type("text/plain"))
+    .and(warp::filters::body::bytes())
+    .and(warp::any().map({
+      let request_context = request_context.clone();
+      move || request_context.clone()
+    }))
+    .and_then(webhook::text_handler);
+  // Form bodies are either the JSON message in a payload field, like Slack's, or its fields
+  let form_filter = warp::path!("api" / "v1" / "matrix" / "hook" / String)
+    .and(warp::filters::method::post())
+    .and(content_type("application/x-www-form-urlencoded"))
+    .and(warp::filters::body::bytes())
+    .and(warp::any().map({
+      let request_context = request_context.clone();
+      move || request_context.clone()
+    }))
+    .and_then(webhook::form_handler);
+  // Replaces an earlier message's text, like a POST with "edits"
+  let edit_filter = warp::path!("api" / "v1" / "matrix" / "hook" / String / "message" / String)
+    .and(warp::filters::method::put())
+    .and(warp::filters::body::json())
+    .and(warp::any().map({
+      let request_context = request_context.clone();
+      move || request_context.clone()
+    }))
+    .and_then(webhook::edit_handler);
+  let redact_filter = warp::path!("api" / "v1" / "matrix" / "hook" / String / "message" / String)
+    .and(warp::filters::method::delete())
+    .and(warp::query::<HashMap<String, String>>())
+    .and(warp::any().map({
+      let request_context = request_context.clone();
+      move || request_context.clone()
+    }))
+    .and_then(webhook::redact_handler);
+  // An array of messages, posted in order
+  let batch_filter = warp::path!("api" / "v1" / "matrix" / "hook" / String / "batch")
+    .and(warp::filters::method::post())
+    .and(warp::filters::body::json())
+    .and(warp::any().map({
+      let request_context = request_context.clone();
+      move || request_context.clone()
+    }))
+    .and_then(webhook::batch_handler);
+  // Other services' payloads are read raw, since some are signed
+  let service_filter = warp::path!("api" / "v1" / "matrix" / "hook" / String / String)
+    .and(warp::filters::method::post())
+    .and(warp::header::headers_cloned())
+    .and(warp::filters::body::bytes())
+    .and(warp::any().map({
+      let request_context = request_context.clone();
+      move || request_context.clone()
+    }))
+    .and_then(webhook::service_handler);
 
   info!("Starting appservice");
   // Start the web server
   let (tx, rx) = oneshot::channel();
-  let (server_addr, server) = warp::serve(appservice.warp_filter().or(webhook_filter))
-    .bind_with_graceful_shutdown(
-      (IpAddr::from_str("::0").unwrap(), opts.port.unwrap()),
-      async {
-        rx.await.ok();
-        info!("Appservice received termination signal. Shutting down webserver");
-      },
-    );
+  let routes = appservice_api::filter(request_context.clone())
+    .or(admin_api::filter(request_context.clone()))
+    .or(provisioning::filter(request_context.clone()))
+    .or(appservice.warp_filter())
+    .or(webhook_filter)
+    .or(multipart_filter)
+    .or(text_filter)
+    .or(form_filter)
+    .or(edit_filter)
+    .or(redact_filter)
+    .or(batch_filter)
+    .or(service_filter);
+  let (server_addr, server) = warp::serve(routes).bind_with_graceful_shutdown(
+    (IpAddr::from_str("::0").unwrap(), opts.port.unwrap()),
+    async {
+      rx.await.ok();
+      info!("Appservice received termination signal. Shutting down webserver");
+    },
+  );
 
   tokio::task::spawn(server);
   info!("Server running on {}", server_addr);
 
   // First, register the @_webhook bot and set hooks for it to respond to invites and !webhook messages
   let client = bot::register_bot(
+    &config,
     &config.webhook_bot.localpart,
     &config.webhook_bot.appearance.display_name,
     &Some(config.webhook_bot.appearance.avatar_url.clone()),
@@ -190,13 +336,24 @@ async fn main() -> Result<()> {
     .sync_once(SyncSettings::new().full_state(true))
     .await?;
 
+  if let Err(e) = space::setup(&config, &store, &hs_registration, &client).await {
+    warn!("Failed to set up the space: {}", e.to_string());
+  }
+
   // Handle invites for the webhook bot to rooms
   client
     .register_event_handler({
       let appservice = appservice.clone();
       let config = config.clone();
+      let store = store.clone();
       move |event: SyncStateEvent<MemberEventContent>, room: Room| {
-        bot::handle_room_member(config.clone(), appservice.clone(), room, event)
+        bot::handle_room_member(
+          config.clone(),
+          store.clone(),
+          appservice.clone(),
+          room,
+          event,
+        )
       }
     })
     .await;
@@ -219,6 +376,72 @@ async fn main() -> Result<()> {
     })
     .await;
 
+  // Handle confirmations of destructive !webhook requests
+  client
+    .register_event_handler({
+      let appservice = appservice.clone();
+      let config = config.clone();
+      let store = store.clone();
+      move |event: SyncMessageEvent<ReactionEventContent>, room: Room| {
+        bot::handle_reaction(
+          config.clone(),
+          store.clone(),
+          appservice.clone(),
+          room,
+          event,
+        )
+      }
+    })
+    .await;
+
+  janitor::spawn(config.clone(), store.clone(), appservice.clone());
+  expiry::spawn(store.clone(), appservice.clone());
+  scheduled::spawn(config.clone(), store.clone(), appservice.clone());
+  digest::spawn(config.clone(), store.clone(), appservice.clone());
+  stale_hooks::spawn(config.clone(), store.clone(), appservice.clone());
+
+  // Encrypted rooms need a client with a device of its own, which syncs rather than relying on the
+  // appservice
+  if config.encryption.is_some() {
+    let encrypted_client =
+      encryption::client(&config, &store, &appservice, &config.webhook_bot.localpart).await?;
+    encrypted_client
+      .register_event_handler({
+        let appservice = appservice.clone();
+        let config = config.clone();
+        let store = store.clone();
+        move |event: SyncMessageEvent<MessageEventContent>, room: Room| {
+          bot::handle_encrypted_room_message(
+            config.clone(),
+            store.clone(),
+            appservice.clone(),
+            room,
+            event,
+          )
+        }
+      })
+      .await;
+    encrypted_client
+      .register_event_handler({
+        let appservice = appservice.clone();
+        let config = config.clone();
+        let store = store.clone();
+        move |event: SyncMessageEvent<ReactionEventContent>, room: Room| {
+          bot::handle_encrypted_reaction(
+            config.clone(),
+            store.clone(),
+            appservice.clone(),
+            room,
+            event,
+          )
+        }
+      })
+      .await;
+    tokio::task::spawn(async move {
+      encrypted_client.sync(SyncSettings::default()).await;
+    });
+  }
+
   info!("Waiting for termination signal");
   tokio::signal::ctrl_c().await?;
   info!("Received termination signal");
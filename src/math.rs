@@ -0,0 +1,175 @@
+// Math for the clients that render it, written in LaTeX between dollar signs like $x^2$, or $$ for a
+// block of its own, as in MSC2191. It's picked out of markdown before it's rendered, so its
+// underscores and asterisks aren't taken for emphasis, and put back in as the MSC's markup.
+use std::ops::Range;
+
+use pulldown_cmark::{Event, Options, Parser, Tag};
+
+use crate::bot::escape_html;
+
+// Stand-ins for the math while the markdown is rendered, which markdown leaves alone
+const START: char = '\u{e000}';
+const END: char = '\u{e001}';
+
+#[derive(Debug, PartialEq)]
+pub struct Math {
+  latex: String,
+  display: bool,
+}
+
+/// The markdown with its math outside of code replaced by stand-ins, and the math
+pub fn extract(markdown: &str) -> (String, Vec<Math>) {
+  let code = code_ranges(markdown);
+  let mut out = String::new();
+  let mut maths = Vec::new();
+  let mut i = 0;
+  while let Some(c) = markdown[i..].chars().next() {
+    if let Some(range) = code.iter().find(|range| range.contains(&i)) {
+      out.push_str(&markdown[i..range.end]);
+      i = range.end;
+      continue;
+    }
+    // Markdown takes \$ for a dollar sign
+    if c == '$' && !markdown[..i].ends_with('\\') {
+      if let Some((latex, display, end)) = find_math(markdown, i, &code) {
+        out.push_str(&format!("{}{}{}", START, maths.len(), END));
+        maths.push(Math {
+          latex: latex.to_string(),
+          display,
+        });
+        i = end;
+        continue;
+      }
+    }
+    out.push(c);
+    i += c.len_utf8();
+  }
+  (out, maths)
+}
+
+/// The rendered html with the math put back in place of its stand-ins
+pub fn insert(html: &str, maths: &[Math]) -> String {
+  let mut html = html.to_string();
+  for (i, math) in maths.iter().enumerate() {
+    let stand_in = format!("{}{}{}", START, i, END);
+    let latex = escape_html(&math.latex);
+    // A block of math that's a paragraph of its own takes its place
+    if math.display {
+      html = html.replace(
+        &format!("<p>{}</p>", stand_in),
+        &format!(
+          "<div data-mx-maths=\"{}\"><code>{}</code></div>",
+          latex, latex
+        ),
+      );
+    }
+    html = html.replace(
+      &stand_in,
+      &format!(
+        "<span data-mx-maths=\"{}\"><code>{}</code></span>",
+        latex, latex
+      ),
+    );
+  }
+  html
+}
+
+// The LaTeX of the math starting at the dollar sign, whether it's a block, and where it ends. Inline
+// math stays on one line, and can't start or end with a space, so prices like $5 and $10 aren't
+// taken for it.
+fn find_math<'a>(
+  markdown: &'a str,
+  start: usize,
+  code: &[Range<usize>],
+) -> Option<(&'a str, bool, usize)> {
+  let rest = &markdown[start..];
+  let (latex, display, len) = if let Some(block) = rest.strip_prefix("$$") {
+    let end = block.find("$$")?;
+    (block[..end].trim(), true, 2 + end + 2)
+  } else {
+    let inline = &rest[1..];
+    let line = &inline[..inline.find('\n').unwrap_or_else(|| inline.len())];
+    let (end, _) = line.char_indices().skip(1).find(|(i, c)| {
+      *c == '$'
+        && !line[..*i].ends_with(char::is_whitespace)
+        && !line[..*i].ends_with('\\')
+        && !line[i + 1..].starts_with(|c: char| c.is_ascii_digit())
+    })?;
+    if line.starts_with(char::is_whitespace) {
+      return None;
+    }
+    (&line[..end], false, 1 + end + 1)
+  };
+  let end = start + len;
+  let crosses_code = code
+    .iter()
+    .any(|range| range.start > start && range.start < end);
+  if latex.is_empty() || crosses_code {
+    return None;
+  }
+  Some((latex, display, end))
+}
+
+// Where the markdown's code spans and blocks are, which dollar signs in are left alone
+fn code_ranges(markdown: &str) -> Vec<Range<usize>> {
+  let options = Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES;
+  let mut ranges = Vec::new();
+  for (event, range) in Parser::new_ext(markdown, options).into_offset_iter() {
+    match event {
+      Event::Code(_) | Event::Start(Tag::CodeBlock(_)) => ranges.push(range),
+      _ => {}
+    }
+  }
+  ranges
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_extract() {
+    let (markdown, maths) =
+      extract("Load is $\\alpha_1 * x^2$, cost $5 to $10, `$not$` and\n\n$$\n\\sum_i x_i\n$$");
+    assert_eq!(
+      markdown,
+      "Load is \u{e000}0\u{e001}, cost $5 to $10, `$not$` and\n\n\u{e000}1\u{e001}"
+    );
+    assert_eq!(
+      maths,
+      vec![
+        Math {
+          latex: "\\alpha_1 * x^2".to_string(),
+          display: false
+        },
+        Math {
+          latex: "\\sum_i x_i".to_string(),
+          display: true
+        },
+      ]
+    );
+    assert_eq!(extract("\\$x$ and $ y$").1, vec![]);
+  }
+
+  #[test]
+  fn test_insert() {
+    let maths = vec![
+      Math {
+        latex: "a<b".to_string(),
+        display: false,
+      },
+      Math {
+        latex: "x^2".to_string(),
+        display: true,
+      },
+    ];
+    assert_eq!(
+      insert(
+        "<p>If \u{e000}0\u{e001}</p>\n<p>\u{e000}1\u{e001}</p>",
+        &maths
+      ),
+      "<p>If <span data-mx-maths=\"a&lt;b\"><code>a&lt;b</code></span></p>\n\
+      <div data-mx-maths=\"x^2\"><code>x^2</code></div>"
+    );
+  }
+}
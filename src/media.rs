@@ -0,0 +1,518 @@
+// Media a webhook posts, from a URL in its payload or uploaded along with it. It's uploaded to the
+// homeserver as the webhook user, since clients can't show media from anywhere else.
+use std::{
+  convert::TryInto,
+  net::{IpAddr, SocketAddr},
+  time::Duration,
+};
+
+use anyhow::{Context, Result};
+use log::*;
+use matrix_sdk::ruma::{
+  events::room::{
+    message::{
+      AudioInfo, AudioMessageEventContent, FileInfo, FileMessageEventContent,
+      ImageMessageEventContent, MessageEventContent, MessageType, VideoInfo,
+      VideoMessageEventContent,
+    },
+    ImageInfo, ThumbnailInfo,
+  },
+  MxcUri, UInt,
+};
+use matrix_sdk::Client;
+use percent_encoding::percent_decode_str;
+use reqwest::{Response, Url};
+
+use crate::{config::Limits, store::Store, webhook::HookError};
+
+/// Media to post, with what it's called and its type
+#[derive(Debug, PartialEq)]
+pub struct Media {
+  pub name: String,
+  pub mime: mime::Mime,
+  pub data: Vec<u8>,
+}
+
+/// The media at the URL, if it's of the expected type, like image, and no bigger than the limit
+pub async fn download(
+  url: &str,
+  expected_type: Option<mime::Name<'_>>,
+  limits: &Limits,
+) -> Result<Media> {
+  let max_bytes = limits.media_bytes_limit();
+  let invalid = |reason: String| HookError::InvalidPayload(format!("{}: {}", url, reason));
+
+  info!("Downloading media at {}", url);
+  let mut response = get_public(url, None).await.map_err(invalid)?;
+
+  let mime: mime::Mime = response
+    .headers()
+    .get(reqwest::header::CONTENT_TYPE)
+    .and_then(|mime| mime.to_str().ok())
+    .and_then(|mime| mime.parse().ok())
+    .ok_or_else(|| invalid("It has no Content-Type".to_string()))?;
+  if let Some(expected_type) = expected_type.filter(|expected| mime.type_() != *expected) {
+    return Err(
+      invalid(format!(
+        "It's {}, not {}",
+        mime.essence_str(),
+        expected_type
+      ))
+      .into(),
+    );
+  }
+
+  // The length may be left out or wrong, so it's checked again while reading
+  let too_big = || invalid(format!("It's bigger than {} bytes", max_bytes));
+  if response
+    .content_length()
+    .map_or(false, |len| len > max_bytes)
+  {
+    return Err(too_big().into());
+  }
+  let mut data = Vec::new();
+  while let Some(chunk) = response.chunk().await.map_err(|e| {
+    warn!("Failed to download {}: {}", url, e);
+    invalid("Couldn't download it".to_string())
+  })? {
+    data.extend_from_slice(&chunk);
+    if data.len() as u64 > max_bytes {
+      return Err(too_big().into());
+    }
+  }
+  if data.is_empty() {
+    return Err(invalid("It's empty".to_string()).into());
+  }
+
+  Ok(Media {
+    name: file_name(
+      url,
+      expected_type.map_or("file", |expected| expected.as_str()),
+    ),
+    mime,
+    data,
+  })
+}
+
+// Enough for link shorteners and CDNs, without following a loop for long
+const MAX_REDIRECTS: usize = 5;
+
+/// GETs the URL and any redirects, as long as they're all on public addresses, so that payloads
+/// can't have the bridge read its own network. Failures are reasons to give the hook's caller.
+pub async fn get_public(url: &str, timeout: Option<Duration>) -> Result<Response, String> {
+  let mut url = Url::parse(url).map_err(|_| "It isn't a URL".to_string())?;
+  for _ in 0..=MAX_REDIRECTS {
+    let address = public_address(&url).await?;
+    // Connecting to the address that was checked, so the host can't resolve elsewhere by then
+    let mut client = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none());
+    if let Some(domain) = url.domain() {
+      client = client.resolve(domain, address);
+    }
+    if let Some(timeout) = timeout {
+      client = client.timeout(timeout);
+    }
+    let failed = |e: reqwest::Error| {
+      warn!("Failed to download {}: {}", url, e);
+      "Couldn't download it".to_string()
+    };
+    let response = client
+      .build()
+      .map_err(failed)?
+      .get(url.clone())
+      .send()
+      .await
+      .map_err(failed)?;
+
+    if !response.status().is_redirection() {
+      if !response.status().is_success() {
+        return Err(format!("Couldn't download it: {}", response.status()));
+      }
+      return Ok(response);
+    }
+    url = response
+      .headers()
+      .get(reqwest::header::LOCATION)
+      .and_then(|location| location.to_str().ok())
+      .and_then(|location| url.join(location).ok())
+      .ok_or_else(|| "It redirects nowhere".to_string())?;
+  }
+  Err("It redirects too many times".to_string())
+}
+
+// Where the URL's host is, if all of its addresses are public
+async fn public_address(url: &Url) -> Result<SocketAddr, String> {
+  if url.scheme() != "http" && url.scheme() != "https" {
+    return Err("It isn't an http(s) URL".to_string());
+  }
+  let port = url.port_or_known_default().unwrap_or(80);
+  let host = url.host_str().ok_or_else(|| "It has no host".to_string())?;
+  // IPv6 hosts are in brackets
+  let addresses: Vec<SocketAddr> = match host
+    .trim_start_matches('[')
+    .trim_end_matches(']')
+    .parse::<IpAddr>()
+  {
+    Ok(ip) => vec![SocketAddr::new(ip, port)],
+    Err(_) => tokio::net::lookup_host((host, port))
+      .await
+      .map_err(|_| "Its host can't be found".to_string())?
+      .collect(),
+  };
+  match addresses.first() {
+    Some(address) if addresses.iter().all(|address| is_public(address.ip())) => Ok(*address),
+    Some(_) => Err("It isn't on a public address".to_string()),
+    None => Err("Its host can't be found".to_string()),
+  }
+}
+
+// Not loopback, private, link-local or otherwise reserved, including IPv4 inside IPv6
+fn is_public(ip: IpAddr) -> bool {
+  match ip {
+    IpAddr::V4(ip) => {
+      let [a, b, ..] = ip.octets();
+      !(ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || a == 0
+        // Carrier-grade NAT's shared space, and the multicast and reserved ranges
+        || (a == 100 && (64..128).contains(&b))
+        || a >= 224)
+    }
+    IpAddr::V6(ip) => {
+      if let Some(ip) = ip.to_ipv4() {
+        return is_public(IpAddr::V4(ip));
+      }
+      let first = ip.segments()[0];
+      !(ip.is_loopback()
+        || ip.is_unspecified()
+        // Unique local, link-local and multicast
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80
+        || (first & 0xff00) == 0xff00)
+    }
+  }
+}
+
+/// The media as an m.image, m.audio or m.video according to its type, or else an m.file
+pub async fn message(client: &Client, media: Media) -> Result<MessageEventContent> {
+  match media.mime.type_() {
+    mime::IMAGE => image_message(client, media).await,
+    mime::AUDIO => audio_message(client, media).await,
+    mime::VIDEO => video_message(client, media).await,
+    _ => file_message(client, media).await,
+  }
+}
+
+/// The image as an m.image, uploaded by the client with its size and dimensions. Webhook images are
+/// small enough for clients to use as their own thumbnail.
+pub async fn image_message(client: &Client, image: Media) -> Result<MessageEventContent> {
+  let content_uri = upload(client, &image).await?;
+  let size = UInt::new(image.data.len() as u64);
+  let (width, height) = match imagesize::blob_size(&image.data) {
+    Ok(dimensions) => (
+      UInt::new(dimensions.width as u64),
+      UInt::new(dimensions.height as u64),
+    ),
+    Err(_) => (None, None),
+  };
+  let mut thumbnail_info = ThumbnailInfo::new();
+  thumbnail_info.width = width;
+  thumbnail_info.height = height;
+  thumbnail_info.mimetype = Some(image.mime.to_string());
+  thumbnail_info.size = size;
+  let mut info = ImageInfo::new();
+  info.width = width;
+  info.height = height;
+  info.mimetype = Some(image.mime.to_string());
+  info.size = size;
+  info.thumbnail_url = Some(content_uri.clone());
+  info.thumbnail_info = Some(Box::new(thumbnail_info));
+
+  Ok(MessageEventContent::new(MessageType::Image(
+    ImageMessageEventContent::plain(image.name, content_uri, Some(Box::new(info))),
+  )))
+}
+
+/// The audio as an m.audio, with its duration if it's WAV or MP4
+pub async fn audio_message(client: &Client, audio: Media) -> Result<MessageEventContent> {
+  let content_uri = upload(client, &audio).await?;
+  let metadata = Metadata::of(&audio.data);
+  let mut info = AudioInfo::new();
+  info.duration = metadata.duration_ms.and_then(UInt::new);
+  info.mimetype = Some(audio.mime.to_string());
+  info.size = UInt::new(audio.data.len() as u64);
+
+  Ok(MessageEventContent::new(MessageType::Audio(
+    AudioMessageEventContent::plain(audio.name, content_uri, Some(Box::new(info))),
+  )))
+}
+
+/// The video as an m.video, with its duration and dimensions if it's MP4 or QuickTime
+pub async fn video_message(client: &Client, video: Media) -> Result<MessageEventContent> {
+  let content_uri = upload(client, &video).await?;
+  let metadata = Metadata::of(&video.data);
+  let mut info = VideoInfo::new();
+  info.duration = metadata.duration_ms.and_then(UInt::new);
+  info.width = metadata.width.and_then(UInt::new);
+  info.height = metadata.height.and_then(UInt::new);
+  info.mimetype = Some(video.mime.to_string());
+  info.size = UInt::new(video.data.len() as u64);
+
+  Ok(MessageEventContent::new(MessageType::Video(
+    VideoMessageEventContent::plain(video.name, content_uri, Some(Box::new(info))),
+  )))
+}
+
+/// The file as an m.file, uploaded by the client under its own name
+pub async fn file_message(client: &Client, file: Media) -> Result<MessageEventContent> {
+  let content_uri = upload(client, &file).await?;
+  let mut info = FileInfo::new();
+  info.mimetype = Some(file.mime.to_string());
+  info.size = UInt::new(file.data.len() as u64);
+  let mut content =
+    FileMessageEventContent::plain(file.name.clone(), content_uri, Some(Box::new(info)));
+  content.filename = Some(file.name);
+  Ok(MessageEventContent::new(MessageType::File(content)))
+}
+
+/// The mxc URI of a custom emoji's image, which is only uploaded the first time it's used
+pub async fn emoticon(
+  client: &Client,
+  store: &Store,
+  url: &str,
+  limits: &Limits,
+) -> Result<String> {
+  let setting = format!("emoticon:{}", url);
+  if let Some(uri) = store.get_setting(&setting).await? {
+    return Ok(uri);
+  }
+  let uri = upload_image(client, url, limits).await?.to_string();
+  store.set_setting(&setting, &uri).await?;
+  Ok(uri)
+}
+
+/// The mxc URI of the image at the URL, once the client has uploaded it
+pub async fn upload_image(client: &Client, url: &str, limits: &Limits) -> Result<MxcUri> {
+  let image = download(url, Some(mime::IMAGE), limits).await?;
+  upload(client, &image).await
+}
+
+async fn upload(client: &Client, media: &Media) -> Result<MxcUri> {
+  let response = client
+    .upload(&media.mime, &mut media.data.as_slice())
+    .await
+    .with_context(|| format!("Failed to upload {}", media.name))?;
+  Ok(response.content_uri)
+}
+
+/// What can be told about audio or video without decoding it
+#[derive(Debug, Default, PartialEq)]
+struct Metadata {
+  duration_ms: Option<u64>,
+  width: Option<u64>,
+  height: Option<u64>,
+}
+
+impl Metadata {
+  fn of(data: &[u8]) -> Self {
+    let mut metadata = Self::default();
+    if data.starts_with(b"RIFF") && data.get(8..12) == Some(&b"WAVE"[..]) {
+      metadata.read_wav(data);
+    } else {
+      metadata.read_mp4(data);
+    }
+    metadata
+  }
+
+  // A WAV's fmt chunk has its bytes per second, and its data chunk the bytes
+  fn read_wav(&mut self, data: &[u8]) {
+    let mut byte_rate = None;
+    let mut rest = &data[12..];
+    while let (Some(id), Some(size)) = (rest.get(0..4), le_u32(rest, 4)) {
+      match id {
+        b"fmt " => byte_rate = le_u32(rest, 16).filter(|rate| *rate > 0),
+        b"data" => {
+          if let Some(byte_rate) = byte_rate {
+            self.duration_ms = Some(size * 1000 / byte_rate);
+          }
+          return;
+        }
+        _ => {}
+      }
+      // Chunks are padded to an even length
+      let next = 8 + size as usize + size as usize % 2;
+      rest = match rest.get(next..) {
+        Some(rest) => rest,
+        None => return,
+      };
+    }
+  }
+
+  // MP4 and QuickTime files have their duration in the movie header box, and each track its
+  // dimensions in its track header box, which are 0 for audio
+  fn read_mp4(&mut self, data: &[u8]) {
+    for (kind, body) in mp4_boxes(data) {
+      let version = body.first().copied().unwrap_or_default();
+      match kind {
+        b"moov" | b"trak" => self.read_mp4(body),
+        b"mvhd" => {
+          let (timescale, duration) = if version == 1 {
+            (be_u32(body, 20), be_u64(body, 24))
+          } else {
+            (be_u32(body, 12), be_u32(body, 16))
+          };
+          if let (Some(timescale), Some(duration)) = (timescale.filter(|t| *t > 0), duration) {
+            self.duration_ms = duration
+              .checked_mul(1000)
+              .map(|duration| duration / timescale);
+          }
+        }
+        b"tkhd" if self.width.is_none() => {
+          let at = if version == 1 { 88 } else { 76 };
+          // They're 16.16 fixed point
+          if let (Some(width), Some(height)) = (be_u32(body, at), be_u32(body, at + 4)) {
+            if width > 0 && height > 0 {
+              self.width = Some(width >> 16);
+              self.height = Some(height >> 16);
+            }
+          }
+        }
+        _ => {}
+      }
+    }
+  }
+}
+
+// The boxes in an MP4 file or another box, as their type and contents
+fn mp4_boxes(mut data: &[u8]) -> Vec<(&[u8], &[u8])> {
+  let mut boxes = Vec::new();
+  while let (Some(size), Some(kind)) = (be_u32(data, 0), data.get(4..8)) {
+    let (header, size) = match size {
+      // To the end of the file
+      0 => (8, data.len() as u64),
+      1 => match be_u64(data, 8) {
+        Some(size) => (16, size),
+        None => break,
+      },
+      size => (8, size),
+    };
+    if size < header || size > data.len() as u64 {
+      break;
+    }
+    boxes.push((kind, &data[header as usize..size as usize]));
+    data = &data[size as usize..];
+  }
+  boxes
+}
+
+fn be_u32(data: &[u8], at: usize) -> Option<u64> {
+  let bytes = data.get(at..at + 4)?.try_into().ok()?;
+  Some(u32::from_be_bytes(bytes) as u64)
+}
+
+fn be_u64(data: &[u8], at: usize) -> Option<u64> {
+  Some(u64::from_be_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
+
+fn le_u32(data: &[u8], at: usize) -> Option<u64> {
+  let bytes = data.get(at..at + 4)?.try_into().ok()?;
+  Some(u32::from_le_bytes(bytes) as u64)
+}
+
+// The last part of the URL's path, which clients show as the media's name
+fn file_name(url: &str, fallback: &str) -> String {
+  reqwest::Url::parse(url)
+    .ok()
+    .and_then(|url| {
+      url
+        .path_segments()?
+        .last()
+        .filter(|name| !name.is_empty())
+        .map(|name| percent_decode_str(name).decode_utf8_lossy().to_string())
+    })
+    .unwrap_or_else(|| fallback.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_is_public() {
+    for ip in &["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+      assert!(is_public(ip.parse().unwrap()), "{}", ip);
+    }
+    for ip in &[
+      "127.0.0.1",
+      "10.1.2.3",
+      "172.16.0.1",
+      "192.168.1.1",
+      "169.254.169.254",
+      "100.64.0.1",
+      "0.0.0.0",
+      "::1",
+      "::",
+      "fd00::1",
+      "fe80::1",
+      "::ffff:127.0.0.1",
+    ] {
+      assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+    }
+  }
+
+  #[test]
+  fn test_file_name() {
+    assert_eq!(
+      file_name("https://ci.example.com/graphs/load.png?from=1h", "image"),
+      "load.png"
+    );
+    assert_eq!(
+      file_name("https://ci.example.com/build%2012.log", "file"),
+      "build 12.log"
+    );
+    assert_eq!(file_name("https://ci.example.com/", "image"), "image");
+    assert_eq!(file_name("not a url", "image"), "image");
+  }
+
+  #[test]
+  fn test_wav_metadata() {
+    // 8kHz mono 16 bit, so 16000 bytes a second, with 24000 bytes of samples
+    let mut wav = b"RIFF\0\0\0\0WAVEfmt \x10\0\0\0\x01\0\x01\0\x40\x1f\0\0\x80\x3e\0\0\x02\0\x10\0data\xc0\x5d\0\0"
+      .to_vec();
+    wav.resize(wav.len() + 24000, 0);
+    assert_eq!(Metadata::of(&wav).duration_ms, Some(1500));
+  }
+
+  #[test]
+  fn test_mp4_metadata() {
+    fn mp4_box(kind: &[u8], body: &[u8]) -> Vec<u8> {
+      let mut mp4_box = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+      mp4_box.extend_from_slice(kind);
+      mp4_box.extend_from_slice(body);
+      mp4_box
+    }
+    // Version 0, with a timescale of 600 and a duration of 2.5 seconds
+    let mut mvhd = vec![0; 100];
+    mvhd[12..16].copy_from_slice(&600u32.to_be_bytes());
+    mvhd[16..20].copy_from_slice(&1500u32.to_be_bytes());
+    let mut tkhd = vec![0; 84];
+    tkhd[76..80].copy_from_slice(&(1280u32 << 16).to_be_bytes());
+    tkhd[80..84].copy_from_slice(&(720u32 << 16).to_be_bytes());
+    let mut moov = mp4_box(b"mvhd", &mvhd);
+    moov.extend(mp4_box(b"trak", &mp4_box(b"tkhd", &tkhd)));
+    let mut mp4 = mp4_box(b"ftyp", b"isom");
+    mp4.extend(mp4_box(b"moov", &moov));
+
+    assert_eq!(
+      Metadata::of(&mp4),
+      Metadata {
+        duration_ms: Some(2500),
+        width: Some(1280),
+        height: Some(720),
+      }
+    );
+    assert_eq!(Metadata::of(b"not a video"), Metadata::default());
+  }
+}
@@ -0,0 +1,200 @@
+// User ids mentioned in a message, which are turned into pills linking to the user so their clients
+// ping them. Rooms and bare matrix.to links are made into pills too, like clients make them.
+use percent_encoding::percent_decode_str;
+
+use crate::{bot, html_text};
+
+const PILL_PREFIX: &str = "https://matrix.to/#/";
+
+/// The user ids in the text, like @alice:example.com, in order and without repeats
+pub fn find_user_ids(text: &str) -> Vec<String> {
+  let mut user_ids: Vec<String> = Vec::new();
+  for (start, end) in user_id_spans(text) {
+    let user_id = &text[start..end];
+    if !user_ids.iter().any(|known| known == user_id) {
+      user_ids.push(user_id.to_string());
+    }
+  }
+  user_ids
+}
+
+/// The html with the user ids outside of links and code made into pills
+pub fn pills(html: &str, user_ids: &[String]) -> String {
+  html_text::map_text(html, |text| {
+    let mut out = String::new();
+    let mut last = 0;
+    for (start, end) in user_id_spans(text) {
+      let user_id = &text[start..end];
+      if user_ids.iter().any(|mentioned| mentioned == user_id) {
+        out.push_str(&text[last..start]);
+        out.push_str(&format!(
+          "<a href=\"{}{}\">{}</a>",
+          PILL_PREFIX, user_id, user_id
+        ));
+        last = end;
+      }
+    }
+    out.push_str(&text[last..]);
+    out
+  })
+}
+
+/// Whether the text has rooms or matrix.to links to make into pills
+pub fn has_link_pills(text: &str) -> bool {
+  text.contains(PILL_PREFIX) || !room_spans(text).is_empty()
+}
+
+/// The html with bare matrix.to links, room ids and room aliases outside of links and code made
+/// into pills
+pub fn link_pills(html: &str) -> String {
+  // Links go first, so the ids in them aren't made into pills of their own
+  let html = html_text::map_text(html, |text| {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(PILL_PREFIX) {
+      out.push_str(&rest[..start]);
+      let link = &rest[start..];
+      let end = link
+        .find(|c: char| c.is_whitespace() || c == '"' || c == '\'')
+        .unwrap_or_else(|| link.len());
+      // Punctuation after a link ends the sentence rather than the link
+      let end = link[..end]
+        .trim_end_matches(|c| ".,;:!?)".contains(c))
+        .len();
+      let target = link[PILL_PREFIX.len()..end]
+        .split('?')
+        .next()
+        .unwrap_or_default();
+      if target.is_empty() {
+        out.push_str(&link[..end]);
+      } else {
+        out.push_str(&format!(
+          "<a href=\"{}\">{}</a>",
+          &link[..end],
+          bot::escape_html(&percent_decode_str(target).decode_utf8_lossy())
+        ));
+      }
+      rest = &link[end..];
+    }
+    out.push_str(rest);
+    out
+  });
+  html_text::map_text(&html, |text| {
+    let mut out = String::new();
+    let mut last = 0;
+    for (start, end) in room_spans(text) {
+      let room = &text[start..end];
+      out.push_str(&text[last..start]);
+      out.push_str(&format!("<a href=\"{}{}\">{}</a>", PILL_PREFIX, room, room));
+      last = end;
+    }
+    out.push_str(&text[last..]);
+    out
+  })
+}
+
+fn user_id_spans(text: &str) -> Vec<(usize, usize)> {
+  id_spans(text, '@')
+}
+
+// Room aliases and ids, whose server names have to have a letter in them so times like #12:30
+// aren't taken for them
+fn room_spans(text: &str) -> Vec<(usize, usize)> {
+  let mut spans: Vec<(usize, usize)> = id_spans(text, '#')
+    .into_iter()
+    .chain(id_spans(text, '!'))
+    .filter(|(start, end)| {
+      let id = &text[*start..*end];
+      id[id.find(':').unwrap_or_default()..].contains(|c: char| c.is_ascii_alphabetic())
+    })
+    .collect();
+  spans.sort_unstable();
+  spans
+}
+
+// Where ids start and end: the sigil, like @, when it doesn't follow a word, the localpart, a colon
+// and the server name, with any port
+fn id_spans(text: &str, sigil: char) -> Vec<(usize, usize)> {
+  let is_localpart = |c: char| c.is_ascii_alphanumeric() || "._=-/+".contains(c);
+  let is_server = |c: char| c.is_ascii_alphanumeric() || c == '.' || c == '-';
+
+  let mut spans = Vec::new();
+  let mut previous = None;
+  for (start, c) in text.char_indices() {
+    let follows_word = previous.map_or(false, |p: char| p.is_alphanumeric());
+    previous = Some(c);
+    if c != sigil || follows_word {
+      continue;
+    }
+    let after = &text[start + 1..];
+    let localpart = after.find(|c| !is_localpart(c)).unwrap_or(after.len());
+    if localpart == 0 || !after[localpart..].starts_with(':') {
+      continue;
+    }
+    let server_start = start + 1 + localpart + 1;
+    let server = &text[server_start..];
+    let server_len = server.find(|c| !is_server(c)).unwrap_or(server.len());
+    // A full stop ends the sentence rather than the server name
+    let server_len = server[..server_len].trim_end_matches('.').len();
+    if server_len == 0 {
+      continue;
+    }
+    let mut end = server_start + server_len;
+    let port = &text[end..];
+    if port.starts_with(':') {
+      let digits = port[1..]
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(port.len() - 1);
+      if digits > 0 {
+        end += 1 + digits;
+      }
+    }
+    spans.push((start, end));
+  }
+  spans
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_find_user_ids() {
+    assert_eq!(
+      find_user_ids("@alice:example.com, paging @bob:localhost:8448 and @alice:example.com."),
+      vec!["@alice:example.com", "@bob:localhost:8448"]
+    );
+    assert!(find_user_ids("alert@example.com: @here @:nowhere").is_empty());
+  }
+
+  #[test]
+  fn test_link_pills() {
+    assert!(has_link_pills("See #ops:example.com"));
+    assert!(!has_link_pills("Fixed in #12:30, see !important"));
+    assert_eq!(
+      link_pills(
+        "Moved to #ops:example.com from !abc:example.com at #12:30, see \
+        https://matrix.to/#/%23ops:example.com?via=example.com. <code>#ops:example.com</code>"
+      ),
+      "Moved to <a href=\"https://matrix.to/#/#ops:example.com\">#ops:example.com</a> from \
+      <a href=\"https://matrix.to/#/!abc:example.com\">!abc:example.com</a> at #12:30, see \
+      <a href=\"https://matrix.to/#/%23ops:example.com?via=example.com\">#ops:example.com</a>. \
+      <code>#ops:example.com</code>"
+    );
+  }
+
+  #[test]
+  fn test_pills() {
+    let user_ids = vec!["@alice:example.com".to_string()];
+    assert_eq!(
+      pills(
+        "<b>@alice:example.com</b> see <a href=\"https://example.com\">@alice:example.com</a> \
+        and <code>@alice:example.com</code>, @bob:example.com",
+        &user_ids
+      ),
+      "<b><a href=\"https://matrix.to/#/@alice:example.com\">@alice:example.com</a></b> see \
+      <a href=\"https://example.com\">@alice:example.com</a> and <code>@alice:example.com</code>, \
+      @bob:example.com"
+    );
+  }
+}
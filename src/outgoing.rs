@@ -0,0 +1,349 @@
+// Outgoing webhooks: room events are POSTed to the URLs configured for the room with
+// `!webhook out add <url>`
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use hmac::{Hmac, Mac, NewMac};
+use lazy_static::lazy_static;
+use log::*;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+  store::{OutgoingWebhook, Store},
+  webhook::{self, RequestContext},
+};
+
+lazy_static! {
+  static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::builder()
+    .timeout(Duration::from_secs(30))
+    .build()
+    .unwrap();
+}
+
+// Attempts are spaced out exponentially, starting at one second
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Same format as GitHub's X-Hub-Signature-256, which most webhook receivers can already verify
+pub const SIGNATURE_HEADER: &str = "X-Hub-Signature-256";
+
+#[derive(Debug, Deserialize)]
+pub struct RoomEvent {
+  pub event_id: String,
+  pub room_id: String,
+  pub sender: String,
+  #[serde(rename = "type")]
+  pub event_type: String,
+  #[serde(default)]
+  pub content: serde_json::Value,
+  #[serde(default)]
+  pub origin_server_ts: u64,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutgoingPayload<'a> {
+  pub event_id: &'a str,
+  pub room_id: &'a str,
+  pub sender: &'a str,
+  pub event_type: &'a str,
+  pub msgtype: Option<&'a str>,
+  pub body: Option<&'a str>,
+  pub formatted_body: Option<&'a str>,
+  pub content: &'a serde_json::Value,
+  pub timestamp: u64,
+}
+
+impl RoomEvent {
+  pub fn payload(&self) -> OutgoingPayload<'_> {
+    let field = |name: &str| self.content.get(name).and_then(|value| value.as_str());
+    OutgoingPayload {
+      event_id: &self.event_id,
+      room_id: &self.room_id,
+      sender: &self.sender,
+      event_type: &self.event_type,
+      msgtype: field("msgtype"),
+      body: field("body"),
+      formatted_body: field("formatted_body"),
+      content: &self.content,
+      timestamp: self.origin_server_ts,
+    }
+  }
+}
+
+/// Whether an event passes the hook's event type, sender and keyword filters
+pub fn matches_filters(hook: &OutgoingWebhook, event: &RoomEvent) -> bool {
+  let event_types = filter_list(&hook.event_types);
+  let type_matches = if event_types.is_empty() {
+    event.event_type == "m.room.message"
+  } else {
+    event_types.contains(&event.event_type.as_str())
+  };
+
+  let senders = filter_list(&hook.senders);
+  let sender_matches = senders.is_empty()
+    || senders
+      .iter()
+      .any(|pattern| glob_matches(pattern, &event.sender));
+
+  let keywords = filter_list(&hook.keywords);
+  let keyword_matches = keywords.is_empty()
+    || match event.content.get("body").and_then(|body| body.as_str()) {
+      Some(body) => {
+        let body = body.to_lowercase();
+        keywords
+          .iter()
+          .any(|keyword| body.contains(&keyword.to_lowercase()))
+      }
+      None => false,
+    };
+
+  type_matches && sender_matches && keyword_matches
+}
+
+fn filter_list(raw: &Option<String>) -> Vec<&str> {
+  match raw {
+    Some(raw) => raw
+      .split(',')
+      .map(str::trim)
+      .filter(|item| !item.is_empty())
+      .collect(),
+    None => vec![],
+  }
+}
+
+// Matches `*` against any run of characters, like in push rules, e.g. `@*:example.com`
+fn glob_matches(pattern: &str, text: &str) -> bool {
+  match pattern.split_once('*') {
+    None => pattern == text,
+    Some((prefix, rest)) => {
+      text.starts_with(prefix)
+        && (prefix.len()..=text.len())
+          .any(|i| text.is_char_boundary(i) && glob_matches(rest, &text[i..]))
+    }
+  }
+}
+
+/// Forwards the room events in a transaction to the room's outgoing hooks. Deliveries happen in
+/// the background, so that the homeserver isn't kept waiting on slow receivers.
+pub async fn forward_events(events: &[serde_json::Value], context: &RequestContext) -> Result<()> {
+  for raw in events {
+    let event: RoomEvent = match serde_json::from_value(raw.clone()) {
+      Ok(event) => event,
+      // State events without a room, and so on
+      Err(_) => continue,
+    };
+    // Never forward what the bridge itself posted, which would loop messages between a paired
+    // incoming and outgoing hook
+    if webhook::is_bridge_user(&context.config, &event.sender) {
+      continue;
+    }
+
+    let hooks: Vec<_> = context
+      .store
+      .get_outgoing_webhooks_for_room(&event.room_id)
+      .await?
+      .into_iter()
+      .filter(|hook| matches_filters(hook, &event))
+      .collect();
+    if hooks.is_empty() {
+      continue;
+    }
+
+    let body = serde_json::to_vec(&event.payload())?;
+    for hook in hooks {
+      let body = body.clone();
+      let event_id = event.event_id.clone();
+      let store = context.store.clone();
+      tokio::spawn(async move {
+        if let Err(e) = deliver(&hook, &event_id, body, store).await {
+          warn!(
+            "Failed to deliver to outgoing webhook {}: {}",
+            &hook.id,
+            e.to_string()
+          );
+        }
+      });
+    }
+  }
+
+  Ok(())
+}
+
+// Retries with backoff until the receiver accepts the event, logging every attempt
+async fn deliver(
+  hook: &OutgoingWebhook,
+  event_id: &str,
+  body: Vec<u8>,
+  store: Arc<Store>,
+) -> Result<()> {
+  let signature = hook.secret.as_ref().map(|secret| sign(secret, &body));
+  for attempt in 1..=MAX_ATTEMPTS {
+    debug!(
+      "Delivering {} to outgoing webhook {}, attempt {}",
+      event_id, &hook.id, attempt
+    );
+    let mut request = HTTP_CLIENT
+      .post(&hook.url)
+      .header(reqwest::header::CONTENT_TYPE, "application/json")
+      .body(body.clone());
+    if let Some(signature) = &signature {
+      request = request.header(SIGNATURE_HEADER, signature);
+    }
+
+    let (status, error) = match request.send().await {
+      Ok(response) if response.status().is_success() => (Some(response.status()), None),
+      Ok(response) => (Some(response.status()), Some(response.status().to_string())),
+      Err(e) => (e.status(), Some(e.to_string())),
+    };
+    store
+      .record_outgoing_delivery(
+        &hook.id,
+        event_id,
+        attempt,
+        status.map(|status| status.as_u16()),
+        error.as_deref(),
+      )
+      .await?;
+
+    match error {
+      None => return Ok(()),
+      Some(error) if attempt < MAX_ATTEMPTS => {
+        debug!(
+          "Delivery to outgoing webhook {} failed, retrying: {}",
+          &hook.id, error
+        );
+        tokio::time::sleep(Duration::from_secs(1 << (attempt - 1))).await;
+      }
+      Some(error) => {
+        return Err(anyhow::anyhow!(
+          "Giving up after {} attempts: {}",
+          MAX_ATTEMPTS,
+          error
+        ))
+      }
+    }
+  }
+
+  Ok(())
+}
+
+/// `sha256=` followed by the hex HMAC-SHA256 of the body, keyed with the hook's secret
+pub fn sign(secret: &str, body: &[u8]) -> String {
+  let mut mac =
+    Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+  mac.update(body);
+  format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use anyhow::Result;
+
+  #[test]
+  fn test_payload() -> Result<()> {
+    let raw_json = r#"
+    {
+      "event_id": "$event",
+      "room_id": "!room:localhost",
+      "sender": "@alice:localhost",
+      "type": "m.room.message",
+      "origin_server_ts": 1234,
+      "content": {
+        "msgtype": "m.text",
+        "body": "Hello world!",
+        "format": "org.matrix.custom.html",
+        "formatted_body": "<b>Hello world!</b>"
+      }
+    }"#;
+
+    let event: RoomEvent = serde_json::from_str(raw_json)?;
+    let content = event.content.clone();
+    assert_eq!(
+      event.payload(),
+      OutgoingPayload {
+        event_id: "$event",
+        room_id: "!room:localhost",
+        sender: "@alice:localhost",
+        event_type: "m.room.message",
+        msgtype: Some("m.text"),
+        body: Some("Hello world!"),
+        formatted_body: Some("<b>Hello world!</b>"),
+        content: &content,
+        timestamp: 1234,
+      }
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_filters() -> Result<()> {
+    let event: RoomEvent = serde_json::from_str(
+      r#"
+    {
+      "event_id": "$event",
+      "room_id": "!room:localhost",
+      "sender": "@alice:example.com",
+      "type": "m.room.message",
+      "content": { "msgtype": "m.text", "body": "Starting DEPLOY of api" }
+    }"#,
+    )?;
+    let hook =
+      |event_types: Option<&str>, senders: Option<&str>, keywords: Option<&str>| OutgoingWebhook {
+        id: "id".into(),
+        room_id: "!room:localhost".into(),
+        user_id: "@bob:localhost".into(),
+        url: "https://example.com/".into(),
+        event_types: event_types.map(str::to_string),
+        senders: senders.map(str::to_string),
+        keywords: keywords.map(str::to_string),
+        secret: None,
+        label: None,
+      };
+
+    assert!(matches_filters(&hook(None, None, None), &event));
+    assert!(!matches_filters(
+      &hook(Some("m.reaction"), None, None),
+      &event
+    ));
+    assert!(matches_filters(
+      &hook(Some("m.reaction, m.room.message"), None, None),
+      &event
+    ));
+    assert!(matches_filters(
+      &hook(None, Some("@*:example.com"), None),
+      &event
+    ));
+    assert!(!matches_filters(
+      &hook(None, Some("@*:localhost"), None),
+      &event
+    ));
+    assert!(matches_filters(
+      &hook(None, None, Some("release,deploy")),
+      &event
+    ));
+    assert!(!matches_filters(&hook(None, None, Some("release")), &event));
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_sign() {
+    // From GitHub's documentation on validating webhook deliveries
+    assert_eq!(
+      sign("It's a Secret to Everybody", b"Hello, World!"),
+      "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17"
+    );
+  }
+
+  #[test]
+  fn test_glob() {
+    assert!(glob_matches("@alice:example.com", "@alice:example.com"));
+    assert!(glob_matches("*", "anything"));
+    assert!(glob_matches("@*:example.com", "@bob:example.com"));
+    assert!(glob_matches("@*bot*:*", "@ci-bot-1:example.com"));
+    assert!(!glob_matches("@*:example.com", "@bob:example.org"));
+  }
+}
@@ -0,0 +1,139 @@
+// Messages too big for the homeserver, which rejects events over 64KiB. They're split into several
+// by line, or cut short with the whole text attached as a file, as the hook's owner picks with
+// !webhook oversize. Either way they lose their HTML, which can't be cut up safely.
+use anyhow::Result;
+use matrix_sdk::ruma::events::room::message::{
+  EmoteMessageEventContent, MessageEventContent, MessageType,
+};
+
+use crate::media::Media;
+
+/// The setting's value for cutting messages short, rather than splitting them
+pub const TRUNCATE: &str = "truncate";
+
+// Leaves room for the rest of the event, like its relations and mentions
+const MAX_CONTENT_BYTES: usize = 60 * 1024;
+
+// Escaping can make text up to twice as big as JSON
+const MAX_TEXT_BYTES: usize = MAX_CONTENT_BYTES / 2;
+
+pub fn is_oversized(message: &MessageEventContent) -> Result<bool> {
+  Ok(serde_json::to_vec(message)?.len() > MAX_CONTENT_BYTES)
+}
+
+/// The message's text in as many messages as it takes
+pub fn split(message: &MessageEventContent) -> Vec<MessageEventContent> {
+  chunks(text(message), MAX_TEXT_BYTES)
+    .into_iter()
+    .map(|chunk| with_text(message, chunk))
+    .collect()
+}
+
+/// The start of the message's text, and the whole of it as a file. A room that can't have the file
+/// only gets the start.
+pub fn truncate(
+  message: &MessageEventContent,
+  attach: bool,
+) -> (MessageEventContent, Option<Media>) {
+  let full = text(message);
+  let start = chunks(full, MAX_TEXT_BYTES)
+    .into_iter()
+    .next()
+    .unwrap_or_default();
+  let note = if attach {
+    "The rest is in the attached file."
+  } else {
+    "The rest was cut off."
+  };
+  let truncated = with_text(message, format!("{}\n\n… {}", start, note));
+  let file = if attach {
+    Some(Media {
+      name: "message.txt".to_string(),
+      mime: mime::TEXT_PLAIN_UTF_8,
+      data: full.as_bytes().to_vec(),
+    })
+  } else {
+    None
+  };
+  (truncated, file)
+}
+
+fn text(message: &MessageEventContent) -> &str {
+  match &message.msgtype {
+    MessageType::Text(content) => &content.body,
+    MessageType::Notice(content) => &content.body,
+    MessageType::Emote(content) => &content.body,
+    _ => "",
+  }
+}
+
+// A plain message of the same type as the one it's part of
+fn with_text(message: &MessageEventContent, text: String) -> MessageEventContent {
+  match &message.msgtype {
+    MessageType::Notice(_) => MessageEventContent::notice_plain(text),
+    MessageType::Emote(_) => {
+      MessageEventContent::new(MessageType::Emote(EmoteMessageEventContent::plain(text)))
+    }
+    _ => MessageEventContent::text_plain(text),
+  }
+}
+
+// The text in pieces of at most max bytes, broken between lines where it can be
+fn chunks(text: &str, max: usize) -> Vec<String> {
+  let mut chunks = Vec::new();
+  let mut chunk = String::new();
+  for line in text.split_inclusive('\n') {
+    if chunk.len() + line.len() > max && !chunk.is_empty() {
+      chunks.push(chunk.trim_end().to_string());
+      chunk = String::new();
+    }
+    let mut line = line;
+    // A line too long to fit is broken wherever it has to be
+    while line.len() > max {
+      let mut end = max;
+      while !line.is_char_boundary(end) {
+        end -= 1;
+      }
+      chunks.push(line[..end].to_string());
+      line = &line[end..];
+    }
+    chunk.push_str(line);
+  }
+  if !chunk.trim().is_empty() {
+    chunks.push(chunk.trim_end().to_string());
+  }
+  chunks
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_chunks() {
+    assert_eq!(chunks("one\ntwo\nthree\n", 8), vec!["one\ntwo", "three"]);
+    assert_eq!(chunks("abcdéfgh", 4), vec!["abcd", "éfg", "h"]);
+  }
+
+  #[test]
+  fn test_split_and_truncate() -> Result<()> {
+    let line = "x".repeat(1000);
+    let long = [line.as_str(); 100].join("\n");
+    let message = MessageEventContent::notice_html(long.clone(), format!("<pre>{}</pre>", long));
+    assert!(is_oversized(&message)?);
+
+    let parts = split(&message);
+    assert_eq!(parts.len(), 4);
+    assert!(parts.iter().all(
+      |part| matches!(&part.msgtype, MessageType::Notice(notice) if notice.formatted.is_none())
+    ));
+    assert!(!is_oversized(&parts[0])?);
+
+    let (truncated, file) = truncate(&message, true);
+    assert!(!is_oversized(&truncated)?);
+    assert!(text(&truncated).ends_with("… The rest is in the attached file."));
+    assert_eq!(file.unwrap().data, long.as_bytes());
+    assert!(truncate(&message, false).1.is_none());
+    Ok(())
+  }
+}
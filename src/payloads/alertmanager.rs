@@ -0,0 +1,157 @@
+// Prometheus Alertmanager's webhook receiver. Each notification becomes one message, with the
+// group's firing alerts first and its resolved ones after.
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use super::{parse_json, Message};
+use crate::webhook_request::WebhookRequest;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Notification {
+  status: String,
+  #[serde(default)]
+  group_labels: BTreeMap<String, String>,
+  #[serde(rename = "externalURL")]
+  external_url: Option<String>,
+  #[serde(default)]
+  alerts: Vec<Alert>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Alert {
+  status: String,
+  #[serde(default)]
+  labels: BTreeMap<String, String>,
+  #[serde(default)]
+  annotations: BTreeMap<String, String>,
+  #[serde(rename = "generatorURL")]
+  generator_url: Option<String>,
+}
+
+pub fn translate(body: &[u8]) -> Result<Option<WebhookRequest>> {
+  let notification: Notification = parse_json(body)?;
+  if notification.alerts.is_empty() {
+    return Ok(None);
+  }
+  Ok(Some(render(&notification).into_request()))
+}
+
+fn render(notification: &Notification) -> Message {
+  let (firing, resolved): (Vec<_>, Vec<_>) = notification
+    .alerts
+    .iter()
+    .partition(|alert| alert.status == "firing");
+
+  let mut message = Message::default();
+  let counts = [("FIRING", firing.len()), ("RESOLVED", resolved.len())]
+    .iter()
+    .filter(|(_, count)| *count > 0)
+    .map(|(status, count)| format!("{}:{}", status, count))
+    .collect::<Vec<_>>()
+    .join(" ");
+  let group = notification
+    .group_labels
+    .values()
+    .cloned()
+    .collect::<Vec<_>>()
+    .join(" ");
+  message.title(
+    format!("[{}] {}", counts, group).trim_end(),
+    notification.external_url.as_deref(),
+  );
+  if notification.status == "resolved" {
+    message.text(" ").colored(RESOLVED_COLOR, "✓");
+  }
+
+  for alert in firing.iter().chain(resolved.iter()) {
+    message.line();
+    write_alert(&mut message, alert);
+  }
+  message
+}
+
+const RESOLVED_COLOR: &str = "#2eb67d";
+
+fn severity_color(severity: &str) -> &'static str {
+  match severity {
+    "critical" | "error" | "page" => "#e01e5a",
+    "warning" => "#ecb22e",
+    _ => "#36c5f0",
+  }
+}
+
+// One line per alert, like `critical HighLatency: p99 is 3s`, with its description on the next
+fn write_alert(message: &mut Message, alert: &Alert) {
+  let severity = alert
+    .labels
+    .get("severity")
+    .map(String::as_str)
+    .unwrap_or("alert");
+  if alert.status == "firing" {
+    message.colored(severity_color(severity), &format!("▌ {}", severity));
+  } else {
+    message.colored(RESOLVED_COLOR, "▌ resolved");
+  }
+  message.text(" ");
+
+  let name = alert
+    .labels
+    .get("alertname")
+    .map(String::as_str)
+    .unwrap_or("Alert");
+  message.title(name, alert.generator_url.as_deref());
+  if let Some(summary) = alert.annotations.get("summary") {
+    message.text(": ").text(summary);
+  }
+  if let Some(description) = alert.annotations.get("description") {
+    message.newline().italic(description);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_render() -> Result<()> {
+    let notification: Notification = serde_json::from_str(
+      r#"{
+        "version": "4",
+        "status": "firing",
+        "groupLabels": {"alertname": "HighLatency"},
+        "externalURL": "http://alertmanager:9093",
+        "alerts": [
+          {
+            "status": "resolved",
+            "labels": {"alertname": "HighLatency", "severity": "warning"},
+            "annotations": {"summary": "eu is fine"}
+          },
+          {
+            "status": "firing",
+            "labels": {"alertname": "HighLatency", "severity": "critical"},
+            "annotations": {"summary": "p99 is 3s", "description": "For 5 minutes"},
+            "generatorURL": "http://prometheus:9090/graph"
+          }
+        ]
+      }"#,
+    )?;
+    let message = render(&notification);
+    assert_eq!(
+      message.plain,
+      "[FIRING:1 RESOLVED:1] HighLatency (http://alertmanager:9093)\n\
+      ▌ critical HighLatency (http://prometheus:9090/graph): p99 is 3s\n\
+      For 5 minutes\n\
+      ▌ resolved HighLatency: eu is fine"
+    );
+    assert!(message
+      .html
+      .contains("<font data-mx-color=\"#e01e5a\">▌ critical</font>"));
+
+    assert!(translate(br#"{"status": "firing", "alerts": []}"#)?.is_none());
+    Ok(())
+  }
+}
@@ -0,0 +1,353 @@
+// Finished CI runs, from CircleCI's webhooks, Travis CI's notifications and GitHub's workflow_run
+// events. They're all summarized the same way, with the pipeline, branch, commit, how long it took
+// and whether it passed.
+use anyhow::Result;
+use serde::Deserialize;
+
+use super::{form_payload, parse_json, Message};
+use crate::{webhook::HookError, webhook_request::WebhookRequest};
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+  Passed,
+  Failed,
+  Canceled,
+}
+
+/// A finished run, whichever service it's from
+#[derive(Debug)]
+pub struct Run {
+  pipeline: String,
+  url: Option<String>,
+  branch: Option<String>,
+  commit: Option<String>,
+  commit_message: Option<String>,
+  duration: Option<i64>,
+  outcome: Outcome,
+}
+
+#[derive(Debug, Deserialize)]
+struct CircleCi {
+  #[serde(rename = "type")]
+  kind: String,
+  project: CircleCiProject,
+  workflow: CircleCiWorkflow,
+  job: Option<CircleCiJob>,
+  pipeline: CircleCiPipeline,
+}
+
+#[derive(Debug, Deserialize)]
+struct CircleCiProject {
+  name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CircleCiWorkflow {
+  name: String,
+  url: Option<String>,
+  status: Option<String>,
+  created_at: Option<String>,
+  stopped_at: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CircleCiJob {
+  name: String,
+  status: String,
+  started_at: Option<String>,
+  stopped_at: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CircleCiPipeline {
+  vcs: Option<CircleCiVcs>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CircleCiVcs {
+  branch: Option<String>,
+  revision: Option<String>,
+  commit: Option<CircleCiCommit>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CircleCiCommit {
+  subject: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Travis {
+  number: String,
+  status_message: String,
+  branch: Option<String>,
+  commit: Option<String>,
+  message: Option<String>,
+  duration: Option<i64>,
+  build_url: Option<String>,
+  repository: TravisRepository,
+}
+
+#[derive(Debug, Deserialize)]
+struct TravisRepository {
+  owner_name: String,
+  name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct WorkflowRunEvent {
+  action: String,
+  repository: ActionsRepository,
+  workflow_run: WorkflowRun,
+}
+
+#[derive(Debug, Deserialize)]
+struct ActionsRepository {
+  full_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct WorkflowRun {
+  name: String,
+  html_url: String,
+  head_branch: Option<String>,
+  head_sha: Option<String>,
+  head_commit: Option<ActionsCommit>,
+  conclusion: Option<String>,
+  run_started_at: Option<String>,
+  updated_at: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ActionsCommit {
+  message: String,
+}
+
+/// The finished run in the service's payload. None for runs still going, and ones skipped or
+/// otherwise not worth posting.
+pub fn parse(service: &str, body: &[u8]) -> Result<Option<Run>> {
+  match service {
+    "actions" => Ok(actions(parse_json(body)?)),
+    "circleci" => Ok(circleci(parse_json(body)?)),
+    "travis" => {
+      let json = form_payload(body).ok_or_else(|| {
+        HookError::InvalidPayload("Travis sends its JSON as a form's payload".into())
+      })?;
+      Ok(travis(parse_json(json.as_bytes())?))
+    }
+    _ => Err(HookError::UnknownService.into()),
+  }
+}
+
+fn circleci(payload: CircleCi) -> Option<Run> {
+  let vcs = payload.pipeline.vcs;
+  let (name, status, started_at, stopped_at) = match (payload.kind.as_str(), payload.job) {
+    ("workflow-completed", _) => (
+      payload.workflow.name,
+      payload.workflow.status?,
+      payload.workflow.created_at,
+      payload.workflow.stopped_at,
+    ),
+    ("job-completed", Some(job)) => (
+      format!("{} / {}", payload.workflow.name, job.name),
+      job.status,
+      job.started_at,
+      job.stopped_at,
+    ),
+    _ => return None,
+  };
+  let outcome = match status.as_str() {
+    "success" => Outcome::Passed,
+    "failed" | "error" | "infrastructure_fail" | "timedout" => Outcome::Failed,
+    "canceled" => Outcome::Canceled,
+    _ => return None,
+  };
+  Some(Run {
+    pipeline: format!("{} / {}", payload.project.name, name),
+    url: payload.workflow.url,
+    branch: vcs.as_ref().and_then(|vcs| vcs.branch.clone()),
+    commit: vcs.as_ref().and_then(|vcs| vcs.revision.clone()),
+    commit_message: vcs
+      .and_then(|vcs| vcs.commit)
+      .and_then(|commit| commit.subject),
+    duration: duration(started_at.as_deref(), stopped_at.as_deref()),
+    outcome,
+  })
+}
+
+fn travis(payload: Travis) -> Option<Run> {
+  let outcome = match payload.status_message.as_str() {
+    "Passed" | "Fixed" => Outcome::Passed,
+    "Broken" | "Failed" | "Still Failing" | "Errored" => Outcome::Failed,
+    "Canceled" => Outcome::Canceled,
+    _ => return None,
+  };
+  Some(Run {
+    pipeline: format!(
+      "{}/{} #{}",
+      payload.repository.owner_name, payload.repository.name, payload.number
+    ),
+    url: payload.build_url,
+    branch: payload.branch,
+    commit: payload.commit,
+    commit_message: payload.message,
+    duration: payload.duration,
+    outcome,
+  })
+}
+
+fn actions(payload: WorkflowRunEvent) -> Option<Run> {
+  if payload.action != "completed" {
+    return None;
+  }
+  let run = payload.workflow_run;
+  let outcome = match run.conclusion.as_deref() {
+    Some("success") => Outcome::Passed,
+    Some("failure") | Some("timed_out") | Some("startup_failure") => Outcome::Failed,
+    Some("cancelled") => Outcome::Canceled,
+    _ => return None,
+  };
+  Some(Run {
+    pipeline: format!("{} / {}", payload.repository.full_name, run.name),
+    url: Some(run.html_url),
+    branch: run.head_branch,
+    commit: run.head_sha,
+    commit_message: run.head_commit.map(|commit| commit.message),
+    duration: duration(run.run_started_at.as_deref(), run.updated_at.as_deref()),
+    outcome,
+  })
+}
+
+impl Run {
+  /// What the run's outcome is called in `!webhook events`
+  pub fn outcome(&self) -> &'static str {
+    match self.outcome {
+      Outcome::Passed => "passed",
+      Outcome::Failed => "failed",
+      Outcome::Canceled => "canceled",
+    }
+  }
+
+  pub fn into_request(self) -> WebhookRequest {
+    self.render().into_request()
+  }
+
+  fn render(&self) -> Message {
+    let emoji = match self.outcome {
+      Outcome::Passed => "✅",
+      Outcome::Failed => "❌",
+      Outcome::Canceled => "⚪",
+    };
+    let mut message = Message::default();
+    message
+      .text(&format!("{} ", emoji))
+      .title(&self.pipeline, self.url.as_deref())
+      .text(&format!(" {}", self.outcome()));
+    if let Some(branch) = &self.branch {
+      message.text(" on ").code(branch);
+    }
+    if let Some(duration) = self.duration {
+      message.text(&format!(" in {}", format_duration(duration)));
+    }
+    if let Some(commit) = &self.commit {
+      message.line().code(&commit[..commit.len().min(7)]);
+      if let Some(commit_message) = &self.commit_message {
+        let summary = commit_message.lines().next().unwrap_or_default();
+        message.text(" ").text(summary);
+      }
+    }
+    message
+  }
+}
+
+fn duration(started_at: Option<&str>, stopped_at: Option<&str>) -> Option<i64> {
+  Some(parse_timestamp(stopped_at?)? - parse_timestamp(started_at?)?)
+}
+
+// Seconds since the epoch of an RFC 3339 time in UTC, like 2021-09-01T22:49:34.317Z
+fn parse_timestamp(raw: &str) -> Option<i64> {
+  let field = |range: std::ops::Range<usize>| raw.get(range)?.parse::<i64>().ok();
+  let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+  let (hours, minutes, seconds) = (field(11..13)?, field(14..16)?, field(17..19)?);
+
+  // A civil date to days since the epoch, from Howard Hinnant's date algorithms
+  let year = if month <= 2 { year - 1 } else { year };
+  let era = year.div_euclid(400);
+  let year_of_era = year.rem_euclid(400);
+  let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+  let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+  let days = era * 146097 + day_of_era - 719468;
+
+  Some(days * 86400 + hours * 3600 + minutes * 60 + seconds)
+}
+
+// Like 1h 2m, 3m 12s or 45s
+fn format_duration(seconds: i64) -> String {
+  let (hours, minutes, seconds) = (seconds / 3600, seconds % 3600 / 60, seconds % 60);
+  if hours > 0 {
+    format!("{}h {}m", hours, minutes)
+  } else if minutes > 0 {
+    format!("{}m {}s", minutes, seconds)
+  } else {
+    format!("{}s", seconds)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_timestamp() {
+    assert_eq!(Some(0), parse_timestamp("1970-01-01T00:00:00Z"));
+    assert_eq!(
+      Some(1630536574),
+      parse_timestamp("2021-09-01T22:49:34.317Z")
+    );
+    assert_eq!(None, parse_timestamp("yesterday"));
+    assert_eq!("3m 12s", format_duration(192));
+  }
+
+  #[test]
+  fn test_circleci() -> Result<()> {
+    let run = parse(
+      "circleci",
+      br#"{
+        "type": "workflow-completed",
+        "project": {"name": "api"},
+        "workflow": {
+          "name": "build-and-test",
+          "url": "https://app.circleci.com/pipelines/gh/o/api/130/workflows/1",
+          "status": "failed",
+          "created_at": "2021-09-01T22:49:03.616Z",
+          "stopped_at": "2021-09-01T22:52:15.947Z"
+        },
+        "pipeline": {"vcs": {
+          "branch": "main",
+          "revision": "1285fe1d835c3e9f9d0dd2e5ce02a2de6ae2c4f2",
+          "commit": {"subject": "Fix the thing"}
+        }}
+      }"#,
+    )?
+    .unwrap();
+    assert_eq!("failed", run.outcome());
+    assert_eq!(
+      run.render().plain,
+      "❌ api / build-and-test (https://app.circleci.com/pipelines/gh/o/api/130/workflows/1) \
+      failed on main in 3m 12s\n1285fe1 Fix the thing"
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn test_travis() -> Result<()> {
+    let run = parse(
+      "travis",
+      b"payload=%7B%22number%22%3A%2212%22%2C%22status_message%22%3A%22Fixed%22%2C\
+      %22duration%22%3A45%2C%22repository%22%3A%7B%22owner_name%22%3A%22o%22%2C\
+      %22name%22%3A%22r%22%7D%7D",
+    )?
+    .unwrap();
+    assert_eq!(run.render().plain, "✅ o/r #12 passed in 45s");
+    Ok(())
+  }
+}
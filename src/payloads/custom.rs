@@ -0,0 +1,86 @@
+// Any JSON at all, rendered through a Tera template set for the hook with `!webhook template` or
+// in the config's templates. The template writes markdown, and sees the payload's fields as
+// variables, as well as the whole payload as `payload`.
+use std::error::Error;
+
+use anyhow::Result;
+use tera::{Context, Tera};
+
+use super::{parse_json, Message};
+use crate::{webhook::HookError, webhook_request::WebhookRequest};
+
+pub fn translate(template: Option<&str>, body: &[u8]) -> Result<Option<WebhookRequest>> {
+  let template = template.ok_or_else(|| {
+    HookError::InvalidPayload(
+      "The webhook has no template. Its owner can set one with !webhook template".into(),
+    )
+  })?;
+  let payload: serde_json::Value = parse_json(body)?;
+  let rendered = render(template, &payload)?;
+  if rendered.trim().is_empty() {
+    return Ok(None);
+  }
+  let mut message = Message::default();
+  message.markdown(&rendered);
+  Ok(Some(message.into_request()))
+}
+
+/// The markdown the template renders for the payload
+pub fn render(template: &str, payload: &serde_json::Value) -> Result<String> {
+  let mut context = if payload.is_object() {
+    Context::from_value(payload.clone())?
+  } else {
+    Context::new()
+  };
+  context.insert("payload", payload);
+  Tera::one_off(template, &context, false).map_err(|e| {
+    HookError::InvalidPayload(format!(
+      "The template couldn't be rendered: {}",
+      describe(&e)
+    ))
+    .into()
+  })
+}
+
+/// Why the template can't be used, if it can't
+pub fn check(template: &str) -> Option<String> {
+  Tera::default()
+    .add_raw_template("template", template)
+    .err()
+    .map(|e| describe(&e))
+}
+
+// Tera's own message only says which template failed, with the reason in its sources
+fn describe(error: &tera::Error) -> String {
+  let mut reasons = vec![error.to_string()];
+  let mut source = error.source();
+  while let Some(e) = source {
+    reasons.push(e.to_string());
+    source = e.source();
+  }
+  reasons.join(": ")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_render() -> Result<()> {
+    let payload = serde_json::json!({
+      "alert": {"name": "DiskFull", "hosts": ["db1", "db2"]},
+    });
+    assert_eq!(
+      render(
+        "**{{ alert.name }}** on {{ alert.hosts | join(sep=\", \") }} \
+        ({{ payload.alert.hosts | length }})",
+        &payload
+      )?,
+      "**DiskFull** on db1, db2 (2)"
+    );
+    assert!(render("{{ missing.field }}", &payload).is_err());
+    assert!(check("{% if %}").is_some());
+    assert!(check("{{ alert.name }}").is_none());
+    Ok(())
+  }
+}
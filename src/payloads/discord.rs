@@ -0,0 +1,173 @@
+// Discord's webhooks, which carry Discord's flavour of markdown and any number of embeds. Embeds
+// follow the content as quotes, bordered in their color.
+use anyhow::Result;
+use serde::Deserialize;
+
+use super::{markdown_html, parse_json, Message};
+use crate::{webhook::HookError, webhook_request::WebhookRequest};
+
+#[derive(Debug, Deserialize)]
+struct Payload {
+  #[serde(default)]
+  content: String,
+  username: Option<String>,
+  avatar_url: Option<String>,
+  #[serde(default)]
+  embeds: Vec<Embed>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Embed {
+  title: Option<String>,
+  url: Option<String>,
+  description: Option<String>,
+  color: Option<u32>,
+  author: Option<Author>,
+  fields: Vec<Field>,
+  image: Option<Image>,
+  footer: Option<Footer>,
+  timestamp: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Author {
+  name: String,
+  url: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Field {
+  name: String,
+  value: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Image {
+  url: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Footer {
+  text: String,
+}
+
+pub fn translate(body: &[u8]) -> Result<WebhookRequest> {
+  let payload: Payload = parse_json(body)?;
+  let message = render(&payload);
+  if message.is_empty() {
+    return Err(HookError::InvalidPayload("The message has nothing in it".into()).into());
+  }
+
+  Ok(
+    message
+      .into_request()
+      .with_sender(payload.username, payload.avatar_url),
+  )
+}
+
+fn render(payload: &Payload) -> Message {
+  let mut message = Message::default();
+  write_markdown(&mut message, &payload.content);
+  for embed in &payload.embeds {
+    write_embed(&mut message, embed);
+  }
+  message
+}
+
+// Discord keeps every line break, where CommonMark would join lines into paragraphs
+fn write_markdown(message: &mut Message, raw: &str) {
+  let mut markdown = vec![];
+  let mut in_code = false;
+  for line in raw.lines() {
+    if line.trim_start().starts_with("```") {
+      in_code = !in_code;
+      markdown.push(line.to_string());
+    } else if in_code {
+      markdown.push(line.to_string());
+    } else {
+      markdown.push(format!("{}  ", line));
+    }
+  }
+  message.rich(raw, &markdown_html(markdown.join("\n").trim_end()));
+}
+
+fn write_embed(message: &mut Message, embed: &Embed) {
+  let mut body = Message::default();
+  if let Some(author) = &embed.author {
+    body.title(&author.name, author.url.as_deref()).line();
+  }
+  if let Some(title) = &embed.title {
+    body.title(title, embed.url.as_deref()).line();
+  }
+  if let Some(description) = &embed.description {
+    write_markdown(&mut body, description);
+  }
+  for field in &embed.fields {
+    body.line().bold(&field.name).text(": ");
+    write_markdown(&mut body, &field.value);
+  }
+  if let Some(image) = &embed.image {
+    body.line().link(&image.url, &image.url);
+  }
+  let footer = [
+    embed.footer.as_ref().map(|footer| footer.text.as_str()),
+    embed.timestamp.as_deref(),
+  ]
+  .iter()
+  .flatten()
+  .filter(|part| !part.is_empty())
+  .copied()
+  .collect::<Vec<_>>();
+  if !footer.is_empty() {
+    body.line().italic(&footer.join(" • "));
+  }
+  if body.is_empty() {
+    return;
+  }
+
+  let mut quoted = Message::default();
+  if let Some(color) = embed.color {
+    quoted.color_bar(&format!("#{:06x}", color));
+  }
+  quoted.append(body);
+  message.quote(quoted);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_embeds() -> Result<()> {
+    let payload: Payload = serde_json::from_str(
+      r##"{
+        "content": "Server **restarted**\nAll good",
+        "username": "Game server",
+        "embeds": [{
+          "title": "Players",
+          "url": "https://game.example.com",
+          "color": 5814783,
+          "fields": [{"name": "Online", "value": "12", "inline": true}],
+          "footer": {"text": "eu-west"}
+        }]
+      }"##,
+    )?;
+    let message = render(&payload);
+    assert_eq!(
+      message.plain,
+      "Server **restarted**\nAll good\n> Players (https://game.example.com)\n> Online: 12\n> eu-west\n"
+    );
+    assert_eq!(
+      message.html,
+      "Server <strong>restarted</strong><br />\nAll good<blockquote>\
+      <font data-mx-color=\"#58b9ff\">▌</font> <b><a href=\"https://game.example.com\">Players</a></b><br>\
+      <b>Online</b>: 12<br><i>eu-west</i></blockquote>"
+    );
+    Ok(())
+  }
+}
@@ -0,0 +1,163 @@
+// Image pushes, from Docker Hub's webhooks or a distribution registry's notifications. The
+// registry notifies about every blob and pull as well, and only pushes of tagged manifests are
+// posted.
+use anyhow::Result;
+use serde::Deserialize;
+
+use super::{parse_json, Message};
+use crate::webhook_request::WebhookRequest;
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Payload {
+  Hub {
+    push_data: PushData,
+    repository: Repository,
+  },
+  Registry {
+    events: Vec<Event>,
+  },
+}
+
+#[derive(Debug, Deserialize)]
+struct PushData {
+  pusher: String,
+  tag: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Repository {
+  repo_name: String,
+  repo_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Event {
+  action: String,
+  target: Target,
+  actor: Option<Actor>,
+  request: Option<Request>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Target {
+  repository: String,
+  tag: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Actor {
+  name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Request {
+  host: Option<String>,
+}
+
+pub fn translate(body: &[u8]) -> Result<Option<WebhookRequest>> {
+  let payload: Payload = parse_json(body)?;
+  let message = render(&payload);
+  if message.is_empty() {
+    return Ok(None);
+  }
+  Ok(Some(message.into_request()))
+}
+
+fn render(payload: &Payload) -> Message {
+  let mut message = Message::default();
+  match payload {
+    Payload::Hub {
+      push_data,
+      repository,
+    } => {
+      message.text("Image ");
+      write_image(
+        &mut message,
+        &format!("{}:{}", repository.repo_name, push_data.tag),
+        repository.repo_url.as_deref(),
+      );
+      message.text(&format!(" pushed by {}", push_data.pusher));
+    }
+    Payload::Registry { events } => {
+      for event in events {
+        let tag = match (&event.target.tag, event.action.as_str()) {
+          (Some(tag), "push") => tag,
+          _ => continue,
+        };
+        let image = match event
+          .request
+          .as_ref()
+          .and_then(|request| request.host.as_ref())
+        {
+          Some(host) => format!("{}/{}:{}", host, event.target.repository, tag),
+          None => format!("{}:{}", event.target.repository, tag),
+        };
+        message.line().text("Image ");
+        write_image(&mut message, &image, None);
+        // Anonymous pushes have an empty name
+        match event
+          .actor
+          .as_ref()
+          .and_then(|actor| actor.name.as_deref())
+          .filter(|name| !name.is_empty())
+        {
+          Some(name) => message.text(&format!(" pushed by {}", name)),
+          None => message.text(" pushed"),
+        };
+      }
+    }
+  }
+  message
+}
+
+fn write_image(message: &mut Message, image: &str, url: Option<&str>) {
+  match url {
+    Some(url) => message.link(image, url),
+    None => message.code(image),
+  };
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_hub() -> Result<()> {
+    let payload: Payload = serde_json::from_str(
+      r#"{
+        "callback_url": "https://registry.hub.docker.com/u/acme/app/hook/1/",
+        "push_data": {"pushed_at": 1700000000, "pusher": "ci-bot", "tag": "1.4.0"},
+        "repository": {"repo_name": "acme/app", "repo_url": "https://hub.docker.com/r/acme/app"}
+      }"#,
+    )?;
+    assert_eq!(
+      render(&payload).plain,
+      "Image acme/app:1.4.0 (https://hub.docker.com/r/acme/app) pushed by ci-bot"
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn test_registry() -> Result<()> {
+    let payload: Payload = serde_json::from_str(
+      r#"{"events": [
+        {"action": "push", "target": {"repository": "app", "digest": "sha256:1"}},
+        {
+          "action": "push",
+          "target": {"repository": "app", "tag": "latest"},
+          "actor": {"name": "dana"},
+          "request": {"host": "registry.example.com"}
+        },
+        {"action": "pull", "target": {"repository": "app", "tag": "latest"}}
+      ]}"#,
+    )?;
+    let message = render(&payload);
+    assert_eq!(
+      message.html,
+      "Image <code>registry.example.com/app:latest</code> pushed by dana"
+    );
+    assert!(translate(br#"{"events": []}"#)?.is_none());
+    Ok(())
+  }
+}
@@ -0,0 +1,346 @@
+// GitHub's repository and organization webhooks. Only events worth a line in a room are posted,
+// and the rest are accepted without posting anything, so GitHub doesn't report them as failing.
+use anyhow::Result;
+use serde::Deserialize;
+
+use super::{parse_json, project_prefix, Message};
+use crate::{webhook::HookError, webhook_request::WebhookRequest};
+
+pub const EVENT_HEADER: &str = "X-GitHub-Event";
+
+// Pushes with more commits than this link to the comparison for the rest
+const MAX_COMMITS: usize = 5;
+
+#[derive(Debug, Deserialize)]
+struct Repository {
+  full_name: String,
+  html_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+  login: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Push {
+  repository: Repository,
+  sender: User,
+  #[serde(rename = "ref")]
+  git_ref: String,
+  #[serde(default)]
+  created: bool,
+  #[serde(default)]
+  deleted: bool,
+  compare: String,
+  #[serde(default)]
+  commits: Vec<Commit>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Commit {
+  id: String,
+  message: String,
+  url: String,
+  author: CommitAuthor,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommitAuthor {
+  name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PullRequestEvent {
+  action: String,
+  repository: Repository,
+  sender: User,
+  pull_request: PullRequest,
+}
+
+#[derive(Debug, Deserialize)]
+struct PullRequest {
+  number: u64,
+  title: String,
+  html_url: String,
+  #[serde(default)]
+  merged: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct IssueEvent {
+  action: String,
+  repository: Repository,
+  sender: User,
+  issue: Issue,
+}
+
+#[derive(Debug, Deserialize)]
+struct Issue {
+  number: u64,
+  title: String,
+  html_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReleaseEvent {
+  action: String,
+  repository: Repository,
+  sender: User,
+  release: Release,
+}
+
+#[derive(Debug, Deserialize)]
+struct Release {
+  tag_name: String,
+  name: Option<String>,
+  html_url: String,
+  #[serde(default)]
+  prerelease: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct WorkflowRunEvent {
+  action: String,
+  repository: Repository,
+  workflow_run: WorkflowRun,
+}
+
+#[derive(Debug, Deserialize)]
+struct WorkflowRun {
+  name: String,
+  head_branch: Option<String>,
+  conclusion: Option<String>,
+  html_url: String,
+}
+
+/// The event the request is for, from its header
+pub fn event(headers: &http::HeaderMap) -> Result<&str> {
+  headers
+    .get(EVENT_HEADER)
+    .and_then(|event| event.to_str().ok())
+    .ok_or_else(|| HookError::InvalidPayload(format!("There's no {} header", EVENT_HEADER)).into())
+}
+
+/// The event's message, or None for events that aren't posted, like the ping GitHub sends when a
+/// hook is set up
+pub fn translate(event: &str, body: &[u8]) -> Result<Option<WebhookRequest>> {
+  let message = match event {
+    "push" => render_push(&parse_json(body)?),
+    "pull_request" => render_pull_request(&parse_json(body)?),
+    "issues" => render_issue(&parse_json(body)?),
+    "release" => render_release(&parse_json(body)?),
+    "workflow_run" => render_workflow_run(&parse_json(body)?),
+    _ => None,
+  };
+  Ok(message.map(Message::into_request))
+}
+
+fn repository_prefix(repository: &Repository) -> Message {
+  project_prefix(&repository.full_name, &repository.html_url)
+}
+
+fn render_push(push: &Push) -> Option<Message> {
+  let (kind, name) = match (
+    push.git_ref.strip_prefix("refs/heads/"),
+    push.git_ref.strip_prefix("refs/tags/"),
+  ) {
+    (Some(branch), _) => ("branch", branch),
+    (_, Some(tag)) => ("tag", tag),
+    _ => ("ref", push.git_ref.as_str()),
+  };
+
+  let mut message = repository_prefix(&push.repository);
+  message.text(&push.sender.login);
+  if push.deleted {
+    message.text(&format!(" deleted {} ", kind)).code(name);
+    return Some(message);
+  }
+  if push.commits.is_empty() {
+    if !push.created {
+      return None;
+    }
+    message.text(&format!(" created {} ", kind)).code(name);
+    return Some(message);
+  }
+
+  let count = push.commits.len();
+  message
+    .text(" pushed ")
+    .link(
+      &format!("{} commit{}", count, if count == 1 { "" } else { "s" }),
+      &push.compare,
+    )
+    .text(" to ")
+    .code(name);
+  for commit in push.commits.iter().take(MAX_COMMITS) {
+    let summary = commit.message.lines().next().unwrap_or_default();
+    message
+      .newline()
+      .link(&commit.id[..commit.id.len().min(7)], &commit.url)
+      .text(&format!(" {} - {}", summary, commit.author.name));
+  }
+  if count > MAX_COMMITS {
+    message
+      .newline()
+      .text(&format!("and {} more", count - MAX_COMMITS));
+  }
+  Some(message)
+}
+
+fn render_pull_request(event: &PullRequestEvent) -> Option<Message> {
+  let action = match event.action.as_str() {
+    "closed" if event.pull_request.merged => "merged",
+    "opened" | "closed" | "reopened" => event.action.as_str(),
+    "ready_for_review" => "marked as ready for review",
+    _ => return None,
+  };
+  let pull_request = &event.pull_request;
+  let mut message = repository_prefix(&event.repository);
+  message
+    .text(&format!("{} {} pull request ", event.sender.login, action))
+    .title(
+      &format!("#{} {}", pull_request.number, pull_request.title),
+      Some(&pull_request.html_url),
+    );
+  Some(message)
+}
+
+fn render_issue(event: &IssueEvent) -> Option<Message> {
+  if !["opened", "closed", "reopened"].contains(&event.action.as_str()) {
+    return None;
+  }
+  let issue = &event.issue;
+  let mut message = repository_prefix(&event.repository);
+  message
+    .text(&format!("{} {} issue ", event.sender.login, event.action))
+    .title(
+      &format!("#{} {}", issue.number, issue.title),
+      Some(&issue.html_url),
+    );
+  Some(message)
+}
+
+fn render_release(event: &ReleaseEvent) -> Option<Message> {
+  if event.action != "published" {
+    return None;
+  }
+  let release = &event.release;
+  let name = release
+    .name
+    .as_deref()
+    .filter(|name| !name.is_empty())
+    .unwrap_or(&release.tag_name);
+  let mut message = repository_prefix(&event.repository);
+  message
+    .text(&format!(
+      "{} published {}",
+      event.sender.login,
+      if release.prerelease {
+        "pre-release "
+      } else {
+        "release "
+      }
+    ))
+    .title(name, Some(&release.html_url));
+  Some(message)
+}
+
+fn render_workflow_run(event: &WorkflowRunEvent) -> Option<Message> {
+  if event.action != "completed" {
+    return None;
+  }
+  let run = &event.workflow_run;
+  let (color, outcome) = match run.conclusion.as_deref() {
+    Some("success") => ("#2cbe4e", "succeeded"),
+    Some("failure") => ("#cb2431", "failed"),
+    Some("timed_out") => ("#cb2431", "timed out"),
+    Some("cancelled") => ("#6a737d", "was cancelled"),
+    // Skipped and neutral runs aren't news
+    _ => return None,
+  };
+  let mut message = repository_prefix(&event.repository);
+  message
+    .text("Workflow ")
+    .title(&run.name, Some(&run.html_url))
+    .text(" ")
+    .colored(color, outcome);
+  if let Some(branch) = &run.head_branch {
+    message.text(" on ").code(branch);
+  }
+  Some(message)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const REPOSITORY: &str =
+    r#""repository": {"full_name": "o/r", "html_url": "https://github.com/o/r"}"#;
+
+  #[test]
+  fn test_push() -> Result<()> {
+    let push: Push = serde_json::from_str(&format!(
+      r#"{{
+        {},
+        "sender": {{"login": "alice"}},
+        "ref": "refs/heads/main",
+        "compare": "https://github.com/o/r/compare/a...b",
+        "commits": [{{
+          "id": "0123456789abcdef",
+          "message": "Fix the <thing>\n\nLonger description",
+          "url": "https://github.com/o/r/commit/0123456",
+          "author": {{"name": "Alice"}}
+        }}]
+      }}"#,
+      REPOSITORY
+    ))?;
+    let message = render_push(&push).unwrap();
+    assert_eq!(
+      message.plain,
+      "[o/r (https://github.com/o/r)] alice pushed 1 commit (https://github.com/o/r/compare/a...b) to main\n\
+      0123456 (https://github.com/o/r/commit/0123456) Fix the <thing> - Alice"
+    );
+    assert_eq!(
+      message.html,
+      "[<a href=\"https://github.com/o/r\">o/r</a>] alice pushed \
+      <a href=\"https://github.com/o/r/compare/a...b\">1 commit</a> to <code>main</code><br>\
+      <a href=\"https://github.com/o/r/commit/0123456\">0123456</a> Fix the &lt;thing&gt; - Alice"
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn test_pull_request() -> Result<()> {
+    let body = format!(
+      r#"{{
+        "action": "closed",
+        {},
+        "sender": {{"login": "bob"}},
+        "pull_request": {{
+          "number": 12, "title": "Add things", "html_url": "https://github.com/o/r/pull/12", "merged": true
+        }}
+      }}"#,
+      REPOSITORY
+    );
+    let message = render_pull_request(&serde_json::from_str(&body)?).unwrap();
+    assert_eq!(
+      message.plain,
+      "[o/r (https://github.com/o/r)] bob merged pull request #12 Add things (https://github.com/o/r/pull/12)"
+    );
+
+    let labeled = body.replace("\"closed\"", "\"labeled\"");
+    assert!(translate("pull_request", labeled.as_bytes())?.is_none());
+    Ok(())
+  }
+
+  #[test]
+  fn test_event() -> Result<()> {
+    let mut headers = http::HeaderMap::new();
+    assert!(event(&headers).is_err());
+    headers.insert(EVENT_HEADER, "ping".parse()?);
+    assert_eq!("ping", event(&headers)?);
+    assert!(translate("ping", br#"{"zen": "Keep it simple"}"#)?.is_none());
+    Ok(())
+  }
+}
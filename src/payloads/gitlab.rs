@@ -0,0 +1,252 @@
+// GitLab's project webhooks. Like GitHub's, only events worth a line in a room are posted, and the
+// rest are accepted without posting anything.
+use anyhow::Result;
+use serde::Deserialize;
+
+use super::{parse_json, project_prefix, Message};
+use crate::webhook_request::WebhookRequest;
+
+/// Holds the secret token set on GitLab's side, which must match the hook's secret if it has one
+pub const TOKEN_HEADER: &str = "X-Gitlab-Token";
+
+// Pushes with more commits than this link to the comparison for the rest
+const MAX_COMMITS: usize = 5;
+
+// The commit a push has before or after it when a branch is created or deleted
+const NO_COMMIT: &str = "0000000000000000000000000000000000000000";
+
+#[derive(Debug, Deserialize)]
+struct Kind {
+  object_kind: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Project {
+  path_with_namespace: String,
+  web_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+  username: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Push {
+  project: Project,
+  user_username: String,
+  #[serde(rename = "ref")]
+  git_ref: String,
+  before: String,
+  after: String,
+  #[serde(default)]
+  commits: Vec<Commit>,
+  #[serde(default)]
+  total_commits_count: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct Commit {
+  id: String,
+  message: String,
+  url: String,
+  author: CommitAuthor,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommitAuthor {
+  name: String,
+}
+
+// Merge requests and issues, which GitLab describes the same way
+#[derive(Debug, Deserialize)]
+struct ObjectEvent {
+  project: Project,
+  user: User,
+  object_attributes: Object,
+}
+
+#[derive(Debug, Deserialize)]
+struct Object {
+  iid: u64,
+  title: String,
+  url: String,
+  action: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PipelineEvent {
+  project: Project,
+  object_attributes: Pipeline,
+}
+
+#[derive(Debug, Deserialize)]
+struct Pipeline {
+  id: u64,
+  #[serde(rename = "ref")]
+  git_ref: String,
+  status: String,
+}
+
+/// The event the request is for, like push or merge_request, from its `object_kind`
+pub fn event(body: &[u8]) -> Result<String> {
+  parse_json::<Kind>(body).map(|kind| kind.object_kind)
+}
+
+/// The event's message, or None for events that aren't posted
+pub fn translate(event: &str, body: &[u8]) -> Result<Option<WebhookRequest>> {
+  let message = match event {
+    "push" | "tag_push" => render_push(&parse_json(body)?),
+    "merge_request" => render_object(&parse_json(body)?, "merge request", "!"),
+    "issue" => render_object(&parse_json(body)?, "issue", "#"),
+    "pipeline" => render_pipeline(&parse_json(body)?),
+    _ => None,
+  };
+  Ok(message.map(Message::into_request))
+}
+
+fn project_message(project: &Project) -> Message {
+  project_prefix(&project.path_with_namespace, &project.web_url)
+}
+
+fn render_push(push: &Push) -> Option<Message> {
+  let (kind, name) = match (
+    push.git_ref.strip_prefix("refs/heads/"),
+    push.git_ref.strip_prefix("refs/tags/"),
+  ) {
+    (Some(branch), _) => ("branch", branch),
+    (_, Some(tag)) => ("tag", tag),
+    _ => ("ref", push.git_ref.as_str()),
+  };
+
+  let mut message = project_message(&push.project);
+  message.text(&push.user_username);
+  if push.after == NO_COMMIT {
+    message.text(&format!(" deleted {} ", kind)).code(name);
+    return Some(message);
+  }
+  if push.before == NO_COMMIT && (kind == "tag" || push.commits.is_empty()) {
+    message.text(&format!(" created {} ", kind)).code(name);
+    return Some(message);
+  }
+  if push.commits.is_empty() {
+    return None;
+  }
+
+  let count = push.total_commits_count.max(push.commits.len());
+  message
+    .text(" pushed ")
+    .link(
+      &format!("{} commit{}", count, if count == 1 { "" } else { "s" }),
+      &format!(
+        "{}/-/compare/{}...{}",
+        push.project.web_url, push.before, push.after
+      ),
+    )
+    .text(" to ")
+    .code(name);
+  for commit in push.commits.iter().take(MAX_COMMITS) {
+    let summary = commit.message.lines().next().unwrap_or_default();
+    message
+      .newline()
+      .link(&commit.id[..commit.id.len().min(8)], &commit.url)
+      .text(&format!(" {} - {}", summary, commit.author.name));
+  }
+  if count > MAX_COMMITS {
+    message
+      .newline()
+      .text(&format!("and {} more", count - MAX_COMMITS));
+  }
+  Some(message)
+}
+
+fn render_object(event: &ObjectEvent, noun: &str, sigil: &str) -> Option<Message> {
+  let object = &event.object_attributes;
+  let action = match object.action.as_deref() {
+    Some("open") => "opened",
+    Some("close") => "closed",
+    Some("reopen") => "reopened",
+    Some("merge") => "merged",
+    _ => return None,
+  };
+  let mut message = project_message(&event.project);
+  message
+    .text(&format!("{} {} {} ", event.user.username, action, noun))
+    .title(
+      &format!("{}{} {}", sigil, object.iid, object.title),
+      Some(&object.url),
+    );
+  Some(message)
+}
+
+fn render_pipeline(event: &PipelineEvent) -> Option<Message> {
+  let pipeline = &event.object_attributes;
+  let (color, outcome) = match pipeline.status.as_str() {
+    "success" => ("#108548", "passed"),
+    "failed" => ("#dd2b0e", "failed"),
+    "canceled" => ("#737278", "was canceled"),
+    // Pipelines starting and running aren't news
+    _ => return None,
+  };
+  let mut message = project_message(&event.project);
+  message
+    .text("Pipeline ")
+    .link(
+      &format!("#{}", pipeline.id),
+      &format!("{}/-/pipelines/{}", event.project.web_url, pipeline.id),
+    )
+    .text(" ")
+    .colored(color, outcome)
+    .text(" on ")
+    .code(&pipeline.git_ref);
+  Some(message)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const PROJECT: &str =
+    r#""project": {"path_with_namespace": "g/p", "web_url": "https://gitlab.com/g/p"}"#;
+
+  #[test]
+  fn test_merge_request() -> Result<()> {
+    let body = format!(
+      r#"{{
+        "object_kind": "merge_request",
+        {},
+        "user": {{"username": "carol"}},
+        "object_attributes": {{
+          "iid": 7, "title": "Tidy up", "url": "https://gitlab.com/g/p/-/merge_requests/7", "action": "merge"
+        }}
+      }}"#,
+      PROJECT
+    );
+    assert_eq!("merge_request", event(body.as_bytes())?);
+    let message = render_object(&serde_json::from_str(&body)?, "merge request", "!").unwrap();
+    assert_eq!(
+      message.plain,
+      "[g/p (https://gitlab.com/g/p)] carol merged merge request !7 Tidy up (https://gitlab.com/g/p/-/merge_requests/7)"
+    );
+
+    let updated = body.replace("\"merge\"", "\"update\"");
+    assert!(translate("merge_request", updated.as_bytes())?.is_none());
+    Ok(())
+  }
+
+  #[test]
+  fn test_pipeline() -> Result<()> {
+    let event: PipelineEvent = serde_json::from_str(&format!(
+      r#"{{{}, "object_attributes": {{"id": 42, "ref": "main", "status": "failed"}}}}"#,
+      PROJECT
+    ))?;
+    let message = render_pipeline(&event).unwrap();
+    assert_eq!(
+      message.html,
+      "[<a href=\"https://gitlab.com/g/p\">g/p</a>] Pipeline \
+      <a href=\"https://gitlab.com/g/p/-/pipelines/42\">#42</a> \
+      <font data-mx-color=\"#dd2b0e\">failed</font> on <code>main</code>"
+    );
+    Ok(())
+  }
+}
@@ -0,0 +1,215 @@
+// Grafana's alert notifications, from unified alerting with its alerts and values as well as from
+// legacy dashboard alerts with their evalMatches. A panel image is uploaded to the homeserver and
+// shown in the message, since clients won't load images from anywhere else.
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use log::*;
+use matrix_sdk::Client;
+use serde::Deserialize;
+
+use super::{parse_json, Message};
+use crate::{bot, webhook_request::WebhookRequest};
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Notification {
+  #[serde(default)]
+  title: String,
+  #[serde(default)]
+  state: String,
+  #[serde(default)]
+  message: String,
+  rule_url: Option<String>,
+  #[serde(rename = "externalURL")]
+  external_url: Option<String>,
+  image_url: Option<String>,
+  #[serde(default)]
+  eval_matches: Vec<EvalMatch>,
+  #[serde(default)]
+  alerts: Vec<Alert>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EvalMatch {
+  metric: String,
+  value: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Alert {
+  status: String,
+  #[serde(default)]
+  labels: BTreeMap<String, String>,
+  #[serde(default)]
+  annotations: BTreeMap<String, String>,
+  // Null rather than missing when there aren't any
+  values: Option<BTreeMap<String, f64>>,
+  #[serde(rename = "panelURL")]
+  panel_url: Option<String>,
+  #[serde(rename = "generatorURL")]
+  generator_url: Option<String>,
+  #[serde(rename = "imageURL")]
+  image_url: Option<String>,
+}
+
+pub async fn translate(body: &[u8], client: &Client) -> Result<Option<WebhookRequest>> {
+  let notification: Notification = parse_json(body)?;
+  let image_url = notification.image_url.as_deref().or_else(|| {
+    notification
+      .alerts
+      .iter()
+      .find_map(|alert| alert.image_url.as_deref())
+  });
+  let image = match image_url {
+    Some(url) => Some((url, upload_image(client, url).await)),
+    None => None,
+  };
+
+  let mut message = render(&notification);
+  match image {
+    Some((_, Ok(content_uri))) => {
+      message.line().rich(
+        "",
+        &format!(
+          "<img src=\"{}\" alt=\"{}\">",
+          bot::escape_html(&content_uri),
+          bot::escape_html(&notification.title)
+        ),
+      );
+    }
+    // The image may have expired or be behind a login, and the alert matters more than it
+    Some((url, Err(e))) => {
+      warn!("Failed to upload Grafana image {}: {}", url, e.to_string());
+      message.line().link("Panel image", url);
+    }
+    None => {}
+  }
+  Ok(Some(message.into_request()))
+}
+
+async fn upload_image(client: &Client, url: &str) -> Result<String> {
+  let (mime, image) = bot::download_image(url).await?;
+  let response = client.upload(&mime, &mut image.as_slice()).await?;
+  Ok(response.content_uri.to_string())
+}
+
+fn state_color(state: &str) -> &'static str {
+  match state {
+    "alerting" | "firing" => "#e02f44",
+    "ok" | "resolved" | "normal" => "#1b855e",
+    _ => "#e0b400",
+  }
+}
+
+fn render(notification: &Notification) -> Message {
+  let mut message = Message::default();
+  message.color_bar(state_color(&notification.state)).title(
+    &notification.title,
+    notification
+      .rule_url
+      .as_deref()
+      .or_else(|| notification.external_url.as_deref()),
+  );
+  // Unified alerting repeats the alerts in its message, which are shown below instead
+  if !notification.message.is_empty() && notification.alerts.is_empty() {
+    message.line().text(&notification.message);
+  }
+
+  for eval_match in &notification.eval_matches {
+    let value = eval_match
+      .value
+      .map(|value| value.to_string())
+      .unwrap_or_else(|| "no value".to_string());
+    message
+      .line()
+      .text(&format!("{}: ", eval_match.metric))
+      .code(&value);
+  }
+
+  for alert in &notification.alerts {
+    message
+      .line()
+      .colored(state_color(&alert.status), &alert.status)
+      .text(" ");
+    let name = alert
+      .labels
+      .get("alertname")
+      .map(String::as_str)
+      .unwrap_or("Alert");
+    message.title(
+      name,
+      alert
+        .panel_url
+        .as_deref()
+        .or_else(|| alert.generator_url.as_deref()),
+    );
+    if let Some(summary) = alert.annotations.get("summary") {
+      message.text(": ").text(summary);
+    }
+    if let Some(values) = alert.values.as_ref().filter(|values| !values.is_empty()) {
+      let values = values
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join(", ");
+      message.text(" ").code(&values);
+    }
+  }
+  message
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_legacy() -> Result<()> {
+    let notification: Notification = serde_json::from_str(
+      r#"{
+        "title": "[Alerting] High CPU",
+        "ruleUrl": "http://grafana/d/abc",
+        "state": "alerting",
+        "message": "CPU is high",
+        "evalMatches": [{"metric": "web-1", "value": 97.5, "tags": {}}],
+        "imageUrl": "http://grafana/render/abc.png"
+      }"#,
+    )?;
+    let message = render(&notification);
+    assert_eq!(
+      message.plain,
+      "[Alerting] High CPU (http://grafana/d/abc)\nCPU is high\nweb-1: 97.5"
+    );
+    assert!(message
+      .html
+      .starts_with("<font data-mx-color=\"#e02f44\">▌</font> "));
+    Ok(())
+  }
+
+  #[test]
+  fn test_unified() -> Result<()> {
+    let notification: Notification = serde_json::from_str(
+      r#"{
+        "title": "[RESOLVED] Disk",
+        "state": "ok",
+        "message": "**Resolved** ...",
+        "externalURL": "http://grafana/",
+        "alerts": [{
+          "status": "resolved",
+          "labels": {"alertname": "Disk"},
+          "annotations": {"summary": "Disk has room again"},
+          "values": {"B": 12, "C": 0},
+          "panelURL": "http://grafana/d/xyz?viewPanel=2"
+        }]
+      }"#,
+    )?;
+    let message = render(&notification);
+    assert_eq!(
+      message.plain,
+      "[RESOLVED] Disk (http://grafana/)\n\
+      resolved Disk (http://grafana/d/xyz?viewPanel=2): Disk has room again B=12, C=0"
+    );
+    Ok(())
+  }
+}
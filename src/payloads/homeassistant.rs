@@ -0,0 +1,105 @@
+// Home Assistant's RESTful notify platform, which sends a notification's message, title and data,
+// as JSON with `method: POST_JSON` or as form fields with `method: POST`. Of the data, `url` links
+// the title and `notice` posts the notification as a notice.
+use anyhow::Result;
+use serde::Deserialize;
+
+use super::{form_field, parse_json, Message};
+use crate::{webhook::HookError, webhook_request::WebhookRequest};
+
+#[derive(Debug, Default, Deserialize)]
+struct Notification {
+  message: String,
+  title: Option<String>,
+  #[serde(default)]
+  data: Data,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Data {
+  url: Option<String>,
+  #[serde(default)]
+  notice: bool,
+}
+
+pub fn translate(body: &[u8]) -> Result<Option<WebhookRequest>> {
+  let notification = if body.starts_with(b"{") {
+    parse_json(body)?
+  } else {
+    Notification {
+      message: form_field(body, "message")
+        .ok_or_else(|| HookError::InvalidPayload("The notification has no message".into()))?,
+      title: form_field(body, "title"),
+      ..Default::default()
+    }
+  };
+
+  let message = render(&notification);
+  if message.is_empty() {
+    return Ok(None);
+  }
+  let request = message.into_request();
+  Ok(Some(if notification.data.notice {
+    request.as_notice()
+  } else {
+    request
+  }))
+}
+
+fn render(notification: &Notification) -> Message {
+  let mut message = Message::default();
+  let url = notification.data.url.as_deref();
+  match notification
+    .title
+    .as_deref()
+    .filter(|title| !title.is_empty())
+  {
+    Some(title) => {
+      message.title(title, url).newline();
+    }
+    None => {
+      if let Some(url) = url {
+        message.link(url, url).newline();
+      }
+    }
+  }
+  for line in notification.message.lines() {
+    message.text(line).newline();
+  }
+  message
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_render() -> Result<()> {
+    let notification: Notification = serde_json::from_str(
+      r#"{
+        "message": "The front door opened\nNobody is home",
+        "title": "Front door",
+        "data": {"url": "https://ha.example.com/lovelace/0"}
+      }"#,
+    )?;
+    let message = render(&notification);
+    assert_eq!(
+      message.plain,
+      "Front door (https://ha.example.com/lovelace/0)\nThe front door opened\nNobody is home\n"
+    );
+    assert_eq!(
+      message.html,
+      "<b><a href=\"https://ha.example.com/lovelace/0\">Front door</a></b><br>\
+      The front door opened<br>Nobody is home<br>"
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn test_form() -> Result<()> {
+    assert!(translate(b"message=Washer+done&title=Laundry")?.is_some());
+    assert!(translate(b"title=Laundry").is_err());
+    assert!(translate(br#"{"message": ""}"#)?.is_none());
+    Ok(())
+  }
+}
@@ -0,0 +1,96 @@
+// The Jenkins Notification plugin's JSON. It notifies at each phase of a build, and only the start
+// and the final result are posted, since COMPLETED and FINALIZED carry the same status.
+use anyhow::Result;
+use serde::Deserialize;
+
+use super::{parse_json, Message};
+use crate::webhook_request::WebhookRequest;
+
+#[derive(Debug, Deserialize)]
+struct Job {
+  name: String,
+  build: Build,
+}
+
+#[derive(Debug, Deserialize)]
+struct Build {
+  number: u64,
+  phase: String,
+  status: Option<String>,
+  full_url: Option<String>,
+  #[serde(default)]
+  scm: Scm,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Scm {
+  branch: Option<String>,
+}
+
+pub fn translate(body: &[u8]) -> Result<Option<WebhookRequest>> {
+  let job: Job = parse_json(body)?;
+  Ok(render(&job).map(Message::into_request))
+}
+
+fn status_color(status: &str) -> &'static str {
+  match status {
+    "SUCCESS" => "#1ea97c",
+    "FAILURE" => "#d24939",
+    "UNSTABLE" => "#f0b000",
+    _ => "#7d7d7d",
+  }
+}
+
+fn render(job: &Job) -> Option<Message> {
+  let build = &job.build;
+  let mut message = Message::default();
+  message
+    .text("[")
+    .text(&job.name)
+    .text("] ")
+    .title(
+      &format!("Build #{}", build.number),
+      build.full_url.as_deref(),
+    )
+    .text(" ");
+  match (build.phase.as_str(), build.status.as_deref()) {
+    ("STARTED", _) => message.text("started"),
+    ("FINALIZED", Some(status)) => message.colored(status_color(status), &status.to_lowercase()),
+    _ => return None,
+  };
+  if let Some(branch) = &build.scm.branch {
+    message.text(" on ").code(branch);
+  }
+  Some(message)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_render() -> Result<()> {
+    let body = r#"{
+      "name": "backend",
+      "url": "job/backend/",
+      "build": {
+        "full_url": "http://jenkins/job/backend/12/",
+        "number": 12,
+        "phase": "FINALIZED",
+        "status": "FAILURE",
+        "url": "job/backend/12/",
+        "scm": {"branch": "origin/main", "commit": "abc"}
+      }
+    }"#;
+    let message = render(&serde_json::from_str(body)?).unwrap();
+    assert_eq!(
+      message.html,
+      "[backend] <b><a href=\"http://jenkins/job/backend/12/\">Build #12</a></b> \
+      <font data-mx-color=\"#d24939\">failure</font> on <code>origin/main</code>"
+    );
+
+    let completed = body.replace("FINALIZED", "COMPLETED");
+    assert!(translate(completed.as_bytes())?.is_none());
+    Ok(())
+  }
+}
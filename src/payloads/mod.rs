@@ -0,0 +1,643 @@
+// Other services' webhook payloads, posted to a hook's URL with the service's name appended, like
+// /api/v1/matrix/hook/<id>/slack. Each is translated into a message as if it came in the bridge's
+// own format, so everything else about posting it stays the same.
+use std::{
+  collections::BTreeMap,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use hmac::{Hmac, Mac, NewMac};
+use matrix_sdk::Client;
+use percent_encoding::percent_decode_str;
+use serde::de::DeserializeOwned;
+use sha2::Sha256;
+
+use crate::{
+  bot, config::Config, store::Store, webhook::HookError, webhook_request::WebhookRequest,
+};
+
+pub mod alertmanager;
+pub mod ci;
+pub mod custom;
+pub mod discord;
+pub mod docker;
+pub mod github;
+pub mod gitlab;
+pub mod grafana;
+pub mod homeassistant;
+pub mod jenkins;
+pub mod monitors;
+pub mod opsgenie;
+pub mod pagerduty;
+pub mod sentry;
+pub mod slack;
+pub mod sns;
+pub mod stripe;
+pub mod teams;
+
+/// The request, translated for the service it's from. None if it's valid but there's nothing to
+/// post, like a service checking that the URL works. The client is the bridge bot's, for uploading
+/// images the payload links to.
+pub async fn translate(
+  service: &str,
+  headers: &http::HeaderMap,
+  body: &[u8],
+  hook_id: &str,
+  store: &Store,
+  config: &Config,
+  client: &Client,
+) -> Result<Option<WebhookRequest>> {
+  let secret = store.get_setting(&secret_setting(hook_id)).await?;
+  let events = store.get_setting(&events_setting(hook_id)).await?;
+  let min_level = store.get_setting(&min_level_setting(hook_id)).await?;
+  match service {
+    "actions" | "circleci" | "travis" => {
+      if let (Some(secret), "actions") = (&secret, service) {
+        verify_hub_signature(secret, headers, body)?;
+      }
+      Ok(
+        ci::parse(service, body)?
+          .filter(|run| is_event_wanted(events.as_deref(), run.outcome()))
+          .map(ci::Run::into_request),
+      )
+    }
+    "alertmanager" => alertmanager::translate(body),
+    "custom" => {
+      let template = match store.get_setting(&template_setting(hook_id)).await? {
+        Some(template) => Some(template),
+        None => config.templates.get(hook_id).cloned(),
+      };
+      custom::translate(template.as_deref(), body)
+    }
+    "discord" => discord::translate(body).map(Some),
+    "docker" => docker::translate(body),
+    "github" => {
+      if let Some(secret) = &secret {
+        verify_hub_signature(secret, headers, body)?;
+      }
+      let event = github::event(headers)?;
+      if !is_event_wanted(events.as_deref(), event) {
+        return Ok(None);
+      }
+      github::translate(event, body)
+    }
+    "gitlab" => {
+      if let Some(secret) = &secret {
+        verify_token(secret, headers, gitlab::TOKEN_HEADER)?;
+      }
+      let event = gitlab::event(body)?;
+      if !is_event_wanted(events.as_deref(), &event) {
+        return Ok(None);
+      }
+      gitlab::translate(&event, body)
+    }
+    "grafana" => grafana::translate(body, client).await,
+    "healthchecks" => monitors::healthchecks(body),
+    "homeassistant" => homeassistant::translate(body),
+    "jenkins" => jenkins::translate(body),
+    "opsgenie" => {
+      let event = opsgenie::event(body)?;
+      if !is_event_wanted(events.as_deref(), &event) {
+        return Ok(None);
+      }
+      opsgenie::translate(body)
+    }
+    "pagerduty" => {
+      if let Some(secret) = &secret {
+        verify_pagerduty_signature(secret, headers, body)?;
+      }
+      let event = pagerduty::event(body)?;
+      if !is_event_wanted(events.as_deref(), &event) {
+        return Ok(None);
+      }
+      pagerduty::translate(&event, body)
+    }
+    "sentry" => sentry::translate(body, min_level.as_deref()),
+    "slack" => slack::translate(body).map(Some),
+    "sns" => sns::translate(body).await,
+    "stripe" => {
+      if let Some(secret) = &secret {
+        verify_stripe_signature(secret, headers, body, SystemTime::now())?;
+      }
+      let event = stripe::event(body)?;
+      if !is_event_wanted(events.as_deref(), &event) {
+        return Ok(None);
+      }
+      stripe::translate(body)
+    }
+    "teams" => teams::translate(body).map(Some),
+    "uptime-kuma" => monitors::uptime_kuma(body),
+    _ => Err(HookError::UnknownService.into()),
+  }
+}
+
+/// The setting holding the secret a hook's signed services are checked against, set with
+/// `!webhook secret`. Without one, requests are only checked for having the hook's URL.
+pub fn secret_setting(hook_id: &str) -> String {
+  format!("secret:{}", hook_id)
+}
+
+/// The setting holding the comma separated event types a hook posts, for services that send
+/// several, set with `!webhook events`. Without one, every type the bridge knows is posted.
+pub fn events_setting(hook_id: &str) -> String {
+  format!("events:{}", hook_id)
+}
+
+/// The setting holding the least severe level a hook posts, for services with levels like Sentry,
+/// set with `!webhook level`
+pub fn min_level_setting(hook_id: &str) -> String {
+  format!("minLevel:{}", hook_id)
+}
+
+/// The setting holding the template `/custom` requests are rendered with, set with
+/// `!webhook template`. It wins over one in the config.
+pub fn template_setting(hook_id: &str) -> String {
+  format!("template:{}", hook_id)
+}
+
+/// The setting holding the templates a hook's requests can name, as a JSON object by name, set
+/// with `!webhook templates`
+pub fn named_templates_setting(hook_id: &str) -> String {
+  format!("namedTemplates:{}", hook_id)
+}
+
+/// The hook's named templates, by name
+pub async fn named_templates(store: &Store, hook_id: &str) -> Result<BTreeMap<String, String>> {
+  match store.get_setting(&named_templates_setting(hook_id)).await? {
+    Some(templates) => Ok(serde_json::from_str(&templates)?),
+    None => Ok(BTreeMap::new()),
+  }
+}
+
+fn is_event_wanted(events: Option<&str>, event: &str) -> bool {
+  match events {
+    Some(events) => events.split(',').any(|wanted| wanted == event),
+    None => true,
+  }
+}
+
+// A header holding the secret itself
+fn verify_token(secret: &str, headers: &http::HeaderMap, header: &str) -> Result<()> {
+  let token = headers
+    .get(header)
+    .map(|token| token.as_bytes())
+    .unwrap_or_default();
+  if !is_secret(secret, token) {
+    return Err(HookError::BadSignature.into());
+  }
+  Ok(())
+}
+
+/// Whether the token is the secret, compared without stopping at the first difference so the time
+/// taken doesn't give away how much of it was right
+pub fn is_secret(secret: &str, token: &[u8]) -> bool {
+  let difference = token
+    .iter()
+    .zip(secret.as_bytes())
+    .fold(0, |difference, (a, b)| difference | (a ^ b));
+  token.len() == secret.len() && difference == 0
+}
+
+// GitHub's X-Hub-Signature-256, the hex HMAC-SHA256 of the body keyed with the secret
+fn verify_hub_signature(secret: &str, headers: &http::HeaderMap, body: &[u8]) -> Result<()> {
+  let matches = headers
+    .get("X-Hub-Signature-256")
+    .and_then(|signature| signature.to_str().ok())
+    .and_then(|signature| signature.strip_prefix("sha256="))
+    .map_or(false, |signature| is_signed_with(secret, body, signature));
+  if !matches {
+    return Err(HookError::BadSignature.into());
+  }
+  Ok(())
+}
+
+// Like GitHub's, except with a v1= prefix, and any of several comma separated signatures may match
+// while the secret is being rotated
+fn verify_pagerduty_signature(secret: &str, headers: &http::HeaderMap, body: &[u8]) -> Result<()> {
+  let matches = headers
+    .get(pagerduty::SIGNATURE_HEADER)
+    .and_then(|signatures| signatures.to_str().ok())
+    .map_or(false, |signatures| {
+      signatures
+        .split(',')
+        .filter_map(|signature| signature.trim().strip_prefix("v1="))
+        .any(|signature| is_signed_with(secret, body, signature))
+    });
+  if !matches {
+    return Err(HookError::BadSignature.into());
+  }
+  Ok(())
+}
+
+// Signatures of the time and the body together, which are turned away once they're old so they
+// can't be replayed
+fn verify_stripe_signature(
+  secret: &str,
+  headers: &http::HeaderMap,
+  body: &[u8],
+  now: SystemTime,
+) -> Result<()> {
+  const TOLERANCE: Duration = Duration::from_secs(5 * 60);
+
+  let header = headers
+    .get(stripe::SIGNATURE_HEADER)
+    .and_then(|header| header.to_str().ok())
+    .ok_or(HookError::BadSignature)?;
+  let parts = header
+    .split(',')
+    .filter_map(|part| part.trim().split_once('='))
+    .collect::<Vec<_>>();
+  let timestamp = parts
+    .iter()
+    .find(|(key, _)| *key == "t")
+    .map(|(_, timestamp)| *timestamp)
+    .ok_or(HookError::BadSignature)?;
+  let signed_at = timestamp
+    .parse::<u64>()
+    .map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds))
+    .map_err(|_| HookError::BadSignature)?;
+  let age = now
+    .duration_since(signed_at)
+    .unwrap_or_else(|e| e.duration());
+  if age > TOLERANCE {
+    return Err(HookError::BadSignature.into());
+  }
+
+  let signed = [timestamp.as_bytes(), b".", body].concat();
+  let matches = parts
+    .iter()
+    .filter(|(key, _)| *key == "v1")
+    .any(|(_, signature)| is_signed_with(secret, &signed, signature));
+  if !matches {
+    return Err(HookError::BadSignature.into());
+  }
+  Ok(())
+}
+
+// Whether the hex signature is the HMAC-SHA256 of the body keyed with the secret
+fn is_signed_with(secret: &str, body: &[u8], signature: &str) -> bool {
+  let signature = match hex::decode(signature) {
+    Ok(signature) => signature,
+    Err(_) => return false,
+  };
+  let mut mac =
+    Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+  mac.update(body);
+  mac.verify(&signature).is_ok()
+}
+
+// The JSON in a form-encoded body, which is all older integrations can send
+fn form_payload(body: &[u8]) -> Option<String> {
+  form_field(body, "payload")
+}
+
+fn form_field(body: &[u8], name: &str) -> Option<String> {
+  let body = std::str::from_utf8(body).ok()?;
+  let encoded = body.split('&').find_map(|pair| {
+    let (key, value) = pair.split_once('=')?;
+    Some(value).filter(|_| key == name)
+  })?;
+  percent_decode_str(&encoded.replace('+', " "))
+    .decode_utf8()
+    .ok()
+    .map(|value| value.to_string())
+}
+
+fn parse_json<T: DeserializeOwned>(body: &[u8]) -> Result<T> {
+  serde_json::from_slice(body).map_err(|e| HookError::InvalidPayload(e.to_string()).into())
+}
+
+/// The plain and html versions of a message, written side by side
+#[derive(Debug, Default)]
+pub struct Message {
+  plain: String,
+  html: String,
+}
+
+impl Message {
+  pub fn text(&mut self, text: &str) -> &mut Self {
+    self.plain.push_str(text);
+    self.html.push_str(&bot::escape_html(text));
+    self
+  }
+
+  pub fn bold(&mut self, text: &str) -> &mut Self {
+    self.plain.push_str(text);
+    self
+      .html
+      .push_str(&format!("<b>{}</b>", bot::escape_html(text)));
+    self
+  }
+
+  pub fn italic(&mut self, text: &str) -> &mut Self {
+    self.plain.push_str(text);
+    self
+      .html
+      .push_str(&format!("<i>{}</i>", bot::escape_html(text)));
+    self
+  }
+
+  pub fn code(&mut self, text: &str) -> &mut Self {
+    self.plain.push_str(text);
+    self
+      .html
+      .push_str(&format!("<code>{}</code>", bot::escape_html(text)));
+    self
+  }
+
+  /// The link's text, with the URL after it in the plain version
+  pub fn link(&mut self, text: &str, url: &str) -> &mut Self {
+    if text.is_empty() || text == url {
+      self.plain.push_str(url);
+    } else {
+      self.plain.push_str(&format!("{} ({})", text, url));
+    }
+    self.html.push_str(&format!(
+      "<a href=\"{}\">{}</a>",
+      bot::escape_html(url),
+      bot::escape_html(if text.is_empty() { url } else { text })
+    ));
+    self
+  }
+
+  /// Bold text, linked if there's a URL
+  pub fn title(&mut self, text: &str, url: Option<&str>) -> &mut Self {
+    match url {
+      Some(url) => {
+        self.plain.push_str(&format!("{} ({})", text, url));
+        self.html.push_str(&format!(
+          "<b><a href=\"{}\">{}</a></b>",
+          bot::escape_html(url),
+          bot::escape_html(text)
+        ));
+      }
+      None => {
+        self.bold(text);
+      }
+    }
+    self
+  }
+
+  /// Text in a color like #ff0000, which only shows in the html version. Anything else isn't a
+  /// color clients accept, and is left out.
+  pub fn colored(&mut self, color: &str, text: &str) -> &mut Self {
+    if !is_hex_color(color) {
+      return self.text(text);
+    }
+    self.plain.push_str(text);
+    self.html.push_str(&format!(
+      "<font data-mx-color=\"{}\">{}</font>",
+      color,
+      bot::escape_html(text)
+    ));
+    self
+  }
+
+  /// A bar in a color like #ff0000, only in the html version, for what other services show with
+  /// a colored border
+  pub fn color_bar(&mut self, color: &str) -> &mut Self {
+    if is_hex_color(color) {
+      self
+        .html
+        .push_str(&format!("<font data-mx-color=\"{}\">▌</font> ", color));
+    }
+    self
+  }
+
+  /// CommonMark, which is also the plain text version
+  pub fn markdown(&mut self, raw: &str) -> &mut Self {
+    self.rich(raw, &markdown_html(raw))
+  }
+
+  /// Plain text and html that were already written
+  pub fn rich(&mut self, plain: &str, html: &str) -> &mut Self {
+    self.plain.push_str(plain);
+    self.html.push_str(html);
+    self
+  }
+
+  pub fn append(&mut self, other: Message) -> &mut Self {
+    self.rich(&other.plain, &other.html)
+  }
+
+  pub fn newline(&mut self) -> &mut Self {
+    self.plain.push('\n');
+    self.html.push_str("<br>");
+    self
+  }
+
+  /// Starts a new line, unless the message is empty or already at the start of one
+  pub fn line(&mut self) -> &mut Self {
+    if !self.plain.is_empty() && !self.plain.ends_with('\n') {
+      self.newline();
+    }
+    self
+  }
+
+  /// Another message, set apart as a quote on lines of its own
+  pub fn quote(&mut self, quoted: Message) -> &mut Self {
+    if quoted.is_empty() {
+      return self;
+    }
+    if !self.plain.is_empty() && !self.plain.ends_with('\n') {
+      self.plain.push('\n');
+    }
+    let lines = quoted
+      .plain
+      .lines()
+      .map(|line| format!("> {}", line))
+      .collect::<Vec<_>>();
+    self.plain.push_str(&lines.join("\n"));
+    self.plain.push('\n');
+    self
+      .html
+      .push_str(&format!("<blockquote>{}</blockquote>", quoted.html));
+    self
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.plain.trim().is_empty()
+  }
+
+  /// Names and their values, as a table in the html and a line each in the plain version
+  pub fn table(&mut self, rows: &[(&str, &str)]) -> &mut Self {
+    if rows.is_empty() {
+      return self;
+    }
+    self.line();
+    self.html.push_str("<table>");
+    for (name, value) in rows {
+      self.plain.push_str(&format!("{}: {}\n", name, value));
+      self.html.push_str(&format!(
+        "<tr><th>{}</th><td>{}</td></tr>",
+        bot::escape_html(name),
+        bot::escape_html(value)
+      ));
+    }
+    self.html.push_str("</table>");
+    self
+  }
+
+  /// The plain and html versions
+  pub fn into_parts(self) -> (String, String) {
+    (
+      self.plain.trim_end().to_string(),
+      self.html.trim_end_matches("<br>").to_string(),
+    )
+  }
+
+  pub fn into_request(self) -> WebhookRequest {
+    let (plain, html) = self.into_parts();
+    WebhookRequest::translated(plain, html)
+  }
+}
+
+/// The project an event is about, like [owner/repo], to start its message
+pub fn project_prefix(name: &str, url: &str) -> Message {
+  let mut message = Message::default();
+  message.text("[").link(name, url).text("] ");
+  message
+}
+
+/// Markdown as html, without the paragraph around it if it's only one line
+pub fn markdown_html(raw: &str) -> String {
+  let html = WebhookRequest::markdown_to_html(raw);
+  match html
+    .strip_prefix("<p>")
+    .and_then(|html| html.strip_suffix("</p>"))
+  {
+    Some(inner) if !inner.contains("<p>") => inner.to_string(),
+    _ => html,
+  }
+}
+
+// Red for P1 down to grey for P5, the way incident tools color their priorities
+fn priority_color(priority: &str) -> &'static str {
+  match priority {
+    "P1" => "#d92b2b",
+    "P2" => "#f06f26",
+    "P3" => "#f5b800",
+    "P4" => "#3d8be8",
+    _ => "#8a8a8a",
+  }
+}
+
+fn is_hex_color(color: &str) -> bool {
+  color.len() == 7 && color.starts_with('#') && color[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_is_secret() {
+    assert!(is_secret("hunter2", b"hunter2"));
+    assert!(!is_secret("hunter2", b"hunter3"));
+    assert!(!is_secret("hunter2", b"hunter"));
+    assert!(!is_secret("hunter2", b"hunter22"));
+  }
+
+  #[test]
+  fn test_message() {
+    let mut message = Message::default();
+    message
+      .bold("Build #3")
+      .text(" failed <again>")
+      .line()
+      .link("log", "https://ci.example.com/3")
+      .line()
+      .colored("#ff0000", "red")
+      .colored("red", " not a color");
+    let mut quoted = Message::default();
+    quoted.text("one").newline().text("two");
+    message.quote(quoted);
+
+    assert_eq!(
+      message.plain,
+      "Build #3 failed <again>\nlog (https://ci.example.com/3)\nred not a color\n> one\n> two\n"
+    );
+    assert_eq!(
+      message.html,
+      "<b>Build #3</b> failed &lt;again&gt;<br><a href=\"https://ci.example.com/3\">log</a><br>\
+      <font data-mx-color=\"#ff0000\">red</font> not a color<blockquote>one<br>two</blockquote>"
+    );
+  }
+
+  #[test]
+  fn test_verify_hub_signature() {
+    let mut headers = http::HeaderMap::new();
+    assert!(verify_hub_signature("secret", &headers, b"{}").is_err());
+    headers.insert(
+      "X-Hub-Signature-256",
+      "sha256=77325902caca812dc259733aacd046b73817372c777b8d95b402647474516e13"
+        .parse()
+        .unwrap(),
+    );
+    assert!(verify_hub_signature("secret", &headers, b"{}").is_ok());
+    assert!(verify_hub_signature("other", &headers, b"{}").is_err());
+  }
+
+  #[test]
+  fn test_verify_pagerduty_signature() {
+    let mut headers = http::HeaderMap::new();
+    headers.insert(
+      pagerduty::SIGNATURE_HEADER,
+      "v1=00, v1=77325902caca812dc259733aacd046b73817372c777b8d95b402647474516e13"
+        .parse()
+        .unwrap(),
+    );
+    assert!(verify_pagerduty_signature("secret", &headers, b"{}").is_ok());
+    assert!(verify_pagerduty_signature("other", &headers, b"{}").is_err());
+  }
+
+  #[test]
+  fn test_verify_stripe_signature() {
+    let mut headers = http::HeaderMap::new();
+    headers.insert(
+      stripe::SIGNATURE_HEADER,
+      "t=1700000000,v1=c8cf6ed9fd9120874a1792902ffdf5b69559118b76d10974da8a20cf21f94bc5"
+        .parse()
+        .unwrap(),
+    );
+    let signed_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    assert!(verify_stripe_signature("whsec_1", &headers, b"{}", signed_at).is_ok());
+    assert!(verify_stripe_signature("whsec_2", &headers, b"{}", signed_at).is_err());
+    let later = signed_at + Duration::from_secs(3600);
+    assert!(verify_stripe_signature("whsec_1", &headers, b"{}", later).is_err());
+  }
+
+  #[test]
+  fn test_verify_token() {
+    let mut headers = http::HeaderMap::new();
+    assert!(verify_token("secret", &headers, "X-Token").is_err());
+    headers.insert("X-Token", "secreT".parse().unwrap());
+    assert!(verify_token("secret", &headers, "X-Token").is_err());
+    headers.insert("X-Token", "secret".parse().unwrap());
+    assert!(verify_token("secret", &headers, "X-Token").is_ok());
+    assert!(verify_token("secret2", &headers, "X-Token").is_err());
+  }
+
+  #[test]
+  fn test_is_event_wanted() {
+    assert!(is_event_wanted(None, "push"));
+    assert!(is_event_wanted(Some("push,pipeline"), "pipeline"));
+    assert!(!is_event_wanted(Some("push,pipeline"), "issue"));
+  }
+
+  #[test]
+  fn test_markdown_html() {
+    assert_eq!(markdown_html("**hi**"), "<strong>hi</strong>");
+    assert_eq!(markdown_html("a\n\nb"), "<p>a</p>\n<p>b</p>");
+  }
+
+  #[test]
+  fn test_form_payload() {
+    assert_eq!(
+      form_payload(b"payload=%7B%22text%22%3A+%22hi%22%7D").as_deref(),
+      Some(r#"{"text": "hi"}"#)
+    );
+    assert_eq!(form_payload(br#"{"text": "hi"}"#), None);
+  }
+}
@@ -0,0 +1,136 @@
+// Uptime monitors, which only ever say whether something is up or down. Uptime Kuma sends its own
+// JSON, and healthchecks.io sends whatever body it's set up with, which the README suggests as
+// {"name": "$NAME", "status": "$STATUS"}.
+use anyhow::Result;
+use serde::Deserialize;
+
+use super::{parse_json, Message};
+use crate::webhook_request::WebhookRequest;
+
+#[derive(Debug, Deserialize)]
+struct UptimeKuma {
+  heartbeat: Option<Heartbeat>,
+  monitor: Option<Monitor>,
+  #[serde(default)]
+  msg: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Heartbeat {
+  status: u8,
+  #[serde(default)]
+  msg: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Monitor {
+  name: String,
+  url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Check {
+  name: String,
+  status: String,
+  url: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Status {
+  Up,
+  Down,
+  Other(&'static str),
+}
+
+pub fn uptime_kuma(body: &[u8]) -> Result<Option<WebhookRequest>> {
+  let payload: UptimeKuma = parse_json(body)?;
+  let message = match (&payload.heartbeat, &payload.monitor) {
+    (Some(heartbeat), Some(monitor)) => {
+      let status = match heartbeat.status {
+        0 => Status::Down,
+        1 => Status::Up,
+        2 => Status::Other("pending"),
+        _ => Status::Other("under maintenance"),
+      };
+      // Only HTTP monitors have a URL worth linking
+      let url = monitor.url.as_deref().filter(|url| url.starts_with("http"));
+      render(&monitor.name, url, status, &heartbeat.msg)
+    }
+    // Kuma's test notifications are only a message
+    _ if !payload.msg.is_empty() => {
+      let mut message = Message::default();
+      message.text(&payload.msg);
+      message
+    }
+    _ => return Ok(None),
+  };
+  Ok(Some(message.into_request()))
+}
+
+pub fn healthchecks(body: &[u8]) -> Result<Option<WebhookRequest>> {
+  let check: Check = parse_json(body)?;
+  let status = match check.status.as_str() {
+    "up" => Status::Up,
+    "down" => Status::Down,
+    _ => Status::Other("in an unknown state"),
+  };
+  Ok(Some(
+    render(&check.name, check.url.as_deref(), status, "").into_request(),
+  ))
+}
+
+fn render(name: &str, url: Option<&str>, status: Status, detail: &str) -> Message {
+  let mut message = Message::default();
+  match status {
+    Status::Up => message.text("✅ ").title(name, url).text(" is up"),
+    Status::Down => message.text("🔴 ").title(name, url).text(" is down"),
+    Status::Other(state) => message
+      .text("⚪ ")
+      .title(name, url)
+      .text(&format!(" is {}", state)),
+  };
+  if !detail.is_empty() {
+    message.text(": ").text(detail);
+  }
+  message
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_render() {
+    let message = render(
+      "NAS",
+      Some("http://nas.lan"),
+      Status::Down,
+      "connect ECONNREFUSED",
+    );
+    assert_eq!(
+      message.plain,
+      "🔴 NAS (http://nas.lan) is down: connect ECONNREFUSED"
+    );
+    assert_eq!(
+      render("Backups", None, Status::Up, "").plain,
+      "✅ Backups is up"
+    );
+  }
+
+  #[test]
+  fn test_payloads() -> Result<()> {
+    assert!(uptime_kuma(
+      r#"{
+        "heartbeat": {"status": 1, "msg": "200 - OK", "ping": 12},
+        "monitor": {"name": "NAS", "url": "http://nas.lan", "type": "http"},
+        "msg": "[NAS] [✅ Up] 200 - OK"
+      }"#
+        .as_bytes()
+    )?
+    .is_some());
+    assert!(uptime_kuma(br#"{"heartbeat": null, "monitor": null, "msg": ""}"#)?.is_none());
+    assert!(healthchecks(br#"{"name": "Backups", "status": "down"}"#)?.is_some());
+    assert!(healthchecks(br#"{"status": "down"}"#).is_err());
+    Ok(())
+  }
+}
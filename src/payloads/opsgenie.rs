@@ -0,0 +1,106 @@
+// Opsgenie's alert notifications, from a Webhook integration. Alerts being created, acknowledged
+// and closed are posted, and the rest of what happens to them isn't.
+use anyhow::Result;
+use serde::Deserialize;
+
+use super::{parse_json, priority_color, Message};
+use crate::webhook_request::WebhookRequest;
+
+#[derive(Debug, Deserialize)]
+struct Payload {
+  action: String,
+  alert: Alert,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Alert {
+  alert_id: String,
+  #[serde(default)]
+  message: String,
+  tiny_id: Option<String>,
+  priority: Option<String>,
+  username: Option<String>,
+  #[serde(default)]
+  tags: Vec<String>,
+}
+
+/// The action, like Create or Acknowledge
+pub fn event(body: &[u8]) -> Result<String> {
+  parse_json::<Payload>(body).map(|payload| payload.action)
+}
+
+pub fn translate(body: &[u8]) -> Result<Option<WebhookRequest>> {
+  let payload: Payload = parse_json(body)?;
+  Ok(render(&payload).map(Message::into_request))
+}
+
+fn render(payload: &Payload) -> Option<Message> {
+  let action = match payload.action.as_str() {
+    "Create" => "created",
+    "Acknowledge" => "acknowledged",
+    "UnAcknowledge" => "unacknowledged",
+    "Escalate" => "escalated",
+    "Close" => "closed",
+    _ => return None,
+  };
+  let alert = &payload.alert;
+
+  let mut message = Message::default();
+  if let Some(priority) = &alert.priority {
+    message
+      .colored(priority_color(priority), &format!("[{}]", priority))
+      .text(" ");
+  }
+  let title = match &alert.tiny_id {
+    Some(tiny_id) => format!("#{} {}", tiny_id, alert.message),
+    None => alert.message.clone(),
+  };
+  message
+    .title(
+      &title,
+      Some(&format!(
+        "https://app.opsgenie.com/alert/detail/{}/details",
+        alert.alert_id
+      )),
+    )
+    .text(&format!(" was {}", action));
+  // Opsgenie names itself as the user for what it did on its own
+  if let Some(username) = alert.username.as_ref().filter(|name| *name != "System") {
+    message.text(&format!(" by {}", username));
+  }
+  if !alert.tags.is_empty() {
+    message.line().text("Tags: ").italic(&alert.tags.join(", "));
+  }
+  Some(message)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_render() -> Result<()> {
+    let body = br#"{
+      "action": "Create",
+      "alert": {
+        "alertId": "70413a06",
+        "message": "Disk full on db-1",
+        "tinyId": "42",
+        "priority": "P3",
+        "username": "System",
+        "tags": ["db", "disk"]
+      },
+      "source": {"name": "", "type": "web"},
+      "integrationName": "Matrix"
+    }"#;
+    assert_eq!("Create", event(body)?);
+    let message = render(&serde_json::from_slice(body)?).unwrap();
+    assert_eq!(
+      message.plain,
+      "[P3] #42 Disk full on db-1 (https://app.opsgenie.com/alert/detail/70413a06/details) was \
+      created\nTags: db, disk"
+    );
+    Ok(())
+  }
+}
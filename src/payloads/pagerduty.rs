@@ -0,0 +1,129 @@
+// PagerDuty's V3 webhooks. Only incident events are posted, since the rest are about PagerDuty's
+// own setup.
+use anyhow::Result;
+use serde::Deserialize;
+
+use super::{parse_json, priority_color, Message};
+use crate::webhook_request::WebhookRequest;
+
+/// Holds `v1=` and the hex HMAC-SHA256 of the body, once for each of the subscription's secrets
+pub const SIGNATURE_HEADER: &str = "X-PagerDuty-Signature";
+
+#[derive(Debug, Deserialize)]
+struct Payload {
+  event: Event,
+}
+
+#[derive(Debug, Deserialize)]
+struct Event {
+  event_type: String,
+  agent: Option<Reference>,
+  data: Incident,
+}
+
+#[derive(Debug, Deserialize)]
+struct Reference {
+  summary: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Incident {
+  #[serde(default)]
+  number: u64,
+  #[serde(default)]
+  title: String,
+  html_url: Option<String>,
+  service: Option<Reference>,
+  priority: Option<Reference>,
+  urgency: Option<String>,
+}
+
+/// The event type, like incident.triggered
+pub fn event(body: &[u8]) -> Result<String> {
+  parse_json::<Payload>(body).map(|payload| payload.event.event_type)
+}
+
+pub fn translate(event: &str, body: &[u8]) -> Result<Option<WebhookRequest>> {
+  if !event.starts_with("incident.") {
+    return Ok(None);
+  }
+  let payload: Payload = parse_json(body)?;
+  Ok(render(&payload.event).map(Message::into_request))
+}
+
+fn render(event: &Event) -> Option<Message> {
+  let action = match event.event_type.as_str() {
+    "incident.triggered" => "triggered",
+    "incident.acknowledged" => "acknowledged",
+    "incident.unacknowledged" => "unacknowledged",
+    "incident.escalated" => "escalated",
+    "incident.reassigned" => "reassigned",
+    "incident.resolved" => "resolved",
+    _ => return None,
+  };
+  let incident = &event.data;
+  // High urgency incidents without a priority are as pressing as P1s
+  let priority = incident
+    .priority
+    .as_ref()
+    .map(|priority| priority.summary.as_str())
+    .or_else(|| match incident.urgency.as_deref() {
+      Some("high") => Some("P1"),
+      _ => None,
+    });
+
+  let mut message = Message::default();
+  if let Some(priority) = priority {
+    message
+      .colored(priority_color(priority), &format!("[{}]", priority))
+      .text(" ");
+  }
+  message
+    .title(
+      &format!("#{} {}", incident.number, incident.title),
+      incident.html_url.as_deref(),
+    )
+    .text(&format!(" was {}", action));
+  if let Some(agent) = &event.agent {
+    message.text(&format!(" by {}", agent.summary));
+  }
+  if let Some(service) = &incident.service {
+    message.line().text("Service: ").italic(&service.summary);
+  }
+  Some(message)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_render() -> Result<()> {
+    let body = br#"{
+      "event": {
+        "id": "01",
+        "event_type": "incident.acknowledged",
+        "resource_type": "incident",
+        "agent": {"summary": "Dana", "type": "user_reference"},
+        "data": {
+          "number": 7,
+          "title": "Checkout is down",
+          "html_url": "https://acme.pagerduty.com/incidents/PGR0VU2",
+          "service": {"summary": "Checkout"},
+          "priority": null,
+          "urgency": "high"
+        }
+      }
+    }"#;
+    assert_eq!("incident.acknowledged", event(body)?);
+    let payload: Payload = serde_json::from_slice(body)?;
+    let message = render(&payload.event).unwrap();
+    assert_eq!(
+      message.plain,
+      "[P1] #7 Checkout is down (https://acme.pagerduty.com/incidents/PGR0VU2) was acknowledged \
+      by Dana\nService: Checkout"
+    );
+    assert!(translate("service.updated", b"{}")?.is_none());
+    Ok(())
+  }
+}
@@ -0,0 +1,172 @@
+// Sentry's issue alerts, from the legacy WebHooks plugin as well as from internal integrations,
+// which nest the event under `data`
+use anyhow::Result;
+use serde::Deserialize;
+
+use super::{parse_json, Message};
+use crate::webhook_request::WebhookRequest;
+
+/// Sentry's levels from least to most severe, for picking the least a hook posts
+pub const LEVELS: &[&str] = &["debug", "info", "warning", "error", "fatal"];
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Payload {
+  Integration { data: IntegrationData },
+  Plugin(PluginAlert),
+}
+
+#[derive(Debug, Deserialize)]
+struct IntegrationData {
+  event: IntegrationEvent,
+}
+
+#[derive(Debug, Deserialize)]
+struct IntegrationEvent {
+  title: String,
+  #[serde(default)]
+  culprit: String,
+  #[serde(default)]
+  level: String,
+  web_url: Option<String>,
+  // Only the project's id is given, not its name
+  project: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PluginAlert {
+  #[serde(alias = "project_slug")]
+  project_name: String,
+  message: String,
+  #[serde(default)]
+  culprit: String,
+  #[serde(default)]
+  level: String,
+  url: Option<String>,
+}
+
+// What both kinds of payload say about the issue
+struct Issue<'a> {
+  project: String,
+  title: &'a str,
+  culprit: &'a str,
+  level: &'a str,
+  url: Option<&'a str>,
+}
+
+impl Payload {
+  fn issue(&self) -> Issue<'_> {
+    match self {
+      Payload::Integration { data } => Issue {
+        project: data
+          .event
+          .project
+          .map(|project| format!("project {}", project))
+          .unwrap_or_else(|| "Sentry".to_string()),
+        title: &data.event.title,
+        culprit: &data.event.culprit,
+        level: &data.event.level,
+        url: data.event.web_url.as_deref(),
+      },
+      Payload::Plugin(alert) => Issue {
+        project: alert.project_name.clone(),
+        title: &alert.message,
+        culprit: &alert.culprit,
+        level: &alert.level,
+        url: alert.url.as_deref(),
+      },
+    }
+  }
+}
+
+/// The alert's message, or None if it's less severe than the hook's minimum level
+pub fn translate(body: &[u8], min_level: Option<&str>) -> Result<Option<WebhookRequest>> {
+  let payload: Payload = parse_json(body)?;
+  let issue = payload.issue();
+  if let Some(min_level) = min_level {
+    if severity(issue.level) < severity(min_level) {
+      return Ok(None);
+    }
+  }
+  Ok(Some(render(&issue).into_request()))
+}
+
+// Levels Sentry doesn't know count as errors, which is what it defaults to
+fn severity(level: &str) -> usize {
+  LEVELS.iter().position(|known| *known == level).unwrap_or(3)
+}
+
+fn level_color(level: &str) -> &'static str {
+  match level {
+    "fatal" => "#a42c30",
+    "error" => "#e03e2f",
+    "warning" => "#f5b000",
+    _ => "#3d74db",
+  }
+}
+
+fn render(issue: &Issue) -> Message {
+  let level = if issue.level.is_empty() {
+    "error"
+  } else {
+    issue.level
+  };
+  let mut message = Message::default();
+  message
+    .color_bar(level_color(level))
+    .text(&format!("[{}] ", issue.project))
+    .colored(level_color(level), &level.to_uppercase())
+    .text(" ")
+    .title(issue.title, issue.url);
+  if !issue.culprit.is_empty() {
+    message.line().italic(issue.culprit);
+  }
+  message
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const PLUGIN_ALERT: &str = r#"{
+    "project_name": "backend",
+    "message": "ZeroDivisionError: division by zero",
+    "culprit": "app.views in index",
+    "level": "warning",
+    "url": "https://sentry.io/organizations/o/issues/1/"
+  }"#;
+
+  #[test]
+  fn test_plugin_alert() -> Result<()> {
+    assert!(translate(PLUGIN_ALERT.as_bytes(), None)?.is_some());
+    assert!(translate(PLUGIN_ALERT.as_bytes(), Some("warning"))?.is_some());
+    assert!(translate(PLUGIN_ALERT.as_bytes(), Some("error"))?.is_none());
+    Ok(())
+  }
+
+  #[test]
+  fn test_render() -> Result<()> {
+    let payload: Payload = serde_json::from_str(
+      r#"{
+        "action": "triggered",
+        "data": {
+          "event": {
+            "title": "TypeError: x is undefined",
+            "culprit": "render(app.js)",
+            "level": "error",
+            "web_url": "https://sentry.io/organizations/o/issues/2/events/abc/",
+            "project": 42
+          },
+          "triggered_rule": "Any new issue"
+        }
+      }"#,
+    )?;
+    let message = render(&payload.issue());
+    assert_eq!(
+      message.plain,
+      "[project 42] ERROR TypeError: x is undefined \
+      (https://sentry.io/organizations/o/issues/2/events/abc/)\nrender(app.js)"
+    );
+    Ok(())
+  }
+}
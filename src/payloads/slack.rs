@@ -0,0 +1,446 @@
+// Slack's incoming webhooks, as JSON or as the form-encoded `payload` older integrations send.
+// Blocks replace the text when there are any, like in Slack, and attachments follow as quotes.
+use anyhow::Result;
+use serde::Deserialize;
+
+use super::{form_payload, markdown_html, parse_json, Message};
+use crate::{webhook::HookError, webhook_request::WebhookRequest};
+
+#[derive(Debug, Deserialize)]
+struct Payload {
+  #[serde(default)]
+  text: String,
+  #[serde(default = "return_true")]
+  mrkdwn: bool,
+  username: Option<String>,
+  icon_url: Option<String>,
+  #[serde(default)]
+  blocks: Vec<Block>,
+  #[serde(default)]
+  attachments: Vec<Attachment>,
+}
+
+fn return_true() -> bool {
+  true
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Block {
+  Section {
+    text: Option<TextObject>,
+    #[serde(default)]
+    fields: Vec<TextObject>,
+  },
+  Header {
+    text: TextObject,
+  },
+  Context {
+    #[serde(default)]
+    elements: Vec<TextObject>,
+  },
+  Divider,
+  Image {
+    image_url: String,
+    #[serde(default)]
+    alt_text: String,
+  },
+  // Buttons and inputs can't do anything in Matrix
+  #[serde(other)]
+  Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct TextObject {
+  #[serde(rename = "type")]
+  kind: String,
+  // Image elements in a context have none
+  #[serde(default)]
+  text: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Attachment {
+  fallback: Option<String>,
+  color: Option<String>,
+  pretext: Option<String>,
+  author_name: Option<String>,
+  author_link: Option<String>,
+  title: Option<String>,
+  title_link: Option<String>,
+  text: Option<String>,
+  fields: Vec<Field>,
+  image_url: Option<String>,
+  footer: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Field {
+  title: String,
+  value: String,
+}
+
+pub fn translate(body: &[u8]) -> Result<WebhookRequest> {
+  let payload: Payload = match form_payload(body) {
+    Some(json) => parse_json(json.as_bytes())?,
+    None => parse_json(body)?,
+  };
+
+  let message = render(&payload);
+  if message.is_empty() {
+    return Err(HookError::InvalidPayload("The message has nothing in it".into()).into());
+  }
+
+  Ok(
+    message
+      .into_request()
+      .with_sender(payload.username, payload.icon_url),
+  )
+}
+
+fn render(payload: &Payload) -> Message {
+  let mut message = Message::default();
+  if payload.blocks.is_empty() {
+    write_text(&mut message, &payload.text, payload.mrkdwn);
+  } else {
+    for block in &payload.blocks {
+      write_block(&mut message, block);
+    }
+  }
+  for attachment in &payload.attachments {
+    write_attachment(&mut message, attachment);
+  }
+  message
+}
+
+fn write_text(message: &mut Message, text: &str, mrkdwn: bool) {
+  if mrkdwn {
+    let (plain, markdown) = mrkdwn_to_markdown(text);
+    message.rich(&plain, &markdown_html(&markdown));
+  } else {
+    message.text(&unescape(text));
+  }
+}
+
+fn write_text_object(message: &mut Message, text: &TextObject) {
+  write_text(message, &text.text, text.kind == "mrkdwn");
+}
+
+fn write_block(message: &mut Message, block: &Block) {
+  match block {
+    Block::Section { text, fields } => {
+      if let Some(text) = text {
+        message.line();
+        write_text_object(message, text);
+      }
+      for field in fields {
+        message.line();
+        write_text_object(message, field);
+      }
+    }
+    Block::Header { text } => {
+      message.line().bold(&unescape(&text.text));
+    }
+    Block::Context { elements } => {
+      message.line();
+      for (i, element) in elements.iter().filter(|e| !e.text.is_empty()).enumerate() {
+        if i > 0 {
+          message.text(" ");
+        }
+        write_text_object(message, element);
+      }
+    }
+    Block::Divider => {
+      message.line().rich("---", "<hr>");
+    }
+    Block::Image {
+      image_url,
+      alt_text,
+    } => {
+      message.line().link(alt_text, image_url);
+    }
+    Block::Other => {}
+  }
+}
+
+fn write_attachment(message: &mut Message, attachment: &Attachment) {
+  if let Some(pretext) = &attachment.pretext {
+    message.line();
+    write_text(message, pretext, true);
+  }
+
+  let mut body = Message::default();
+  if let Some(author) = &attachment.author_name {
+    body.title(author, attachment.author_link.as_deref()).line();
+  }
+  if let Some(title) = &attachment.title {
+    body.title(title, attachment.title_link.as_deref()).line();
+  }
+  if let Some(text) = &attachment.text {
+    write_text(&mut body, text, true);
+  }
+  for field in &attachment.fields {
+    body.line().bold(&unescape(&field.title)).text(": ");
+    write_text(&mut body, &field.value, true);
+  }
+  if let Some(image_url) = &attachment.image_url {
+    body.line().link(image_url, image_url);
+  }
+  if let Some(footer) = &attachment.footer {
+    body.line().italic(&unescape(footer));
+  }
+  if body.is_empty() {
+    match &attachment.fallback {
+      Some(fallback) => body.text(&unescape(fallback)),
+      None => return,
+    };
+  }
+
+  // Slack borders attachments in their color
+  let mut quoted = Message::default();
+  if let Some(attachment_color) = &attachment.color {
+    quoted.color_bar(&color(attachment_color));
+  }
+  quoted.append(body);
+  message.quote(quoted);
+}
+
+/// Slack's names for its colors, or a hex color with or without the #
+pub fn color(color: &str) -> String {
+  match color {
+    "good" => "#2eb886".to_string(),
+    "warning" => "#daa038".to_string(),
+    "danger" => "#a30200".to_string(),
+    hex if hex.starts_with('#') => hex.to_string(),
+    hex => format!("#{}", hex),
+  }
+}
+
+// Slack escapes these three in text, and nothing else
+fn unescape(text: &str) -> String {
+  text
+    .replace("&lt;", "<")
+    .replace("&gt;", ">")
+    .replace("&amp;", "&")
+}
+
+// The escape at the start of the text, unescaped, and how long it is
+fn entity(text: &str) -> Option<(&'static str, usize)> {
+  [("&lt;", "<"), ("&gt;", ">"), ("&amp;", "&")]
+    .iter()
+    .find(|(entity, _)| text.starts_with(entity))
+    .map(|(entity, unescaped)| (*unescaped, entity.len()))
+}
+
+fn escape_markdown(text: &str) -> String {
+  let mut escaped = String::new();
+  for c in text.chars() {
+    if "\\`*_{}[]()<>#+-.!|~".contains(c) {
+      escaped.push('\\');
+    }
+    escaped.push(c);
+  }
+  escaped
+}
+
+// Slack's mrkdwn as readable plain text, and as CommonMark for the html. Single asterisks and
+// tildes are Slack's bold and strikethrough, line breaks are kept, and links, mentions and
+// escapes have to be resolved.
+fn mrkdwn_to_markdown(raw: &str) -> (String, String) {
+  let mut plain = String::new();
+  let mut markdown = String::new();
+  // The backticks the code being written was opened with
+  let mut code: Option<&str> = None;
+  let mut line_start = true;
+  let mut rest = raw;
+
+  while let Some(c) = rest.chars().next() {
+    if let Some((unescaped, len)) = entity(rest) {
+      plain.push_str(unescaped);
+      // Code shows entities as they are
+      if code.is_some() {
+        markdown.push_str(unescaped);
+      } else {
+        markdown.push_str(&rest[..len]);
+      }
+      rest = &rest[len..];
+      line_start = false;
+      continue;
+    }
+
+    if let Some(fence) = code {
+      if rest.starts_with(fence) {
+        code = None;
+        plain.push_str(fence);
+        markdown.push_str(fence);
+        rest = &rest[fence.len()..];
+      } else {
+        plain.push(c);
+        markdown.push(c);
+        rest = &rest[c.len_utf8()..];
+      }
+      continue;
+    }
+
+    if rest.starts_with("```") || c == '`' {
+      let fence = if rest.starts_with("```") { "```" } else { "`" };
+      code = Some(fence);
+      plain.push_str(fence);
+      markdown.push_str(fence);
+      // Slack allows text straight after the fence, where CommonMark would take it for the language
+      if fence == "```" {
+        markdown.push('\n');
+      }
+      rest = &rest[fence.len()..];
+      line_start = false;
+      continue;
+    }
+
+    match c {
+      '<' => match rest.find('>') {
+        Some(end) => {
+          let (token_plain, token_markdown) = token(&rest[1..end]);
+          plain.push_str(&token_plain);
+          markdown.push_str(&token_markdown);
+          rest = &rest[end + 1..];
+          line_start = false;
+          continue;
+        }
+        None => {
+          plain.push('<');
+          markdown.push_str("\\<");
+        }
+      },
+      '*' if !(line_start && rest[1..].starts_with(' ')) => {
+        plain.push('*');
+        markdown.push_str("**");
+      }
+      '~' => {
+        plain.push('~');
+        markdown.push_str("~~");
+      }
+      '\n' => {
+        plain.push('\n');
+        markdown.push_str("  \n");
+      }
+      '[' | ']' | '\\' | '#' => {
+        plain.push(c);
+        markdown.push('\\');
+        markdown.push(c);
+      }
+      _ => {
+        plain.push(c);
+        markdown.push(c);
+      }
+    }
+    line_start = c == '\n';
+    rest = &rest[c.len_utf8()..];
+  }
+
+  if code == Some("```") {
+    markdown.push_str("\n```");
+  }
+  (plain, markdown)
+}
+
+// What's between angle brackets: a link, or a mention of a user, channel or everyone
+fn token(inner: &str) -> (String, String) {
+  let (target, label) = match inner.split_once('|') {
+    Some((target, label)) => (target, Some(unescape(label))),
+    None => (inner, None),
+  };
+  let target = unescape(target);
+  let mention = |prefix: &str, name: &str| {
+    let name = format!("{}{}", prefix, name.trim_start_matches(prefix));
+    (name.clone(), escape_markdown(&name))
+  };
+
+  match target.chars().next() {
+    Some('@') => mention("@", label.as_deref().unwrap_or(&target[1..])),
+    Some('#') => mention("#", label.as_deref().unwrap_or(&target[1..])),
+    // !here, !channel and !everyone, or things like user groups and dates that have a label
+    Some('!') => match label {
+      Some(label) => (label.clone(), escape_markdown(&label)),
+      None => mention("@", &target[1..]),
+    },
+    _ => match label {
+      Some(label) => (
+        format!("{} ({})", label, target),
+        format!("[{}](<{}>)", escape_markdown(&label), target),
+      ),
+      None => (target.clone(), format!("<{}>", target)),
+    },
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_mrkdwn() {
+    let (plain, markdown) = mrkdwn_to_markdown(
+      "*Deploy* of <https://example.com/app|app> ~failed~ &lt;sad&gt;\n<@U123|alice> <!here> `a*b`",
+    );
+    assert_eq!(
+      plain,
+      "*Deploy* of app (https://example.com/app) ~failed~ <sad>\n@alice @here `a*b`"
+    );
+    assert_eq!(
+      markdown_html(&markdown),
+      "<strong>Deploy</strong> of <a href=\"https://example.com/app\">app</a> <del>failed</del> &lt;sad&gt;<br />\n\
+      @alice @here <code>a*b</code>"
+    );
+  }
+
+  #[test]
+  fn test_attachments() -> Result<()> {
+    let payload: Payload = serde_json::from_str(
+      r##"{
+        "username": "CI",
+        "text": "Build finished",
+        "attachments": [{
+          "color": "good",
+          "title": "Build #12",
+          "title_link": "https://ci.example.com/12",
+          "fields": [{"title": "Branch", "value": "main", "short": true}]
+        }]
+      }"##,
+    )?;
+    let message = render(&payload);
+    assert_eq!(
+      message.plain,
+      "Build finished\n> Build #12 (https://ci.example.com/12)\n> Branch: main\n"
+    );
+    assert_eq!(
+      message.html,
+      "Build finished<blockquote><font data-mx-color=\"#2eb886\">▌</font> \
+      <b><a href=\"https://ci.example.com/12\">Build #12</a></b><br><b>Branch</b>: main</blockquote>"
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn test_blocks() -> Result<()> {
+    let payload: Payload = serde_json::from_str(
+      r##"{
+        "text": "Not shown",
+        "blocks": [
+          {"type": "header", "text": {"type": "plain_text", "text": "Deploy"}},
+          {"type": "section", "text": {"type": "mrkdwn", "text": "*prod* is up"}},
+          {"type": "actions", "elements": []},
+          {"type": "context", "elements": [{"type": "image", "image_url": "x"}, {"type": "plain_text", "text": "by bob"}]}
+        ]
+      }"##,
+    )?;
+    let message = render(&payload);
+    assert_eq!(message.plain, "Deploy\n*prod* is up\nby bob");
+    assert_eq!(
+      message.html,
+      "<b>Deploy</b><br><strong>prod</strong> is up<br>by bob"
+    );
+    Ok(())
+  }
+}
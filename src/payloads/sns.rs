@@ -0,0 +1,264 @@
+// Amazon SNS's HTTPS subscriptions. Every message is checked against the signature AWS made with
+// its certificate, then subscriptions are confirmed by visiting their SubscribeURL, and
+// notifications are posted, with CloudWatch alarms' JSON made readable.
+use anyhow::{anyhow, Context, Result};
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use log::*;
+use openssl::{
+  base64,
+  hash::MessageDigest,
+  pkey::{PKey, Public},
+  sign::Verifier,
+  x509::X509,
+};
+use serde::Deserialize;
+
+use super::{parse_json, Message};
+use crate::{webhook::HookError, webhook_request::WebhookRequest};
+
+// AWS's signing certificates by URL, since they're the same for every message until AWS rotates
+// them, which gives them a new URL
+lazy_static! {
+  static ref SIGNING_CERTS: DashMap<String, PKey<Public>> = DashMap::new();
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SnsMessage {
+  #[serde(rename = "Type")]
+  kind: String,
+  message_id: String,
+  token: Option<String>,
+  topic_arn: String,
+  subject: Option<String>,
+  message: String,
+  timestamp: String,
+  #[serde(default = "default_signature_version")]
+  signature_version: String,
+  signature: String,
+  #[serde(rename = "SigningCertURL")]
+  signing_cert_url: String,
+  #[serde(rename = "SubscribeURL")]
+  subscribe_url: Option<String>,
+}
+
+fn default_signature_version() -> String {
+  "1".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Alarm {
+  alarm_name: String,
+  new_state_value: String,
+  #[serde(default)]
+  new_state_reason: String,
+  alarm_description: Option<String>,
+}
+
+pub async fn translate(body: &[u8]) -> Result<Option<WebhookRequest>> {
+  let message: SnsMessage = parse_json(body)?;
+  let key = signing_key(&message.signing_cert_url).await?;
+  if !is_signed(&key, &message)? {
+    return Err(HookError::BadSignature.into());
+  }
+
+  match message.kind.as_str() {
+    "SubscriptionConfirmation" => {
+      confirm_subscription(&message).await?;
+      let mut notice = Message::default();
+      notice
+        .text("Subscribed to SNS topic ")
+        .code(&message.topic_arn);
+      Ok(Some(notice.into_request().as_notice()))
+    }
+    "Notification" => Ok(Some(render(&message).into_request())),
+    _ => Ok(None),
+  }
+}
+
+// Only SNS's own hosts, so a forged message can't have the bridge fetch from anywhere it likes
+fn is_sns_url(url: &str) -> bool {
+  match reqwest::Url::parse(url) {
+    Ok(url) => {
+      url.scheme() == "https"
+        && url.host_str().map_or(false, |host| {
+          host.starts_with("sns.") && host.ends_with(".amazonaws.com")
+        })
+    }
+    Err(_) => false,
+  }
+}
+
+async fn signing_key(url: &str) -> Result<PKey<Public>> {
+  if !is_sns_url(url) {
+    return Err(HookError::BadSignature.into());
+  }
+  if let Some(key) = SIGNING_CERTS.get(url) {
+    return Ok(key.clone());
+  }
+
+  info!("Downloading SNS signing certificate at {}", url);
+  let pem = reqwest::get(url).await?.error_for_status()?.bytes().await?;
+  let key = X509::from_pem(&pem)
+    .context("SNS's signing certificate isn't valid")?
+    .public_key()?;
+  SIGNING_CERTS.insert(url.to_string(), key.clone());
+  Ok(key)
+}
+
+// The message's fields that are signed, in order, each followed by its value on the next line
+fn string_to_sign(message: &SnsMessage) -> String {
+  let fields = if message.kind == "Notification" {
+    vec![
+      ("Message", Some(&message.message)),
+      ("MessageId", Some(&message.message_id)),
+      ("Subject", message.subject.as_ref()),
+      ("Timestamp", Some(&message.timestamp)),
+      ("TopicArn", Some(&message.topic_arn)),
+      ("Type", Some(&message.kind)),
+    ]
+  } else {
+    vec![
+      ("Message", Some(&message.message)),
+      ("MessageId", Some(&message.message_id)),
+      ("SubscribeURL", message.subscribe_url.as_ref()),
+      ("Timestamp", Some(&message.timestamp)),
+      ("Token", message.token.as_ref()),
+      ("TopicArn", Some(&message.topic_arn)),
+      ("Type", Some(&message.kind)),
+    ]
+  };
+  fields
+    .into_iter()
+    .filter_map(|(name, value)| Some(format!("{}\n{}\n", name, value?)))
+    .collect()
+}
+
+// Version 1 signatures are of the SHA1 hash, and version 2 of the SHA256 one
+fn is_signed(key: &PKey<Public>, message: &SnsMessage) -> Result<bool> {
+  let digest = match message.signature_version.as_str() {
+    "1" => MessageDigest::sha1(),
+    "2" => MessageDigest::sha256(),
+    _ => return Ok(false),
+  };
+  let signature = match base64::decode_block(&message.signature) {
+    Ok(signature) => signature,
+    Err(_) => return Ok(false),
+  };
+  let mut verifier = Verifier::new(digest, key)?;
+  verifier.update(string_to_sign(message).as_bytes())?;
+  Ok(verifier.verify(&signature).unwrap_or(false))
+}
+
+async fn confirm_subscription(message: &SnsMessage) -> Result<()> {
+  let url = message
+    .subscribe_url
+    .as_deref()
+    .filter(|url| is_sns_url(url))
+    .ok_or_else(|| HookError::InvalidPayload("The SubscribeURL isn't SNS's".into()))?;
+  reqwest::get(url)
+    .await?
+    .error_for_status()
+    .map_err(|e| anyhow!("Failed to confirm the SNS subscription: {}", e))?;
+  info!("Confirmed SNS subscription to {}", &message.topic_arn);
+  Ok(())
+}
+
+fn state_color(state: &str) -> &'static str {
+  match state {
+    "ALARM" => "#d13212",
+    "OK" => "#1d8102",
+    _ => "#8a8a8a",
+  }
+}
+
+fn render(message: &SnsMessage) -> Message {
+  let mut rendered = Message::default();
+  if let Ok(alarm) = serde_json::from_str::<Alarm>(&message.message) {
+    rendered
+      .colored(
+        state_color(&alarm.new_state_value),
+        &format!("[{}]", alarm.new_state_value),
+      )
+      .text(" ")
+      .bold(&alarm.alarm_name);
+    if let Some(description) = alarm.alarm_description.filter(|d| !d.is_empty()) {
+      rendered.text(": ").text(&description);
+    }
+    if !alarm.new_state_reason.is_empty() {
+      rendered.line().italic(&alarm.new_state_reason);
+    }
+    return rendered;
+  }
+
+  if let Some(subject) = &message.subject {
+    rendered.bold(subject).line();
+  }
+  rendered.text(&message.message);
+  rendered
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use openssl::{rsa::Rsa, sign::Signer};
+
+  fn notification(message: &str) -> SnsMessage {
+    SnsMessage {
+      kind: "Notification".to_string(),
+      message_id: "1".to_string(),
+      token: None,
+      topic_arn: "arn:aws:sns:eu-west-1:123:alarms".to_string(),
+      subject: None,
+      message: message.to_string(),
+      timestamp: "2021-01-01T00:00:00.000Z".to_string(),
+      signature_version: "2".to_string(),
+      signature: String::new(),
+      signing_cert_url: "https://sns.eu-west-1.amazonaws.com/cert.pem".to_string(),
+      subscribe_url: None,
+    }
+  }
+
+  #[test]
+  fn test_is_signed() -> Result<()> {
+    let mut message = notification("hello");
+    assert_eq!(
+      "Message\nhello\nMessageId\n1\nTimestamp\n2021-01-01T00:00:00.000Z\n\
+      TopicArn\narn:aws:sns:eu-west-1:123:alarms\nType\nNotification\n",
+      string_to_sign(&message)
+    );
+
+    let private_key = PKey::from_rsa(Rsa::generate(2048)?)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &private_key)?;
+    signer.update(string_to_sign(&message).as_bytes())?;
+    message.signature = base64::encode_block(&signer.sign_to_vec()?);
+    let public_key = PKey::public_key_from_pem(&private_key.public_key_to_pem()?)?;
+    assert!(is_signed(&public_key, &message)?);
+
+    message.message = "forged".to_string();
+    assert!(!is_signed(&public_key, &message)?);
+    Ok(())
+  }
+
+  #[test]
+  fn test_is_sns_url() {
+    assert!(is_sns_url(
+      "https://sns.us-east-1.amazonaws.com/SimpleNotificationService-1.pem"
+    ));
+    assert!(!is_sns_url("http://sns.us-east-1.amazonaws.com/a.pem"));
+    assert!(!is_sns_url("https://sns.amazonaws.com.evil.example/a.pem"));
+  }
+
+  #[test]
+  fn test_render_alarm() {
+    let message = notification(
+      r#"{"AlarmName": "High CPU", "NewStateValue": "ALARM", "NewStateReason": "Threshold crossed"}"#,
+    );
+    assert_eq!(
+      render(&message).plain,
+      "[ALARM] High CPU\nThreshold crossed"
+    );
+  }
+}
@@ -0,0 +1,182 @@
+// Stripe's events, summarized as notices. Only payment, invoice and subscription events are
+// posted, since the rest are mostly steps along the way to those.
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::{parse_json, Message};
+use crate::webhook_request::WebhookRequest;
+
+/// Holds `t=` the time it was signed, and `v1=` signatures of the time and the body
+pub const SIGNATURE_HEADER: &str = "Stripe-Signature";
+
+// Currencies Stripe counts in whole units rather than cents
+const ZERO_DECIMAL_CURRENCIES: &[&str] = &[
+  "bif", "clp", "djf", "gnf", "jpy", "kmf", "krw", "mga", "pyg", "rwf", "ugx", "vnd", "vuv", "xaf",
+  "xof", "xpf",
+];
+
+#[derive(Debug, Deserialize)]
+struct Event {
+  #[serde(rename = "type")]
+  kind: String,
+  #[serde(default = "return_true")]
+  livemode: bool,
+  data: Data,
+}
+
+fn return_true() -> bool {
+  true
+}
+
+// The objects differ for every type of event, so their fields are picked out as needed
+#[derive(Debug, Deserialize)]
+struct Data {
+  object: Value,
+}
+
+/// The event's type, like invoice.paid
+pub fn event(body: &[u8]) -> Result<String> {
+  parse_json::<Event>(body).map(|event| event.kind)
+}
+
+pub fn translate(body: &[u8]) -> Result<Option<WebhookRequest>> {
+  let event: Event = parse_json(body)?;
+  Ok(render(&event).map(|message| message.into_request().as_notice()))
+}
+
+fn render(event: &Event) -> Option<Message> {
+  let object = &event.data.object;
+  let text = |field: &str| object.get(field).and_then(Value::as_str);
+  let amount = |field: &str| {
+    let amount = object.get(field).and_then(Value::as_i64)?;
+    Some(format_amount(amount, text("currency").unwrap_or_default()))
+  };
+
+  let mut message = Message::default();
+  if !event.livemode {
+    message.italic("[test mode]").text(" ");
+  }
+  match event.kind.as_str() {
+    "payment_intent.succeeded" => {
+      message.text(&format!(
+        "Payment of {} succeeded",
+        amount("amount_received")?
+      ));
+    }
+    "payment_intent.payment_failed" => {
+      message.text(&format!("Payment of {} failed", amount("amount")?));
+      let reason = object
+        .pointer("/last_payment_error/message")
+        .and_then(Value::as_str);
+      if let Some(reason) = reason {
+        message.text(&format!(": {}", reason));
+      }
+    }
+    "charge.refunded" => {
+      message.text(&format!("Refunded {}", amount("amount_refunded")?));
+    }
+    "charge.dispute.created" => {
+      message.text(&format!("Payment of {} was disputed", amount("amount")?));
+      if let Some(reason) = text("reason") {
+        message.text(&format!(" as {}", reason.replace('_', " ")));
+      }
+    }
+    "checkout.session.completed" => {
+      message.text(&format!(
+        "Checkout for {} completed",
+        amount("amount_total")?
+      ));
+      if let Some(email) = object
+        .pointer("/customer_details/email")
+        .and_then(Value::as_str)
+      {
+        message.text(&format!(" by {}", email));
+      }
+    }
+    "invoice.paid" | "invoice.payment_failed" => {
+      let (field, outcome) = if event.kind == "invoice.paid" {
+        ("amount_paid", "was paid")
+      } else {
+        ("amount_due", "failed to be paid")
+      };
+      let name = format!("Invoice {}", text("number").unwrap_or_default());
+      message
+        .title(name.trim_end(), text("hosted_invoice_url"))
+        .text(&format!(" for {} {}", amount(field)?, outcome));
+      if let Some(email) = text("customer_email") {
+        message.text(&format!(" by {}", email));
+      }
+    }
+    "customer.subscription.created" | "customer.subscription.deleted" => {
+      let action = if event.kind.ends_with("created") {
+        "started"
+      } else {
+        "ended"
+      };
+      message
+        .text("Subscription ")
+        .code(text("id")?)
+        .text(&format!(" {}", action));
+      if let Some(customer) = text("customer") {
+        message.text(" for ").code(customer);
+      }
+    }
+    _ => return None,
+  }
+  Some(message)
+}
+
+// Like 12.34 USD
+fn format_amount(amount: i64, currency: &str) -> String {
+  let currency = currency.to_lowercase();
+  if ZERO_DECIMAL_CURRENCIES.contains(&currency.as_str()) {
+    format!("{} {}", amount, currency.to_uppercase())
+  } else {
+    let sign = if amount < 0 { "-" } else { "" };
+    format!(
+      "{}{}.{:02} {}",
+      sign,
+      amount.abs() / 100,
+      amount.abs() % 100,
+      currency.to_uppercase()
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_format_amount() {
+    assert_eq!("12.05 USD", format_amount(1205, "usd"));
+    assert_eq!("-0.50 EUR", format_amount(-50, "eur"));
+    assert_eq!("500 JPY", format_amount(500, "jpy"));
+  }
+
+  #[test]
+  fn test_render() -> Result<()> {
+    let event: Event = serde_json::from_str(
+      r#"{
+        "id": "evt_1",
+        "type": "invoice.payment_failed",
+        "livemode": false,
+        "data": {"object": {
+          "number": "A1-0003",
+          "amount_due": 4900,
+          "currency": "usd",
+          "customer_email": "pat@example.com",
+          "hosted_invoice_url": "https://invoice.stripe.com/i/1"
+        }}
+      }"#,
+    )?;
+    assert_eq!(
+      render(&event).unwrap().plain,
+      "[test mode] Invoice A1-0003 (https://invoice.stripe.com/i/1) for 49.00 USD failed to be \
+      paid by pat@example.com"
+    );
+    assert!(translate(br#"{"type": "customer.updated", "data": {"object": {}}}"#)?.is_none());
+    Ok(())
+  }
+}
@@ -0,0 +1,338 @@
+// Microsoft Teams connector cards, the Office 365 MessageCard as well as Adaptive Cards, either
+// bare or wrapped in a message's attachments the way Teams workflows take them
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+
+use super::{parse_json, Message};
+use crate::{webhook::HookError, webhook_request::WebhookRequest};
+
+const ADAPTIVE_CARD_TYPE: &str = "application/vnd.microsoft.card.adaptive";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct MessageCard {
+  theme_color: Option<String>,
+  summary: Option<String>,
+  title: Option<String>,
+  text: Option<String>,
+  sections: Vec<Section>,
+  potential_action: Vec<Action>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Section {
+  activity_title: Option<String>,
+  activity_subtitle: Option<String>,
+  activity_text: Option<String>,
+  title: Option<String>,
+  text: Option<String>,
+  facts: Vec<Fact>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Fact {
+  name: String,
+  value: String,
+}
+
+// OpenUri actions have a URL per OS, and the older ViewAction a list of them
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Action {
+  name: String,
+  targets: Vec<Target>,
+  target: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Target {
+  os: String,
+  uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Attachment {
+  #[serde(rename = "contentType")]
+  content_type: String,
+  content: Value,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct AdaptiveCard {
+  body: Vec<Element>,
+  actions: Vec<AdaptiveAction>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+enum Element {
+  TextBlock {
+    #[serde(default)]
+    text: String,
+    weight: Option<String>,
+    size: Option<String>,
+  },
+  FactSet {
+    #[serde(default)]
+    facts: Vec<AdaptiveFact>,
+  },
+  Container {
+    #[serde(default)]
+    items: Vec<Element>,
+  },
+  ColumnSet {
+    #[serde(default)]
+    columns: Vec<Column>,
+  },
+  Image {
+    url: String,
+    #[serde(rename = "altText")]
+    alt_text: Option<String>,
+  },
+  // Inputs and the like can't do anything in Matrix
+  #[serde(other)]
+  Other,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct AdaptiveFact {
+  title: String,
+  value: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Column {
+  items: Vec<Element>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct AdaptiveAction {
+  #[serde(rename = "type")]
+  kind: String,
+  title: String,
+  url: Option<String>,
+}
+
+pub fn translate(body: &[u8]) -> Result<WebhookRequest> {
+  let payload: Value = parse_json(body)?;
+  let mut message = Message::default();
+  if let Some(attachments) = payload.get("attachments") {
+    let attachments: Vec<Attachment> = from_value(attachments.clone())?;
+    for attachment in attachments {
+      if attachment.content_type == ADAPTIVE_CARD_TYPE {
+        message
+          .line()
+          .append(render_adaptive_card(&from_value(attachment.content)?));
+      }
+    }
+  } else if payload.get("type").and_then(Value::as_str) == Some("AdaptiveCard") {
+    message.append(render_adaptive_card(&from_value(payload)?));
+  } else {
+    message.append(render_message_card(&from_value(payload)?));
+  }
+
+  if message.is_empty() {
+    return Err(HookError::InvalidPayload("The card has nothing in it".into()).into());
+  }
+  Ok(message.into_request())
+}
+
+fn from_value<T: DeserializeOwned>(value: Value) -> Result<T> {
+  serde_json::from_value(value).map_err(|e| HookError::InvalidPayload(e.to_string()).into())
+}
+
+fn render_message_card(card: &MessageCard) -> Message {
+  let mut message = Message::default();
+  if let Some(color) = &card.theme_color {
+    message.color_bar(&format!("#{}", color.trim_start_matches('#')));
+  }
+  if let Some(title) = card.title.as_ref().or_else(|| card.summary.as_ref()) {
+    message.bold(title);
+  }
+  if let Some(text) = &card.text {
+    message.line().markdown(text);
+  }
+
+  for section in &card.sections {
+    if let Some(title) = section
+      .activity_title
+      .as_ref()
+      .or_else(|| section.title.as_ref())
+    {
+      message.line().bold(title.trim_matches('*'));
+    }
+    if let Some(subtitle) = &section.activity_subtitle {
+      message.line().italic(subtitle);
+    }
+    for text in section.activity_text.iter().chain(section.text.iter()) {
+      message.line().markdown(text);
+    }
+    for fact in &section.facts {
+      message
+        .line()
+        .bold(&fact.name)
+        .text(": ")
+        .markdown(&fact.value);
+    }
+  }
+
+  let links = card
+    .potential_action
+    .iter()
+    .filter_map(|action| {
+      let uri = action
+        .targets
+        .iter()
+        .find(|target| target.os == "default")
+        .or_else(|| action.targets.first())
+        .map(|target| target.uri.as_str())
+        .or_else(|| action.target.first().map(String::as_str))?;
+      Some((action.name.as_str(), uri))
+    })
+    .collect::<Vec<_>>();
+  write_links(&mut message, &links);
+  message
+}
+
+fn render_adaptive_card(card: &AdaptiveCard) -> Message {
+  let mut message = Message::default();
+  for element in &card.body {
+    write_element(&mut message, element);
+  }
+  let links = card
+    .actions
+    .iter()
+    .filter(|action| action.kind == "Action.OpenUrl")
+    .filter_map(|action| Some((action.title.as_str(), action.url.as_deref()?)))
+    .collect::<Vec<_>>();
+  write_links(&mut message, &links);
+  message
+}
+
+fn write_element(message: &mut Message, element: &Element) {
+  match element {
+    Element::TextBlock { text, weight, size } => {
+      let emphasized = weight.as_deref() == Some("bolder")
+        || matches!(size.as_deref(), Some("large") | Some("extraLarge"));
+      message.line();
+      if emphasized {
+        message.bold(text);
+      } else {
+        message.markdown(text);
+      }
+    }
+    Element::FactSet { facts } => {
+      for fact in facts {
+        message
+          .line()
+          .bold(&fact.title)
+          .text(": ")
+          .markdown(&fact.value);
+      }
+    }
+    Element::Container { items } => {
+      for item in items {
+        write_element(message, item);
+      }
+    }
+    Element::ColumnSet { columns } => {
+      for item in columns.iter().flat_map(|column| &column.items) {
+        write_element(message, item);
+      }
+    }
+    Element::Image { url, alt_text } => {
+      message
+        .line()
+        .link(alt_text.as_deref().unwrap_or("Image"), url);
+    }
+    Element::Other => {}
+  }
+}
+
+// The card's buttons, as links on a line of their own
+fn write_links(message: &mut Message, links: &[(&str, &str)]) {
+  for (i, (name, uri)) in links.iter().enumerate() {
+    if i == 0 {
+      message.line();
+    } else {
+      message.text(" · ");
+    }
+    message.link(name, uri);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_message_card() -> Result<()> {
+    let card: MessageCard = serde_json::from_str(
+      r#"{
+        "@type": "MessageCard",
+        "themeColor": "0076D7",
+        "summary": "Deploy finished",
+        "title": "Deploy finished",
+        "sections": [{
+          "activityTitle": "Production",
+          "facts": [{"name": "Version", "value": "1.2.3"}]
+        }],
+        "potentialAction": [{
+          "@type": "OpenUri",
+          "name": "View",
+          "targets": [{"os": "default", "uri": "https://deploy.example.com/42"}]
+        }]
+      }"#,
+    )?;
+    let message = render_message_card(&card);
+    assert_eq!(
+      message.plain,
+      "Deploy finished\nProduction\nVersion: 1.2.3\nView (https://deploy.example.com/42)"
+    );
+    assert_eq!(
+      message.html,
+      "<font data-mx-color=\"#0076D7\">▌</font> <b>Deploy finished</b><br>\
+      <b>Production</b><br><b>Version</b>: 1.2.3<br>\
+      <a href=\"https://deploy.example.com/42\">View</a>"
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn test_adaptive_card() -> Result<()> {
+    let request = translate(
+      br#"{
+        "type": "message",
+        "attachments": [{
+          "contentType": "application/vnd.microsoft.card.adaptive",
+          "content": {
+            "type": "AdaptiveCard",
+            "body": [
+              {"type": "TextBlock", "text": "Backup failed", "weight": "bolder"},
+              {"type": "ColumnSet", "columns": [{"items": [
+                {"type": "FactSet", "facts": [{"title": "Host", "value": "db-1"}]}
+              ]}]},
+              {"type": "Input.Text", "id": "comment"}
+            ],
+            "actions": [{"type": "Action.OpenUrl", "title": "Logs", "url": "https://logs.example.com"}]
+          }
+        }]
+      }"#,
+    );
+    assert!(request.is_ok());
+
+    let card: AdaptiveCard = serde_json::from_str(
+      r#"{"body": [{"type": "TextBlock", "text": "Backup *failed*"}], "actions": []}"#,
+    )?;
+    assert_eq!(render_adaptive_card(&card).html, "Backup <em>failed</em>");
+    Ok(())
+  }
+}
@@ -0,0 +1,154 @@
+// Just enough of m.room.power_levels to tell what a user may do. Parsed from the raw state
+// event, since rooms in the wild don't always match ruma's strict types.
+use std::collections::HashMap;
+
+use anyhow::Result;
+use matrix_sdk::{room::Joined, ruma::events::EventType};
+use serde::Deserialize;
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+pub struct PowerLevels {
+  #[serde(default)]
+  pub users: HashMap<String, i64>,
+  #[serde(default)]
+  pub users_default: i64,
+  #[serde(default)]
+  pub invite: i64,
+  #[serde(default)]
+  pub events: HashMap<String, i64>,
+  #[serde(default)]
+  pub events_default: i64,
+  // Unlike the other levels, the spec defaults this to 50 when the room has power levels at all
+  #[serde(default = "default_state_level")]
+  pub state_default: i64,
+}
+
+fn default_state_level() -> i64 {
+  50
+}
+
+#[derive(Deserialize)]
+struct PowerLevelsEvent {
+  content: PowerLevels,
+}
+
+impl PowerLevels {
+  /// The room's current power levels, or the defaults if it has none
+  pub async fn of_room(room: &Joined) -> Result<Self> {
+    match room.get_state_event(EventType::RoomPowerLevels, "").await? {
+      Some(raw) => {
+        let event: PowerLevelsEvent = serde_json::from_str(raw.json().get())?;
+        Ok(event.content)
+      }
+      None => Ok(Self::default()),
+    }
+  }
+
+  pub fn user_level(&self, user_id: &str) -> i64 {
+    self
+      .users
+      .get(user_id)
+      .copied()
+      .unwrap_or(self.users_default)
+  }
+
+  pub fn can_invite(&self, user_id: &str) -> bool {
+    self.user_level(user_id) >= self.invite
+  }
+
+  /// The level needed to send message events of the type
+  pub fn event_level(&self, event_type: &str) -> i64 {
+    self
+      .events
+      .get(event_type)
+      .copied()
+      .unwrap_or(self.events_default)
+  }
+
+  pub fn can_send(&self, user_id: &str, event_type: &str) -> bool {
+    self.user_level(user_id) >= self.event_level(event_type)
+  }
+
+  /// The level needed to change the room's state, barring per-type overrides
+  pub fn state_level(&self) -> i64 {
+    self.state_default
+  }
+
+  /// The level needed to send state events of the type
+  pub fn state_event_level(&self, event_type: &str) -> i64 {
+    self
+      .events
+      .get(event_type)
+      .copied()
+      .unwrap_or(self.state_default)
+  }
+
+  pub fn can_send_state(&self, user_id: &str, event_type: &str) -> bool {
+    self.user_level(user_id) >= self.state_event_level(event_type)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use anyhow::Result;
+
+  #[test]
+  fn test_can_invite() -> Result<()> {
+    let levels: PowerLevels = serde_json::from_str(
+      r#"
+    {
+      "users": { "@admin:localhost": 100, "@_webhook:localhost": 10 },
+      "users_default": 0,
+      "invite": 50
+    }"#,
+    )?;
+
+    assert!(levels.can_invite("@admin:localhost"));
+    assert!(!levels.can_invite("@_webhook:localhost"));
+    assert!(!levels.can_invite("@nobody:localhost"));
+    assert!(PowerLevels::default().can_invite("@nobody:localhost"));
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_can_send() -> Result<()> {
+    let levels: PowerLevels = serde_json::from_str(
+      r#"
+    {
+      "users": { "@_webhook__abc:localhost": 10 },
+      "events": { "m.room.message": 10, "m.reaction": 50 },
+      "events_default": 20
+    }"#,
+    )?;
+
+    assert!(levels.can_send("@_webhook__abc:localhost", "m.room.message"));
+    assert!(!levels.can_send("@_webhook__abc:localhost", "m.reaction"));
+    assert!(!levels.can_send("@_webhook__abc:localhost", "m.sticker"));
+    assert!(!levels.can_send("@nobody:localhost", "m.room.message"));
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_state_level() -> Result<()> {
+    let levels: PowerLevels = serde_json::from_str("{}")?;
+    assert_eq!(50, levels.state_level());
+    let levels: PowerLevels = serde_json::from_str(r#"{ "state_default": 10 }"#)?;
+    assert_eq!(10, levels.state_level());
+    assert_eq!(0, PowerLevels::default().state_level());
+
+    let levels: PowerLevels = serde_json::from_str(
+      r#"
+    {
+      "users": { "@_webhook__abc:localhost": 50 },
+      "events": { "m.room.name": 100 }
+    }"#,
+    )?;
+    assert!(levels.can_send_state("@_webhook__abc:localhost", "m.room.topic"));
+    assert!(!levels.can_send_state("@_webhook__abc:localhost", "m.room.name"));
+
+    Ok(())
+  }
+}
@@ -0,0 +1,220 @@
+// Previews of the first link in a hook's messages, for hooks that turn them on with
+// `!webhook preview`. Many clients don't show their own previews for messages from appservice users,
+// so the page's OpenGraph title, description and image are quoted after the text instead.
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::*;
+use matrix_sdk::Client;
+use reqwest::Url;
+use scraper::{Html, Selector};
+
+use crate::{bot::escape_html, config::Limits, media, payloads::Message};
+
+const TIMEOUT_SECONDS: u64 = 10;
+
+// The tags previews come from are in the page's head, so the rest isn't needed
+const MAX_PAGE_BYTES: usize = 512 * 1024;
+
+const MAX_DESCRIPTION_CHARS: usize = 300;
+
+#[derive(Debug, PartialEq)]
+struct Preview {
+  title: Option<String>,
+  description: Option<String>,
+  image_url: Option<String>,
+}
+
+/// The first http or https URL in the text
+pub fn first_url(text: &str) -> Option<String> {
+  let start = ["https://", "http://"]
+    .iter()
+    .filter_map(|scheme| text.find(scheme))
+    .min()?;
+  let rest = &text[start..];
+  let end = rest
+    .find(|c: char| c.is_whitespace() || matches!(c, '<' | '>' | '"' | '\'' | '`'))
+    .unwrap_or_else(|| rest.len());
+  // Punctuation after a link is usually the sentence's, or markdown's
+  let url = rest[..end].trim_end_matches(&['.', ',', ';', ':', '!', '?', ')', ']', '*', '_'][..]);
+  Url::parse(url).ok().map(|_| url.to_string())
+}
+
+/// The preview of the page at the URL as a quote, in plain text and html, if it has one. Its image
+/// is uploaded as the client, if it's wanted.
+pub async fn render(
+  client: &Client,
+  url: &str,
+  limits: &Limits,
+  with_image: bool,
+) -> Result<Option<(String, String)>> {
+  let preview = match fetch(url).await? {
+    Some(preview) => preview,
+    None => return Ok(None),
+  };
+  let image = match preview.image_url.as_deref().filter(|_| with_image) {
+    Some(image_url) => match media::upload_image(client, image_url, limits).await {
+      Ok(uri) => Some(uri.to_string()),
+      Err(e) => {
+        warn!(
+          "Failed to upload the preview image for {}: {}",
+          url,
+          e.to_string()
+        );
+        None
+      }
+    },
+    None => None,
+  };
+  Ok(Some(quote(&preview, url, image.as_deref())))
+}
+
+async fn fetch(url: &str) -> Result<Option<Preview>> {
+  let mut response = media::get_public(url, Some(Duration::from_secs(TIMEOUT_SECONDS)))
+    .await
+    .map_err(|reason| anyhow!("{}: {}", url, reason))?;
+  let is_html = response
+    .headers()
+    .get(reqwest::header::CONTENT_TYPE)
+    .and_then(|mime| mime.to_str().ok())
+    .and_then(|mime| mime.parse::<mime::Mime>().ok())
+    .map_or(false, |mime| mime.essence_str() == "text/html");
+  if !is_html {
+    return Ok(None);
+  }
+  // Redirects are followed, and relative image URLs are relative to where they end up
+  let page_url = response.url().clone();
+  let mut page = Vec::new();
+  while let Some(chunk) = response.chunk().await? {
+    page.extend_from_slice(&chunk);
+    if page.len() > MAX_PAGE_BYTES {
+      break;
+    }
+  }
+  Ok(parse(&String::from_utf8_lossy(&page), &page_url))
+}
+
+// The page's OpenGraph tags, or else the ones other previews use. A page with neither a title nor a
+// description has nothing to show.
+fn parse(page: &str, page_url: &Url) -> Option<Preview> {
+  let document = Html::parse_document(page);
+  let meta = |names: &[&str]| {
+    names.iter().find_map(|name| {
+      let selector =
+        Selector::parse(&format!("meta[property=\"{0}\"], meta[name=\"{0}\"]", name)).ok()?;
+      document
+        .select(&selector)
+        .find_map(|element| element.value().attr("content"))
+        .map(str::trim)
+        .filter(|content| !content.is_empty())
+        .map(str::to_string)
+    })
+  };
+  let title = meta(&["og:title", "twitter:title"]).or_else(|| {
+    let selector = Selector::parse("title").ok()?;
+    let title = document
+      .select(&selector)
+      .next()?
+      .text()
+      .collect::<String>();
+    Some(title.trim().to_string()).filter(|title| !title.is_empty())
+  });
+  let description =
+    meta(&["og:description", "twitter:description", "description"]).map(|description| {
+      match description.char_indices().nth(MAX_DESCRIPTION_CHARS) {
+        Some((end, _)) => format!("{}…", description[..end].trim_end()),
+        None => description,
+      }
+    });
+  let image_url = meta(&["og:image", "og:image:url", "twitter:image"])
+    .and_then(|image| page_url.join(&image).ok())
+    .filter(|image| matches!(image.scheme(), "http" | "https"))
+    .map(String::from);
+  if title.is_none() && description.is_none() {
+    return None;
+  }
+  Some(Preview {
+    title,
+    description,
+    image_url,
+  })
+}
+
+fn quote(preview: &Preview, url: &str, image: Option<&str>) -> (String, String) {
+  let mut quoted = Message::default();
+  if let Some(title) = &preview.title {
+    quoted.title(title, Some(url));
+  }
+  if let Some(description) = &preview.description {
+    quoted.line().text(description);
+  }
+  if let Some(image) = image {
+    let alt = preview.title.as_deref().unwrap_or(url);
+    quoted.line().rich(
+      "",
+      &format!(
+        "<img src=\"{}\" alt=\"{}\" height=\"120\">",
+        escape_html(image),
+        escape_html(alt)
+      ),
+    );
+  }
+  let mut message = Message::default();
+  message.quote(quoted);
+  message.into_parts()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_first_url() {
+    assert_eq!(
+      first_url("Deployed (see https://ci.example.com/builds/42).").as_deref(),
+      Some("https://ci.example.com/builds/42")
+    );
+    assert_eq!(
+      first_url("[the PR](http://git.example.com/pr/7) and https://example.com").as_deref(),
+      Some("http://git.example.com/pr/7")
+    );
+    assert_eq!(first_url("No links, just https:// alone"), None);
+  }
+
+  #[test]
+  fn test_parse_and_quote() {
+    let page_url = Url::parse("https://example.com/posts/1").unwrap();
+    let page = "<html><head><title>Fallback</title>\
+      <meta property=\"og:title\" content=\"Release 2.0\">\
+      <meta name=\"description\" content=\" Faster & smaller \">\
+      <meta property=\"og:image\" content=\"/cover.png\"></head><body>Hi</body></html>";
+    let preview = parse(page, &page_url).unwrap();
+    assert_eq!(
+      preview,
+      Preview {
+        title: Some("Release 2.0".to_string()),
+        description: Some("Faster & smaller".to_string()),
+        image_url: Some("https://example.com/cover.png".to_string()),
+      }
+    );
+    assert_eq!(
+      quote(&preview, page_url.as_str(), Some("mxc://example.com/abc")),
+      (
+        "> Release 2.0 (https://example.com/posts/1)\n> Faster & smaller".to_string(),
+        "<blockquote><b><a href=\"https://example.com/posts/1\">Release 2.0</a></b><br>\
+        Faster &amp; smaller<br><img src=\"mxc://example.com/abc\" alt=\"Release 2.0\" \
+        height=\"120\"></blockquote>"
+          .to_string()
+      )
+    );
+
+    assert_eq!(
+      parse("<title> Just a title </title>", &page_url)
+        .unwrap()
+        .title
+        .as_deref(),
+      Some("Just a title")
+    );
+    assert_eq!(parse("<p>Nothing</p>", &page_url), None);
+  }
+}
@@ -0,0 +1,489 @@
+// The nodejs bridge's provisioning API, served under /api/v1/provision when the config's
+// `provisioning.secret` is set, so integration managers like Dimension can manage a room's hooks
+// without talking to the bot. Requests give the secret as the `token` query parameter, and the user
+// they're made for as `userId`, who needs enough power to create hooks in the room.
+use std::{collections::HashMap, convert::TryFrom, fmt};
+
+use anyhow::Result;
+use log::*;
+use matrix_sdk::{room::Joined, ruma::UserId, Client};
+use serde::Deserialize;
+use warp::{hyper::body::Bytes, Filter, Rejection, Reply};
+
+use crate::{
+  appservice_api::{decode_path_segment, with_context},
+  bot, bridge_info, payloads, room_reference, space,
+  store::{LimitExceeded, Webhook},
+  webhook::RequestContext,
+};
+
+// The placeholder in the nodejs bridge's sample config, which mustn't open the API to anyone who
+// has read it
+const PLACEHOLDER_SECRET: &str = "CHANGE_ME";
+
+type Query = HashMap<String, String>;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct HookOptions {
+  label: Option<String>,
+}
+
+/// Why a request was turned away, which is the caller's to fix
+#[derive(Debug)]
+struct Refusal(http::StatusCode, &'static str);
+
+impl fmt::Display for Refusal {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.1)
+  }
+}
+
+impl std::error::Error for Refusal {}
+
+pub fn filter(
+  context: RequestContext,
+) -> impl Filter<Extract = (Box<dyn Reply>,), Error = Rejection> + Clone {
+  let info = warp::path!("api" / "v1" / "provision" / "info")
+    .and(warp::filters::method::get())
+    .and(warp::query::<Query>())
+    .and(with_context(context.clone()))
+    .and_then(info);
+
+  let hooks = warp::path!("api" / "v1" / "provision" / String / "hooks")
+    .and(warp::filters::method::get())
+    .and(warp::query::<Query>())
+    .and(with_context(context.clone()))
+    .and_then(hooks);
+
+  // Dimension sends the label, but the body may be empty
+  let create = warp::path!("api" / "v1" / "provision" / String / "hook")
+    .and(warp::filters::method::put())
+    .and(warp::query::<Query>())
+    .and(warp::filters::body::bytes())
+    .and(with_context(context.clone()))
+    .and_then(create);
+
+  let get = warp::path!("api" / "v1" / "provision" / String / "hook" / String)
+    .and(warp::filters::method::get())
+    .and(warp::query::<Query>())
+    .and(with_context(context.clone()))
+    .and_then(get);
+
+  let update = warp::path!("api" / "v1" / "provision" / String / "hook" / String)
+    .and(warp::filters::method::put())
+    .and(warp::query::<Query>())
+    .and(warp::filters::body::bytes())
+    .and(with_context(context.clone()))
+    .and_then(update);
+
+  let delete = warp::path!("api" / "v1" / "provision" / String / "hook" / String)
+    .and(warp::filters::method::delete())
+    .and(warp::query::<Query>())
+    .and(with_context(context))
+    .and_then(delete);
+
+  info
+    .or(hooks)
+    .unify()
+    .or(create)
+    .unify()
+    .or(get)
+    .unify()
+    .or(update)
+    .unify()
+    .or(delete)
+    .unify()
+}
+
+// The secret is only accepted as a query parameter, since that's how the nodejs bridge took it
+fn unauthorized(context: &RequestContext, query: &Query) -> Option<Box<dyn Reply>> {
+  let secret = context
+    .config
+    .provisioning
+    .as_ref()
+    .map(|provisioning| provisioning.secret.as_str())
+    .filter(|secret| !secret.is_empty() && *secret != PLACEHOLDER_SECRET);
+  match (secret, query.get("token")) {
+    (Some(secret), Some(token)) if payloads::is_secret(secret, token.as_bytes()) => None,
+    (None, _) => Some(error_reply(
+      http::StatusCode::NOT_FOUND,
+      "The provisioning API is disabled",
+    )),
+    _ => Some(error_reply(
+      http::StatusCode::FORBIDDEN,
+      "Unknown provisioning secret",
+    )),
+  }
+}
+
+fn error_reply(status: http::StatusCode, message: &str) -> Box<dyn Reply> {
+  Box::new(warp::reply::with_status(
+    warp::reply::json(&serde_json::json!({ "success": false, "message": message })),
+    status,
+  ))
+}
+
+fn result_reply(action: &str, result: Result<serde_json::Value>) -> Box<dyn Reply> {
+  match result {
+    Ok(mut json) => {
+      json["success"] = true.into();
+      Box::new(warp::reply::json(&json))
+    }
+    Err(e) => {
+      if let Some(Refusal(status, message)) = e.downcast_ref::<Refusal>() {
+        return error_reply(*status, message);
+      }
+      if let Some(limit) = e.downcast_ref::<LimitExceeded>() {
+        return error_reply(http::StatusCode::BAD_REQUEST, &limit.to_string());
+      }
+      error!(
+        "Error handling provisioning request to {}: {}",
+        action,
+        e.to_string()
+      );
+      error_reply(http::StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+    }
+  }
+}
+
+// The hook as the nodejs bridge described it
+fn hook_json(context: &RequestContext, hook: &Webhook) -> serde_json::Value {
+  serde_json::json!({
+    "id": hook.id,
+    "label": hook.label,
+    "url": bot::hook_url(&context.config, &hook.id),
+    "userId": hook.user_id,
+    "roomId": hook.room_id,
+    "type": "incoming",
+  })
+}
+
+fn options(body: &Bytes) -> Result<HookOptions> {
+  if body.iter().all(u8::is_ascii_whitespace) {
+    return Ok(HookOptions::default());
+  }
+  serde_json::from_slice(body)
+    .map_err(|_| Refusal(http::StatusCode::BAD_REQUEST, "The body isn't valid JSON").into())
+}
+
+// The bot's client, the room whose hooks the request is for and the user it's for, if they may
+// manage them. The bot has to be in the room already, since integration managers invite it first.
+async fn authorize(
+  context: &RequestContext,
+  room: &str,
+  query: &Query,
+) -> Result<(Client, Joined, UserId)> {
+  let user_id = query
+    .get("userId")
+    .and_then(|user_id| UserId::try_from(user_id.as_str()).ok())
+    .ok_or(Refusal(
+      http::StatusCode::BAD_REQUEST,
+      "userId should be the user the request is for",
+    ))?;
+  let client = context
+    .appservice
+    .virtual_user_client(&context.config.webhook_bot.localpart)
+    .await?;
+  let room = room_reference::resolve(&client, &decode_path_segment(room))
+    .await?
+    .and_then(|room_id| client.get_joined_room(&room_id))
+    .ok_or(Refusal(
+      http::StatusCode::BAD_REQUEST,
+      "The bridge isn't in that room. Invite it first",
+    ))?;
+
+  let is_member = room
+    .joined_members_no_sync()
+    .await?
+    .iter()
+    .any(|member| member.user_id() == &user_id);
+  if !is_member {
+    return Err(Refusal(http::StatusCode::FORBIDDEN, "The user isn't in that room").into());
+  }
+  if bot::missing_create_level(&context.config, &room, &user_id)
+    .await?
+    .is_some()
+  {
+    return Err(
+      Refusal(
+        http::StatusCode::FORBIDDEN,
+        "The user doesn't have enough power to manage webhooks in that room",
+      )
+      .into(),
+    );
+  }
+  Ok((client, room, user_id))
+}
+
+// One of the room's hooks, by id
+async fn room_hook(context: &RequestContext, room: &Joined, hook_id: &str) -> Result<Webhook> {
+  context
+    .store
+    .get_webhook_by_id(hook_id)
+    .await?
+    .filter(|hook| hook.room_id == room.room_id().as_str())
+    .ok_or_else(|| {
+      Refusal(
+        http::StatusCode::NOT_FOUND,
+        "That room has no webhook with that id",
+      )
+      .into()
+    })
+}
+
+// One of the room's hooks that the user may change, like with !webhook delete, being its creator, a
+// bridge admin or, as for !webhook purge, one of the room's moderators
+async fn managed_hook(
+  context: &RequestContext,
+  room: &Joined,
+  hook_id: &str,
+  user_id: &UserId,
+) -> Result<Webhook> {
+  let hook = room_hook(context, room, hook_id).await?;
+  if hook.user_id != user_id.as_str()
+    && !context.config.webhook_bot.is_admin(user_id.as_str())
+    && !bot::is_room_moderator(room, user_id).await?
+  {
+    return Err(
+      Refusal(
+        http::StatusCode::FORBIDDEN,
+        "Only the webhook's creator, the room's moderators or a bridge admin can change it",
+      )
+      .into(),
+    );
+  }
+  Ok(hook)
+}
+
+// Labels follow the same rules as in !webhook create, and stay unique within the room
+async fn check_label(context: &RequestContext, room: &Joined, label: &str) -> Result<()> {
+  if !bot::is_valid_label(label) {
+    return Err(Refusal(http::StatusCode::BAD_REQUEST, bot::INVALID_LABEL_MESSAGE).into());
+  }
+  if context
+    .store
+    .is_label_in_use(room.room_id().as_str(), label)
+    .await?
+  {
+    return Err(
+      Refusal(
+        http::StatusCode::CONFLICT,
+        "That room already has a hook with that label",
+      )
+      .into(),
+    );
+  }
+  Ok(())
+}
+
+async fn info(query: Query, context: RequestContext) -> Result<Box<dyn Reply>, Rejection> {
+  if let Some(reply) = unauthorized(&context, &query) {
+    return Ok(reply);
+  }
+  Ok(result_reply(
+    "get the bridge's info",
+    bot::bot_user_id(&context.config)
+      .map(|bot_user_id| serde_json::json!({ "botUserId": bot_user_id })),
+  ))
+}
+
+async fn hooks(
+  room: String,
+  query: Query,
+  context: RequestContext,
+) -> Result<Box<dyn Reply>, Rejection> {
+  if let Some(reply) = unauthorized(&context, &query) {
+    return Ok(reply);
+  }
+  Ok(result_reply(
+    "list a room's webhooks",
+    handle_hooks(&room, &query, &context).await,
+  ))
+}
+
+async fn handle_hooks(
+  room: &str,
+  query: &Query,
+  context: &RequestContext,
+) -> Result<serde_json::Value> {
+  let (_, room, _) = authorize(context, room, query).await?;
+  let hooks: Vec<_> = context
+    .store
+    .get_webhooks_for_room(room.room_id().as_str())
+    .await?
+    .iter()
+    .map(|hook| hook_json(context, hook))
+    .collect();
+  Ok(serde_json::json!({ "results": hooks }))
+}
+
+async fn create(
+  room: String,
+  query: Query,
+  body: Bytes,
+  context: RequestContext,
+) -> Result<Box<dyn Reply>, Rejection> {
+  if let Some(reply) = unauthorized(&context, &query) {
+    return Ok(reply);
+  }
+  Ok(result_reply(
+    "create a webhook",
+    handle_create(&room, &query, &body, &context).await,
+  ))
+}
+
+// Like !webhook create, made by the user the request is for
+async fn handle_create(
+  room: &str,
+  query: &Query,
+  body: &Bytes,
+  context: &RequestContext,
+) -> Result<serde_json::Value> {
+  let options = options(body)?;
+  let (client, room, user_id) = authorize(context, room, query).await?;
+  let label = options.label.as_deref().filter(|label| !label.is_empty());
+  if let Some(label) = label {
+    check_label(context, &room, label).await?;
+  }
+
+  info!(
+    "Creating webhook for room {} through the provisioning API",
+    room.room_id()
+  );
+  let hook = context
+    .store
+    .create_webhook(
+      room.room_id().as_str(),
+      user_id.as_str(),
+      label,
+      None,
+      &context.config.limits,
+    )
+    .await?;
+  space::add_bridged_room(&client, room.room_id()).await;
+  bridge_info::publish(&context.config, &client, &hook).await;
+  Ok(hook_json(context, &hook))
+}
+
+async fn get(
+  room: String,
+  hook_id: String,
+  query: Query,
+  context: RequestContext,
+) -> Result<Box<dyn Reply>, Rejection> {
+  if let Some(reply) = unauthorized(&context, &query) {
+    return Ok(reply);
+  }
+  Ok(result_reply(
+    "get a webhook",
+    handle_get(&room, &hook_id, &query, &context).await,
+  ))
+}
+
+async fn handle_get(
+  room: &str,
+  hook_id: &str,
+  query: &Query,
+  context: &RequestContext,
+) -> Result<serde_json::Value> {
+  let (_, room, _) = authorize(context, room, query).await?;
+  let hook = room_hook(context, &room, hook_id).await?;
+  Ok(hook_json(context, &hook))
+}
+
+async fn update(
+  room: String,
+  hook_id: String,
+  query: Query,
+  body: Bytes,
+  context: RequestContext,
+) -> Result<Box<dyn Reply>, Rejection> {
+  if let Some(reply) = unauthorized(&context, &query) {
+    return Ok(reply);
+  }
+  Ok(result_reply(
+    "update a webhook",
+    handle_update(&room, &hook_id, &query, &body, &context).await,
+  ))
+}
+
+// Only the label can be changed. An empty one removes it.
+async fn handle_update(
+  room: &str,
+  hook_id: &str,
+  query: &Query,
+  body: &Bytes,
+  context: &RequestContext,
+) -> Result<serde_json::Value> {
+  let options = options(body)?;
+  let (client, room, user_id) = authorize(context, room, query).await?;
+  let hook = managed_hook(context, &room, hook_id, &user_id).await?;
+  let label = options.label.as_deref().filter(|label| !label.is_empty());
+  if let Some(label) = label.filter(|label| Some(*label) != hook.label.as_deref()) {
+    check_label(context, &room, label).await?;
+  }
+
+  context.store.set_webhook_label(&hook.id, label).await?;
+  info!(
+    "Webhook {} was relabeled by {} through the provisioning API",
+    &hook.id, user_id
+  );
+  let hook = room_hook(context, &room, hook_id).await?;
+  bridge_info::publish(&context.config, &client, &hook).await;
+  Ok(hook_json(context, &hook))
+}
+
+async fn delete(
+  room: String,
+  hook_id: String,
+  query: Query,
+  context: RequestContext,
+) -> Result<Box<dyn Reply>, Rejection> {
+  if let Some(reply) = unauthorized(&context, &query) {
+    return Ok(reply);
+  }
+  Ok(result_reply(
+    "delete a webhook",
+    handle_delete(&room, &hook_id, &query, &context).await,
+  ))
+}
+
+async fn handle_delete(
+  room: &str,
+  hook_id: &str,
+  query: &Query,
+  context: &RequestContext,
+) -> Result<serde_json::Value> {
+  let (_, room, user_id) = authorize(context, room, query).await?;
+  let hook = managed_hook(context, &room, hook_id, &user_id).await?;
+  context.store.delete_webhook(&hook.id).await?;
+  info!(
+    "Webhook {} was deleted by {} through the provisioning API",
+    &hook.id, user_id
+  );
+  Ok(serde_json::json!({}))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_options() {
+    assert_eq!(options(&Bytes::from_static(b"")).unwrap().label, None);
+    assert_eq!(options(&Bytes::from_static(b" \n")).unwrap().label, None);
+    assert_eq!(
+      options(&Bytes::from_static(br#"{"label": "ci"}"#))
+        .unwrap()
+        .label
+        .as_deref(),
+      Some("ci")
+    );
+    let refusal = options(&Bytes::from_static(b"label=ci")).unwrap_err();
+    assert_eq!(
+      refusal.downcast_ref::<Refusal>().unwrap().0,
+      http::StatusCode::BAD_REQUEST
+    );
+  }
+}
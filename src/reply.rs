@@ -0,0 +1,174 @@
+// Rich replies to an earlier event in the room. Text replies quote the event as a fallback, for
+// clients that don't show replies.
+use std::convert::TryFrom;
+
+use anyhow::Result;
+use matrix_sdk::ruma::{
+  api::client::r0::room::get_room_event,
+  events::room::message::{
+    FormattedBody, InReplyTo, MessageEventContent, MessageType, NoticeMessageEventContent,
+    Relation, TextMessageEventContent,
+  },
+  EventId, RoomId,
+};
+use matrix_sdk::Client;
+use serde::Deserialize;
+
+use crate::{bot, webhook::HookError};
+
+#[derive(Debug, Deserialize)]
+struct RepliedTo {
+  sender: String,
+  #[serde(default)]
+  content: Content,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Content {
+  #[serde(default)]
+  body: String,
+  format: Option<String>,
+  formatted_body: Option<String>,
+  #[serde(rename = "m.relates_to")]
+  relates_to: Option<serde_json::Value>,
+}
+
+/// Makes the message a reply to the event, which the client must be able to see
+pub async fn reply_to(
+  client: &Client,
+  room_id: &RoomId,
+  event_id: &str,
+  message: &mut MessageEventContent,
+) -> Result<()> {
+  let not_found = || HookError::InvalidPayload(format!("{} isn't an event in the room", event_id));
+  let event_id = EventId::try_from(event_id).map_err(|_| not_found())?;
+  let response = client
+    .send(get_room_event::Request::new(room_id, &event_id), None)
+    .await
+    .map_err(|_| not_found())?;
+  let replied_to: RepliedTo = serde_json::from_str(response.event.json().get())?;
+
+  match &mut message.msgtype {
+    MessageType::Text(TextMessageEventContent {
+      body, formatted, ..
+    })
+    | MessageType::Notice(NoticeMessageEventContent {
+      body, formatted, ..
+    }) => {
+      let html = match formatted {
+        Some(formatted) => formatted.body.clone(),
+        None => text_html(body),
+      };
+      *formatted = Some(FormattedBody::html(html_fallback(
+        room_id.as_str(),
+        event_id.as_str(),
+        &replied_to,
+        &html,
+      )));
+      *body = plain_fallback(&replied_to, body);
+    }
+    // Media has no body to quote the event in
+    _ => {}
+  }
+  message.relates_to = Some(Relation::Reply {
+    in_reply_to: InReplyTo::new(event_id),
+  });
+  Ok(())
+}
+
+fn text_html(plain: &str) -> String {
+  bot::escape_html(plain).replace('\n', "<br>")
+}
+
+// The event's lines quoted, with its sender on the first, then the reply
+fn plain_fallback(replied_to: &RepliedTo, reply: &str) -> String {
+  let body = if replied_to.content.relates_to.is_some() {
+    strip_plain_fallback(&replied_to.content.body)
+  } else {
+    &replied_to.content.body
+  };
+  let mut lines = body.lines();
+  let mut fallback = format!(
+    "> <{}> {}\n",
+    replied_to.sender,
+    lines.next().unwrap_or_default()
+  );
+  for line in lines {
+    fallback.push_str(&format!("> {}\n", line));
+  }
+  format!("{}\n{}", fallback, reply)
+}
+
+fn html_fallback(room_id: &str, event_id: &str, replied_to: &RepliedTo, reply: &str) -> String {
+  let content = &replied_to.content;
+  let quoted = match (&content.format, &content.formatted_body) {
+    (Some(format), Some(html)) if format == "org.matrix.custom.html" => {
+      strip_html_fallback(html).to_string()
+    }
+    _ => text_html(&content.body),
+  };
+  format!(
+    "<mx-reply><blockquote><a href=\"https://matrix.to/#/{}/{}\">In reply to</a> \
+    <a href=\"https://matrix.to/#/{}\">{}</a><br>{}</blockquote></mx-reply>{}",
+    room_id,
+    event_id,
+    replied_to.sender,
+    bot::escape_html(&replied_to.sender),
+    quoted,
+    reply
+  )
+}
+
+// A reply's own quote of what it replied to, so quotes don't nest
+fn strip_plain_fallback(body: &str) -> &str {
+  if !body.starts_with("> ") {
+    return body;
+  }
+  match body.find("\n\n") {
+    Some(end) => &body[end + 2..],
+    None => body,
+  }
+}
+
+fn strip_html_fallback(html: &str) -> &str {
+  match html.find("</mx-reply>") {
+    Some(end) => &html[end + "</mx-reply>".len()..],
+    None => html,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_fallback() -> Result<()> {
+    let replied_to: RepliedTo = serde_json::from_str(
+      r#"{
+        "sender": "@_webhook__ci:localhost",
+        "content": {
+          "body": "> <@alice:localhost> Deploy?\n\nDeploy started\non prod",
+          "format": "org.matrix.custom.html",
+          "formatted_body": "<mx-reply>Deploy?</mx-reply>Deploy started<br>on <b>prod</b>",
+          "m.relates_to": {"m.in_reply_to": {"event_id": "$1"}}
+        }
+      }"#,
+    )?;
+    assert_eq!(
+      plain_fallback(&replied_to, "Deploy finished"),
+      "> <@_webhook__ci:localhost> Deploy started\n> on prod\n\nDeploy finished"
+    );
+    assert_eq!(
+      html_fallback(
+        "!room:localhost",
+        "$2",
+        &replied_to,
+        "Deploy <b>finished</b>"
+      ),
+      "<mx-reply><blockquote><a href=\"https://matrix.to/#/!room:localhost/$2\">In reply to</a> \
+      <a href=\"https://matrix.to/#/@_webhook__ci:localhost\">@_webhook__ci:localhost</a><br>\
+      Deploy started<br>on <b>prod</b></blockquote></mx-reply>Deploy <b>finished</b>"
+    );
+    Ok(())
+  }
+}
@@ -0,0 +1,103 @@
+// Rooms as people paste them: room ids, aliases, matrix.to links and matrix: URIs
+use std::convert::TryFrom;
+
+use anyhow::Result;
+use matrix_sdk::{
+  ruma::{RoomAliasId, RoomId},
+  Client,
+};
+
+#[derive(Debug, PartialEq)]
+pub enum RoomReference {
+  Id(String),
+  Alias(String),
+}
+
+/// Extracts the room id or alias, ignoring any event, `via` servers and the like
+pub fn parse(input: &str) -> Option<RoomReference> {
+  let input = input.trim();
+  let identifier = if let Some(rest) = input
+    .strip_prefix("https://matrix.to/#/")
+    .or_else(|| input.strip_prefix("http://matrix.to/#/"))
+  {
+    let rest = rest.split('?').next()?;
+    decode(rest.split('/').next()?)
+  } else if let Some(rest) = input.strip_prefix("matrix:") {
+    let rest = rest.split(&['?', '#'][..]).next()?;
+    let mut segments = rest.split('/');
+    let sigil = match segments.next()? {
+      "roomid" => '!',
+      "r" => '#',
+      _ => return None,
+    };
+    format!("{}{}", sigil, decode(segments.next()?))
+  } else {
+    input.to_string()
+  };
+
+  match identifier.chars().next()? {
+    '!' => Some(RoomReference::Id(identifier)),
+    '#' => Some(RoomReference::Alias(identifier)),
+    _ => None,
+  }
+}
+
+fn decode(segment: &str) -> String {
+  percent_encoding::percent_decode_str(segment)
+    .decode_utf8_lossy()
+    .to_string()
+}
+
+/// The room's id, asking the homeserver if it's an alias. None if the input isn't a room at all,
+/// or the alias doesn't exist.
+pub async fn resolve(client: &Client, input: &str) -> Result<Option<RoomId>> {
+  match parse(input) {
+    Some(RoomReference::Id(room_id)) => Ok(RoomId::try_from(room_id).ok()),
+    Some(RoomReference::Alias(alias)) => {
+      let alias = match RoomAliasId::try_from(alias) {
+        Ok(alias) => alias,
+        Err(_) => return Ok(None),
+      };
+      match client.resolve_room_alias(&alias).await {
+        Ok(response) => Ok(Some(response.room_id)),
+        Err(_) => Ok(None),
+      }
+    }
+    None => Ok(None),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse() {
+    use RoomReference::*;
+
+    assert_eq!(Some(Id("!abc:localhost".into())), parse("!abc:localhost"));
+    assert_eq!(
+      Some(Alias("#room:localhost".into())),
+      parse(" #room:localhost ")
+    );
+    assert_eq!(
+      Some(Id("!abc:localhost".into())),
+      parse("https://matrix.to/#/!abc:localhost/$event?via=localhost")
+    );
+    assert_eq!(
+      Some(Alias("#room:localhost".into())),
+      parse("https://matrix.to/#/%23room%3Alocalhost")
+    );
+    assert_eq!(
+      Some(Id("!abc:localhost".into())),
+      parse("matrix:roomid/abc:localhost?via=localhost")
+    );
+    assert_eq!(
+      Some(Alias("#room:localhost".into())),
+      parse("matrix:r/room:localhost/e/event")
+    );
+    assert_eq!(None, parse("matrix:u/alice:localhost"));
+    assert_eq!(None, parse("https://example.com/"));
+    assert_eq!(None, parse(""));
+  }
+}
@@ -0,0 +1,135 @@
+// Cleans the HTML hooks post down to what the Matrix spec recommends clients allow, so whoever has a
+// hook's URL can't inject scripts or abusive markup into the room
+use std::{borrow::Cow, collections::HashSet};
+
+use crate::config::Html;
+
+const TAGS: &[&str] = &[
+  "font",
+  "del",
+  "h1",
+  "h2",
+  "h3",
+  "h4",
+  "h5",
+  "h6",
+  "blockquote",
+  "p",
+  "a",
+  "ul",
+  "ol",
+  "sup",
+  "sub",
+  "li",
+  "b",
+  "i",
+  "u",
+  "strong",
+  "em",
+  "strike",
+  "code",
+  "hr",
+  "br",
+  "div",
+  "table",
+  "thead",
+  "tbody",
+  "tr",
+  "th",
+  "td",
+  "caption",
+  "pre",
+  "span",
+  "img",
+  "details",
+  "summary",
+];
+
+const TAG_ATTRIBUTES: &[(&str, &[&str])] = &[
+  ("font", &["data-mx-bg-color", "data-mx-color", "color"]),
+  (
+    "span",
+    &[
+      "data-mx-bg-color",
+      "data-mx-color",
+      "data-mx-spoiler",
+      "data-mx-maths",
+    ],
+  ),
+  ("a", &["name", "target", "href"]),
+  ("img", &["width", "height", "alt", "title", "src"]),
+  ("ol", &["start"]),
+  ("div", &["data-mx-maths"]),
+  ("code", &["class"]),
+];
+
+const URL_SCHEMES: &[&str] = &["http", "https", "ftp", "mailto", "magnet", "mxc"];
+
+/// The html with only the allowed tags and attributes left
+pub fn sanitize(html: &str, policy: &Html) -> String {
+  let tags: HashSet<&str> = TAGS
+    .iter()
+    .copied()
+    .chain(policy.extra_tags.iter().map(String::as_str))
+    .filter(|tag| {
+      !policy
+        .forbidden_tags
+        .iter()
+        .any(|forbidden| forbidden == tag)
+    })
+    .collect();
+  let tag_attributes = TAG_ATTRIBUTES
+    .iter()
+    .map(|(tag, attributes)| (*tag, attributes.iter().copied().collect()))
+    .collect();
+
+  ammonia::Builder::default()
+    .tags(tags)
+    .tag_attributes(tag_attributes)
+    .url_schemes(URL_SCHEMES.iter().copied().collect())
+    .link_rel(None)
+    .attribute_filter(|tag, attribute, value| match (tag, attribute) {
+      // Clients only load images from the homeserver, so others would leak who reads the room
+      ("img", "src") if !value.starts_with("mxc://") => None,
+      // Only the class naming a code block's language means anything
+      ("code", "class") if !value.starts_with("language-") => None,
+      _ => Some(Cow::Borrowed(value)),
+    })
+    .clean(html)
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_sanitize() {
+    let policy = Html::default();
+    assert_eq!(
+      sanitize(
+        "<b onclick=\"steal()\">Deployed</b><script>steal()</script> \
+        <a href=\"javascript:steal()\">log</a> <a href=\"https://example.com\">diff</a> \
+        <img src=\"https://example.com/track.png\"><img src=\"mxc://example.com/abc\"> \
+        <span data-mx-spoiler>secret</span> <code class=\"language-rust\">x</code> \
+        <code class=\"evil\">y</code> <marquee>hi</marquee>",
+        &policy
+      ),
+      "<b>Deployed</b> <a>log</a> <a href=\"https://example.com\">diff</a> \
+      <img><img src=\"mxc://example.com/abc\"> <span data-mx-spoiler=\"\">secret</span> \
+      <code class=\"language-rust\">x</code> <code>y</code> hi"
+    );
+
+    let policy = Html {
+      extra_tags: vec!["marquee".to_string()],
+      forbidden_tags: vec!["img".to_string()],
+    };
+    assert_eq!(
+      sanitize(
+        "<marquee>hi</marquee><img src=\"mxc://example.com/abc\">",
+        &policy
+      ),
+      "<marquee>hi</marquee>"
+    );
+  }
+}
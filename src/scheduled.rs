@@ -0,0 +1,89 @@
+// Posts the messages that were sent with a `sendAt` time once it comes. They're kept in the store,
+// so none are forgotten over a restart.
+use std::{
+  sync::Arc,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use log::*;
+use matrix_sdk_appservice::AppService;
+
+use crate::{
+  config::Config,
+  store::Store,
+  webhook::{self, HookError},
+  webhook_request::WebhookRequest,
+};
+
+const INTERVAL_SECONDS: u64 = 5;
+
+// Messages that fail to post are retried until they're this late, in case the homeserver was only
+// down. Those it refused for good reason, like the hook being paused, aren't.
+const GIVE_UP_AFTER_SECONDS: i64 = 60 * 60;
+
+pub fn spawn(config: Arc<Config>, store: Arc<Store>, appservice: AppService) {
+  tokio::task::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(INTERVAL_SECONDS));
+    loop {
+      interval.tick().await;
+      if let Err(e) = send_due(&config, &store, &appservice).await {
+        warn!("Failed to send scheduled messages: {}", e.to_string());
+      }
+    }
+  });
+}
+
+async fn send_due(config: &Arc<Config>, store: &Arc<Store>, appservice: &AppService) -> Result<()> {
+  let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+  for message in store.due_messages().await? {
+    let request: WebhookRequest = match serde_json::from_str(&message.request) {
+      Ok(request) => request,
+      Err(e) => {
+        warn!(
+          "Dropping scheduled message {} for webhook {}, which can't be read: {}",
+          message.id,
+          message.hook_id,
+          e.to_string()
+        );
+        store.delete_scheduled_message(message.id).await?;
+        continue;
+      }
+    };
+    let result = webhook::handler_inner(
+      &message.hook_id,
+      request,
+      config.clone(),
+      appservice.clone(),
+      store.clone(),
+    )
+    .await;
+    match result {
+      Ok(_) => {
+        debug!(
+          "Sent scheduled message {} for webhook {}",
+          message.id, message.hook_id
+        );
+      }
+      Err(e) if !e.is::<HookError>() && now - message.send_at < GIVE_UP_AFTER_SECONDS => {
+        warn!(
+          "Failed to send scheduled message {} for webhook {}, will retry: {}",
+          message.id,
+          message.hook_id,
+          e.to_string()
+        );
+        continue;
+      }
+      Err(e) => {
+        warn!(
+          "Giving up on scheduled message {} for webhook {}: {}",
+          message.id,
+          message.hook_id,
+          e.to_string()
+        );
+      }
+    }
+    store.delete_scheduled_message(message.id).await?;
+  }
+  Ok(())
+}
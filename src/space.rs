@@ -0,0 +1,141 @@
+// Optionally gathers the rooms the bot creates into a Space, configured under `space:`
+use std::{convert::TryFrom, sync::RwLock, time::Duration};
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use log::*;
+use matrix_sdk::{
+  ruma::{api::appservice::Registration, RoomId, UserId},
+  Client,
+};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde_json::json;
+
+use crate::{bot, config::Config, store::Store};
+
+const SPACE_SETTING: &str = "spaceRoomId";
+
+lazy_static! {
+  static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::builder()
+    .timeout(Duration::from_secs(30))
+    .build()
+    .unwrap();
+  // Set once at startup, if there is a space
+  static ref SPACE: RwLock<Option<Space>> = RwLock::new(None);
+}
+
+#[derive(Debug, Clone)]
+struct Space {
+  room_id: RoomId,
+  include_bridged_rooms: bool,
+}
+
+/// Finds the space created on an earlier run, or creates it
+pub async fn setup(
+  config: &Config,
+  store: &Store,
+  registration: &Registration,
+  client: &Client,
+) -> Result<()> {
+  let space_config = match &config.space {
+    Some(space_config) => space_config,
+    None => return Ok(()),
+  };
+
+  let existing = match store.get_setting(SPACE_SETTING).await? {
+    Some(room_id) => Some(RoomId::try_from(room_id)?),
+    None => None,
+  };
+  let room_id = match existing {
+    Some(room_id) if client.get_joined_room(&room_id).is_some() => room_id,
+    _ => {
+      let room_id = create_space(config, registration, &space_config.name).await?;
+      store.set_setting(SPACE_SETTING, room_id.as_str()).await?;
+      info!("Created space {}", room_id);
+      room_id
+    }
+  };
+
+  *SPACE.write().unwrap() = Some(Space {
+    room_id,
+    include_bridged_rooms: space_config.include_bridged_rooms,
+  });
+  Ok(())
+}
+
+/// Adds a room the bot made to talk to someone to the space, and invites them to it
+pub async fn add_admin_room(client: &Client, room_id: &RoomId, counterparty: &UserId) {
+  let space = SPACE.read().unwrap().clone();
+  if let Some(space) = space {
+    add_child(client, &space.room_id, room_id).await;
+    if let Some(space_room) = client.get_joined_room(&space.room_id) {
+      if let Err(e) = space_room.invite_user_by_id(counterparty).await {
+        warn!(
+          "Failed to invite {} to space {}: {}",
+          counterparty,
+          &space.room_id,
+          e.to_string()
+        );
+      }
+    }
+  }
+}
+
+/// Adds a room with a hook to the space, if it's configured to include those
+pub async fn add_bridged_room(client: &Client, room_id: &RoomId) {
+  let space = SPACE.read().unwrap().clone();
+  if let Some(space) = space.filter(|space| space.include_bridged_rooms) {
+    add_child(client, &space.room_id, room_id).await;
+  }
+}
+
+// Not worth failing whatever created the room over
+async fn add_child(client: &Client, space_id: &RoomId, room_id: &RoomId) {
+  let content = json!({ "via": [room_id.server_name().as_str()] });
+  let result =
+    bot::send_state_event_raw(client, space_id, "m.space.child", room_id.as_str(), content).await;
+
+  match result {
+    Ok(()) => debug!("Added {} to space {}", room_id, space_id),
+    Err(e) => warn!(
+      "Failed to add {} to space {}: {}",
+      room_id,
+      space_id,
+      e.to_string()
+    ),
+  }
+}
+
+// The SDK can't set the room type at creation yet, so this goes straight to the client-server API
+async fn create_space(config: &Config, registration: &Registration, name: &str) -> Result<RoomId> {
+  let bot_user_id = bot::bot_user_id(config)?;
+  let url = format!(
+    "{}/_matrix/client/r0/createRoom?user_id={}",
+    config.homeserver.url.trim_end_matches('/'),
+    utf8_percent_encode(bot_user_id.as_str(), NON_ALPHANUMERIC)
+  );
+  let body = json!({
+    "name": name,
+    // Public, so it's never mistaken for an admin room
+    "preset": "public_chat",
+    "creation_content": { "type": "m.space" },
+  });
+
+  let response = HTTP_CLIENT
+    .post(&url)
+    .bearer_auth(&registration.as_token)
+    .header(reqwest::header::CONTENT_TYPE, "application/json")
+    .body(serde_json::to_vec(&body)?)
+    .send()
+    .await?;
+  let status = response.status();
+  let response: serde_json::Value = serde_json::from_slice(&response.bytes().await?)?;
+  if !status.is_success() {
+    return Err(anyhow!("Failed to create space: {} {}", status, response));
+  }
+
+  match response.get("room_id").and_then(|room_id| room_id.as_str()) {
+    Some(room_id) => Ok(RoomId::try_from(room_id)?),
+    None => Err(anyhow!("createRoom response had no room_id")),
+  }
+}
@@ -0,0 +1,100 @@
+// Reminds owners of hooks that haven't posted in a while, so forgotten URLs get deleted rather
+// than lingering wherever they were pasted
+use std::{convert::TryFrom, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use log::*;
+use matrix_sdk::ruma::UserId;
+use matrix_sdk_appservice::AppService;
+
+use crate::{
+  bot,
+  config::Config,
+  store::{Store, Webhook},
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// The setting holding when the hook was last used as of its owner's last reminder, so they're
+// only reminded again if it's used and then forgotten again
+fn reminder_setting(hook_id: &str) -> String {
+  format!("staleReminder:{}", hook_id)
+}
+
+pub fn spawn(config: Arc<Config>, store: Arc<Store>, appservice: AppService) {
+  let days = match config.webhook_bot.stale_hook_days {
+    Some(days) => days,
+    None => return,
+  };
+  tokio::task::spawn(async move {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+      interval.tick().await;
+      if let Err(e) = remind_owners(&config, &store, &appservice, days).await {
+        warn!("Failed to look for stale webhooks: {}", e.to_string());
+      }
+    }
+  });
+}
+
+async fn remind_owners(
+  config: &Config,
+  store: &Store,
+  appservice: &AppService,
+  days: u64,
+) -> Result<()> {
+  let client = appservice
+    .virtual_user_client(&config.webhook_bot.localpart)
+    .await?;
+  let bot_user_id = bot::bot_user_id(config)?;
+
+  for hook in store
+    .get_webhooks_unused_for(days as i64 * 24 * 60 * 60)
+    .await?
+  {
+    let stats = store.get_webhook_stats(&hook.id).await?;
+    let last_activity = match stats.last_used_at.or(stats.created_at) {
+      Some(last_activity) => last_activity.to_string(),
+      None => continue,
+    };
+    if store.get_setting(&reminder_setting(&hook.id)).await? == Some(last_activity.clone()) {
+      continue;
+    }
+    // Hooks created for aliases belong to the bot, which has nobody to tell
+    let owner = UserId::try_from(hook.user_id.as_str())?;
+    if owner == bot_user_id {
+      continue;
+    }
+
+    let (plain, html) = reminder_message(&hook, days);
+    bot::send_admin_notice(&client, &owner, plain, html).await?;
+    store
+      .set_setting(&reminder_setting(&hook.id), &last_activity)
+      .await?;
+    info!("Reminded {} about stale webhook {}", owner, &hook.id);
+  }
+
+  Ok(())
+}
+
+fn reminder_message(hook: &Webhook, days: u64) -> (String, String) {
+  let label = hook
+    .label
+    .as_ref()
+    .map(|label| format!(" ({})", label))
+    .unwrap_or_default();
+  (
+    format!(
+      "Your webhook {}{} in {} hasn't posted anything in {} days. If it's no longer needed, send !webhook delete {} here to delete it.",
+      &hook.id, label, &hook.room_id, days, &hook.id
+    ),
+    format!(
+      "Your webhook <code>{}</code>{} in {} hasn't posted anything in {} days. If it's no longer needed, send <code>!webhook delete {}</code> here to delete it.",
+      &hook.id,
+      bot::escape_html(&label),
+      &hook.room_id,
+      days,
+      &hook.id
+    ),
+  )
+}
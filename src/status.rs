@@ -0,0 +1,82 @@
+// What `!webhook status` reports, so the bridge can be checked on without access to its logs
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use matrix_sdk::{ruma::api::client::unversioned::get_supported_versions, Client};
+
+use crate::{bot, store::Store, webhook};
+
+lazy_static! {
+  static ref STARTED_AT: Instant = Instant::now();
+}
+
+/// Starts the clock for the uptime
+pub fn mark_started() {
+  lazy_static::initialize(&STARTED_AT);
+}
+
+/// The plain and html versions of the report
+pub async fn report(store: &Store, client: &Client) -> (String, String) {
+  let database = match store.ping().await {
+    Ok(()) => "ok".to_string(),
+    Err(e) => format!("unreachable ({})", e),
+  };
+  let started = Instant::now();
+  let homeserver = match client
+    .send(get_supported_versions::Request::new(), None)
+    .await
+  {
+    Ok(_) => format!("{} ms round trip", started.elapsed().as_millis()),
+    Err(e) => format!("unreachable ({})", e),
+  };
+
+  let lines = [
+    ("Version", env!("CARGO_PKG_VERSION").to_string()),
+    ("Uptime", format_duration(STARTED_AT.elapsed())),
+    ("Database", database),
+    ("Homeserver", homeserver),
+    (
+      "Messages waiting for an invite",
+      webhook::pending_message_count().to_string(),
+    ),
+  ];
+  let plain = lines
+    .iter()
+    .map(|(name, value)| format!("{}: {}", name, value))
+    .collect::<Vec<_>>()
+    .join("\n");
+  let html = lines
+    .iter()
+    .map(|(name, value)| format!("<b>{}</b>: {}", name, bot::escape_html(value)))
+    .collect::<Vec<_>>()
+    .join("<br>");
+  (plain, html)
+}
+
+// Like 3d 4h 5m, leaving out the larger units while they're zero
+fn format_duration(duration: Duration) -> String {
+  let minutes = duration.as_secs() / 60;
+  let (days, hours, minutes) = (minutes / 1440, minutes % 1440 / 60, minutes % 60);
+  if days > 0 {
+    format!("{}d {}h {}m", days, hours, minutes)
+  } else if hours > 0 {
+    format!("{}h {}m", hours, minutes)
+  } else {
+    format!("{}m", minutes)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_format_duration() {
+    assert_eq!("0m", format_duration(Duration::from_secs(59)));
+    assert_eq!("1h 0m", format_duration(Duration::from_secs(3600)));
+    assert_eq!(
+      "3d 4h 5m",
+      format_duration(Duration::from_secs(((3 * 24 + 4) * 60 + 5) * 60))
+    );
+  }
+}
@@ -1,5 +1,10 @@
+use crate::config::Limits;
 use anyhow::Result;
-use sqlx::{sqlite::SqliteConnectOptions, Executor, SqlitePool};
+use sqlx::{
+  sqlite::{SqliteConnectOptions, SqliteConnection},
+  SqlitePool,
+};
+use std::fmt;
 
 #[derive(Debug)]
 pub struct Store(SqlitePool);
@@ -11,44 +16,255 @@ pub struct Webhook {
   pub room_id: String,
   pub user_id: String,
   pub label: Option<String>,
+  /// Chosen with `!webhook create --as`, instead of the hashed default
+  pub puppet_localpart: Option<String>,
+  /// Used when a request doesn't give one. Set with `!webhook set-name`.
+  pub display_name: Option<String>,
+  /// Used when a request doesn't give one. Set with `!webhook set-avatar`.
+  pub avatar_url: Option<String>,
+  /// Paused hooks turn requests away. Set with `!webhook pause` and `!webhook resume`.
+  pub enabled: bool,
 }
 
-impl Store {
-  pub async fn connect(path: &str) -> Result<Self> {
-    let opts = SqliteConnectOptions::new()
-      .filename(path)
-      .create_if_missing(true);
-    let conn = SqlitePool::connect_with(opts).await?;
-    conn
-      .execute(sqlx::query(
-        r#"CREATE TABLE IF NOT EXISTS "webhooks" 
+#[derive(Debug, PartialEq, sqlx::FromRow)]
+#[sqlx(rename_all = "camelCase")]
+pub struct Puppet {
+  pub localpart: String,
+  pub hook_id: String,
+}
+
+#[derive(Debug, PartialEq, sqlx::FromRow)]
+#[sqlx(rename_all = "camelCase")]
+pub struct OutgoingWebhook {
+  pub id: String,
+  pub room_id: String,
+  pub user_id: String,
+  pub url: String,
+  pub event_types: Option<String>,
+  pub senders: Option<String>,
+  pub keywords: Option<String>,
+  pub secret: Option<String>,
+  pub label: Option<String>,
+}
+
+/// When a hook was made and last posted, as unix timestamps, and how its requests went. Unknown
+/// for hooks made before these were tracked.
+#[derive(Debug, Default, PartialEq, sqlx::FromRow)]
+#[sqlx(rename_all = "camelCase")]
+pub struct WebhookStats {
+  pub hook_id: String,
+  pub created_at: Option<i64>,
+  pub last_used_at: Option<i64>,
+  pub deliveries: i64,
+  pub failures: i64,
+}
+
+/// A message to redact once its `ttlSeconds` are up, as the webhook user that sent it
+#[derive(Debug, PartialEq, sqlx::FromRow)]
+#[sqlx(rename_all = "camelCase")]
+pub struct ScheduledRedaction {
+  pub id: i64,
+  pub room_id: String,
+  pub event_id: String,
+  pub localpart: String,
+  /// A unix timestamp
+  pub redact_at: i64,
+}
+
+/// A request to post once its `sendAt` time comes, kept as its JSON
+#[derive(Debug, PartialEq, sqlx::FromRow)]
+#[sqlx(rename_all = "camelCase")]
+pub struct ScheduledMessage {
+  pub id: i64,
+  pub hook_id: String,
+  pub request: String,
+  /// A unix timestamp
+  pub send_at: i64,
+}
+
+/// A message a hook in digest mode is holding, to post with the others
+#[derive(Debug, PartialEq, sqlx::FromRow)]
+#[sqlx(rename_all = "camelCase")]
+pub struct DigestItem {
+  pub id: i64,
+  pub hook_id: String,
+  pub body: String,
+  pub formatted_body: Option<String>,
+  /// A unix timestamp
+  pub received_at: i64,
+}
+
+/// How many messages a hook is holding for its digest, and since when
+#[derive(Debug, PartialEq, sqlx::FromRow)]
+#[sqlx(rename_all = "camelCase")]
+pub struct DigestQueue {
+  pub hook_id: String,
+  /// A unix timestamp
+  pub oldest: i64,
+  pub items: i64,
+}
+
+/// Creating a hook would go over one of the limits in the config
+#[derive(Debug, PartialEq)]
+pub enum LimitExceeded {
+  Room(u32),
+  User(u32),
+}
+
+impl fmt::Display for LimitExceeded {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      LimitExceeded::Room(max) => write!(
+        f,
+        "That room already has the most webhooks allowed ({})",
+        max
+      ),
+      LimitExceeded::User(max) => write!(f, "You already have the most webhooks allowed ({})", max),
+    }
+  }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// Comma-separated lists restricting what an outgoing hook receives. Unset means no restriction,
+/// except for event types which default to m.room.message.
+#[derive(Debug, Default, PartialEq)]
+pub struct OutgoingFilters {
+  pub event_types: Option<String>,
+  pub senders: Option<String>,
+  pub keywords: Option<String>,
+}
+
+// Applied in order, once each, tracking progress with sqlite's user_version pragma.
+// The first entry is the schema of the node version, so its databases work unchanged.
+const MIGRATIONS: &[&str] = &[
+  r#"CREATE TABLE IF NOT EXISTS "webhooks" 
     (
       "id" VARCHAR  PRIMARY KEY NOT NULL, 
       "roomId" VARCHAR  NOT NULL, 
       "userId" VARCHAR  NOT NULL, 
       "label" VARCHAR
     );"#,
-      ))
+  r#"CREATE TABLE IF NOT EXISTS "puppets"
+    (
+      "localpart" VARCHAR PRIMARY KEY NOT NULL,
+      "hookId" VARCHAR NOT NULL
+    );"#,
+  r#"CREATE TABLE IF NOT EXISTS "outgoing_webhooks"
+    (
+      "id" VARCHAR PRIMARY KEY NOT NULL,
+      "roomId" VARCHAR NOT NULL,
+      "userId" VARCHAR NOT NULL,
+      "url" VARCHAR NOT NULL
+    );"#,
+  r#"ALTER TABLE "outgoing_webhooks" ADD COLUMN "eventTypes" VARCHAR;"#,
+  r#"ALTER TABLE "outgoing_webhooks" ADD COLUMN "senders" VARCHAR;"#,
+  r#"ALTER TABLE "outgoing_webhooks" ADD COLUMN "keywords" VARCHAR;"#,
+  r#"ALTER TABLE "outgoing_webhooks" ADD COLUMN "secret" VARCHAR;"#,
+  r#"CREATE TABLE IF NOT EXISTS "outgoing_deliveries"
+    (
+      "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+      "hookId" VARCHAR NOT NULL,
+      "eventId" VARCHAR NOT NULL,
+      "attempt" INTEGER NOT NULL,
+      "status" INTEGER,
+      "error" VARCHAR,
+      "timestamp" INTEGER NOT NULL
+    );"#,
+  r#"ALTER TABLE "outgoing_webhooks" ADD COLUMN "label" VARCHAR;"#,
+  r#"ALTER TABLE "webhooks" ADD COLUMN "puppetLocalpart" VARCHAR;"#,
+  r#"CREATE UNIQUE INDEX IF NOT EXISTS "webhooks_puppetLocalpart" ON "webhooks" ("puppetLocalpart");"#,
+  r#"CREATE TABLE IF NOT EXISTS "settings"
+    (
+      "key" VARCHAR PRIMARY KEY NOT NULL,
+      "value" VARCHAR NOT NULL
+    );"#,
+  r#"CREATE TABLE IF NOT EXISTS "webhook_rooms"
+    (
+      "hookId" VARCHAR NOT NULL,
+      "roomId" VARCHAR NOT NULL,
+      PRIMARY KEY ("hookId", "roomId")
+    );"#,
+  r#"CREATE TABLE IF NOT EXISTS "webhook_stats"
+    (
+      "hookId" VARCHAR PRIMARY KEY NOT NULL,
+      "createdAt" INTEGER,
+      "lastUsedAt" INTEGER
+    );"#,
+  r#"ALTER TABLE "webhook_stats" ADD COLUMN "deliveries" INTEGER NOT NULL DEFAULT 0;"#,
+  r#"ALTER TABLE "webhook_stats" ADD COLUMN "failures" INTEGER NOT NULL DEFAULT 0;"#,
+  r#"ALTER TABLE "webhooks" ADD COLUMN "displayName" VARCHAR;"#,
+  r#"ALTER TABLE "webhooks" ADD COLUMN "avatarUrl" VARCHAR;"#,
+  r#"ALTER TABLE "webhooks" ADD COLUMN "enabled" BOOLEAN NOT NULL DEFAULT 1;"#,
+  r#"CREATE TABLE IF NOT EXISTS "scheduled_redactions"
+    (
+      "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+      "roomId" VARCHAR NOT NULL,
+      "eventId" VARCHAR NOT NULL,
+      "localpart" VARCHAR NOT NULL,
+      "redactAt" INTEGER NOT NULL
+    );"#,
+  r#"CREATE TABLE IF NOT EXISTS "scheduled_messages"
+    (
+      "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+      "hookId" VARCHAR NOT NULL,
+      "request" VARCHAR NOT NULL,
+      "sendAt" INTEGER NOT NULL
+    );"#,
+  r#"CREATE TABLE IF NOT EXISTS "digest_items"
+    (
+      "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+      "hookId" VARCHAR NOT NULL,
+      "body" VARCHAR NOT NULL,
+      "formattedBody" VARCHAR,
+      "receivedAt" INTEGER NOT NULL
+    );"#,
+];
+
+impl Store {
+  pub async fn connect(path: &str) -> Result<Self> {
+    let opts = SqliteConnectOptions::new()
+      .filename(path)
+      .create_if_missing(true);
+    let conn = SqlitePool::connect_with(opts).await?;
+
+    let version: i64 = sqlx::query_scalar("PRAGMA user_version")
+      .fetch_one(&conn)
       .await?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+      let mut tx = conn.begin().await?;
+      sqlx::query(migration).execute(&mut tx).await?;
+      sqlx::query(&format!("PRAGMA user_version = {}", i + 1))
+        .execute(&mut tx)
+        .await?;
+      tx.commit().await?;
+    }
 
     Ok(Self(conn))
   }
 
-  pub async fn create_webhook(&self, room_id: &str, user_id: &str) -> Result<Webhook> {
-    let id = randid::randid_str(32);
+  pub async fn create_webhook(
+    &self,
+    room_id: &str,
+    user_id: &str,
+    label: Option<&str>,
+    puppet_localpart: Option<&str>,
+    limits: &Limits,
+  ) -> Result<Webhook> {
     let hook = Webhook {
-      id,
+      id: randid::randid_str(32),
       room_id: room_id.to_string(),
       user_id: user_id.to_string(),
-      label: None,
+      label: label.map(str::to_string),
+      puppet_localpart: puppet_localpart.map(str::to_string),
+      display_name: None,
+      avatar_url: None,
+      enabled: true,
     };
-
-    sqlx::query("INSERT INTO webhooks ( id, roomId, userId, label ) VALUES ( ?1, ?2, ?3, null );")
-      .bind(&hook.id)
-      .bind(&hook.room_id)
-      .bind(&hook.user_id)
-      .execute(&mut (self.0.acquire().await?))
-      .await?;
+    let mut tx = self.0.begin().await?;
+    check_limits(&mut tx, limits, room_id, user_id).await?;
+    insert_webhook(&mut tx, &hook).await?;
+    tx.commit().await?;
 
     Ok(hook)
   }
@@ -62,6 +278,746 @@ impl Store {
 
     Ok(possible)
   }
+
+  pub async fn get_all_webhooks(&self) -> Result<Vec<Webhook>> {
+    let hooks = sqlx::query_as::<_, Webhook>("SELECT * FROM webhooks")
+      .fetch_all(&mut (self.0.acquire().await?))
+      .await?;
+
+    Ok(hooks)
+  }
+
+  pub async fn get_webhooks_for_room(&self, room_id: &str) -> Result<Vec<Webhook>> {
+    let hooks = sqlx::query_as::<_, Webhook>("SELECT * FROM webhooks WHERE roomId = ?")
+      .bind(room_id)
+      .fetch_all(&mut (self.0.acquire().await?))
+      .await?;
+
+    Ok(hooks)
+  }
+
+  /// Gives the hook a new random id, so the old URL stops working. Everything else about the hook
+  /// carries over, including settings keyed as `name:<id>`. Since default puppet localparts are
+  /// derived from the id, one can be pinned so messages keep coming from the same user. Returns
+  /// the updated hook if it existed.
+  pub async fn regenerate_webhook_id(
+    &self,
+    id: &str,
+    puppet_localpart: Option<&str>,
+  ) -> Result<Option<Webhook>> {
+    let new_id = randid::randid_str(32);
+    let mut tx = self.0.begin().await?;
+    let result = sqlx::query(
+      "UPDATE webhooks SET id = ?1, puppetLocalpart = COALESCE(?2, puppetLocalpart) WHERE id = ?3",
+    )
+    .bind(&new_id)
+    .bind(puppet_localpart)
+    .bind(id)
+    .execute(&mut tx)
+    .await?;
+    if result.rows_affected() == 0 {
+      return Ok(None);
+    }
+    for table in &[
+      "webhook_rooms",
+      "puppets",
+      "webhook_stats",
+      "scheduled_messages",
+      "digest_items",
+    ] {
+      sqlx::query(&format!(
+        "UPDATE {} SET hookId = ?1 WHERE hookId = ?2",
+        table
+      ))
+      .bind(&new_id)
+      .bind(id)
+      .execute(&mut tx)
+      .await?;
+    }
+    sqlx::query(
+      "UPDATE settings SET key = substr(key, 1, length(key) - length(?2)) || ?1 \
+      WHERE key LIKE '%:' || ?2",
+    )
+    .bind(&new_id)
+    .bind(id)
+    .execute(&mut tx)
+    .await?;
+    let hook = sqlx::query_as::<_, Webhook>("SELECT * FROM webhooks WHERE id = ?")
+      .bind(&new_id)
+      .fetch_one(&mut tx)
+      .await?;
+    tx.commit().await?;
+
+    Ok(Some(hook))
+  }
+
+  /// Deletes the hook along with the extra rooms it may post to, the messages it has scheduled or
+  /// is holding for its digest, and its settings. Returns whether it existed.
+  pub async fn delete_webhook(&self, id: &str) -> Result<bool> {
+    let mut tx = self.0.begin().await?;
+    let result = sqlx::query("DELETE FROM webhooks WHERE id = ?")
+      .bind(id)
+      .execute(&mut tx)
+      .await?;
+    for table in &[
+      "webhook_rooms",
+      "webhook_stats",
+      "scheduled_messages",
+      "digest_items",
+    ] {
+      sqlx::query(&format!("DELETE FROM {} WHERE hookId = ?", table))
+        .bind(id)
+        .execute(&mut tx)
+        .await?;
+    }
+    sqlx::query("DELETE FROM settings WHERE key LIKE '%:' || ?")
+      .bind(id)
+      .execute(&mut tx)
+      .await?;
+    tx.commit().await?;
+
+    Ok(result.rows_affected() > 0)
+  }
+
+  /// Deletes every hook in the room, in one go. Returns how many there were.
+  pub async fn delete_webhooks_for_room(&self, room_id: &str) -> Result<u64> {
+    let mut tx = self.0.begin().await?;
+    for table in &[
+      "webhook_rooms",
+      "webhook_stats",
+      "scheduled_messages",
+      "digest_items",
+    ] {
+      sqlx::query(&format!(
+        "DELETE FROM {} WHERE hookId IN (SELECT id FROM webhooks WHERE roomId = ?)",
+        table
+      ))
+      .bind(room_id)
+      .execute(&mut tx)
+      .await?;
+    }
+    sqlx::query(
+      "DELETE FROM settings WHERE EXISTS \
+      (SELECT 1 FROM webhooks WHERE roomId = ? AND settings.key LIKE '%:' || webhooks.id)",
+    )
+    .bind(room_id)
+    .execute(&mut tx)
+    .await?;
+    let result = sqlx::query("DELETE FROM webhooks WHERE roomId = ?")
+      .bind(room_id)
+      .execute(&mut tx)
+      .await?;
+    tx.commit().await?;
+
+    Ok(result.rows_affected())
+  }
+
+  pub async fn get_webhook_stats(&self, hook_id: &str) -> Result<WebhookStats> {
+    let stats = sqlx::query_as::<_, WebhookStats>("SELECT * FROM webhook_stats WHERE hookId = ?")
+      .bind(hook_id)
+      .fetch_optional(&mut (self.0.acquire().await?))
+      .await?;
+
+    Ok(stats.unwrap_or_else(|| WebhookStats {
+      hook_id: hook_id.to_string(),
+      ..Default::default()
+    }))
+  }
+
+  /// Hooks that haven't posted anything for this many seconds, counting from their creation if
+  /// they never have. Hooks made before this was tracked are left out.
+  pub async fn get_webhooks_unused_for(&self, seconds: i64) -> Result<Vec<Webhook>> {
+    let hooks = sqlx::query_as::<_, Webhook>(
+      "SELECT webhooks.* FROM webhooks JOIN webhook_stats ON webhook_stats.hookId = webhooks.id \
+      WHERE COALESCE(webhook_stats.lastUsedAt, webhook_stats.createdAt) \
+      < CAST(strftime('%s', 'now') AS INTEGER) - ?",
+    )
+    .bind(seconds)
+    .fetch_all(&mut (self.0.acquire().await?))
+    .await?;
+
+    Ok(hooks)
+  }
+
+  /// Notes that the hook just posted a message
+  pub async fn record_webhook_delivery(&self, hook_id: &str) -> Result<()> {
+    sqlx::query(
+      "INSERT INTO webhook_stats ( hookId, lastUsedAt, deliveries ) \
+      VALUES ( ?1, CAST(strftime('%s', 'now') AS INTEGER), 1 ) \
+      ON CONFLICT ( hookId ) DO UPDATE SET \
+      lastUsedAt = excluded.lastUsedAt, deliveries = deliveries + 1;",
+    )
+    .bind(hook_id)
+    .execute(&mut (self.0.acquire().await?))
+    .await?;
+
+    Ok(())
+  }
+
+  /// Notes that a request to the hook failed. Ignored if there's no such hook, so requests to
+  /// made up ids don't fill the table.
+  pub async fn record_webhook_failure(&self, hook_id: &str) -> Result<()> {
+    sqlx::query(
+      "INSERT INTO webhook_stats ( hookId, failures ) \
+      SELECT id, 1 FROM webhooks WHERE id = ?1 \
+      ON CONFLICT ( hookId ) DO UPDATE SET failures = failures + 1;",
+    )
+    .bind(hook_id)
+    .execute(&mut (self.0.acquire().await?))
+    .await?;
+
+    Ok(())
+  }
+
+  pub async fn get_webhooks_by_label(&self, label: &str) -> Result<Vec<Webhook>> {
+    let hooks = sqlx::query_as::<_, Webhook>("SELECT * FROM webhooks WHERE label = ?")
+      .bind(label)
+      .fetch_all(&mut (self.0.acquire().await?))
+      .await?;
+
+    Ok(hooks)
+  }
+
+  /// Points the hook at another room. Returns whether there was a hook with that id.
+  pub async fn set_webhook_room(&self, id: &str, room_id: &str) -> Result<bool> {
+    let result = sqlx::query("UPDATE webhooks SET roomId = ?1 WHERE id = ?2")
+      .bind(room_id)
+      .bind(id)
+      .execute(&mut (self.0.acquire().await?))
+      .await?;
+
+    Ok(result.rows_affected() > 0)
+  }
+
+  /// Pauses or resumes the hook. Returns whether there was a hook with that id.
+  pub async fn set_webhook_enabled(&self, id: &str, enabled: bool) -> Result<bool> {
+    let result = sqlx::query("UPDATE webhooks SET enabled = ?1 WHERE id = ?2")
+      .bind(enabled)
+      .bind(id)
+      .execute(&mut (self.0.acquire().await?))
+      .await?;
+
+    Ok(result.rows_affected() > 0)
+  }
+
+  /// Sets or clears the display name and avatar the hook posts with by default. Returns whether
+  /// there was a hook with that id.
+  pub async fn set_webhook_appearance(
+    &self,
+    id: &str,
+    display_name: Option<&str>,
+    avatar_url: Option<&str>,
+  ) -> Result<bool> {
+    let result = sqlx::query("UPDATE webhooks SET displayName = ?1, avatarUrl = ?2 WHERE id = ?3")
+      .bind(display_name)
+      .bind(avatar_url)
+      .bind(id)
+      .execute(&mut (self.0.acquire().await?))
+      .await?;
+
+    Ok(result.rows_affected() > 0)
+  }
+
+  /// Sets or removes the hook's label. Returns whether there was a hook with that id.
+  pub async fn set_webhook_label(&self, id: &str, label: Option<&str>) -> Result<bool> {
+    let result = sqlx::query("UPDATE webhooks SET label = ?1 WHERE id = ?2")
+      .bind(label)
+      .bind(id)
+      .execute(&mut (self.0.acquire().await?))
+      .await?;
+
+    Ok(result.rows_affected() > 0)
+  }
+
+  /// Returns whether there was a hook with that id
+  pub async fn set_webhook_owner(&self, id: &str, user_id: &str) -> Result<bool> {
+    let result = sqlx::query("UPDATE webhooks SET userId = ?1 WHERE id = ?2")
+      .bind(user_id)
+      .bind(id)
+      .execute(&mut (self.0.acquire().await?))
+      .await?;
+
+    Ok(result.rows_affected() > 0)
+  }
+
+  /// Rooms besides its own that a hook may post to, when the request names them
+  pub async fn get_webhook_rooms(&self, hook_id: &str) -> Result<Vec<String>> {
+    let rooms = sqlx::query_scalar("SELECT roomId FROM webhook_rooms WHERE hookId = ?")
+      .bind(hook_id)
+      .fetch_all(&mut (self.0.acquire().await?))
+      .await?;
+
+    Ok(rooms)
+  }
+
+  pub async fn add_webhook_room(&self, hook_id: &str, room_id: &str) -> Result<()> {
+    sqlx::query("INSERT OR IGNORE INTO webhook_rooms ( hookId, roomId ) VALUES ( ?1, ?2 );")
+      .bind(hook_id)
+      .bind(room_id)
+      .execute(&mut (self.0.acquire().await?))
+      .await?;
+
+    Ok(())
+  }
+
+  /// Returns whether the room was allowed before
+  pub async fn remove_webhook_room(&self, hook_id: &str, room_id: &str) -> Result<bool> {
+    let result = sqlx::query("DELETE FROM webhook_rooms WHERE hookId = ?1 AND roomId = ?2")
+      .bind(hook_id)
+      .bind(room_id)
+      .execute(&mut (self.0.acquire().await?))
+      .await?;
+
+    Ok(result.rows_affected() > 0)
+  }
+
+  /// Whether a hook has chosen the localpart, or a puppet with it has already been registered
+  pub async fn is_puppet_localpart_taken(&self, localpart: &str) -> Result<bool> {
+    let count: i64 = sqlx::query_scalar(
+      "SELECT (SELECT COUNT(*) FROM webhooks WHERE puppetLocalpart = ?1) + \
+      (SELECT COUNT(*) FROM puppets WHERE localpart = ?1)",
+    )
+    .bind(localpart)
+    .fetch_one(&mut (self.0.acquire().await?))
+    .await?;
+
+    Ok(count > 0)
+  }
+
+  /// Remember that the puppet user for a hook has been registered with the homeserver
+  pub async fn mark_puppet_registered(&self, localpart: &str, hook_id: &str) -> Result<()> {
+    sqlx::query("INSERT OR REPLACE INTO puppets ( localpart, hookId ) VALUES ( ?1, ?2 );")
+      .bind(localpart)
+      .bind(hook_id)
+      .execute(&mut (self.0.acquire().await?))
+      .await?;
+
+    Ok(())
+  }
+
+  pub async fn create_outgoing_webhook(
+    &self,
+    room_id: &str,
+    user_id: &str,
+    url: &str,
+    filters: OutgoingFilters,
+  ) -> Result<OutgoingWebhook> {
+    let hook = new_outgoing_webhook(room_id, user_id, url, filters, None);
+    let mut conn = self.0.acquire().await?;
+    insert_outgoing_webhook(&mut conn, &hook).await?;
+
+    Ok(hook)
+  }
+
+  /// Creates an incoming and an outgoing hook for the room sharing a label, so that a service can
+  /// both post to and receive from the room
+  pub async fn create_connection(
+    &self,
+    room_id: &str,
+    user_id: &str,
+    label: &str,
+    url: &str,
+    filters: OutgoingFilters,
+    limits: &Limits,
+  ) -> Result<(Webhook, OutgoingWebhook)> {
+    let incoming = Webhook {
+      id: randid::randid_str(32),
+      room_id: room_id.to_string(),
+      user_id: user_id.to_string(),
+      label: Some(label.to_string()),
+      puppet_localpart: None,
+      display_name: None,
+      avatar_url: None,
+      enabled: true,
+    };
+    let outgoing = new_outgoing_webhook(room_id, user_id, url, filters, Some(label));
+
+    let mut tx = self.0.begin().await?;
+    check_limits(&mut tx, limits, room_id, user_id).await?;
+    insert_webhook(&mut tx, &incoming).await?;
+    insert_outgoing_webhook(&mut tx, &outgoing).await?;
+    tx.commit().await?;
+
+    Ok((incoming, outgoing))
+  }
+
+  /// Deletes both halves of a connection, and everything the incoming one had like delete_webhook
+  /// does. Returns whether there was anything with that label.
+  pub async fn delete_connection(&self, room_id: &str, label: &str) -> Result<bool> {
+    let mut tx = self.0.begin().await?;
+    for table in &[
+      "webhook_rooms",
+      "webhook_stats",
+      "scheduled_messages",
+      "digest_items",
+    ] {
+      sqlx::query(&format!(
+        "DELETE FROM {} WHERE hookId IN (SELECT id FROM webhooks WHERE roomId = ?1 AND label = ?2)",
+        table
+      ))
+      .bind(room_id)
+      .bind(label)
+      .execute(&mut tx)
+      .await?;
+    }
+    sqlx::query(
+      "DELETE FROM settings WHERE EXISTS \
+      (SELECT 1 FROM webhooks \
+      WHERE roomId = ?1 AND label = ?2 AND settings.key LIKE '%:' || webhooks.id)",
+    )
+    .bind(room_id)
+    .bind(label)
+    .execute(&mut tx)
+    .await?;
+    let incoming = sqlx::query("DELETE FROM webhooks WHERE roomId = ?1 AND label = ?2")
+      .bind(room_id)
+      .bind(label)
+      .execute(&mut tx)
+      .await?;
+    let outgoing = sqlx::query("DELETE FROM outgoing_webhooks WHERE roomId = ?1 AND label = ?2")
+      .bind(room_id)
+      .bind(label)
+      .execute(&mut tx)
+      .await?;
+    tx.commit().await?;
+
+    Ok(incoming.rows_affected() + outgoing.rows_affected() > 0)
+  }
+
+  /// Whether any incoming or outgoing hook in the room already uses the label
+  pub async fn is_label_in_use(&self, room_id: &str, label: &str) -> Result<bool> {
+    let count: i64 = sqlx::query_scalar(
+      "SELECT (SELECT COUNT(*) FROM webhooks WHERE roomId = ?1 AND label = ?2) + \
+      (SELECT COUNT(*) FROM outgoing_webhooks WHERE roomId = ?1 AND label = ?2)",
+    )
+    .bind(room_id)
+    .bind(label)
+    .fetch_one(&mut (self.0.acquire().await?))
+    .await?;
+
+    Ok(count > 0)
+  }
+
+  pub async fn get_outgoing_webhooks_for_room(
+    &self,
+    room_id: &str,
+  ) -> Result<Vec<OutgoingWebhook>> {
+    let hooks =
+      sqlx::query_as::<_, OutgoingWebhook>("SELECT * FROM outgoing_webhooks WHERE roomId = ?")
+        .bind(room_id)
+        .fetch_all(&mut (self.0.acquire().await?))
+        .await?;
+
+    Ok(hooks)
+  }
+
+  /// Returns whether there was an outgoing hook with that id in the room
+  pub async fn delete_outgoing_webhook(&self, id: &str, room_id: &str) -> Result<bool> {
+    let result = sqlx::query("DELETE FROM outgoing_webhooks WHERE id = ?1 AND roomId = ?2")
+      .bind(id)
+      .bind(room_id)
+      .execute(&mut (self.0.acquire().await?))
+      .await?;
+
+    Ok(result.rows_affected() > 0)
+  }
+
+  /// Adds an attempt to deliver an event to an outgoing hook to the audit log
+  pub async fn record_outgoing_delivery(
+    &self,
+    hook_id: &str,
+    event_id: &str,
+    attempt: u32,
+    status: Option<u16>,
+    error: Option<&str>,
+  ) -> Result<()> {
+    sqlx::query(
+      "INSERT INTO outgoing_deliveries ( hookId, eventId, attempt, status, error, timestamp ) \
+      VALUES ( ?1, ?2, ?3, ?4, ?5, CAST(strftime('%s', 'now') AS INTEGER) );",
+    )
+    .bind(hook_id)
+    .bind(event_id)
+    .bind(attempt)
+    .bind(status)
+    .bind(error)
+    .execute(&mut (self.0.acquire().await?))
+    .await?;
+
+    Ok(())
+  }
+
+  /// Fails if the database can't be queried
+  pub async fn ping(&self) -> Result<()> {
+    sqlx::query("SELECT 1")
+      .execute(&mut (self.0.acquire().await?))
+      .await?;
+
+    Ok(())
+  }
+
+  /// State the bridge keeps for itself, like the id of the room it created for something
+  pub async fn get_setting(&self, key: &str) -> Result<Option<String>> {
+    let value = sqlx::query_scalar("SELECT value FROM settings WHERE key = ?")
+      .bind(key)
+      .fetch_optional(&mut (self.0.acquire().await?))
+      .await?;
+
+    Ok(value)
+  }
+
+  pub async fn set_setting(&self, key: &str, value: &str) -> Result<()> {
+    sqlx::query("INSERT OR REPLACE INTO settings ( key, value ) VALUES ( ?1, ?2 );")
+      .bind(key)
+      .bind(value)
+      .execute(&mut (self.0.acquire().await?))
+      .await?;
+
+    Ok(())
+  }
+
+  pub async fn delete_setting(&self, key: &str) -> Result<()> {
+    sqlx::query("DELETE FROM settings WHERE key = ?")
+      .bind(key)
+      .execute(&mut (self.0.acquire().await?))
+      .await?;
+
+    Ok(())
+  }
+
+  pub async fn schedule_redaction(
+    &self,
+    room_id: &str,
+    event_id: &str,
+    localpart: &str,
+    delay_seconds: u64,
+  ) -> Result<()> {
+    sqlx::query(
+      "INSERT INTO scheduled_redactions ( roomId, eventId, localpart, redactAt ) \
+      VALUES ( ?1, ?2, ?3, CAST(strftime('%s', 'now') AS INTEGER) + ?4 );",
+    )
+    .bind(room_id)
+    .bind(event_id)
+    .bind(localpart)
+    .bind(delay_seconds as i64)
+    .execute(&mut (self.0.acquire().await?))
+    .await?;
+
+    Ok(())
+  }
+
+  /// The redactions whose time has come, oldest first
+  pub async fn due_redactions(&self) -> Result<Vec<ScheduledRedaction>> {
+    let due = sqlx::query_as::<_, ScheduledRedaction>(
+      "SELECT * FROM scheduled_redactions \
+      WHERE redactAt <= CAST(strftime('%s', 'now') AS INTEGER) ORDER BY redactAt",
+    )
+    .fetch_all(&mut (self.0.acquire().await?))
+    .await?;
+
+    Ok(due)
+  }
+
+  pub async fn delete_scheduled_redaction(&self, id: i64) -> Result<()> {
+    sqlx::query("DELETE FROM scheduled_redactions WHERE id = ?")
+      .bind(id)
+      .execute(&mut (self.0.acquire().await?))
+      .await?;
+
+    Ok(())
+  }
+
+  pub async fn schedule_message(&self, hook_id: &str, request: &str, send_at: i64) -> Result<()> {
+    sqlx::query(
+      "INSERT INTO scheduled_messages ( hookId, request, sendAt ) VALUES ( ?1, ?2, ?3 );",
+    )
+    .bind(hook_id)
+    .bind(request)
+    .bind(send_at)
+    .execute(&mut (self.0.acquire().await?))
+    .await?;
+
+    Ok(())
+  }
+
+  /// The scheduled messages whose time has come, oldest first
+  pub async fn due_messages(&self) -> Result<Vec<ScheduledMessage>> {
+    let due = sqlx::query_as::<_, ScheduledMessage>(
+      "SELECT * FROM scheduled_messages \
+      WHERE sendAt <= CAST(strftime('%s', 'now') AS INTEGER) ORDER BY sendAt, id",
+    )
+    .fetch_all(&mut (self.0.acquire().await?))
+    .await?;
+
+    Ok(due)
+  }
+
+  pub async fn delete_scheduled_message(&self, id: i64) -> Result<()> {
+    sqlx::query("DELETE FROM scheduled_messages WHERE id = ?")
+      .bind(id)
+      .execute(&mut (self.0.acquire().await?))
+      .await?;
+
+    Ok(())
+  }
+
+  pub async fn add_digest_item(
+    &self,
+    hook_id: &str,
+    body: &str,
+    formatted_body: Option<&str>,
+  ) -> Result<()> {
+    sqlx::query(
+      "INSERT INTO digest_items ( hookId, body, formattedBody, receivedAt ) \
+      VALUES ( ?1, ?2, ?3, CAST(strftime('%s', 'now') AS INTEGER) );",
+    )
+    .bind(hook_id)
+    .bind(body)
+    .bind(formatted_body)
+    .execute(&mut (self.0.acquire().await?))
+    .await?;
+
+    Ok(())
+  }
+
+  /// The hooks holding messages for their digests
+  pub async fn digest_queues(&self) -> Result<Vec<DigestQueue>> {
+    let queues = sqlx::query_as::<_, DigestQueue>(
+      "SELECT hookId, MIN(receivedAt) AS oldest, COUNT(*) AS items FROM digest_items \
+      GROUP BY hookId",
+    )
+    .fetch_all(&mut (self.0.acquire().await?))
+    .await?;
+
+    Ok(queues)
+  }
+
+  /// The messages the hook is holding, in the order they came in
+  pub async fn get_digest_items(&self, hook_id: &str) -> Result<Vec<DigestItem>> {
+    let items =
+      sqlx::query_as::<_, DigestItem>("SELECT * FROM digest_items WHERE hookId = ? ORDER BY id")
+        .bind(hook_id)
+        .fetch_all(&mut (self.0.acquire().await?))
+        .await?;
+
+    Ok(items)
+  }
+
+  /// Deletes the hook's held messages up to the last one in its digest, keeping any that came in
+  /// since
+  pub async fn delete_digest_items(&self, hook_id: &str, last_id: i64) -> Result<()> {
+    sqlx::query("DELETE FROM digest_items WHERE hookId = ?1 AND id <= ?2")
+      .bind(hook_id)
+      .bind(last_id)
+      .execute(&mut (self.0.acquire().await?))
+      .await?;
+
+    Ok(())
+  }
+
+  pub async fn get_puppet(&self, localpart: &str) -> Result<Option<Puppet>> {
+    let possible = sqlx::query_as::<_, Puppet>("SELECT * FROM puppets WHERE localpart = ?")
+      .bind(localpart)
+      .fetch_optional(&mut (self.0.acquire().await?))
+      .await?;
+
+    Ok(possible)
+  }
+}
+
+fn new_outgoing_webhook(
+  room_id: &str,
+  user_id: &str,
+  url: &str,
+  filters: OutgoingFilters,
+  label: Option<&str>,
+) -> OutgoingWebhook {
+  OutgoingWebhook {
+    id: randid::randid_str(12),
+    room_id: room_id.to_string(),
+    user_id: user_id.to_string(),
+    url: url.to_string(),
+    event_types: filters.event_types,
+    senders: filters.senders,
+    keywords: filters.keywords,
+    secret: Some(randid::randid_str(32)),
+    label: label.map(str::to_string),
+  }
+}
+
+// Fails with LimitExceeded if the room or user already has as many hooks as allowed
+async fn check_limits(
+  conn: &mut SqliteConnection,
+  limits: &Limits,
+  room_id: &str,
+  user_id: &str,
+) -> Result<()> {
+  if let Some(max) = limits.max_hooks_per_room {
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM webhooks WHERE roomId = ?")
+      .bind(room_id)
+      .fetch_one(&mut *conn)
+      .await?;
+    if count >= max as i64 {
+      return Err(LimitExceeded::Room(max).into());
+    }
+  }
+  if let Some(max) = limits.max_hooks_per_user {
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM webhooks WHERE userId = ?")
+      .bind(user_id)
+      .fetch_one(&mut *conn)
+      .await?;
+    if count >= max as i64 {
+      return Err(LimitExceeded::User(max).into());
+    }
+  }
+
+  Ok(())
+}
+
+async fn insert_webhook(conn: &mut SqliteConnection, hook: &Webhook) -> Result<()> {
+  sqlx::query(
+    "INSERT INTO webhooks ( id, roomId, userId, label, puppetLocalpart ) \
+    VALUES ( ?1, ?2, ?3, ?4, ?5 );",
+  )
+  .bind(&hook.id)
+  .bind(&hook.room_id)
+  .bind(&hook.user_id)
+  .bind(&hook.label)
+  .bind(&hook.puppet_localpart)
+  .execute(&mut *conn)
+  .await?;
+  sqlx::query(
+    "INSERT INTO webhook_stats ( hookId, createdAt ) \
+    VALUES ( ?1, CAST(strftime('%s', 'now') AS INTEGER) );",
+  )
+  .bind(&hook.id)
+  .execute(conn)
+  .await?;
+
+  Ok(())
+}
+
+async fn insert_outgoing_webhook(
+  conn: &mut SqliteConnection,
+  hook: &OutgoingWebhook,
+) -> Result<()> {
+  sqlx::query(
+    "INSERT INTO outgoing_webhooks \
+    ( id, roomId, userId, url, eventTypes, senders, keywords, secret, label ) \
+    VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9 );",
+  )
+  .bind(&hook.id)
+  .bind(&hook.room_id)
+  .bind(&hook.user_id)
+  .bind(&hook.url)
+  .bind(&hook.event_types)
+  .bind(&hook.senders)
+  .bind(&hook.keywords)
+  .bind(&hook.secret)
+  .bind(&hook.label)
+  .execute(conn)
+  .await?;
+
+  Ok(())
 }
 
 mod tests {
@@ -70,9 +1026,501 @@ mod tests {
   async fn test_basic() {
     let s = super::Store::connect("sqlite::memory:").await.unwrap();
 
-    let h1 = s.create_webhook("room1", "userblah").await.unwrap();
+    let h1 = s
+      .create_webhook("room1", "userblah", None, None, &Default::default())
+      .await
+      .unwrap();
     let id = h1.id.clone();
 
-    assert_eq!(Some(h1), s.get_webhook_by_id(&id).await.unwrap());
+    assert_eq!(Some(&h1), s.get_webhook_by_id(&id).await.unwrap().as_ref());
+    assert!(s
+      .get_webhooks_for_room("room1")
+      .await
+      .unwrap()
+      .contains(&h1));
+
+    assert!(s.set_webhook_owner(&id, "newowner").await.unwrap());
+    assert_eq!(
+      "newowner",
+      s.get_webhook_by_id(&id).await.unwrap().unwrap().user_id
+    );
+    assert!(!s
+      .set_webhook_owner("nonexistent", "newowner")
+      .await
+      .unwrap());
+
+    assert!(s.set_webhook_room(&id, "room2").await.unwrap());
+    assert_eq!(
+      "room2",
+      s.get_webhook_by_id(&id).await.unwrap().unwrap().room_id
+    );
+    assert!(!s
+      .get_webhooks_for_room("room1")
+      .await
+      .unwrap()
+      .iter()
+      .any(|hook| hook.id == id));
+    assert!(!s.set_webhook_room("nonexistent", "room2").await.unwrap());
+
+    assert!(h1.enabled);
+    assert!(s.set_webhook_enabled(&id, false).await.unwrap());
+    assert!(!s.get_webhook_by_id(&id).await.unwrap().unwrap().enabled);
+    assert!(s.set_webhook_enabled(&id, true).await.unwrap());
+    assert!(s.get_webhook_by_id(&id).await.unwrap().unwrap().enabled);
+  }
+
+  #[tokio::test]
+  async fn test_puppets() {
+    let s = super::Store::connect("sqlite::memory:").await.unwrap();
+    let localpart = format!("_webhook__{}", uuid::Uuid::new_v4());
+
+    assert_eq!(None, s.get_puppet(&localpart).await.unwrap());
+    s.mark_puppet_registered(&localpart, "hook1").await.unwrap();
+    assert_eq!(
+      Some(super::Puppet {
+        localpart: localpart.clone(),
+        hook_id: "hook1".into()
+      }),
+      s.get_puppet(&localpart).await.unwrap()
+    );
+  }
+
+  #[tokio::test]
+  async fn test_chosen_puppet_localpart() {
+    let s = super::Store::connect("sqlite::memory:").await.unwrap();
+    let localpart = format!("_webhook_{}", uuid::Uuid::new_v4());
+
+    assert!(!s.is_puppet_localpart_taken(&localpart).await.unwrap());
+    let hook = s
+      .create_webhook("room1", "user", None, Some(&localpart), &Default::default())
+      .await
+      .unwrap();
+    assert_eq!(Some(localpart.clone()), hook.puppet_localpart);
+    assert!(s.is_puppet_localpart_taken(&localpart).await.unwrap());
+    assert!(s
+      .create_webhook("room2", "user", None, Some(&localpart), &Default::default())
+      .await
+      .is_err());
+  }
+
+  #[tokio::test]
+  async fn test_webhook_rooms() {
+    let s = super::Store::connect("sqlite::memory:").await.unwrap();
+    let hook = s
+      .create_webhook("room1", "user", None, None, &Default::default())
+      .await
+      .unwrap();
+
+    assert!(s.get_webhook_rooms(&hook.id).await.unwrap().is_empty());
+    s.add_webhook_room(&hook.id, "room2").await.unwrap();
+    s.add_webhook_room(&hook.id, "room2").await.unwrap();
+    assert_eq!(
+      vec!["room2".to_string()],
+      s.get_webhook_rooms(&hook.id).await.unwrap()
+    );
+    assert!(s.remove_webhook_room(&hook.id, "room2").await.unwrap());
+    assert!(!s.remove_webhook_room(&hook.id, "room2").await.unwrap());
+  }
+
+  #[tokio::test]
+  async fn test_regenerate_webhook_id() {
+    let s = super::Store::connect("sqlite::memory:").await.unwrap();
+    let hook = s
+      .create_webhook("room1", "user", Some("ci"), None, &Default::default())
+      .await
+      .unwrap();
+    s.add_webhook_room(&hook.id, "room2").await.unwrap();
+    s.set_setting(&format!("kicked:{}", hook.id), "true")
+      .await
+      .unwrap();
+    let localpart = format!("_webhook__{}", uuid::Uuid::new_v4());
+
+    let regenerated = s
+      .regenerate_webhook_id(&hook.id, Some(&localpart))
+      .await
+      .unwrap()
+      .unwrap();
+    assert_ne!(hook.id, regenerated.id);
+    assert_eq!(hook.label, regenerated.label);
+    assert_eq!(Some(localpart), regenerated.puppet_localpart);
+    assert_eq!(None, s.get_webhook_by_id(&hook.id).await.unwrap());
+    assert_eq!(
+      vec!["room2".to_string()],
+      s.get_webhook_rooms(&regenerated.id).await.unwrap()
+    );
+    assert_eq!(
+      Some("true".to_string()),
+      s.get_setting(&format!("kicked:{}", regenerated.id))
+        .await
+        .unwrap()
+    );
+    assert_eq!(None, s.regenerate_webhook_id(&hook.id, None).await.unwrap());
+  }
+
+  #[tokio::test]
+  async fn test_delete_webhooks_for_room() {
+    let s = super::Store::connect("sqlite::memory:").await.unwrap();
+    let room_id = uuid::Uuid::new_v4().to_string();
+    let first = s
+      .create_webhook(&room_id, "user", None, None, &Default::default())
+      .await
+      .unwrap();
+    s.create_webhook(&room_id, "user", None, None, &Default::default())
+      .await
+      .unwrap();
+    s.add_webhook_room(&first.id, "room2").await.unwrap();
+    let other = s
+      .create_webhook("room2", "user", None, None, &Default::default())
+      .await
+      .unwrap();
+    s.set_setting(&format!("secret:{}", first.id), "shh")
+      .await
+      .unwrap();
+    s.set_setting(&format!("secret:{}", other.id), "shh")
+      .await
+      .unwrap();
+
+    assert_eq!(2, s.delete_webhooks_for_room(&room_id).await.unwrap());
+    assert!(s.get_webhooks_for_room(&room_id).await.unwrap().is_empty());
+    assert!(s.get_webhook_rooms(&first.id).await.unwrap().is_empty());
+    assert!(s.get_webhook_by_id(&other.id).await.unwrap().is_some());
+    assert_eq!(
+      None,
+      s.get_setting(&format!("secret:{}", first.id))
+        .await
+        .unwrap()
+    );
+    assert!(s
+      .get_setting(&format!("secret:{}", other.id))
+      .await
+      .unwrap()
+      .is_some());
+    assert_eq!(0, s.delete_webhooks_for_room(&room_id).await.unwrap());
+  }
+
+  #[tokio::test]
+  async fn test_webhook_appearance() {
+    let s = super::Store::connect("sqlite::memory:").await.unwrap();
+    let hook = s
+      .create_webhook("room1", "user", None, None, &Default::default())
+      .await
+      .unwrap();
+    assert_eq!(None, hook.display_name);
+
+    assert!(s
+      .set_webhook_appearance(&hook.id, Some("CI"), Some("https://example.com/ci.png"))
+      .await
+      .unwrap());
+    let hook = s.get_webhook_by_id(&hook.id).await.unwrap().unwrap();
+    assert_eq!(Some("CI".to_string()), hook.display_name);
+    assert_eq!(
+      Some("https://example.com/ci.png".to_string()),
+      hook.avatar_url
+    );
+
+    assert!(s
+      .set_webhook_appearance(&hook.id, None, hook.avatar_url.as_deref())
+      .await
+      .unwrap());
+    let hook = s.get_webhook_by_id(&hook.id).await.unwrap().unwrap();
+    assert_eq!(None, hook.display_name);
+    assert!(hook.avatar_url.is_some());
+
+    assert!(!s
+      .set_webhook_appearance("unknown", None, None)
+      .await
+      .unwrap());
+  }
+
+  #[tokio::test]
+  async fn test_webhook_label() {
+    let s = super::Store::connect("sqlite::memory:").await.unwrap();
+    let hook = s
+      .create_webhook("room1", "user", None, None, &Default::default())
+      .await
+      .unwrap();
+
+    assert!(s.set_webhook_label(&hook.id, Some("ci")).await.unwrap());
+    assert!(s.is_label_in_use("room1", "ci").await.unwrap());
+    assert!(s.set_webhook_label(&hook.id, None).await.unwrap());
+    let hook = s.get_webhook_by_id(&hook.id).await.unwrap().unwrap();
+    assert_eq!(None, hook.label);
+
+    assert!(!s.set_webhook_label("unknown", Some("ci")).await.unwrap());
+  }
+
+  #[tokio::test]
+  async fn test_webhook_stats() {
+    let s = super::Store::connect("sqlite::memory:").await.unwrap();
+    let hook = s
+      .create_webhook("room1", "user", None, None, &Default::default())
+      .await
+      .unwrap();
+
+    let stats = s.get_webhook_stats(&hook.id).await.unwrap();
+    assert!(stats.created_at.is_some());
+    assert_eq!(None, stats.last_used_at);
+    let unused = |hooks: Vec<super::Webhook>| hooks.iter().any(|other| other.id == hook.id);
+    assert!(unused(s.get_webhooks_unused_for(-60).await.unwrap()));
+    assert!(!unused(s.get_webhooks_unused_for(60).await.unwrap()));
+
+    s.record_webhook_delivery(&hook.id).await.unwrap();
+    s.record_webhook_delivery(&hook.id).await.unwrap();
+    s.record_webhook_failure(&hook.id).await.unwrap();
+    let stats = s.get_webhook_stats(&hook.id).await.unwrap();
+    assert!(stats.last_used_at >= stats.created_at);
+    assert_eq!(2, stats.deliveries);
+    assert_eq!(1, stats.failures);
+
+    s.record_webhook_failure("unknown").await.unwrap();
+
+    // Hooks from before stats were kept
+    let stats = s.get_webhook_stats("unknown").await.unwrap();
+    assert_eq!(None, stats.created_at);
+
+    s.delete_webhook(&hook.id).await.unwrap();
+    let stats = s.get_webhook_stats(&hook.id).await.unwrap();
+    assert_eq!(None, stats.created_at);
+  }
+
+  #[tokio::test]
+  async fn test_limits() {
+    let s = super::Store::connect("sqlite::memory:").await.unwrap();
+    let room_id = format!("!{}:localhost", uuid::Uuid::new_v4());
+    let user_id = format!("@{}:localhost", uuid::Uuid::new_v4());
+    let limits = crate::config::Limits {
+      max_hooks_per_room: Some(2),
+      max_hooks_per_user: Some(1),
+    };
+
+    s.create_webhook(&room_id, &user_id, None, None, &limits)
+      .await
+      .unwrap();
+    let error = s
+      .create_webhook(&room_id, &user_id, None, None, &limits)
+      .await
+      .unwrap_err();
+    assert_eq!(
+      Some(&super::LimitExceeded::User(1)),
+      error.downcast_ref::<super::LimitExceeded>()
+    );
+
+    s.create_webhook(&room_id, "someone else", None, None, &limits)
+      .await
+      .unwrap();
+    let error = s
+      .create_webhook(&room_id, "a third user", None, None, &limits)
+      .await
+      .unwrap_err();
+    assert_eq!(
+      Some(&super::LimitExceeded::Room(2)),
+      error.downcast_ref::<super::LimitExceeded>()
+    );
+  }
+
+  #[tokio::test]
+  async fn test_settings() {
+    let s = super::Store::connect("sqlite::memory:").await.unwrap();
+    let key = uuid::Uuid::new_v4().to_string();
+
+    assert_eq!(None, s.get_setting(&key).await.unwrap());
+    s.set_setting(&key, "one").await.unwrap();
+    s.set_setting(&key, "two").await.unwrap();
+    assert_eq!(Some("two".to_string()), s.get_setting(&key).await.unwrap());
+    s.delete_setting(&key).await.unwrap();
+    assert_eq!(None, s.get_setting(&key).await.unwrap());
+  }
+
+  #[tokio::test]
+  async fn test_scheduled_redactions() {
+    let s = super::Store::connect("sqlite::memory:").await.unwrap();
+    let room_id = format!("!{}:localhost", uuid::Uuid::new_v4());
+    s.schedule_redaction(&room_id, "$now", "_webhook__abc", 0)
+      .await
+      .unwrap();
+    s.schedule_redaction(&room_id, "$later", "_webhook__abc", 3600)
+      .await
+      .unwrap();
+
+    // Other tests share the database, so only this room's redactions count
+    let due: Vec<_> = s
+      .due_redactions()
+      .await
+      .unwrap()
+      .into_iter()
+      .filter(|redaction| redaction.room_id == room_id)
+      .collect();
+    assert_eq!(1, due.len());
+    assert_eq!("$now", due[0].event_id);
+    s.delete_scheduled_redaction(due[0].id).await.unwrap();
+    assert!(!s
+      .due_redactions()
+      .await
+      .unwrap()
+      .iter()
+      .any(|redaction| redaction.room_id == room_id));
+
+    let later: i64 = sqlx::query_scalar("SELECT id FROM scheduled_redactions WHERE roomId = ?")
+      .bind(&room_id)
+      .fetch_one(&s.0)
+      .await
+      .unwrap();
+    s.delete_scheduled_redaction(later).await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_scheduled_messages() {
+    let s = super::Store::connect("sqlite::memory:").await.unwrap();
+    let hook = s
+      .create_webhook("room1", "user", None, None, &Default::default())
+      .await
+      .unwrap();
+    s.schedule_message(&hook.id, "{\"text\": \"now\"}", 0)
+      .await
+      .unwrap();
+    s.schedule_message(&hook.id, "{\"text\": \"later\"}", i64::MAX)
+      .await
+      .unwrap();
+
+    // Other tests share the database, so only this hook's messages count
+    let due: Vec<_> = s
+      .due_messages()
+      .await
+      .unwrap()
+      .into_iter()
+      .filter(|message| message.hook_id == hook.id)
+      .collect();
+    assert_eq!(1, due.len());
+    assert_eq!("{\"text\": \"now\"}", due[0].request);
+    s.delete_scheduled_message(due[0].id).await.unwrap();
+    assert!(!s
+      .due_messages()
+      .await
+      .unwrap()
+      .iter()
+      .any(|message| message.hook_id == hook.id));
+
+    // They go with the hook, like its settings
+    s.set_setting(&format!("template:{}", hook.id), "{{ text }}")
+      .await
+      .unwrap();
+    s.delete_webhook(&hook.id).await.unwrap();
+    assert_eq!(
+      None,
+      s.get_setting(&format!("template:{}", hook.id))
+        .await
+        .unwrap()
+    );
+    let remaining: i64 =
+      sqlx::query_scalar("SELECT COUNT(*) FROM scheduled_messages WHERE hookId = ?")
+        .bind(&hook.id)
+        .fetch_one(&s.0)
+        .await
+        .unwrap();
+    assert_eq!(0, remaining);
+  }
+
+  #[tokio::test]
+  async fn test_digest_items() {
+    let s = super::Store::connect("sqlite::memory:").await.unwrap();
+    let hook = uuid::Uuid::new_v4().to_string();
+    let other = uuid::Uuid::new_v4().to_string();
+    s.add_digest_item(&hook, "one", None).await.unwrap();
+    s.add_digest_item(&hook, "two", Some("<b>two</b>"))
+      .await
+      .unwrap();
+    s.add_digest_item(&other, "three", None).await.unwrap();
+
+    // Other tests share the database, so only these hooks' queues count
+    let queues = s.digest_queues().await.unwrap();
+    let queued = |hook_id: &str| {
+      queues
+        .iter()
+        .find(|queue| queue.hook_id == hook_id)
+        .map(|queue| queue.items)
+    };
+    assert_eq!(Some(2), queued(&hook));
+    assert_eq!(Some(1), queued(&other));
+    let items = s.get_digest_items(&hook).await.unwrap();
+    assert_eq!(Some("<b>two</b>".to_string()), items[1].formatted_body);
+
+    s.delete_digest_items(&hook, items[0].id).await.unwrap();
+    let items = s.get_digest_items(&hook).await.unwrap();
+    assert_eq!(1, items.len());
+    assert_eq!("two", items[0].body);
+  }
+
+  #[tokio::test]
+  async fn test_outgoing_webhooks() {
+    let s = super::Store::connect("sqlite::memory:").await.unwrap();
+    let room_id = format!("!{}:localhost", uuid::Uuid::new_v4());
+
+    let hook = s
+      .create_outgoing_webhook(
+        &room_id,
+        "user",
+        "https://example.com/",
+        super::OutgoingFilters {
+          keywords: Some("deploy".into()),
+          ..Default::default()
+        },
+      )
+      .await
+      .unwrap();
+    assert_eq!(
+      vec![&hook],
+      s.get_outgoing_webhooks_for_room(&room_id)
+        .await
+        .unwrap()
+        .iter()
+        .collect::<Vec<_>>()
+    );
+
+    assert!(!s
+      .delete_outgoing_webhook(&hook.id, "!other:localhost")
+      .await
+      .unwrap());
+    assert!(s.delete_outgoing_webhook(&hook.id, &room_id).await.unwrap());
+    assert!(s
+      .get_outgoing_webhooks_for_room(&room_id)
+      .await
+      .unwrap()
+      .is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_connections() {
+    let s = super::Store::connect("sqlite::memory:").await.unwrap();
+    let room_id = format!("!{}:localhost", uuid::Uuid::new_v4());
+
+    assert!(!s.is_label_in_use(&room_id, "ci").await.unwrap());
+    let (incoming, outgoing) = s
+      .create_connection(
+        &room_id,
+        "user",
+        "ci",
+        "https://example.com/",
+        Default::default(),
+        &Default::default(),
+      )
+      .await
+      .unwrap();
+    assert_eq!(incoming.label.as_deref(), Some("ci"));
+    assert_eq!(outgoing.label.as_deref(), Some("ci"));
+    assert!(s.is_label_in_use(&room_id, "ci").await.unwrap());
+
+    s.schedule_message(&incoming.id, "{}", 0).await.unwrap();
+    s.add_digest_item(&incoming.id, "held", None).await.unwrap();
+
+    assert!(s.delete_connection(&room_id, "ci").await.unwrap());
+    assert!(!s.is_label_in_use(&room_id, "ci").await.unwrap());
+    assert_eq!(None, s.get_webhook_by_id(&incoming.id).await.unwrap());
+    assert!(!s
+      .due_messages()
+      .await
+      .unwrap()
+      .iter()
+      .any(|message| message.hook_id == incoming.id));
+    assert!(s.get_digest_items(&incoming.id).await.unwrap().is_empty());
   }
 }
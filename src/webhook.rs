@@ -1,22 +1,281 @@
 use anyhow::{anyhow, Context, Result};
-use matrix_sdk::ruma::RoomId;
+use matrix_sdk::ruma::api::appservice::Registration;
+use matrix_sdk::ruma::api::client::r0::message::send_message_event;
+use matrix_sdk::ruma::api::client::r0::redact::redact_event;
+use matrix_sdk::ruma::api::client::r0::room::get_room_event;
+use matrix_sdk::ruma::api::client::r0::state::send_state_event;
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::{EventId, RoomId};
 use matrix_sdk::ruma::{ServerName, UserId};
-use matrix_sdk::SyncSettings;
+use matrix_sdk::{Client, SyncSettings};
 use sha2::{Digest, Sha256};
-use std::{convert::TryFrom, sync::Arc};
+use std::{
+  collections::HashMap,
+  convert::TryFrom,
+  fmt,
+  sync::Arc,
+  time::{SystemTime, UNIX_EPOCH},
+};
+use uuid::Uuid;
 
-use crate::store::Store;
+use crate::digest;
+use crate::media::{self, Media};
+use crate::oversize;
+use crate::payloads;
+use crate::power_levels::PowerLevels;
+use crate::preview;
+use crate::reply;
+use crate::room_reference;
+use crate::store::{Store, Webhook};
 use crate::webhook_request::WebhookRequest;
-use crate::{bot, config::Config};
+use crate::{
+  bot,
+  config::{Config, PuppetMode},
+  encryption,
+};
+use dashmap::DashMap;
+use futures::TryStreamExt;
+use lazy_static::lazy_static;
 use log::*;
 use matrix_sdk_appservice::AppService;
+use warp::hyper::body::Buf;
+use warp::multipart::FormData;
 use warp::{Rejection, Reply};
 
+// Messages that couldn't be posted yet because the bot may not invite their puppet, keyed by room
+// id and puppet user id. They're posted as soon as someone else invites the puppet.
+lazy_static! {
+  static ref PENDING_MESSAGES: DashMap<(String, String), Vec<Outgoing>> = DashMap::new();
+}
+
+// Everything a hook posts is an m.room.message, whatever its msgtype
+const MESSAGE_EVENT_TYPE: &str = "m.room.message";
+const REACTION_EVENT_TYPE: &str = "m.reaction";
+
+// MSC3440's relation for messages in a thread, until it's in the spec
+const THREAD_REL_TYPE: &str = "io.element.thread";
+
+// Enough for chatty integrations, without one request holding the hook's users up for long
+const MAX_BATCH_MESSAGES: usize = 100;
+
+// Older messages are dropped beyond this, so a busy hook can't use up all our memory
+const MAX_PENDING_MESSAGES: usize = 20;
+
+/// An event for a webhook user to send, kept as JSON since the SDK has no types for thread relations
+/// or edits
+#[derive(Debug, Clone)]
+struct Outgoing {
+  event_type: String,
+  content: serde_json::Value,
+}
+
+/// Where a request's events were posted, for its response. Messages held for later have none yet.
+#[derive(Debug, Default)]
+pub struct Posted {
+  pub room_id: Option<RoomId>,
+  pub event_ids: Vec<EventId>,
+}
+
+/// Problems with a hook that its owner has to fix, as opposed to the bridge failing
+#[derive(Debug)]
+pub enum HookError {
+  InviteForbidden { required: i64, actual: i64 },
+  RoomEncrypted,
+  SendForbidden { required: i64, actual: i64 },
+  Kicked,
+  RoomNotAllowed,
+  Paused,
+  UnknownService,
+  InvalidPayload(String),
+  BadSignature,
+  RoomStateNotAllowed,
+  CustomEventsNotAllowed,
+  RoomMentionNotAllowed,
+}
+
+impl HookError {
+  fn status(&self) -> http::StatusCode {
+    match self {
+      HookError::InviteForbidden { .. } => http::StatusCode::FORBIDDEN,
+      HookError::RoomEncrypted => http::StatusCode::CONFLICT,
+      HookError::SendForbidden { .. } => http::StatusCode::FORBIDDEN,
+      HookError::Kicked => http::StatusCode::FORBIDDEN,
+      HookError::RoomNotAllowed => http::StatusCode::FORBIDDEN,
+      HookError::Paused => http::StatusCode::CONFLICT,
+      HookError::UnknownService => http::StatusCode::NOT_FOUND,
+      HookError::InvalidPayload(_) => http::StatusCode::BAD_REQUEST,
+      HookError::BadSignature => http::StatusCode::UNAUTHORIZED,
+      HookError::RoomStateNotAllowed => http::StatusCode::FORBIDDEN,
+      HookError::CustomEventsNotAllowed => http::StatusCode::FORBIDDEN,
+      HookError::RoomMentionNotAllowed => http::StatusCode::FORBIDDEN,
+    }
+  }
+
+  fn errcode(&self) -> &'static str {
+    match self {
+      HookError::InviteForbidden { .. } => "INVITE_FORBIDDEN",
+      HookError::RoomEncrypted => "ROOM_ENCRYPTED",
+      HookError::SendForbidden { .. } => "SEND_FORBIDDEN",
+      HookError::Kicked => "KICKED",
+      HookError::RoomNotAllowed => "ROOM_NOT_ALLOWED",
+      HookError::Paused => "PAUSED",
+      HookError::UnknownService => "UNKNOWN_SERVICE",
+      HookError::InvalidPayload(_) => "INVALID_PAYLOAD",
+      HookError::BadSignature => "BAD_SIGNATURE",
+      HookError::RoomStateNotAllowed => "ROOM_STATE_NOT_ALLOWED",
+      HookError::CustomEventsNotAllowed => "CUSTOM_EVENTS_NOT_ALLOWED",
+      HookError::RoomMentionNotAllowed => "ROOM_MENTION_NOT_ALLOWED",
+    }
+  }
+}
+
+impl fmt::Display for HookError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      HookError::InviteForbidden { required, actual } => write!(
+        f,
+        "The bridge bot needs power level {} to invite the webhook user to the room, but has {}. \
+        The message will be posted once the webhook user is invited",
+        required, actual
+      ),
+      HookError::RoomEncrypted => write!(
+        f,
+        "The room is encrypted, and the bridge doesn't have encryption enabled"
+      ),
+      HookError::SendForbidden { required, actual } => write!(
+        f,
+        "The webhook user needs power level {} to post in the room, but has {}",
+        required, actual
+      ),
+      HookError::Kicked => write!(
+        f,
+        "The webhook was kicked from the room, and is paused until its owner brings it back"
+      ),
+      HookError::RoomNotAllowed => write!(
+        f,
+        "The webhook may not post to that room. Its owner can allow it with !webhook rooms add"
+      ),
+      HookError::Paused => write!(
+        f,
+        "The webhook is paused. Its owner can turn it back on with !webhook resume"
+      ),
+      HookError::UnknownService => write!(f, "The bridge doesn't know that service's payloads"),
+      HookError::InvalidPayload(reason) => write!(f, "The payload isn't valid: {}", reason),
+      HookError::BadSignature => write!(
+        f,
+        "The request isn't signed with the webhook's secret. Its owner can see or change the \
+        secret with !webhook secret"
+      ),
+      HookError::RoomStateNotAllowed => write!(
+        f,
+        "The webhook may not change the room's name or topic. Its owner can allow it with \
+        !webhook state"
+      ),
+      HookError::CustomEventsNotAllowed => write!(
+        f,
+        "The webhook may not send custom events. Its owner can allow it with !webhook custom"
+      ),
+      HookError::RoomMentionNotAllowed => write!(
+        f,
+        "The webhook may not ping the whole room. Its owner can allow it with !webhook atroom"
+      ),
+    }
+  }
+}
+
+impl std::error::Error for HookError {}
+
 #[derive(Debug, Clone)]
 pub struct RequestContext {
   pub config: Arc<Config>,
   pub appservice: AppService,
   pub store: Arc<Store>,
+  pub registration: Arc<Registration>,
+}
+
+/// The localpart of the virtual user that posts on behalf of a hook
+pub fn puppet_localpart(config: &Config, hook_id: &str) -> String {
+  let mut hasher = Sha256::new();
+  hasher.update(hook_id);
+  let id_hash = hex::encode(&hasher.finalize()[0..16]);
+  format!("{}__{}", &config.webhook_bot.localpart, &id_hash)
+}
+
+/// The setting marking a hook as paused since the bot or its user was kicked from its room
+pub fn kicked_setting(hook_id: &str) -> String {
+  format!("kicked:{}", hook_id)
+}
+
+/// The setting marking a hook as posting everything in a thread, set with `!webhook thread`
+pub fn auto_thread_setting(hook_id: &str) -> String {
+  format!("autoThread:{}", hook_id)
+}
+
+/// The setting holding the room and event id of the thread an auto-threading hook posts in
+pub fn thread_root_setting(hook_id: &str) -> String {
+  format!("threadRoot:{}", hook_id)
+}
+
+/// The setting allowing a hook to ping the whole room with `mentionRoom`, set with `!webhook atroom`
+pub fn room_mention_setting(hook_id: &str) -> String {
+  format!("roomMention:{}", hook_id)
+}
+
+/// The setting holding how long and how many messages a hook holds for its digest, set with
+/// `!webhook digest`. Hooks without it post messages as they come.
+pub fn digest_setting(hook_id: &str) -> String {
+  format!("digest:{}", hook_id)
+}
+
+/// The setting adding previews of the first link in a hook's messages, set with `!webhook preview`
+pub fn preview_setting(hook_id: &str) -> String {
+  format!("preview:{}", hook_id)
+}
+
+/// The setting saying what's done with messages too big to send, set with `!webhook oversize`.
+/// They're split unless it says to truncate them.
+pub fn oversize_setting(hook_id: &str) -> String {
+  format!("oversize:{}", hook_id)
+}
+
+/// The setting allowing a hook to send events of its own types, set with `!webhook custom`
+pub fn custom_events_setting(hook_id: &str) -> String {
+  format!("allowCustomEvents:{}", hook_id)
+}
+
+/// The setting allowing a hook to change its room's name and topic, set with `!webhook state`
+pub fn room_state_setting(hook_id: &str) -> String {
+  format!("roomState:{}", hook_id)
+}
+
+/// Whether the user is the bot or one of the webhook users
+pub fn is_bridge_user(config: &Config, user_id: &str) -> bool {
+  let localpart = user_id
+    .strip_prefix('@')
+    .and_then(|user_id| user_id.split(':').next())
+    .unwrap_or_default();
+  localpart.starts_with(&config.webhook_bot.localpart)
+    && user_id.ends_with(&format!(":{}", config.homeserver.domain))
+}
+
+/// The localpart chosen for the hook with `!webhook create --as`, or the default one
+pub fn hook_puppet_localpart(config: &Config, hook: &Webhook) -> String {
+  match &hook.puppet_localpart {
+    Some(localpart) => localpart.clone(),
+    None => puppet_localpart(config, &hook.id),
+  }
+}
+
+/// The localpart of the virtual user that posts a hook's messages under one display name, for
+/// `puppetMode: displayName`
+pub fn display_name_puppet_localpart(config: &Config, hook_id: &str, display_name: &str) -> String {
+  let mut hasher = Sha256::new();
+  hasher.update(hook_id);
+  // Keeps ("ab", "c") and ("a", "bc") apart
+  hasher.update([0]);
+  hasher.update(display_name);
+  let id_hash = hex::encode(&hasher.finalize()[0..16]);
+  format!("{}__{}", &config.webhook_bot.localpart, &id_hash)
 }
 
 pub async fn handler(
@@ -32,76 +291,1088 @@ pub async fn handler(
     context.store,
   )
   .await;
-  Ok(match res {
-    Ok(_) => Box::new(warp::reply::json(&serde_json::json!({"success": true}))),
-    Err(e) => {
-      error!(
-        "Error responding to webhook request with id {}: {}",
+  Ok(respond(&webhook_id, res))
+}
+
+/// Like handler, for another service's payloads, posted to the hook's URL with the service's name
+/// appended
+pub async fn service_handler(
+  webhook_id: String,
+  service: String,
+  headers: http::HeaderMap,
+  body: warp::hyper::body::Bytes,
+  context: RequestContext,
+) -> Result<Box<dyn Reply>, Rejection> {
+  let client = context
+    .appservice
+    .virtual_user_client(&context.config.webhook_bot.localpart)
+    .await;
+  let translated = match client {
+    Ok(client) => {
+      payloads::translate(
+        &service,
+        &headers,
+        &body,
         &webhook_id,
-        e.to_string()
-      );
-      Box::new(warp::reply::with_status(
-        warp::reply::json(&serde_json::json!({"success": false, "message": e.to_string()})),
-        http::status::StatusCode::INTERNAL_SERVER_ERROR,
-      ))
+        &context.store,
+        &context.config,
+        &client,
+      )
+      .await
+    }
+    Err(e) => Err(e.into()),
+  };
+  let res = match translated {
+    Ok(Some(body)) => {
+      handler_inner(
+        &webhook_id,
+        body,
+        context.config,
+        context.appservice,
+        context.store,
+      )
+      .await
     }
+    Ok(None) => Ok(Posted::default()),
+    Err(e) => Err(e),
+  };
+  Ok(respond(&webhook_id, res))
+}
+
+/// Like handler, for a PUT to one of the hook's earlier messages, which edits it
+pub async fn edit_handler(
+  webhook_id: String,
+  event_id: String,
+  body: WebhookRequest,
+  context: RequestContext,
+) -> Result<Box<dyn Reply>, Rejection> {
+  let res = handler_inner(
+    &webhook_id,
+    body.with_edits(event_id),
+    context.config,
+    context.appservice,
+    context.store,
+  )
+  .await;
+  Ok(respond(&webhook_id, res))
+}
+
+/// Redacts one of the hook's earlier messages, as whichever of its webhook users posted it. The
+/// `room` and `reason` query parameters pick the room, like a message's `room`, and say why.
+pub async fn redact_handler(
+  webhook_id: String,
+  event_id: String,
+  query: HashMap<String, String>,
+  context: RequestContext,
+) -> Result<Box<dyn Reply>, Rejection> {
+  let res = redact_message(
+    &webhook_id,
+    &event_id,
+    query.get("room").map(String::as_str),
+    query.get("reason").map(String::as_str),
+    &context,
+  )
+  .await;
+  Ok(respond(&webhook_id, res))
+}
+
+async fn redact_message(
+  webhook_id: &str,
+  event_id: &str,
+  room: Option<&str>,
+  reason: Option<&str>,
+  context: &RequestContext,
+) -> Result<Posted> {
+  let hook = match context.store.get_webhook_by_id(webhook_id).await? {
+    Some(hook) => hook,
+    None => return Err(anyhow::anyhow!("Could not find webhook")),
+  };
+  if !hook.enabled {
+    return Err(HookError::Paused.into());
+  }
+
+  let bot_client = context
+    .appservice
+    .virtual_user_client(&context.config.webhook_bot.localpart)
+    .await?;
+  let room_id = target_room(&bot_client, &context.store, &hook, room).await?;
+  let (event_id, sender) = event_sender(&bot_client, &room_id, event_id).await?;
+  if !sent_by_hook(&context.config, &context.store, &hook, &sender).await? {
+    return Err(not_hook_message(event_id.as_str()).into());
+  }
+  let sender = UserId::try_from(sender.as_str())?;
+
+  let client = context
+    .appservice
+    .virtual_user_client(sender.localpart())
+    .await?;
+  let txn_id = Uuid::new_v4().to_string();
+  let mut request = redact_event::Request::new(&room_id, &event_id, &txn_id);
+  request.reason = reason;
+  let response = client.send(request, None).await?;
+  info!("Webhook {} redacted {}", &hook.id, event_id);
+  Ok(Posted {
+    room_id: Some(room_id),
+    event_ids: vec![response.event_id],
   })
 }
 
-async fn handler_inner(
+/// Like handler, for the scripts and devices that post nothing but the message's text
+pub async fn text_handler(
+  webhook_id: String,
+  body: warp::hyper::body::Bytes,
+  context: RequestContext,
+) -> Result<Box<dyn Reply>, Rejection> {
+  // Bytes that aren't UTF-8, like a log cut off partway through a character, are dropped
+  let text = match String::from_utf8(body.to_vec()) {
+    Ok(text) => text,
+    Err(e) => String::from_utf8_lossy(e.as_bytes()).replace(char::REPLACEMENT_CHARACTER, ""),
+  };
+  let res = handler_inner(
+    &webhook_id,
+    WebhookRequest::from_text(text),
+    context.config,
+    context.appservice,
+    context.store,
+  )
+  .await;
+  Ok(respond(&webhook_id, res))
+}
+
+/// Posts several messages in one request, in order, each as it would be on its own. The response
+/// has each one's outcome, so one failing doesn't stop the rest.
+pub async fn batch_handler(
+  webhook_id: String,
+  bodies: Vec<WebhookRequest>,
+  context: RequestContext,
+) -> Result<Box<dyn Reply>, Rejection> {
+  if bodies.len() > MAX_BATCH_MESSAGES {
+    let too_many = HookError::InvalidPayload(format!(
+      "A batch can have at most {} messages",
+      MAX_BATCH_MESSAGES
+    ));
+    return Ok(respond(&webhook_id, Err(too_many.into())));
+  }
+
+  let mut results = Vec::new();
+  for body in bodies {
+    let res = handler_inner(
+      &webhook_id,
+      body,
+      context.config.clone(),
+      context.appservice.clone(),
+      context.store.clone(),
+    )
+    .await;
+    results.push(outcome(&webhook_id, res).0);
+  }
+  let success = results.iter().all(|result| result["success"] == true);
+  Ok(Box::new(warp::reply::json(
+    &serde_json::json!({"success": success, "results": results}),
+  )))
+}
+
+/// Like handler, for messages posted as a form
+pub async fn form_handler(
+  webhook_id: String,
+  body: warp::hyper::body::Bytes,
+  context: RequestContext,
+) -> Result<Box<dyn Reply>, Rejection> {
+  let res = match WebhookRequest::from_form(&body) {
+    Ok(body) => {
+      handler_inner(
+        &webhook_id,
+        body,
+        context.config,
+        context.appservice,
+        context.store,
+      )
+      .await
+    }
+    Err(e) => Err(e.into()),
+  };
+  Ok(respond(&webhook_id, res))
+}
+
+/// Like handler, for multipart/form-data requests. Parts with a filename are posted as images, audio,
+/// video or files by their type, after the message in the `payload` part if there is one.
+pub async fn multipart_handler(
+  webhook_id: String,
+  form: FormData,
+  context: RequestContext,
+) -> Result<Box<dyn Reply>, Rejection> {
+  let res = match read_multipart(form).await {
+    Ok(body) => {
+      handler_inner(
+        &webhook_id,
+        body,
+        context.config,
+        context.appservice,
+        context.store,
+      )
+      .await
+    }
+    Err(e) => Err(e),
+  };
+  Ok(respond(&webhook_id, res))
+}
+
+async fn read_multipart(mut form: FormData) -> Result<WebhookRequest> {
+  let invalid = |e: warp::Error| HookError::InvalidPayload(e.to_string());
+  let mut payload = None;
+  let mut files = Vec::new();
+  while let Some(part) = form.try_next().await.map_err(invalid)? {
+    let name = part.name().to_string();
+    let filename = part.filename().map(str::to_string);
+    let mime = part
+      .content_type()
+      .and_then(|mime| mime.parse().ok())
+      .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+    let data = part
+      .stream()
+      .try_fold(Vec::new(), |mut data, chunk| {
+        data.extend_from_slice(chunk.chunk());
+        async move { Ok(data) }
+      })
+      .await
+      .map_err(invalid)?;
+    match filename {
+      Some(filename) => files.push(Media {
+        name: filename,
+        mime,
+        data,
+      }),
+      None if name == "payload" => payload = Some(data),
+      None => {}
+    }
+  }
+
+  let body: WebhookRequest = serde_json::from_slice(payload.as_deref().unwrap_or(b"{}"))
+    .map_err(|e| HookError::InvalidPayload(e.to_string()))?;
+  Ok(body.with_files(files))
+}
+
+// The posted events' ids are returned so they can be edited, redacted, reacted to or replied to later
+fn respond(webhook_id: &str, res: Result<Posted>) -> Box<dyn Reply> {
+  let (json, status) = outcome(webhook_id, res);
+  Box::new(warp::reply::with_status(warp::reply::json(&json), status))
+}
+
+// What a request's response says about how it went
+fn outcome(webhook_id: &str, res: Result<Posted>) -> (serde_json::Value, http::StatusCode) {
+  match res {
+    // The first event is the message, which the rest, like its media, follow
+    Ok(posted) => (
+      serde_json::json!({
+        "success": true,
+        "eventId": posted.event_ids.first(),
+        "roomId": posted.room_id,
+        "eventIds": posted.event_ids,
+      }),
+      http::StatusCode::OK,
+    ),
+    Err(e) => match e.downcast_ref::<HookError>() {
+      Some(hook_error) => {
+        warn!(
+          "Webhook request with id {} failed: {}",
+          &webhook_id, hook_error
+        );
+        (
+          serde_json::json!({
+            "success": false,
+            "errcode": hook_error.errcode(),
+            "message": hook_error.to_string(),
+          }),
+          hook_error.status(),
+        )
+      }
+      None => {
+        error!(
+          "Error responding to webhook request with id {}: {}",
+          &webhook_id,
+          e.to_string()
+        );
+        (
+          serde_json::json!({"success": false, "message": e.to_string()}),
+          http::status::StatusCode::INTERNAL_SERVER_ERROR,
+        )
+      }
+    },
+  }
+}
+
+/// Posts the request for the hook, keeping count of how its requests went for !webhook stats
+pub async fn handler_inner(
   webhook_id: &str,
   body: WebhookRequest,
   config: Arc<Config>,
   appservice: AppService,
   store: Arc<Store>,
-) -> Result<()> {
+) -> Result<Posted> {
+  let result = post_message(webhook_id, body, config, appservice, store.clone()).await;
+  let recorded = match &result {
+    Ok(_) => store.record_webhook_delivery(webhook_id).await,
+    Err(_) => store.record_webhook_failure(webhook_id).await,
+  };
+  // Whether or not the message was delivered, this shouldn't change the response
+  if let Err(e) = recorded {
+    warn!(
+      "Failed to record stats for webhook {}: {}",
+      webhook_id,
+      e.to_string()
+    );
+  }
+  result
+}
+
+async fn post_message(
+  webhook_id: &str,
+  mut body: WebhookRequest,
+  config: Arc<Config>,
+  appservice: AppService,
+  store: Arc<Store>,
+) -> Result<Posted> {
   debug!("Received webhook for id {}", webhook_id);
   let hook = match store.get_webhook_by_id(webhook_id).await? {
     Some(hook) => hook,
     None => return Err(anyhow::anyhow!("Could not find webhook")),
   };
+  if !hook.enabled {
+    return Err(HookError::Paused.into());
+  }
+  // A message for later is kept as its request, which the scheduler posts again when it's time
+  if let Some(send_at) = body.get_send_at()? {
+    if send_at > SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64 {
+      if body.has_files() {
+        return Err(
+          HookError::InvalidPayload("Uploaded files can't be scheduled".to_string()).into(),
+        );
+      }
+      store
+        .schedule_message(&hook.id, &serde_json::to_string(&body)?, send_at)
+        .await?;
+      return Ok(Posted::default());
+    }
+  }
+  if let Some(name) = body.get_template().map(str::to_string) {
+    let template = payloads::named_templates(&store, &hook.id)
+      .await?
+      .remove(&name)
+      .ok_or_else(|| {
+        HookError::InvalidPayload(format!("The webhook has no template named {}", name))
+      })?;
+    body = body.with_template(&template)?;
+  }
 
-  let room_id = RoomId::try_from(hook.room_id)?;
+  let bot_client = appservice
+    .virtual_user_client(&config.webhook_bot.localpart)
+    .await?;
+  let room_id = target_room(&bot_client, &store, &hook, body.get_room()).await?;
 
-  let mut hasher = Sha256::new();
-  hasher.update(&hook.id);
-  let id_hash = hex::encode(&hasher.finalize()[0..16]);
-  let bot_localpart = format!("{}__{}", &config.webhook_bot.localpart, &id_hash);
+  let encrypted = bot_client
+    .get_joined_room(&room_id)
+    .map_or(false, |room| room.is_encrypted());
+  if encrypted && config.encryption.is_none() {
+    // Only tell the owner once, rather than on every request
+    let notice_setting = format!("encryptionNoticeSent:{}", &hook.id);
+    if store.get_setting(&notice_setting).await?.is_none() {
+      let owner = UserId::try_from(hook.user_id.as_str())?;
+      let message = format!(
+        "Your webhook for {} can't post, because the room is encrypted. The bridge doesn't have \
+        encryption enabled, so the webhook will keep failing until it's moved to an unencrypted room.",
+        room_id
+      );
+      bot::send_admin_notice(&bot_client, &owner, message.clone(), message).await?;
+      store.set_setting(&notice_setting, "true").await?;
+    }
+    return Err(HookError::RoomEncrypted.into());
+  }
+  // Uploads aren't encrypted, so they'd give away what the room is sent
+  if encrypted && body.has_media() {
+    return Err(
+      HookError::InvalidPayload("Media can't be posted to encrypted rooms yet".to_string()).into(),
+    );
+  }
+
+  if store
+    .get_setting(&kicked_setting(&hook.id))
+    .await?
+    .is_some()
+  {
+    return Err(HookError::Kicked.into());
+  }
+
+  // Whatever the request leaves out comes from the hook, or else the bridge bot
+  let appearance = &config.webhook_bot.appearance;
+  let display_name = body.get_display_name(
+    hook
+      .display_name
+      .as_deref()
+      .unwrap_or(&appearance.display_name),
+  );
+  let avatar_url = body.get_avatar_url(
+    hook
+      .avatar_url
+      .as_deref()
+      .or(Some(appearance.avatar_url.as_str())),
+  );
+  // A localpart chosen for the hook wins over the puppet mode
+  let bot_localpart = match (&hook.puppet_localpart, &config.webhook_bot.puppet_mode) {
+    (Some(_), _) | (None, PuppetMode::Hook) => hook_puppet_localpart(&config, &hook),
+    (None, PuppetMode::DisplayName) => {
+      display_name_puppet_localpart(&config, &hook.id, &display_name)
+    }
+  };
+  let puppet_user_id = UserId::parse_with_server_name(
+    bot_localpart.as_str(),
+    <&ServerName>::try_from(config.homeserver.domain.as_str())?,
+  )?;
+
+  // Off by default, since anyone with the hook's URL could page the room
+  let room_mention_allowed = (body.mentions_room() || body.is_critical())
+    && store
+      .get_setting(&room_mention_setting(&hook.id))
+      .await?
+      .is_some();
+  if body.mentions_room() && !room_mention_allowed {
+    return Err(HookError::RoomMentionNotAllowed.into());
+  }
+  // Critical messages page the room when the hook may, and are still posted when it may not
+  if body.is_critical() && room_mention_allowed {
+    body = body.with_room_mention();
+  }
+  body = body
+    .with_html_policy(config.html.clone())
+    .with_emoji_default(config.emoji.by_default);
+  // Custom emoji are uploaded as the bot, since every hook shares them. One that can't be is left
+  // as its name.
+  let mut emoticons = HashMap::new();
+  for name in body.custom_emoji_names(&config.custom_emoji) {
+    match media::emoticon(
+      &bot_client,
+      &store,
+      &config.custom_emoji[&name],
+      &config.limits,
+    )
+    .await
+    {
+      Ok(uri) => {
+        emoticons.insert(name, uri);
+      }
+      Err(e) => warn!("Failed to upload custom emoji {}: {}", name, e.to_string()),
+    }
+  }
+  body = body.with_custom_emoji(emoticons);
+  // Many clients don't preview links in appservice users' messages, so hooks can add their own.
+  // Uploads aren't encrypted, so encrypted rooms get them without their images.
+  if let Some(url) = body.first_url() {
+    if store
+      .get_setting(&preview_setting(&hook.id))
+      .await?
+      .is_some()
+    {
+      match preview::render(&bot_client, &url, &config.limits, !encrypted).await {
+        Ok(Some((plain, html))) => body = body.with_preview(plain, html),
+        Ok(None) => {}
+        Err(e) => warn!(
+          "Failed to preview {} for webhook {}: {}",
+          url,
+          &hook.id,
+          e.to_string()
+        ),
+      }
+    }
+  }
+  if body.is_digestible()
+    && store
+      .get_setting(&digest_setting(&hook.id))
+      .await?
+      .is_some()
+  {
+    let (plain, html) = digest::parts(&body.create_message());
+    store
+      .add_digest_item(&hook.id, &plain, html.as_deref())
+      .await?;
+    return Ok(Posted::default());
+  }
+  let custom_event = body.create_custom_event()?;
+  if custom_event.is_some()
+    && store
+      .get_setting(&custom_events_setting(&hook.id))
+      .await?
+      .is_none()
+  {
+    return Err(HookError::CustomEventsNotAllowed.into());
+  }
+  let state_events = body.create_state_events();
+  if !state_events.is_empty()
+    && store
+      .get_setting(&room_state_setting(&hook.id))
+      .await?
+      .is_none()
+  {
+    return Err(HookError::RoomStateNotAllowed.into());
+  }
+
+  // The homeserver would refuse the message anyway, but with an error the sender can't act on
+  if let Some(room) = bot_client.get_joined_room(&room_id) {
+    let power_levels = PowerLevels::of_room(&room).await?;
+    for event_type in body.event_types() {
+      if !power_levels.can_send(puppet_user_id.as_str(), event_type) {
+        return Err(
+          HookError::SendForbidden {
+            required: power_levels.event_level(event_type),
+            actual: power_levels.user_level(puppet_user_id.as_str()),
+          }
+          .into(),
+        );
+      }
+    }
+    for (event_type, _) in &state_events {
+      if !power_levels.can_send_state(puppet_user_id.as_str(), event_type) {
+        return Err(
+          HookError::SendForbidden {
+            required: power_levels.state_event_level(event_type),
+            actual: power_levels.user_level(puppet_user_id.as_str()),
+          }
+          .into(),
+        );
+      }
+    }
+  }
 
   let client = bot::register_bot(
+    &config,
     &bot_localpart,
-    &body.get_display_name(),
-    &body.get_avatar_url(),
+    &display_name,
+    &avatar_url,
     appservice.clone(),
   )
   .await?;
+  store
+    .mark_puppet_registered(&bot_localpart, &hook.id)
+    .await?;
 
   // May be over-cautious
   client.sync_once(SyncSettings::default()).await?;
 
+  // The bot looks the event up, since the webhook user may not be in the room yet
+  let edits = match body.get_edits() {
+    Some(_) if body.has_media() => {
+      return Err(HookError::InvalidPayload("Only a message's text can be edited".into()).into());
+    }
+    // An edit has to come from whoever sent the message
+    Some(event_id) => {
+      let (event_id, sender) = event_sender(&bot_client, &room_id, event_id).await?;
+      if sender != puppet_user_id.as_str() {
+        return Err(not_hook_message(event_id.as_str()).into());
+      }
+      Some(event_id)
+    }
+    None => None,
+  };
+
+  // The text and location, then any media, uploaded as the webhook user so it's theirs
+  let mut messages = Vec::new();
+  if body.has_text() {
+    let message = body.create_message();
+    if edits.is_none() && oversize::is_oversized(&message)? {
+      let truncate = store.get_setting(&oversize_setting(&hook.id)).await?;
+      if truncate.as_deref() == Some(oversize::TRUNCATE) {
+        // Files can't be posted to encrypted rooms yet
+        let (truncated, file) = oversize::truncate(&message, !encrypted);
+        messages.push(truncated);
+        if let Some(file) = file {
+          messages.push(media::file_message(&client, file).await?);
+        }
+      } else {
+        messages.extend(oversize::split(&message));
+      }
+    } else {
+      messages.push(message);
+    }
+  }
+  if let Some(location) = body.create_location_message()? {
+    messages.push(location);
+  }
+  let urls = [
+    (body.get_image_url(), Some(mime::IMAGE)),
+    (body.get_audio_url(), Some(mime::AUDIO)),
+    (body.get_video_url(), Some(mime::VIDEO)),
+  ];
+  for (url, expected_type) in urls.iter() {
+    if let Some(url) = url {
+      let media = media::download(url, *expected_type, &config.limits).await?;
+      messages.push(media::message(&client, media).await?);
+    }
+  }
+  // Whatever its type, since it was asked for as a file
+  if let Some(file_url) = body.get_file_url() {
+    let file = media::download(file_url, None, &config.limits).await?;
+    messages.push(media::file_message(&client, file).await?);
+  }
+  for file in body.take_files() {
+    messages.push(media::message(&client, file).await?);
+  }
+  if edits.is_some() && (messages.len() != 1 || body.event_types().len() != 1) {
+    return Err(HookError::InvalidPayload("An edit replaces one message's text".into()).into());
+  }
+  // The bot looks the event up, since the webhook user may not be in the room yet
+  if let (Some(event_id), Some(first), None) =
+    (body.get_in_reply_to(), messages.first_mut(), &edits)
+  {
+    reply::reply_to(&bot_client, &room_id, event_id, first).await?;
+  }
+
+  // Sent as JSON from here on, since the SDK has no type for thread relations. An edit stays where
+  // the message was.
+  let auto_thread = edits.is_none()
+    && store
+      .get_setting(&auto_thread_setting(&hook.id))
+      .await?
+      .is_some();
+  let thread_root = match &edits {
+    Some(_) => None,
+    None => thread_root(&store, &hook, &room_id, body.get_thread_root(), auto_thread).await?,
+  };
+  let starts_thread = auto_thread && thread_root.is_none() && !messages.is_empty();
+  let mentions = body.get_mentions()?;
+  let mut messages = messages
+    .iter()
+    .enumerate()
+    .map(|(i, message)| {
+      let mut content = serde_json::to_value(message)?;
+      // Ruma doesn't know about intentional mentions, which ping only the users listed. Quiet
+      // messages list none, so names in the text don't ping anyone either.
+      if i == 0 && (!mentions.is_empty() || body.mentions_room() || body.is_quiet()) {
+        content["m.mentions"] = serde_json::json!({ "user_ids": mentions });
+        if body.mentions_room() {
+          content["m.mentions"]["room"] = true.into();
+        }
+      }
+      if let Some(thread_root) = &thread_root {
+        add_thread_relation(&mut content, thread_root);
+      }
+      if let Some(edits) = &edits {
+        content = edit_content(content, edits.as_str());
+      }
+      Ok(Outgoing {
+        event_type: MESSAGE_EVENT_TYPE.to_string(),
+        content,
+      })
+    })
+    .collect::<Result<Vec<_>>>()?;
+  if let Some((event_type, content)) = custom_event {
+    messages.push(Outgoing {
+      event_type,
+      content,
+    });
+  }
+  // Deployment bots mark their earlier messages as done this way
+  if let Some(content) = body.create_reaction()? {
+    let event_id = body.get_reaction_target().unwrap_or_default();
+    let (_, sender) = event_sender(&bot_client, &room_id, event_id).await?;
+    if !sent_by_hook(&config, &store, &hook, &sender).await? {
+      return Err(not_hook_message(event_id).into());
+    }
+    messages.push(Outgoing {
+      event_type: REACTION_EVENT_TYPE.to_string(),
+      content,
+    });
+  }
+
   // Have the bot invite the webhook to the room only if it's not already joined
   if client.get_joined_room(&room_id).is_none() {
-    let bot_client = appservice
-      .virtual_user_client(&config.webhook_bot.localpart)
-      .await?;
     let room = bot_client
       .get_joined_room(&room_id)
       .map_or(Err(anyhow!("Couldn't get joined room from bot")), Ok)?;
 
+    let power_levels = PowerLevels::of_room(&room).await?;
+    let bot_user_id = bot::bot_user_id(&config)?;
+    if !power_levels.can_invite(bot_user_id.as_str()) {
+      let error = HookError::InviteForbidden {
+        required: power_levels.invite,
+        actual: power_levels.user_level(bot_user_id.as_str()),
+      };
+      if queue_pending_messages(&room_id, &puppet_user_id, messages) {
+        let owner = UserId::try_from(hook.user_id.as_str())?;
+        let message = format!(
+          "Your webhook for {} can't post: {}. Give me that power level, or invite {} yourself.",
+          room_id, error, puppet_user_id
+        );
+        bot::send_admin_notice(&bot_client, &owner, message.clone(), message).await?;
+      }
+      return Err(error.into());
+    }
+
     room
-      .invite_user_by_id(&UserId::parse_with_server_name(
-        bot_localpart.as_str(),
-        <&ServerName>::try_from(config.homeserver.domain.as_str())?,
-      )?)
+      .invite_user_by_id(&puppet_user_id)
       .await
       .context("Failed to have bot invite the webhook")?;
 
     client.join_room_by_id(&room_id).await?;
   }
 
-  client
-    .room_send(&room_id, body.create_message(), None)
+  let responses = if encrypted {
+    send_encrypted(
+      &config,
+      &store,
+      &appservice,
+      &bot_localpart,
+      &room_id,
+      messages,
+    )
+    .await?
+  } else {
+    let mut responses = Vec::new();
+    for message in &messages {
+      responses.push(send_raw(&client, &room_id, message).await?);
+    }
+    responses
+  };
+
+  // Redacted by the expiry task, which outlives restarts since they're stored
+  if let Some(ttl_seconds) = body.get_ttl_seconds() {
+    for response in &responses {
+      store
+        .schedule_redaction(
+          room_id.as_str(),
+          response.event_id.as_str(),
+          &bot_localpart,
+          ttl_seconds,
+        )
+        .await?;
+    }
+  }
+
+  // Later messages go in the thread this one started
+  if let (true, Some(first)) = (starts_thread, responses.first()) {
+    store
+      .set_setting(
+        &thread_root_setting(&hook.id),
+        &format!("{} {}", room_id, first.event_id),
+      )
+      .await?;
+  }
+
+  // The message is already delivered, so a failed receipt shouldn't fail the request
+  if config.webhook_bot.send_read_receipts {
+    if let (Some(room), Some(response)) = (client.get_joined_room(&room_id), responses.last()) {
+      if let Err(e) = room.read_receipt(&response.event_id).await {
+        warn!(
+          "Failed to send read receipt for webhook {}: {}",
+          &hook.id,
+          e.to_string()
+        );
+      }
+    }
+  }
+
+  // State isn't encrypted, so the appservice's client sends it either way
+  let mut event_ids: Vec<EventId> = responses
+    .into_iter()
+    .map(|response| response.event_id)
+    .collect();
+  for (event_type, content) in &state_events {
+    let body = Raw::from_json(serde_json::value::to_raw_value(content)?);
+    let request = send_state_event::Request::new_raw(&room_id, event_type, "", body);
+    event_ids.push(client.send(request, None).await?.event_id);
+  }
+  Ok(Posted {
+    room_id: Some(room_id),
+    event_ids,
+  })
+}
+
+// The event's id and sender, looked up by the bot since the webhook user may not be in the room
+async fn event_sender(
+  bot_client: &Client,
+  room_id: &RoomId,
+  event_id: &str,
+) -> Result<(EventId, String)> {
+  let event_id = EventId::try_from(event_id).map_err(|_| not_hook_message(event_id))?;
+  let response = bot_client
+    .send(get_room_event::Request::new(room_id, &event_id), None)
+    .await
+    .map_err(|_| not_hook_message(event_id.as_str()))?;
+  let event: serde_json::Value = serde_json::from_str(response.event.json().get())?;
+  let sender = event["sender"].as_str().unwrap_or_default().to_string();
+  Ok((event_id, sender))
+}
+
+// Any of the hook's webhook users, since with `puppetMode: displayName` it has several
+async fn sent_by_hook(
+  config: &Config,
+  store: &Store,
+  hook: &Webhook,
+  sender: &str,
+) -> Result<bool> {
+  let sender = match UserId::try_from(sender) {
+    Ok(sender) if is_bridge_user(config, sender.as_str()) => sender,
+    _ => return Ok(false),
+  };
+  Ok(
+    store
+      .get_puppet(sender.localpart())
+      .await?
+      .map_or(false, |puppet| puppet.hook_id == hook.id),
+  )
+}
+
+// Edits, redactions and reactions are only for the hook's own messages
+fn not_hook_message(event_id: &str) -> HookError {
+  HookError::InvalidPayload(format!(
+    "{} isn't one of the webhook's messages in the room",
+    event_id
+  ))
+}
+
+// Clients that don't know edits show the fallback, the new text marked with a *
+fn edit_content(new_content: serde_json::Value, event_id: &str) -> serde_json::Value {
+  let mut content = new_content.clone();
+  for field in &["body", "formatted_body"] {
+    if let Some(text) = content[*field].as_str() {
+      content[*field] = format!("* {}", text).into();
+    }
+  }
+  content["m.new_content"] = new_content;
+  content["m.relates_to"] = serde_json::json!({
+    "rel_type": "m.replace",
+    "event_id": event_id,
+  });
+  content
+}
+
+// The thread the messages go in: the request's, or else if the hook is auto-threading, the last one
+// it started in the room
+async fn thread_root(
+  store: &Store,
+  hook: &Webhook,
+  room_id: &RoomId,
+  requested: Option<&str>,
+  auto_thread: bool,
+) -> Result<Option<String>> {
+  if let Some(requested) = requested {
+    EventId::try_from(requested)
+      .map_err(|_| HookError::InvalidPayload(format!("{} isn't an event id", requested)))?;
+    return Ok(Some(requested.to_string()));
+  }
+  if !auto_thread {
+    return Ok(None);
+  }
+  let root = store.get_setting(&thread_root_setting(&hook.id)).await?;
+  Ok(root.and_then(|root| {
+    let (root_room_id, event_id) = root.split_once(' ')?;
+    Some(event_id.to_string()).filter(|_| root_room_id == room_id.as_str())
+  }))
+}
+
+// Keeps a reply's m.in_reply_to, which clients without threads show instead
+fn add_thread_relation(content: &mut serde_json::Value, thread_root: &str) {
+  let in_reply_to = content
+    .get("m.relates_to")
+    .and_then(|relation| relation.get("m.in_reply_to"))
+    .cloned();
+  let mut relation = serde_json::json!({
+    "rel_type": THREAD_REL_TYPE,
+    "event_id": thread_root,
+  });
+  if let Some(in_reply_to) = in_reply_to {
+    relation["m.in_reply_to"] = in_reply_to;
+  }
+  content["m.relates_to"] = relation;
+}
+
+async fn send_raw(
+  client: &Client,
+  room_id: &RoomId,
+  event: &Outgoing,
+) -> Result<send_message_event::Response> {
+  let txn_id = Uuid::new_v4().to_string();
+  let body = Raw::from_json(serde_json::value::to_raw_value(&event.content)?);
+  let request = send_message_event::Request::new_raw(room_id, &txn_id, &event.event_type, body);
+  Ok(client.send(request, None).await?)
+}
+
+// Sends the messages from the puppet's own device, which has to catch up on the room first since it
+// was only just joined through the appservice
+async fn send_encrypted(
+  config: &Config,
+  store: &Store,
+  appservice: &AppService,
+  localpart: &str,
+  room_id: &RoomId,
+  messages: Vec<Outgoing>,
+) -> Result<Vec<send_message_event::Response>> {
+  let client = encryption::client(config, store, appservice, localpart).await?;
+  client.sync_once(SyncSettings::default()).await?;
+  let room = client
+    .get_joined_room(room_id)
+    .ok_or_else(|| anyhow!("{} isn't in {} yet", localpart, room_id))?;
+  let mut responses = Vec::new();
+  for message in messages {
+    responses.push(
+      room
+        .send_raw(message.content, &message.event_type, None)
+        .await?,
+    );
+  }
+  Ok(responses)
+}
+
+// The hook's own room, unless the request names another one it's allowed to post to
+async fn target_room(
+  bot_client: &Client,
+  store: &Store,
+  hook: &Webhook,
+  requested: Option<&str>,
+) -> Result<RoomId> {
+  let own_room_id = RoomId::try_from(hook.room_id.as_str())?;
+  let requested = match requested {
+    Some(requested) => requested,
+    None => return Ok(own_room_id),
+  };
+
+  let room_id = match room_reference::resolve(bot_client, requested).await? {
+    Some(room_id) => room_id,
+    None => return Err(HookError::RoomNotAllowed.into()),
+  };
+  if room_id == own_room_id
+    || store
+      .get_webhook_rooms(&hook.id)
+      .await?
+      .iter()
+      .any(|allowed| allowed == room_id.as_str())
+  {
+    Ok(room_id)
+  } else {
+    Err(HookError::RoomNotAllowed.into())
+  }
+}
+
+// Returns whether these are the first messages waiting for the puppet, so the owner only gets told
+// once
+fn queue_pending_messages(
+  room_id: &RoomId,
+  puppet_user_id: &UserId,
+  messages: Vec<Outgoing>,
+) -> bool {
+  let mut pending = PENDING_MESSAGES
+    .entry((room_id.to_string(), puppet_user_id.to_string()))
+    .or_default();
+  let first = pending.is_empty();
+  pending.extend(messages);
+  if pending.len() > MAX_PENDING_MESSAGES {
+    let excess = pending.len() - MAX_PENDING_MESSAGES;
+    pending.drain(..excess);
+  }
+  first
+}
+
+/// How many messages are waiting for their webhook user to be invited, across all rooms
+pub fn pending_message_count() -> usize {
+  PENDING_MESSAGES.iter().map(|pending| pending.len()).sum()
+}
+
+/// Has a puppet that was just invited join the room and post whatever it couldn't earlier
+pub async fn post_pending_messages(
+  appservice: &AppService,
+  room_id: &RoomId,
+  puppet_user_id: &UserId,
+) -> Result<()> {
+  let (_, messages) =
+    match PENDING_MESSAGES.remove(&(room_id.to_string(), puppet_user_id.to_string())) {
+      Some(pending) => pending,
+      None => return Ok(()),
+    };
+
+  info!(
+    "Posting {} pending messages as {} in {}",
+    messages.len(),
+    puppet_user_id,
+    room_id
+  );
+  let client = appservice
+    .virtual_user_client(puppet_user_id.localpart())
     .await?;
+  client.join_room_by_id(room_id).await?;
+  for message in &messages {
+    send_raw(&client, room_id, message).await?;
+  }
 
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_outcome() {
+    let posted = Posted {
+      room_id: Some(RoomId::try_from("!room:example.com").unwrap()),
+      event_ids: vec![
+        EventId::try_from("$text:example.com").unwrap(),
+        EventId::try_from("$image:example.com").unwrap(),
+      ],
+    };
+    assert_eq!(
+      outcome("hook", Ok(posted)),
+      (
+        serde_json::json!({
+          "success": true,
+          "eventId": "$text:example.com",
+          "roomId": "!room:example.com",
+          "eventIds": ["$text:example.com", "$image:example.com"],
+        }),
+        http::StatusCode::OK
+      )
+    );
+    assert_eq!(
+      outcome("hook", Err(HookError::Paused.into())).1,
+      HookError::Paused.status()
+    );
+  }
+
+  #[test]
+  fn test_edit_content() {
+    let content = serde_json::json!({
+      "msgtype": "m.text",
+      "body": "Deploy finished",
+      "format": "org.matrix.custom.html",
+      "formatted_body": "Deploy <b>finished</b>",
+    });
+    assert_eq!(
+      edit_content(content.clone(), "$started"),
+      serde_json::json!({
+        "msgtype": "m.text",
+        "body": "* Deploy finished",
+        "format": "org.matrix.custom.html",
+        "formatted_body": "* Deploy <b>finished</b>",
+        "m.new_content": content,
+        "m.relates_to": {"rel_type": "m.replace", "event_id": "$started"},
+      })
+    );
+  }
+
+  #[test]
+  fn test_add_thread_relation() {
+    let mut content = serde_json::json!({"msgtype": "m.text", "body": "Deploy finished"});
+    add_thread_relation(&mut content, "$root");
+    assert_eq!(
+      content["m.relates_to"],
+      serde_json::json!({"rel_type": "io.element.thread", "event_id": "$root"})
+    );
+
+    let mut content = serde_json::json!({
+      "msgtype": "m.text",
+      "body": "Deploy finished",
+      "m.relates_to": {"m.in_reply_to": {"event_id": "$started"}},
+    });
+    add_thread_relation(&mut content, "$root");
+    assert_eq!(
+      content["m.relates_to"]["m.in_reply_to"]["event_id"],
+      "$started"
+    );
+  }
+}
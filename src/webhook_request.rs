@@ -1,35 +1,197 @@
-use crate::emoji;
+use crate::{
+  ansi, bot,
+  config::Html,
+  emoji, math,
+  media::Media,
+  mentions,
+  payloads::{self, Message},
+  preview,
+  sanitize::sanitize,
+  webhook::HookError,
+};
 use matrix_sdk::ruma::events::room::message::{
-  EmoteMessageEventContent, MessageEventContent, MessageType,
+  EmoteMessageEventContent, LocationMessageEventContent, MessageEventContent, MessageType,
 };
-use serde::Deserialize;
+use matrix_sdk::ruma::UserId;
+use pulldown_cmark::{html, Alignment, Event, Options, Parser, Tag};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, convert::TryFrom};
 
-#[derive(Debug, PartialEq, Deserialize)]
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub struct WebhookRequest {
+  #[serde(default)]
   text: String,
+  #[serde(default)]
   format: Format,
+  /// Renders LaTeX between dollar signs in markdown as math
+  #[serde(default)]
+  math: bool,
+  /// Posts the text verbatim as a code block, highlighted as the language if it's given one
+  code: Option<CodeBlock>,
+  /// One of the hook's named templates, rendered with `vars` as the message's text
+  template: Option<String>,
+  vars: Option<serde_json::Value>,
   #[serde(rename = "displayName")]
   display_name: Option<String>,
   #[serde(rename = "avatarUrl")]
   avatar_url: Option<String>,
-  #[serde(default = "return_true")]
-  emoji: bool,
+  /// Whether emoji names are replaced, which otherwise depends on the config
+  emoji: Option<bool>,
   #[serde(default, rename = "msgtype")]
   message_type: MsgType,
+  /// Another room the hook may post to, instead of its own
+  room: Option<String>,
+  /// An image the bridge downloads and posts after the text, if there is any
+  #[serde(rename = "imageUrl")]
+  image_url: Option<String>,
+  #[serde(rename = "audioUrl")]
+  audio_url: Option<String>,
+  #[serde(rename = "videoUrl")]
+  video_url: Option<String>,
+  /// Like image_url, for any other kind of file
+  #[serde(rename = "fileUrl")]
+  file_url: Option<String>,
+  /// A place posted as an m.location after the text
+  geo: Option<Geo>,
+  /// An annotation on an earlier message from the hook, sent after everything else
+  reaction: Option<Reaction>,
+  /// A custom event to send with `content`, if the hook is allowed to, for bots and widgets in the
+  /// room
+  #[serde(rename = "eventType")]
+  event_type: Option<String>,
+  content: Option<serde_json::Value>,
+  /// A new name or topic for the room, if the hook is allowed to change them
+  #[serde(rename = "roomState")]
+  room_state: Option<RoomState>,
+  /// When to post the message, as an RFC 3339 timestamp, if it's for later
+  #[serde(rename = "sendAt")]
+  send_at: Option<String>,
+  /// Redacts what the request posts after this long, for pings that are soon out of date
+  #[serde(rename = "ttlSeconds")]
+  ttl_seconds: Option<u64>,
+  /// Users to ping, along with any user ids in the text
+  #[serde(default)]
+  mentions: Vec<String>,
+  /// Pings everyone in the room with @room, if the hook is allowed to
+  #[serde(default, rename = "mentionRoom")]
+  mention_room: bool,
+  /// How loudly the message notifies the room's members
+  #[serde(default)]
+  priority: Priority,
+  /// An earlier event in the room the message replies to
+  #[serde(rename = "inReplyTo")]
+  in_reply_to: Option<String>,
+  /// The root event of a thread to post the message in
+  #[serde(rename = "threadRoot")]
+  thread_root: Option<String>,
+  /// An earlier message from the hook that the text replaces, rather than being posted
+  edits: Option<String>,
+  /// Files uploaded along with the request, posted last
+  #[serde(skip)]
+  files: Vec<Media>,
+  /// Set apart after the text as colored quotes, like Slack's
+  #[serde(default)]
+  attachments: Vec<Attachment>,
+  /// The plain text version of html translated from another service's payload, which says more
+  /// than the html with its tags stripped
+  #[serde(skip)]
+  plain_text: Option<String>,
+  /// Which tags the message's HTML may have, which are set by the config
+  #[serde(skip)]
+  html_policy: Html,
+  /// The mxc URIs of the custom emoji in the text, by name
+  #[serde(skip)]
+  custom_emoji: HashMap<String, String>,
+  /// A preview of the first link in the text, as a quote in plain text and html
+  #[serde(skip)]
+  preview: Option<(String, String)>,
+  /// Whether it's a hook's digest of the messages it held, which is posted rather than held again
+  #[serde(skip)]
+  digest: bool,
 
   // Slack-compatible fields
   icon_url: Option<String>,
   username: Option<String>,
 }
 
-#[derive(Debug, PartialEq, Deserialize)]
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+struct Attachment {
+  /// A hex color or one of Slack's names for them, like danger
+  color: Option<String>,
+  title: Option<String>,
+  title_link: Option<String>,
+  text: Option<String>,
+  fields: Vec<AttachmentField>,
+  image_url: Option<String>,
+  footer: Option<String>,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+struct AttachmentField {
+  title: String,
+  value: String,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+struct Geo {
+  lat: f64,
+  lon: f64,
+  description: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+enum CodeBlock {
+  Plain(bool),
+  Language(String),
+}
+
+impl CodeBlock {
+  fn html(&self, code: &str) -> String {
+    match self {
+      CodeBlock::Language(language) if !language.is_empty() => format!(
+        "<pre><code class=\"language-{}\">{}</code></pre>",
+        bot::escape_html(language),
+        bot::escape_html(code)
+      ),
+      _ => format!("<pre><code>{}</code></pre>", bot::escape_html(code)),
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+struct Reaction {
+  #[serde(rename = "eventId")]
+  event_id: String,
+  key: String,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+struct RoomState {
+  name: Option<String>,
+  topic: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 enum Format {
   Plain,
   Html,
+  /// CommonMark, which is also the plain text version
+  Markdown,
+  /// Terminal output, posted as a code block in the colors its escape sequences give it
+  Ansi,
+}
+
+impl Default for Format {
+  fn default() -> Self {
+    Self::Plain
+  }
 }
 
-#[derive(Debug, PartialEq, Deserialize)]
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 enum MsgType {
   Regular,
@@ -43,76 +205,770 @@ impl Default for MsgType {
   }
 }
 
-fn return_true() -> bool {
-  true
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Priority {
+  /// Posted as a notice, which clients don't notify about by default, and pings no one
+  Low,
+  Normal,
+  /// Posted as a regular message even if it's a notice, so it notifies
+  High,
+  /// Like high, and pings the whole room if the hook may
+  Critical,
+}
+
+impl Default for Priority {
+  fn default() -> Self {
+    Self::Normal
+  }
 }
 
 impl WebhookRequest {
+  /// A message translated from another service's payload
+  pub fn translated(plain: String, html: String) -> Self {
+    Self {
+      text: html,
+      format: Format::Html,
+      math: false,
+      code: None,
+      template: None,
+      vars: None,
+      display_name: None,
+      avatar_url: None,
+      emoji: None,
+      message_type: MsgType::Regular,
+      room: None,
+      image_url: None,
+      audio_url: None,
+      video_url: None,
+      file_url: None,
+      geo: None,
+      reaction: None,
+      event_type: None,
+      content: None,
+      room_state: None,
+      send_at: None,
+      ttl_seconds: None,
+      mentions: Vec::new(),
+      mention_room: false,
+      priority: Priority::Normal,
+      in_reply_to: None,
+      thread_root: None,
+      edits: None,
+      files: Vec::new(),
+      attachments: Vec::new(),
+      plain_text: Some(plain),
+      html_policy: Html::default(),
+      custom_emoji: HashMap::new(),
+      preview: None,
+      digest: false,
+      icon_url: None,
+      username: None,
+    }
+  }
+
+  /// A message that's just the text, as posted with a text/plain body
+  pub fn from_text(text: String) -> Self {
+    Self {
+      text,
+      format: Format::default(),
+      plain_text: None,
+      ..Self::translated(String::new(), String::new())
+    }
+  }
+
+  /// A message posted as a form, either as JSON in its `payload` field or as the message's fields
+  /// themselves
+  pub fn from_form(body: &[u8]) -> Result<Self, HookError> {
+    let invalid = |e: &dyn std::fmt::Display| HookError::InvalidPayload(e.to_string());
+    let fields: HashMap<String, String> =
+      serde_urlencoded::from_bytes(body).map_err(|e| invalid(&e))?;
+    match fields.get("payload") {
+      Some(payload) => serde_json::from_str(payload).map_err(|e| invalid(&e)),
+      None => serde_urlencoded::from_bytes(body).map_err(|e| invalid(&e)),
+    }
+  }
+
+  /// The name of the hook's template the request fills in, if it names one
+  pub fn get_template(&self) -> Option<&str> {
+    self.template.as_deref()
+  }
+
+  /// With its text rendered, as markdown, from the template filled in with the request's vars
+  pub fn with_template(mut self, template: &str) -> anyhow::Result<Self> {
+    let vars = self.vars.take().unwrap_or_else(|| serde_json::json!({}));
+    self.text = payloads::custom::render(template, &vars)?;
+    self.format = Format::Markdown;
+    Ok(self)
+  }
+
+  /// Whether emoji names are replaced when the request doesn't say
+  pub fn with_emoji_default(mut self, default: bool) -> Self {
+    self.emoji.get_or_insert(default);
+    self
+  }
+
+  /// Cleans the message's HTML down to the policy's tags, rather than the spec's
+  pub fn with_html_policy(mut self, policy: Html) -> Self {
+    self.html_policy = policy;
+    self
+  }
+
+  /// The operator's custom emoji the text has, out of those configured
+  pub fn custom_emoji_names(&self, configured: &HashMap<String, String>) -> Vec<String> {
+    emoji::custom_emoji_names(&self.text, configured)
+  }
+
+  /// Shows the custom emoji as their images, whose mxc URIs are given by name
+  pub fn with_custom_emoji(mut self, emoticons: HashMap<String, String>) -> Self {
+    self.custom_emoji = emoticons;
+    self
+  }
+
+  /// Who the message is from, if the payload says
+  pub fn with_sender(mut self, display_name: Option<String>, avatar_url: Option<String>) -> Self {
+    self.display_name = display_name;
+    self.avatar_url = avatar_url;
+    self
+  }
+
+  /// The first link in the text, which the hook may add a preview of
+  pub fn first_url(&self) -> Option<String> {
+    preview::first_url(&self.text)
+  }
+
+  /// Quotes a preview of the first link after the text
+  pub fn with_preview(mut self, plain: String, html: String) -> Self {
+    self.preview = Some((plain, html));
+    self
+  }
+
+  /// As a hook's digest of the messages it held
+  pub fn as_digest(mut self) -> Self {
+    self.digest = true;
+    self
+  }
+
+  /// Whether it's only a message, which a hook in digest mode can hold to post with others.
+  /// Urgent messages, and those that need to be posted on their own, aren't held.
+  pub fn is_digestible(&self) -> bool {
+    !self.digest
+      && self.has_text()
+      && !self.has_media()
+      && self.event_types() == ["m.room.message"]
+      && self.geo.is_none()
+      && self.room.is_none()
+      && self.edits.is_none()
+      && self.in_reply_to.is_none()
+      && self.thread_root.is_none()
+      && self.ttl_seconds.is_none()
+      && !self.mention_room
+      && matches!(self.priority, Priority::Low | Priority::Normal)
+  }
+
+  /// Posted as a notice, for messages bots send
+  pub fn as_notice(mut self) -> Self {
+    self.message_type = MsgType::Notice;
+    self
+  }
+
   pub fn create_message(&self) -> MessageEventContent {
     use Format::*;
     use MsgType::*;
 
     let parsed = self.parse_text();
-    match (&self.message_type, &self.format) {
-      (Regular, Plain) => MessageEventContent::text_plain(parsed),
-      (Regular, Html) => MessageEventContent::text_html(Self::html_to_text(&parsed), parsed),
-      (Notice, Plain) => MessageEventContent::notice_plain(parsed),
-      (Notice, Html) => MessageEventContent::notice_html(Self::html_to_text(&parsed), parsed),
-      (Emote, Plain) => {
-        MessageEventContent::new(MessageType::Emote(EmoteMessageEventContent::plain(parsed)))
+    let code = self
+      .code
+      .as_ref()
+      .filter(|code| **code != CodeBlock::Plain(false));
+    let (plain, html) = match (code, &self.format) {
+      // Emoji names are left as they are, since code is verbatim
+      (Some(code), _) => {
+        let text = ansi::strip(&self.text);
+        let html = code.html(&text);
+        (text, Some(html))
+      }
+      (None, Plain) => (parsed, None),
+      (None, Ansi) => (
+        ansi::strip(&self.text),
+        Some(format!(
+          "<pre><code>{}</code></pre>",
+          ansi::to_html(&self.text)
+        )),
+      ),
+      (None, Html) => {
+        let html = sanitize(&parsed, &self.html_policy);
+        match &self.plain_text {
+          Some(plain) => (self.clean_text(plain), Some(html)),
+          None => (Self::html_to_text(&html), Some(html)),
+        }
+      }
+      // Markdown can have HTML in it too
+      (None, Markdown) => {
+        let html = if self.math {
+          let (markdown, maths) = math::extract(&parsed);
+          math::insert(&Self::markdown_to_html(&markdown), &maths)
+        } else {
+          Self::markdown_to_html(&parsed)
+        };
+        let html = sanitize(&html, &self.html_policy);
+        (hide_spoilers(&align_tables(&parsed)), Some(html))
+      }
+    };
+    let (plain, html) = if self.attachments.is_empty() {
+      (plain, html)
+    } else {
+      let html = html.unwrap_or_else(|| bot::escape_html(&plain).replace('\n', "<br>"));
+      let (attachments_plain, attachments_html) = self.render_attachments().into_parts();
+      (
+        format!("{}\n{}", plain, self.clean_text(&attachments_plain)),
+        Some(html + &self.clean_text(&attachments_html)),
+      )
+    };
+    let (plain, html) = match &self.preview {
+      Some((preview_plain, preview_html)) => {
+        let html = html.unwrap_or_else(|| bot::escape_html(&plain).replace('\n', "<br>"));
+        (
+          format!("{}\n{}", plain, preview_plain),
+          Some(html + preview_html),
+        )
+      }
+      None => (plain, html),
+    };
+    // Pills need html, so plain text that mentions anyone gets it too
+    let mentioned = self.mentioned_user_ids();
+    let needs_html = (plain.contains('@') && !mentioned.is_empty())
+      || mentions::has_link_pills(&plain)
+      || !self.custom_emoji.is_empty();
+    let html = match html {
+      Some(html) => Some(html),
+      None if needs_html => Some(bot::escape_html(&plain).replace('\n', "<br>")),
+      None => None,
+    };
+    let html = html.map(|html| {
+      let html = mentions::pills(&mentions::link_pills(&html), &mentioned);
+      emoji::custom_emoji_html(&html, &self.custom_emoji)
+    });
+    // Older clients ping the room for @room in the body
+    let (plain, html) = if self.mention_room {
+      (
+        format!("@room {}", plain),
+        html.map(|html| format!("@room {}", html)),
+      )
+    } else {
+      (plain, html)
+    };
+    let message_type = match (&self.priority, &self.message_type) {
+      (Priority::Low, Regular) => &Notice,
+      (Priority::High | Priority::Critical, Notice) => &Regular,
+      (_, message_type) => message_type,
+    };
+    match (message_type, html) {
+      (Regular, None) => MessageEventContent::text_plain(plain),
+      (Regular, Some(html)) => MessageEventContent::text_html(plain, html),
+      (Notice, None) => MessageEventContent::notice_plain(plain),
+      (Notice, Some(html)) => MessageEventContent::notice_html(plain, html),
+      (Emote, None) => {
+        MessageEventContent::new(MessageType::Emote(EmoteMessageEventContent::plain(plain)))
       }
-      (Emote, Html) => MessageEventContent::new(MessageType::Emote(
-        EmoteMessageEventContent::html(Self::html_to_text(&parsed), parsed),
+      (Emote, Some(html)) => MessageEventContent::new(MessageType::Emote(
+        EmoteMessageEventContent::html(plain, html),
       )),
     }
   }
 
-  pub fn get_display_name(&self) -> String {
+  /// The request's display name, or else the default for the hook
+  pub fn get_display_name(&self, default: &str) -> String {
     let name = if let Some(name) = self.display_name.clone() {
       name
     } else if let Some(name) = self.username.clone() {
       name
     } else {
-      "Incoming Webhook".to_string()
+      default.to_string()
     };
-    if self.emoji {
-      emoji::replace_emoji(&name)
-    } else {
-      name
+    self.clean_text(&name)
+  }
+
+  pub fn get_room(&self) -> Option<&str> {
+    self.room.as_deref()
+  }
+
+  pub fn get_in_reply_to(&self) -> Option<&str> {
+    self.in_reply_to.as_deref()
+  }
+
+  /// The users the message pings, for its `m.mentions`
+  pub fn get_mentions(&self) -> Result<Vec<String>, HookError> {
+    let mentioned = self.mentioned_user_ids();
+    for user_id in &mentioned {
+      UserId::try_from(user_id.as_str())
+        .map_err(|_| HookError::InvalidPayload(format!("{} isn't a user id", user_id)))?;
+    }
+    if self.is_quiet() {
+      return Ok(Vec::new());
+    }
+    Ok(mentioned)
+  }
+
+  /// Whether the message is low priority, so it shouldn't ping anyone
+  pub fn is_quiet(&self) -> bool {
+    self.priority == Priority::Low
+  }
+
+  pub fn is_critical(&self) -> bool {
+    self.priority == Priority::Critical
+  }
+
+  /// Pings the whole room, as if the request had `mentionRoom`
+  pub fn with_room_mention(mut self) -> Self {
+    self.mention_room = true;
+    self
+  }
+
+  /// When to post the message, as a unix timestamp, if the request says
+  pub fn get_send_at(&self) -> Result<Option<i64>, HookError> {
+    match &self.send_at {
+      Some(send_at) => chrono::DateTime::parse_from_rfc3339(send_at)
+        .map(|time| Some(time.timestamp()))
+        .map_err(|_| {
+          HookError::InvalidPayload(format!(
+            "{:?} isn't an RFC 3339 timestamp, like 2021-11-01T09:00:00Z",
+            send_at
+          ))
+        }),
+      None => Ok(None),
+    }
+  }
+
+  pub fn get_ttl_seconds(&self) -> Option<u64> {
+    self.ttl_seconds
+  }
+
+  pub fn mentions_room(&self) -> bool {
+    self.mention_room
+  }
+
+  fn mentioned_user_ids(&self) -> Vec<String> {
+    let mut mentioned = self.mentions.clone();
+    for user_id in mentions::find_user_ids(&self.text) {
+      if !mentioned.contains(&user_id) {
+        mentioned.push(user_id);
+      }
     }
+    mentioned
+  }
+
+  pub fn get_thread_root(&self) -> Option<&str> {
+    self.thread_root.as_deref()
+  }
+
+  pub fn get_edits(&self) -> Option<&str> {
+    self.edits.as_deref()
+  }
+
+  /// As an edit of the event, for a PUT to one of the hook's messages
+  pub fn with_edits(mut self, event_id: String) -> Self {
+    self.edits = Some(event_id);
+    self
+  }
+
+  pub fn get_image_url(&self) -> Option<&str> {
+    self.image_url.as_deref()
+  }
+
+  pub fn get_audio_url(&self) -> Option<&str> {
+    self.audio_url.as_deref()
+  }
+
+  pub fn get_video_url(&self) -> Option<&str> {
+    self.video_url.as_deref()
+  }
+
+  pub fn get_file_url(&self) -> Option<&str> {
+    self.file_url.as_deref()
+  }
+
+  /// With files uploaded along with the request
+  pub fn with_files(mut self, files: Vec<Media>) -> Self {
+    self.files = files;
+    self
+  }
+
+  pub fn has_files(&self) -> bool {
+    !self.files.is_empty()
+  }
+
+  pub fn take_files(&mut self) -> Vec<Media> {
+    std::mem::take(&mut self.files)
   }
 
-  pub fn get_avatar_url(&self) -> Option<String> {
+  /// Whether there's an image, audio, video or file to post
+  pub fn has_media(&self) -> bool {
+    self.image_url.is_some()
+      || self.audio_url.is_some()
+      || self.video_url.is_some()
+      || self.file_url.is_some()
+      || !self.files.is_empty()
+  }
+
+  /// Whether there's a text message to post, which media and locations may go without
+  pub fn has_text(&self) -> bool {
+    !self.text.trim().is_empty()
+      || !self.attachments.is_empty()
+      || (!self.has_media()
+        && self.geo.is_none()
+        && self.reaction.is_none()
+        && self.event_type.is_none()
+        && self.room_state.is_none())
+  }
+
+  /// The types of event the request sends, so the webhook user's power level can be checked first
+  pub fn event_types(&self) -> Vec<&str> {
+    let mut event_types = Vec::new();
+    if self.has_text() || self.has_media() || self.geo.is_some() {
+      event_types.push("m.room.message");
+    }
+    if let Some(event_type) = &self.event_type {
+      event_types.push(event_type);
+    }
+    if self.reaction.is_some() {
+      event_types.push("m.reaction");
+    }
+    event_types
+  }
+
+  /// The custom event's type and content, if the payload has one. Types in the spec's `m.`
+  /// namespace are left to the fields for them, which check what they send.
+  pub fn create_custom_event(&self) -> Result<Option<(String, serde_json::Value)>, HookError> {
+    let event_type = match &self.event_type {
+      Some(event_type) => event_type,
+      None => return Ok(None),
+    };
+    if event_type.is_empty() || event_type.starts_with("m.") {
+      return Err(HookError::InvalidPayload(format!(
+        "{:?} isn't a custom event type, like com.example.metric",
+        event_type
+      )));
+    }
+    match &self.content {
+      Some(content) if content.is_object() => Ok(Some((event_type.clone(), content.clone()))),
+      _ => Err(HookError::InvalidPayload(
+        "A custom event needs a content object".to_string(),
+      )),
+    }
+  }
+
+  /// The m.location for the payload's geo field, if it has one
+  pub fn create_location_message(&self) -> Result<Option<MessageEventContent>, HookError> {
+    let geo = match &self.geo {
+      Some(geo) => geo,
+      None => return Ok(None),
+    };
+    if !(-90.0..=90.0).contains(&geo.lat) || !(-180.0..=180.0).contains(&geo.lon) {
+      return Err(HookError::InvalidPayload(format!(
+        "{}, {} isn't a latitude and longitude",
+        geo.lat, geo.lon
+      )));
+    }
+    let body = match &geo.description {
+      Some(description) => self.clean_text(description),
+      None => format!("Location {}, {}", geo.lat, geo.lon),
+    };
+    let geo_uri = format!("geo:{},{}", geo.lat, geo.lon);
+    Ok(Some(MessageEventContent::new(MessageType::Location(
+      LocationMessageEventContent::new(body, geo_uri),
+    ))))
+  }
+
+  pub fn get_reaction_target(&self) -> Option<&str> {
+    self
+      .reaction
+      .as_ref()
+      .map(|reaction| reaction.event_id.as_str())
+  }
+
+  /// The m.reaction content for the payload's reaction, which can name its emoji like :tada:
+  pub fn create_reaction(&self) -> Result<Option<serde_json::Value>, HookError> {
+    let reaction = match &self.reaction {
+      Some(reaction) => reaction,
+      None => return Ok(None),
+    };
+    if reaction.key.trim().is_empty() {
+      return Err(HookError::InvalidPayload(
+        "A reaction needs a key, like an emoji".to_string(),
+      ));
+    }
+    Ok(Some(serde_json::json!({
+      "m.relates_to": {
+        "rel_type": "m.annotation",
+        "event_id": reaction.event_id,
+        "key": self.clean_text(&reaction.key),
+      }
+    })))
+  }
+
+  /// The m.room.name and m.room.topic events for the payload's room state, by type
+  pub fn create_state_events(&self) -> Vec<(&'static str, serde_json::Value)> {
+    let mut events = Vec::new();
+    if let Some(room_state) = &self.room_state {
+      if let Some(name) = &room_state.name {
+        events.push((
+          "m.room.name",
+          serde_json::json!({"name": self.clean_text(name)}),
+        ));
+      }
+      if let Some(topic) = &room_state.topic {
+        events.push((
+          "m.room.topic",
+          serde_json::json!({"topic": self.clean_text(topic)}),
+        ));
+      }
+    }
+    events
+  }
+
+  /// The request's avatar, or else the default for the hook
+  pub fn get_avatar_url(&self, default: Option<&str>) -> Option<String> {
     if let Some(url) = self.avatar_url.clone() {
       Some(url)
+    } else if let Some(url) = self.icon_url.clone() {
+      Some(url)
     } else {
-      self.icon_url.clone()
+      default.map(str::to_string)
+    }
+  }
+
+  fn render_attachments(&self) -> Message {
+    let mut message = Message::default();
+    for attachment in &self.attachments {
+      let mut quoted = Message::default();
+      if let Some(color) = &attachment.color {
+        quoted.color_bar(&payloads::slack::color(color));
+      }
+      if let Some(title) = &attachment.title {
+        quoted.title(title, attachment.title_link.as_deref()).line();
+      }
+      if let Some(text) = &attachment.text {
+        for (i, line) in text.lines().enumerate() {
+          if i > 0 {
+            quoted.newline();
+          }
+          quoted.text(line);
+        }
+      }
+      let fields = attachment
+        .fields
+        .iter()
+        .map(|field| (field.title.as_str(), field.value.as_str()))
+        .collect::<Vec<_>>();
+      quoted.table(&fields);
+      if let Some(image_url) = &attachment.image_url {
+        quoted.line().link(image_url, image_url);
+      }
+      if let Some(footer) = &attachment.footer {
+        quoted.line().italic(footer);
+      }
+      message.quote(quoted);
     }
+    message
   }
 
   fn parse_text(&self) -> String {
-    if self.emoji {
-      emoji::replace_emoji(&self.text)
+    self.clean_text(&self.text)
+  }
+
+  // Without control characters, which could garble how clients show it, and with emoji names
+  // replaced if they are
+  fn clean_text(&self, raw: &str) -> String {
+    let raw = ansi::strip(raw);
+    if self.emoji.unwrap_or(true) {
+      emoji::replace_emoji(&raw)
     } else {
-      self.text.clone()
+      raw
     }
   }
 
+  pub fn markdown_to_html(raw: &str) -> String {
+    let mut html = String::new();
+    let options = Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES;
+    html::push_html(
+      &mut html,
+      with_spoilers(Parser::new_ext(raw, options)).into_iter(),
+    );
+    html.trim_end().to_string()
+  }
+
+  // Spoilers stay hidden, like in clients that show them
   fn html_to_text(raw: &str) -> String {
+    use scraper::node::Node;
+
+    let is_spoiler = |node: &Node| match node {
+      Node::Element(element) => element.attr("data-mx-spoiler").is_some(),
+      _ => false,
+    };
     let frag = scraper::Html::parse_fragment(raw);
-    frag
-      .tree
-      .into_iter()
-      .filter_map(|node| {
-        if let scraper::node::Node::Text(text) = node {
-          Some(text.text.to_string())
-        } else {
-          None
+    let mut text = String::new();
+    for node in frag.tree.root().descendants() {
+      if node
+        .ancestors()
+        .any(|ancestor| is_spoiler(ancestor.value()))
+      {
+        continue;
+      }
+      match node.value() {
+        Node::Text(node_text) => text.push_str(&node_text.text),
+        node if is_spoiler(node) => text.push_str(SPOILER_TEXT),
+        _ => {}
+      }
+    }
+    text
+  }
+}
+
+const SPOILER_TEXT: &str = "[spoiler]";
+
+// Markdown spoilers are ||between bars||, which CommonMark doesn't have, so they're picked out of
+// its text. Code is left alone, and a spoiler still open at the end of a block is closed there.
+fn with_spoilers<'a>(events: impl Iterator<Item = Event<'a>>) -> Vec<Event<'a>> {
+  let mut out = Vec::new();
+  let mut open = false;
+  let mut in_code = false;
+  for event in events {
+    match event {
+      Event::Text(text) if !in_code && text.contains("||") => {
+        for (i, part) in text.split("||").enumerate() {
+          if i > 0 {
+            let tag = if open {
+              "</span>"
+            } else {
+              "<span data-mx-spoiler>"
+            };
+            out.push(Event::Html(tag.into()));
+            open = !open;
+          }
+          if !part.is_empty() {
+            out.push(Event::Text(part.to_string().into()));
+          }
+        }
+      }
+      Event::Start(Tag::CodeBlock(_)) => {
+        in_code = true;
+        out.push(event);
+      }
+      Event::End(Tag::CodeBlock(_)) => {
+        in_code = false;
+        out.push(event);
+      }
+      Event::End(Tag::Paragraph | Tag::Heading(_) | Tag::Item | Tag::TableCell) if open => {
+        out.push(Event::Html("</span>".into()));
+        open = false;
+        out.push(event);
+      }
+      event => out.push(event),
+    }
+  }
+  out
+}
+
+// The plain text version of markdown with spoilers, which would otherwise give them away
+fn hide_spoilers(markdown: &str) -> String {
+  markdown
+    .split("||")
+    .enumerate()
+    .map(|(i, part)| if i % 2 == 1 { SPOILER_TEXT } else { part })
+    .collect()
+}
+
+// The plain text version of markdown's tables, with their columns lined up so they can still be
+// read. Those in quotes and lists are left as they are, since lining them up would lose their
+// markers.
+fn align_tables(markdown: &str) -> String {
+  let options = Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES;
+  let mut out = String::new();
+  let mut copied = 0;
+  let mut nesting = 0;
+  let mut alignments = None;
+  let mut rows: Vec<Vec<&str>> = Vec::new();
+  for (event, range) in Parser::new_ext(markdown, options).into_offset_iter() {
+    match event {
+      Event::Start(Tag::BlockQuote | Tag::List(_)) => nesting += 1,
+      Event::End(Tag::BlockQuote | Tag::List(_)) => nesting -= 1,
+      Event::Start(Tag::Table(table_alignments)) if nesting == 0 => {
+        alignments = Some(table_alignments);
+        rows.clear();
+      }
+      Event::Start(Tag::TableHead | Tag::TableRow) if alignments.is_some() => rows.push(Vec::new()),
+      Event::Start(Tag::TableCell) if alignments.is_some() => {
+        if let Some(row) = rows.last_mut() {
+          row.push(&markdown[range]);
         }
+      }
+      Event::End(Tag::Table(_)) => {
+        if let Some(alignments) = alignments.take() {
+          out.push_str(&markdown[copied..range.start]);
+          out.push_str(&format_table(&alignments, &rows));
+          if markdown[range.clone()].ends_with('\n') {
+            out.push('\n');
+          }
+          copied = range.end;
+        }
+      }
+      _ => {}
+    }
+  }
+  out.push_str(&markdown[copied..]);
+  out
+}
+
+fn format_table<'a>(alignments: &[Alignment], rows: &[Vec<&'a str>]) -> String {
+  // Rows can have fewer cells than the table has columns
+  let cell = |row: &Vec<&'a str>, column: usize| row.get(column).copied().unwrap_or_default();
+  let widths: Vec<usize> = (0..alignments.len())
+    .map(|column| {
+      rows
+        .iter()
+        .map(|row| cell(row, column).chars().count())
+        .max()
+        .unwrap_or_default()
+        .max(3)
+    })
+    .collect();
+  let line = |cells: Vec<String>| format!("| {} |", cells.join(" | "));
+
+  let mut lines = Vec::new();
+  for (i, row) in rows.iter().enumerate() {
+    let cells = alignments
+      .iter()
+      .zip(&widths)
+      .enumerate()
+      .map(|(column, (alignment, width))| {
+        let text = cell(row, column);
+        let padding = width - text.chars().count();
+        let (before, after) = match alignment {
+          Alignment::Right => (padding, 0),
+          Alignment::Center => (padding / 2, padding - padding / 2),
+          Alignment::Left | Alignment::None => (0, padding),
+        };
+        format!("{}{}{}", " ".repeat(before), text, " ".repeat(after))
       })
-      .collect::<Vec<String>>()
-      .join("")
+      .collect();
+    lines.push(line(cells));
+    // The header is followed by the row saying how the columns are aligned
+    if i == 0 {
+      let delimiters = alignments
+        .iter()
+        .zip(&widths)
+        .map(|(alignment, width)| match alignment {
+          Alignment::Left => format!(":{}", "-".repeat(width - 1)),
+          Alignment::Right => format!("{}:", "-".repeat(width - 1)),
+          Alignment::Center => format!(":{}:", "-".repeat(width - 2)),
+          Alignment::None => "-".repeat(*width),
+        })
+        .collect();
+      lines.push(line(delimiters));
+    }
   }
+  lines.join("\n")
 }
 
 #[cfg(test)]
@@ -134,10 +990,39 @@ mod tests {
     let expected = WebhookRequest {
       text: "Hello world!".into(),
       format: Format::Plain,
+      math: false,
+      code: None,
+      template: None,
+      vars: None,
       display_name: Some("My Cool Webhook".into()),
       avatar_url: Some("http://i.imgur.com/IDOBtEJ.png".into()),
-      emoji: true,
+      emoji: None,
       message_type: MsgType::Regular,
+      room: None,
+      image_url: None,
+      audio_url: None,
+      video_url: None,
+      file_url: None,
+      geo: None,
+      reaction: None,
+      event_type: None,
+      content: None,
+      room_state: None,
+      send_at: None,
+      ttl_seconds: None,
+      mentions: Vec::new(),
+      mention_room: false,
+      priority: Priority::Normal,
+      in_reply_to: None,
+      thread_root: None,
+      edits: None,
+      files: Vec::new(),
+      attachments: vec![],
+      plain_text: None,
+      html_policy: Html::default(),
+      custom_emoji: HashMap::new(),
+      preview: None,
+      digest: false,
       icon_url: None,
       username: None,
     };
@@ -156,6 +1041,69 @@ mod tests {
     Ok(())
   }
 
+  #[test]
+  fn test_from_text() -> Result<()> {
+    let request = WebhookRequest::from_text("Backup <done> & *dusted*".to_string());
+    if let MessageType::Text(actual_message) = request.create_message().msgtype {
+      assert_eq!("Backup <done> & *dusted*", actual_message.body);
+      assert!(actual_message.formatted.is_none());
+    } else {
+      panic!("Not text");
+    }
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_from_form() -> Result<()> {
+    let request = WebhookRequest::from_form(
+      b"payload=%7B%22text%22%3A%22Deployed%22%2C%22username%22%3A%22ci%22%7D",
+    )?;
+    assert_eq!(request.text, "Deployed");
+    assert_eq!(request.get_display_name("hook"), "ci");
+
+    let request = WebhookRequest::from_form(
+      b"text=*Deployed*+to+prod&format=markdown&emoji=false&ttlSeconds=60&displayName=CI",
+    )?;
+    assert_eq!(request.text, "*Deployed* to prod");
+    assert_eq!(request.format, Format::Markdown);
+    assert_eq!(request.emoji, Some(false));
+    assert_eq!(request.ttl_seconds, Some(60));
+    assert_eq!(request.get_display_name("hook"), "CI");
+
+    assert!(WebhookRequest::from_form(b"payload=%7Bnope").is_err());
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_priority() -> Result<()> {
+    let message_type = |raw_json: &str| -> Result<MessageType> {
+      let parsed = serde_json::from_str::<WebhookRequest>(raw_json)?;
+      Ok(parsed.create_message().msgtype)
+    };
+    assert!(matches!(
+      message_type(r#"{"text": "Disk 80% full", "priority": "low"}"#)?,
+      MessageType::Notice(_)
+    ));
+    assert!(matches!(
+      message_type(r#"{"text": "Disk full", "msgtype": "notice", "priority": "high"}"#)?,
+      MessageType::Text(_)
+    ));
+    assert!(matches!(
+      message_type(r#"{"text": "Disk full", "msgtype": "emote", "priority": "critical"}"#)?,
+      MessageType::Emote(_)
+    ));
+
+    let quiet = serde_json::from_str::<WebhookRequest>(
+      r#"{"text": "cc @alice:example.com", "priority": "low"}"#,
+    )?;
+    assert!(quiet.get_mentions()?.is_empty());
+    assert!(serde_json::from_str::<WebhookRequest>(r#"{"priority": "urgent"}"#).is_err());
+
+    Ok(())
+  }
+
   #[test]
   fn test_html() -> Result<()> {
     let raw_json = r#"
@@ -267,11 +1215,184 @@ mod tests {
       formatted.body,
       "<b>foo❤️❤️</b> <br><ol><li>aa</li> <li>bb</li></ol>"
     );
-    assert_eq!(parsed.get_display_name(), "My Cool Webhook ❤️");
+    assert_eq!(parsed.get_display_name("Webhook"), "My Cool Webhook ❤️");
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_markdown() -> Result<()> {
+    let raw_json = r#"
+    {
+      "text": "**Build** passed :heart:\n\n- [log](https://ci.example.com/1)",
+      "format": "markdown"
+  }"#;
+
+    let parsed = serde_json::from_str::<WebhookRequest>(raw_json)?;
+    let actual = if let MessageType::Text(actual_message) = parsed.create_message().msgtype {
+      actual_message
+    } else {
+      panic!("Not text");
+    };
+
+    assert_eq!(
+      actual.body,
+      "**Build** passed ❤️\n\n- [log](https://ci.example.com/1)"
+    );
+    assert_eq!(
+      actual.formatted.unwrap().body,
+      "<p><strong>Build</strong> passed ❤️</p>\n<ul>\n<li><a href=\"https://ci.example.com/1\">log</a></li>\n</ul>"
+    );
 
     Ok(())
   }
 
+  #[test]
+  fn test_tables() -> Result<()> {
+    let parsed = serde_json::from_str::<WebhookRequest>(
+      r#"{"text": "Load:\n\n| Host | Load |\n|:--|--:|\n| db1.example.com | 0.5 |\n| db2 | 12 |", "format": "markdown"}"#,
+    )?;
+    let text = match parsed.create_message().msgtype {
+      MessageType::Text(text) => text,
+      _ => panic!("Not text"),
+    };
+    assert_eq!(
+      text.body,
+      "Load:\n\n| Host            | Load |\n| :-------------- | ---: |\n| db1.example.com |  0.5 |\n\
+      | db2             |   12 |"
+    );
+    assert!(text
+      .formatted
+      .unwrap()
+      .body
+      .contains("<table><thead><tr><th>Host</th>"));
+    Ok(())
+  }
+
+  #[test]
+  fn test_code() -> Result<()> {
+    let html = |json: &str| -> Result<String> {
+      match serde_json::from_str::<WebhookRequest>(json)?
+        .create_message()
+        .msgtype
+      {
+        MessageType::Text(text) => Ok(text.formatted.unwrap().body),
+        _ => panic!("Not text"),
+      }
+    };
+    assert_eq!(
+      html(r#"{"text": "```rust\nfn main() {}\n```", "format": "markdown"}"#)?,
+      "<pre><code class=\"language-rust\">fn main() {}\n</code></pre>"
+    );
+    assert_eq!(
+      html(r#"{"text": "if a < b { panic!(\":boom:\") }", "code": "rust"}"#)?,
+      "<pre><code class=\"language-rust\">if a &lt; b { panic!(&quot;:boom:&quot;) }</code></pre>"
+    );
+    assert_eq!(
+      html(r#"{"text": "**not bold**", "format": "markdown", "code": true}"#)?,
+      "<pre><code>**not bold**</code></pre>"
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn test_send_at() -> Result<()> {
+    let parsed = serde_json::from_str::<WebhookRequest>(
+      r#"{"text": "Maintenance starts now", "sendAt": "2021-11-01T09:00:00+01:00", "mentions": ["@ops:example.com"]}"#,
+    )?;
+    assert_eq!(parsed.get_send_at()?, Some(1635753600));
+    // It's kept as JSON until it's sent
+    let kept = serde_json::from_str::<WebhookRequest>(&serde_json::to_string(&parsed)?)?;
+    assert_eq!(kept, parsed);
+
+    let parsed = serde_json::from_str::<WebhookRequest>(r#"{"sendAt": "tomorrow"}"#)?;
+    assert!(parsed.get_send_at().is_err());
+    Ok(())
+  }
+
+  #[test]
+  fn test_template() -> Result<()> {
+    let parsed = serde_json::from_str::<WebhookRequest>(
+      r#"{"template": "deploy", "vars": {"service": "api", "ok": true}}"#,
+    )?;
+    assert_eq!(parsed.get_template(), Some("deploy"));
+    let rendered =
+      parsed.with_template("{% if ok %}**{{ service }}** deployed{% else %}Failed{% endif %}")?;
+    assert_eq!(rendered.text, "**api** deployed");
+    assert_eq!(rendered.format, Format::Markdown);
+    Ok(())
+  }
+
+  #[test]
+  fn test_control_characters() -> Result<()> {
+    let text = |json: &str| -> Result<(String, Option<String>)> {
+      match serde_json::from_str::<WebhookRequest>(json)?
+        .create_message()
+        .msgtype
+      {
+        MessageType::Text(text) => Ok((text.body, text.formatted.map(|html| html.body))),
+        _ => panic!("Not text"),
+      }
+    };
+    assert_eq!(
+      text(r#"{"text": "\u001b[31mFAIL\u001b[0m\u0000 tests\r\n"}"#)?,
+      ("FAIL tests\n".to_string(), None)
+    );
+    assert_eq!(
+      text(r#"{"text": "\u001b[31mFAIL\u001b[0m <lib>", "format": "ansi"}"#)?,
+      (
+        "FAIL <lib>".to_string(),
+        Some(
+          "<pre><code><span data-mx-color=\"#cd0000\">FAIL</span> &lt;lib&gt;</code></pre>"
+            .to_string()
+        )
+      )
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn test_math() -> Result<()> {
+    let parsed = serde_json::from_str::<WebhookRequest>(
+      r#"{"text": "p99 is $x_1 * y_2$, up $5", "format": "markdown", "math": true}"#,
+    )?;
+    let text = match parsed.create_message().msgtype {
+      MessageType::Text(text) => text,
+      _ => panic!("Not text"),
+    };
+    assert_eq!(text.body, "p99 is $x_1 * y_2$, up $5");
+    assert_eq!(
+      text.formatted.unwrap().body,
+      "<p>p99 is <span data-mx-maths=\"x_1 * y_2\"><code>x_1 * y_2</code></span>, up $5</p>"
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn test_spoilers() -> Result<()> {
+    let parsed = serde_json::from_str::<WebhookRequest>(
+      r#"{"text": "The answer is ||42||, `not ||43||`", "format": "markdown"}"#,
+    )?;
+    let text = match parsed.create_message().msgtype {
+      MessageType::Text(text) => text,
+      _ => panic!("Not text"),
+    };
+    assert_eq!(text.body, "The answer is [spoiler], `not [spoiler]`");
+    assert_eq!(
+      text.formatted.unwrap().body,
+      "<p>The answer is <span data-mx-spoiler=\"\">42</span>, <code>not ||43||</code></p>"
+    );
+
+    let parsed = serde_json::from_str::<WebhookRequest>(
+      r#"{"text": "The answer is <span data-mx-spoiler>4<b>2</b></span>", "format": "html"}"#,
+    )?;
+    match parsed.create_message().msgtype {
+      MessageType::Text(text) => assert_eq!(text.body, "The answer is [spoiler]"),
+      _ => panic!("Not text"),
+    }
+    Ok(())
+  }
+
   #[test]
   fn test_slack_like() -> Result<()> {
     let raw_json = r#"
@@ -283,11 +1404,229 @@ mod tests {
   }"#;
 
     let parsed = serde_json::from_str::<WebhookRequest>(raw_json)?;
-    assert_eq!(parsed.get_display_name(), "My Cool Webhook ❤️");
+    assert_eq!(parsed.get_display_name("Webhook"), "My Cool Webhook ❤️");
     assert_eq!(
-      parsed.get_avatar_url().unwrap(),
+      parsed.get_avatar_url(None).unwrap(),
       "https://i.imgur.com/IDOBtEJ.png"
     );
     Ok(())
   }
+
+  #[test]
+  fn test_attachments() -> Result<()> {
+    let raw_json = r#"
+    {
+      "text": "Deploy finished :rocket:",
+      "format": "plain",
+      "attachments": [{
+        "color": "danger",
+        "title": "api",
+        "title_link": "https://ci.example.com/7",
+        "fields": [{"title": "Env", "value": "prod"}, {"title": "Took", "value": "3m"}],
+        "footer": "deploybot"
+      }]
+  }"#;
+
+    let parsed = serde_json::from_str::<WebhookRequest>(raw_json)?;
+    let actual = if let MessageType::Text(actual_message) = parsed.create_message().msgtype {
+      actual_message
+    } else {
+      panic!("Not text");
+    };
+
+    assert_eq!(
+      actual.body,
+      "Deploy finished 🚀\n> api (https://ci.example.com/7)\n> Env: prod\n> Took: 3m\n> deploybot"
+    );
+    assert_eq!(
+      actual.formatted.unwrap().body,
+      "Deploy finished 🚀<blockquote><font data-mx-color=\"#a30200\">▌</font> \
+      <b><a href=\"https://ci.example.com/7\">api</a></b><br><table><tr><th>Env</th><td>prod</td></tr>\
+      <tr><th>Took</th><td>3m</td></tr></table><i>deploybot</i></blockquote>"
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_preview() -> Result<()> {
+    let raw_json = r#"{"text": "Released https://example.com/v2 <3", "format": "plain"}"#;
+
+    let parsed = serde_json::from_str::<WebhookRequest>(raw_json)?;
+    assert_eq!(
+      parsed.first_url().as_deref(),
+      Some("https://example.com/v2")
+    );
+    let parsed = parsed.with_preview(
+      "> v2".to_string(),
+      "<blockquote>v2</blockquote>".to_string(),
+    );
+    let actual = if let MessageType::Text(actual_message) = parsed.create_message().msgtype {
+      actual_message
+    } else {
+      panic!("Not text");
+    };
+
+    assert_eq!(actual.body, "Released https://example.com/v2 <3\n> v2");
+    assert_eq!(
+      actual.formatted.unwrap().body,
+      "Released https://example.com/v2 &lt;3<blockquote>v2</blockquote>"
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_image() -> Result<()> {
+    let parsed = serde_json::from_str::<WebhookRequest>(
+      r#"{"imageUrl": "https://grafana.example.com/render/load.png"}"#,
+    )?;
+    assert_eq!(
+      parsed.get_image_url(),
+      Some("https://grafana.example.com/render/load.png")
+    );
+    assert!(!parsed.has_text());
+
+    let parsed = serde_json::from_str::<WebhookRequest>(
+      r#"{"text": "Load", "format": "plain", "imageUrl": "https://grafana.example.com/load.png"}"#,
+    )?;
+    assert!(parsed.has_text());
+
+    let parsed =
+      serde_json::from_str::<WebhookRequest>(r#"{"fileUrl": "https://ci.example.com/build.log"}"#)?;
+    assert!(parsed.has_media());
+    assert!(!parsed.has_text());
+    Ok(())
+  }
+
+  #[test]
+  fn test_location() -> Result<()> {
+    let parsed = serde_json::from_str::<WebhookRequest>(
+      r#"{"geo": {"lat": 51.5, "lon": -0.125, "description": "Truck 12 :truck:"}}"#,
+    )?;
+    assert!(!parsed.has_text());
+    let location = match parsed.create_location_message()?.unwrap().msgtype {
+      MessageType::Location(location) => location,
+      _ => panic!("Not location"),
+    };
+    assert_eq!(location.body, "Truck 12 🚚");
+    assert_eq!(location.geo_uri, "geo:51.5,-0.125");
+
+    let parsed = serde_json::from_str::<WebhookRequest>(r#"{"geo": {"lat": 91, "lon": 0}}"#)?;
+    assert!(parsed.create_location_message().is_err());
+    Ok(())
+  }
+
+  #[test]
+  fn test_reaction() -> Result<()> {
+    let parsed = serde_json::from_str::<WebhookRequest>(
+      r#"{"reaction": {"eventId": "$deploy", "key": ":white_check_mark:"}}"#,
+    )?;
+    assert!(!parsed.has_text());
+    assert_eq!(parsed.event_types(), vec!["m.reaction"]);
+    assert_eq!(
+      parsed.create_reaction()?,
+      Some(serde_json::json!({
+        "m.relates_to": {"rel_type": "m.annotation", "event_id": "$deploy", "key": "✅"}
+      }))
+    );
+
+    let parsed = serde_json::from_str::<WebhookRequest>(
+      r#"{"text": "Deployed", "reaction": {"eventId": "$deploy", "key": " "}}"#,
+    )?;
+    assert_eq!(parsed.event_types(), vec!["m.room.message", "m.reaction"]);
+    assert!(parsed.create_reaction().is_err());
+    Ok(())
+  }
+
+  #[test]
+  fn test_mentions() -> Result<()> {
+    let parsed = serde_json::from_str::<WebhookRequest>(
+      r#"{"text": "Disk full, @alice:example.com", "mentions": ["@bob:example.com"]}"#,
+    )?;
+    assert_eq!(
+      parsed.get_mentions()?,
+      vec!["@bob:example.com", "@alice:example.com"]
+    );
+    let text = match parsed.create_message().msgtype {
+      MessageType::Text(text) => text,
+      _ => panic!("Not text"),
+    };
+    assert_eq!(text.body, "Disk full, @alice:example.com");
+    assert_eq!(
+      text.formatted.unwrap().body,
+      "Disk full, <a href=\"https://matrix.to/#/@alice:example.com\">@alice:example.com</a>"
+    );
+
+    let parsed =
+      serde_json::from_str::<WebhookRequest>(r#"{"text": "Disk full", "mentions": ["bob"]}"#)?;
+    assert!(parsed.get_mentions().is_err());
+
+    let parsed =
+      serde_json::from_str::<WebhookRequest>(r#"{"text": "Site down", "mentionRoom": true}"#)?;
+    assert!(parsed.mentions_room());
+    match parsed.create_message().msgtype {
+      MessageType::Text(text) => assert_eq!(text.body, "@room Site down"),
+      _ => panic!("Not text"),
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn test_custom_event() -> Result<()> {
+    let parsed = serde_json::from_str::<WebhookRequest>(
+      r#"{"eventType": "com.example.metric", "content": {"cpu": 0.5}}"#,
+    )?;
+    assert!(!parsed.has_text());
+    assert_eq!(parsed.event_types(), vec!["com.example.metric"]);
+    assert_eq!(
+      parsed.create_custom_event()?,
+      Some((
+        "com.example.metric".to_string(),
+        serde_json::json!({"cpu": 0.5})
+      ))
+    );
+
+    let parsed = serde_json::from_str::<WebhookRequest>(
+      r#"{"eventType": "m.room.redaction", "content": {"redacts": "$1"}}"#,
+    )?;
+    assert!(parsed.create_custom_event().is_err());
+    let parsed = serde_json::from_str::<WebhookRequest>(r#"{"eventType": "com.example.metric"}"#)?;
+    assert!(parsed.create_custom_event().is_err());
+    Ok(())
+  }
+
+  #[test]
+  fn test_room_state() -> Result<()> {
+    let parsed = serde_json::from_str::<WebhookRequest>(
+      r#"{"roomState": {"topic": "On call: @alice:example.com :pager:"}}"#,
+    )?;
+    assert!(!parsed.has_text());
+    assert_eq!(
+      parsed.create_state_events(),
+      vec![(
+        "m.room.topic",
+        serde_json::json!({"topic": "On call: @alice:example.com 📟"})
+      )]
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn test_hook_defaults() -> Result<()> {
+    // Neither is required
+    let parsed = serde_json::from_str::<WebhookRequest>(r#"{"text": "foo", "format": "plain"}"#)?;
+    assert_eq!(parsed.get_display_name("CI"), "CI");
+    assert_eq!(
+      parsed.get_avatar_url(Some("https://example.com/ci.png")),
+      Some("https://example.com/ci.png".to_string())
+    );
+    assert_eq!(parsed.get_avatar_url(None), None);
+
+    let parsed = serde_json::from_str::<WebhookRequest>(
+      r#"{"text": "foo", "format": "plain", "displayName": "Deploys"}"#,
+    )?;
+    assert_eq!(parsed.get_display_name("CI"), "Deploys");
+    Ok(())
+  }
 }